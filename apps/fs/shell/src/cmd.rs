@@ -24,13 +24,16 @@ type CmdHandler = fn(&str);
 const CMD_TABLE: &[(&str, CmdHandler)] = &[
     ("cat", do_cat),
     ("cd", do_cd),
+    ("df", do_df),
     ("echo", do_echo),
     ("exit", do_exit),
     ("help", do_help),
     ("ls", do_ls),
     ("mkdir", do_mkdir),
+    ("mv", do_mv),
     ("pwd", do_pwd),
     ("rm", do_rm),
+    ("sync", do_sync),
     ("uname", do_uname),
 ];
 
@@ -196,6 +199,86 @@ fn do_rm(args: &str) {
     }
 }
 
+fn do_mv(args: &str) {
+    let paths = args.split_whitespace().collect::<Vec<_>>();
+    if paths.len() < 2 {
+        print_err!("mv", "missing operand");
+        return;
+    }
+    let (srcs, dst) = (&paths[..paths.len() - 1], paths[paths.len() - 1]);
+    let dst_is_dir = fs::metadata(dst).map_or(false, |m| m.is_dir());
+    if srcs.len() > 1 && !dst_is_dir {
+        print_err!("mv", format_args!("target '{dst}'"), "Not a directory");
+        return;
+    }
+
+    for src in srcs {
+        // move into the directory, keeping the name
+        let target = if dst_is_dir {
+            let name = src.trim_end_matches('/').rsplit('/').next().unwrap_or(src);
+            String::from(dst.trim_end_matches('/')) + "/" + name
+        } else {
+            String::from(dst)
+        };
+        if let Err(e) = fs::rename(src, &target) {
+            print_err!(
+                "mv",
+                format_args!("cannot move '{src}' to '{target}'"),
+                e.as_str()
+            );
+        }
+    }
+}
+
+fn do_df(args: &str) {
+    let args = if args.is_empty() { "/" } else { args };
+
+    fn type_name(fs_type: u64) -> &'static str {
+        match fs_type {
+            0xef53 => "ext2",
+            0x4d44 => "vfat",
+            0x1373 => "devfs",
+            _ => "?",
+        }
+    }
+
+    fn df_one(path: &str) -> io::Result<()> {
+        let info = fs::statfs(path)?;
+        let kb = |blocks: u64| blocks * info.block_size() / 1024;
+        let total = kb(info.blocks());
+        let used = total - kb(info.free_blocks());
+        let avail = kb(info.avail_blocks());
+        // the share of what users may use, rounded up
+        let use_pct = if used + avail == 0 {
+            String::from("-")
+        } else {
+            libax::format!("{}%", (used * 100 + used + avail - 1) / (used + avail))
+        };
+        println!(
+            "{:<8} {:>10} {:>10} {:>10} {:>4} {:>8} {:>8} {}",
+            type_name(info.fs_type()),
+            total,
+            used,
+            avail,
+            use_pct,
+            info.files(),
+            info.free_files(),
+            path
+        );
+        Ok(())
+    }
+
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>4} {:>8} {:>8} Path",
+        "Type", "1K-blocks", "Used", "Available", "Use%", "Inodes", "IFree"
+    );
+    for path in args.split_whitespace() {
+        if let Err(e) = df_one(path) {
+            print_err!("df", path, e.as_str());
+        }
+    }
+}
+
 fn do_cd(mut args: &str) {
     if args.is_empty() {
         args = "/";
@@ -214,6 +297,12 @@ fn do_pwd(_args: &str) {
     println!("{}", pwd);
 }
 
+fn do_sync(_args: &str) {
+    if let Err(e) = fs::sync() {
+        print_err!("sync", e);
+    }
+}
+
 fn do_uname(_args: &str) {
     let arch = option_env!("ARCH").unwrap_or("");
     let platform = option_env!("PLATFORM").unwrap_or("");
@@ -92,6 +92,11 @@ impl VfsNodeOps for DirNode {
         Err(VfsError::PermissionDenied) // do not support to remove nodes dynamically
     }
 
+    fn rename(&self, src_path: &str, dst_path: &str) -> VfsResult {
+        log::debug!("rename at devfs: {} -> {}", src_path, dst_path);
+        Err(VfsError::PermissionDenied) // do not support to rename nodes dynamically
+    }
+
     axfs_vfs::impl_vfs_dir_default! {}
 }
 
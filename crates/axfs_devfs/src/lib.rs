@@ -16,9 +16,14 @@ pub use self::null::NullDev;
 pub use self::zero::ZeroDev;
 
 use alloc::sync::Arc;
-use axfs_vfs::{VfsNodeRef, VfsOps, VfsResult};
+use axfs_vfs::{FileSystemInfo, VfsNodeRef, VfsOps, VfsResult};
 use spin::once::Once;
 
+/// Filesystem type reported by `statfs`, that of the Linux devfs.
+const DEVFS_SUPER_MAGIC: u64 = 0x1373;
+/// Longest name of a device, limited by [`axfs_vfs::VfsDirEntry`].
+const MAX_NAME_LEN: u64 = 63;
+
 pub struct DeviceFileSystem {
     parent: Once<VfsNodeRef>,
     root: Arc<DirNode>,
@@ -51,6 +56,11 @@ impl VfsOps for DeviceFileSystem {
         Ok(())
     }
 
+    fn statfs(&self) -> VfsResult<FileSystemInfo> {
+        // devices take no space
+        Ok(FileSystemInfo::new(DEVFS_SUPER_MAGIC, 4096, MAX_NAME_LEN))
+    }
+
     fn root_dir(&self) -> VfsNodeRef {
         self.root.clone()
     }
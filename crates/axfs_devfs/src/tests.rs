@@ -8,6 +8,10 @@ fn test_devfs_ops(devfs: &DeviceFileSystem) -> VfsResult {
     const N: usize = 32;
     let mut buf = [1; N];
 
+    let info = devfs.statfs()?;
+    assert_eq!(info.fs_type(), 0x1373);
+    assert_eq!((info.blocks(), info.free_blocks(), info.files()), (0, 0, 0));
+
     let root = devfs.root_dir();
     assert!(root.get_attr()?.is_dir());
     assert_eq!(root.get_attr()?.file_type(), VfsNodeType::Dir);
@@ -9,10 +9,11 @@ mod structs;
 
 pub mod path;
 
-use alloc::sync::Arc;
+use alloc::{string::String, sync::Arc, vec::Vec};
 use axerrno::{ax_err, AxError, AxResult};
 
-pub use self::structs::{FileSystemInfo, VfsDirEntry, VfsNodeAttr, VfsNodePerm, VfsNodeType};
+pub use self::structs::{FileSystemInfo, VfsDirEntry, VfsFallocMode, VfsMountFlags};
+pub use self::structs::{VfsNodeAttr, VfsNodePerm, VfsNodeType};
 
 pub type VfsNodeRef = Arc<dyn VfsNodeOps>;
 
@@ -41,6 +42,11 @@ pub trait VfsOps: Send + Sync {
         ax_err!(Unsupported)
     }
 
+    /// Write all dirty data of the filesystem to its device.
+    fn sync(&self) -> VfsResult {
+        Ok(())
+    }
+
     /// Get the root directory of the filesystem.
     fn root_dir(&self) -> VfsNodeRef;
 }
@@ -60,6 +66,16 @@ pub trait VfsNodeOps: Send + Sync {
     /// Get the attributes of the node.
     fn get_attr(&self) -> VfsResult<VfsNodeAttr>;
 
+    /// Change the permission mode of the node.
+    fn set_perm(&self, _perm: VfsNodePerm) -> VfsResult {
+        ax_err!(Unsupported)
+    }
+
+    /// Change the owner and/or group of the node, `None` keeps them.
+    fn set_owner(&self, _uid: Option<u32>, _gid: Option<u32>) -> VfsResult {
+        ax_err!(Unsupported)
+    }
+
     // file operations:
 
     /// Read data from the file at given offset.
@@ -82,6 +98,17 @@ pub trait VfsNodeOps: Send + Sync {
         ax_err!(InvalidInput)
     }
 
+    /// Allocate, deallocate or zero the `len` bytes of the file from `offset`
+    /// on, as `mode` says.
+    fn fallocate(&self, _mode: VfsFallocMode, _offset: u64, _len: u64) -> VfsResult {
+        ax_err!(Unsupported)
+    }
+
+    /// Read the target of the symbolic link.
+    fn readlink(&self) -> VfsResult<String> {
+        ax_err!(InvalidInput)
+    }
+
     // directory operations:
 
     /// Get the parent directory of this directory. Return `None` if the node is a file.
@@ -105,10 +132,45 @@ pub trait VfsNodeOps: Send + Sync {
         ax_err!(Unsupported)
     }
 
+    /// Create a symbolic link with given `path` in the directory, pointing to
+    /// `target`.
+    fn symlink(&self, _path: &str, _target: &str) -> VfsResult {
+        ax_err!(Unsupported)
+    }
+
+    /// Rename or move the node at `src_path` to `dst_path`, both relative to
+    /// this directory. An existing node at `dst_path` is replaced.
+    fn rename(&self, _src_path: &str, _dst_path: &str) -> VfsResult {
+        ax_err!(Unsupported)
+    }
+
     /// Read directory entries into `dirents`, starting from `start_idx`.
     fn read_dir(&self, _start_idx: usize, _dirents: &mut [VfsDirEntry]) -> VfsResult<usize> {
         ax_err!(Unsupported)
     }
+
+    // extended attribute operations:
+
+    /// Get the value of the extended attribute `name`, such as `user.comment`.
+    fn getxattr(&self, _name: &str) -> VfsResult<Vec<u8>> {
+        ax_err!(Unsupported)
+    }
+
+    /// Set the extended attribute `name` to `value`, creating it if it does
+    /// not exist.
+    fn setxattr(&self, _name: &str, _value: &[u8]) -> VfsResult {
+        ax_err!(Unsupported)
+    }
+
+    /// List the names of the extended attributes of the node.
+    fn listxattr(&self) -> VfsResult<Vec<String>> {
+        ax_err!(Unsupported)
+    }
+
+    /// Remove the extended attribute `name`.
+    fn removexattr(&self, _name: &str) -> VfsResult {
+        ax_err!(Unsupported)
+    }
 }
 
 pub mod __priv {
@@ -1,4 +1,42 @@
-pub struct FileSystemInfo; // TODO
+/// Filesystem attributes, as reported by `statfs`.
+#[derive(Debug, Clone, Copy)]
+pub struct FileSystemInfo {
+    /// Magic number of the filesystem type.
+    fs_type: u64,
+    /// Size of blocks, in bytes.
+    block_size: u64,
+    /// Total number of blocks.
+    blocks: u64,
+    /// Number of free blocks.
+    free_blocks: u64,
+    /// Number of free blocks that unprivileged users may allocate.
+    avail_blocks: u64,
+    /// Total number of inodes.
+    files: u64,
+    /// Number of free inodes.
+    free_files: u64,
+    /// Filesystem ID.
+    fsid: u64,
+    /// Maximum length of file names, in bytes.
+    name_len: u64,
+    /// How the filesystem is mounted.
+    flags: VfsMountFlags,
+}
+
+bitflags::bitflags! {
+    /// How a filesystem is mounted, with the values of `statvfs`.
+    #[derive(Debug, Clone, Copy)]
+    pub struct VfsMountFlags: u64 {
+        /// Read-only.
+        const RDONLY = 1;
+        /// Set-user-ID and set-group-ID bits are ignored.
+        const NOSUID = 2;
+        /// Device files cannot be opened.
+        const NODEV = 4;
+        /// Files cannot be executed.
+        const NOEXEC = 8;
+    }
+}
 
 /// File (inode) attribute
 #[allow(dead_code)]
@@ -12,12 +50,29 @@ pub struct VfsNodeAttr {
     size: u64,
     /// Number of 512B blocks allocated.
     blocks: u64,
+    /// User ID of the owner.
+    uid: u32,
+    /// Group ID of the owner.
+    gid: u32,
+    /// Last access time, in seconds since the Unix epoch.
+    atime: u64,
+    /// Last modification time, in seconds since the Unix epoch.
+    mtime: u64,
+    /// Last status change time, in seconds since the Unix epoch.
+    ctime: u64,
 }
 
 bitflags::bitflags! {
     /// File (inode) permission mode.
     #[derive(Debug, Clone, Copy)]
     pub struct VfsNodePerm: u16 {
+        /// Set user ID on execution.
+        const SET_UID = 0o4000;
+        /// Set group ID on execution, new entries of a directory get its group.
+        const SET_GID = 0o2000;
+        /// Only owners may remove or rename entries of a directory.
+        const STICKY = 0o1000;
+
         /// Owner has read permission.
         const OWNER_READ = 0o400;
         /// Owner has write permission.
@@ -41,6 +96,21 @@ bitflags::bitflags! {
     }
 }
 
+bitflags::bitflags! {
+    /// How [`fallocate`](crate::VfsNodeOps::fallocate) changes a range of a
+    /// file, without flags the range is allocated and the file grows to
+    /// cover it.
+    #[derive(Debug, Clone, Copy)]
+    pub struct VfsFallocMode: u32 {
+        /// Do not change the file size.
+        const KEEP_SIZE = 0x01;
+        /// Deallocate the range, which then reads as zeros. Requires `KEEP_SIZE`.
+        const PUNCH_HOLE = 0x02;
+        /// Make the range read as zeros, with its blocks allocated.
+        const ZERO_RANGE = 0x10;
+    }
+}
+
 /// File (inode) type.
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -67,6 +137,91 @@ pub struct VfsDirEntry {
     d_name: [u8; 63],
 }
 
+impl FileSystemInfo {
+    /// Information of a filesystem without blocks or inodes to count.
+    pub const fn new(fs_type: u64, block_size: u64, name_len: u64) -> Self {
+        Self {
+            fs_type,
+            block_size,
+            blocks: 0,
+            free_blocks: 0,
+            avail_blocks: 0,
+            files: 0,
+            free_files: 0,
+            fsid: 0,
+            name_len,
+            flags: VfsMountFlags::empty(),
+        }
+    }
+
+    /// Set the total, free and available numbers of blocks
+    pub const fn with_blocks(mut self, blocks: u64, free: u64, avail: u64) -> Self {
+        self.blocks = blocks;
+        self.free_blocks = free;
+        self.avail_blocks = avail;
+        self
+    }
+
+    /// Set the total and free numbers of inodes
+    pub const fn with_files(mut self, files: u64, free: u64) -> Self {
+        self.files = files;
+        self.free_files = free;
+        self
+    }
+
+    /// Set the filesystem ID
+    pub const fn with_fsid(mut self, fsid: u64) -> Self {
+        self.fsid = fsid;
+        self
+    }
+
+    /// Set the mount flags
+    pub const fn with_flags(mut self, flags: VfsMountFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub const fn fs_type(&self) -> u64 {
+        self.fs_type
+    }
+
+    pub const fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub const fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub const fn free_blocks(&self) -> u64 {
+        self.free_blocks
+    }
+
+    pub const fn avail_blocks(&self) -> u64 {
+        self.avail_blocks
+    }
+
+    pub const fn files(&self) -> u64 {
+        self.files
+    }
+
+    pub const fn free_files(&self) -> u64 {
+        self.free_files
+    }
+
+    pub const fn fsid(&self) -> u64 {
+        self.fsid
+    }
+
+    pub const fn name_len(&self) -> u64 {
+        self.name_len
+    }
+
+    pub const fn flags(&self) -> VfsMountFlags {
+        self.flags
+    }
+}
+
 impl VfsNodePerm {
     pub const fn default_file() -> Self {
         Self::from_bits_truncate(0o666)
@@ -130,6 +285,10 @@ impl VfsNodeType {
         matches!(self, Self::Dir)
     }
 
+    pub const fn is_symlink(self) -> bool {
+        matches!(self, Self::SymLink)
+    }
+
     pub const fn as_char(self) -> char {
         match self {
             Self::Fifo => 'p',
@@ -150,6 +309,11 @@ impl VfsNodeAttr {
             ty,
             size,
             blocks,
+            uid: 0,
+            gid: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
         }
     }
 
@@ -159,6 +323,11 @@ impl VfsNodeAttr {
             ty: VfsNodeType::File,
             size,
             blocks,
+            uid: 0,
+            gid: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
         }
     }
 
@@ -168,13 +337,57 @@ impl VfsNodeAttr {
             ty: VfsNodeType::Dir,
             size,
             blocks,
+            uid: 0,
+            gid: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
         }
     }
 
+    /// Set the owner, which is root by default
+    pub const fn with_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Set the access, modification and status change times
+    pub const fn with_times(mut self, atime: u64, mtime: u64, ctime: u64) -> Self {
+        self.atime = atime;
+        self.mtime = mtime;
+        self.ctime = ctime;
+        self
+    }
+
     pub const fn size(&self) -> u64 {
         self.size
     }
 
+    pub const fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub const fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub const fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub const fn atime(&self) -> u64 {
+        self.atime
+    }
+
+    pub const fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub const fn ctime(&self) -> u64 {
+        self.ctime
+    }
+
     pub const fn perm(&self) -> VfsNodePerm {
         self.mode
     }
@@ -194,6 +407,10 @@ impl VfsNodeAttr {
     pub const fn is_dir(&self) -> bool {
         self.ty.is_dir()
     }
+
+    pub const fn is_symlink(&self) -> bool {
+        self.ty.is_symlink()
+    }
 }
 
 impl VfsDirEntry {
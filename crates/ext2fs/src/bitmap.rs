@@ -1,35 +1,39 @@
-use super::{config::BLOCK_SIZE};
-use crate::mutex::SpinMutex;
 use crate::block_cache_manager::BlockCacheManager;
+use crate::csum::Checksum;
+use crate::error::{Ext2Error, Ext2Result};
+use core::ops::Range;
 use log::*;
 /// A bitmap block
-type BitmapBlock = [u64; BLOCK_SIZE/8];
-/// Number of bits in a block
-const BLOCK_BITS: usize = BLOCK_SIZE * 8;
+type BitmapBlock = [u64];
 /// A bitmap
+#[derive(Clone, Copy)]
 pub struct Bitmap {
     block_id: usize,
     offset: usize,
+    /// Number of bits in use, at most the bits of a block
+    bits: usize,
 }
 
 impl Bitmap {
-    /// A new bitmap from start block id and number of blocks
-    pub fn new(block_id: usize, offset: usize) -> Self {
+    /// A new bitmap from block id, the first bit and number of bits
+    pub fn new(block_id: usize, offset: usize, bits: usize) -> Self {
         Self {
             block_id,
             offset,
+            bits,
         }
     }
     /// Allocate a new block from a block device
-    pub fn alloc(&self, manager: &SpinMutex<BlockCacheManager>) -> Option<usize> {
-        let bitmap_block = manager.lock().get_block_cache(self.block_id);
+    pub fn alloc(&self, manager: &BlockCacheManager) -> Ext2Result<Option<usize>> {
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
         let bit = bitmap_block.lock()
-        .modify(0, |bitmap_block: &mut BitmapBlock| {
+        .modify_slice(|bitmap_block: &mut BitmapBlock| {
             if let Some((bits64_pos, inner_pos)) = bitmap_block
                 .iter()
                 .enumerate()
                 .find(|(_, bits64)| **bits64 != u64::MAX)
                 .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
+                .filter(|(bits64_pos, inner_pos)| bits64_pos * 64 + inner_pos < self.bits)
             {
                 // modify cache
                 bitmap_block[bits64_pos] |= 1u64 << inner_pos;
@@ -38,47 +42,123 @@ impl Bitmap {
                 None
             }
         });
-        manager.lock().release_block(bitmap_block);
-        bit
+        manager.release_block(bitmap_block);
+        Ok(bit)
+    }
+    /// Find a free run of at most `max` consecutive bits, return its first bit and length.
+    /// The run starts at `goal` if it is free, otherwise at the first free run long
+    /// enough, or the first free bit if there is none. Bits in `reserved` count as used.
+    pub fn find_run(
+        &self,
+        manager: &BlockCacheManager,
+        goal: Option<usize>,
+        max: usize,
+        reserved: &[Range<usize>],
+    ) -> Ext2Result<Option<(usize, usize)>> {
+        assert!(max > 0);
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
+        let run = bitmap_block.lock()
+        .read_slice(|bitmap_block: &BitmapBlock| {
+            let is_free = |idx: usize| {
+                idx < self.bits && bitmap_block[idx / 64] & (1u64 << (idx % 64)) == 0
+                    && !reserved.iter().any(|range| range.contains(&(self.offset + idx)))
+            };
+            let run_len = |start: usize| {
+                (start..(start + max).min(self.bits))
+                    .take_while(|idx| is_free(*idx))
+                    .count()
+            };
+            let start = match goal {
+                Some(goal) if goal >= self.offset && is_free(goal - self.offset) => {
+                    Some(goal - self.offset)
+                }
+                _ => {
+                    let mut first_free = None;
+                    let mut found = None;
+                    let mut idx = 0;
+                    while idx < self.bits {
+                        if bitmap_block[idx / 64] == u64::MAX {
+                            idx = idx / 64 * 64 + 64;
+                            continue;
+                        }
+                        let len = run_len(idx);
+                        if len == max {
+                            found = Some(idx);
+                            break;
+                        }
+                        if len > 0 && first_free.is_none() {
+                            first_free = Some(idx);
+                        }
+                        idx += len + 1;
+                    }
+                    found.or(first_free)
+                }
+            };
+            start.map(|start| (self.offset + start, run_len(start)))
+        });
+        manager.release_block(bitmap_block);
+        Ok(run)
+    }
+    /// Allocate `len` free bits from `start`, as found by [`Bitmap::find_run`]
+    pub fn alloc_range(&self, manager: &BlockCacheManager, start: usize, len: usize) -> Ext2Result {
+        assert!(start >= self.minimum() && start + len <= self.maximum());
+        let start = start - self.offset;
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
+        bitmap_block.lock()
+            .modify_slice(|bitmap_block: &mut BitmapBlock| {
+                for idx in start..start + len {
+                    bitmap_block[idx / 64] |= 1u64 << (idx % 64);
+                }
+            });
+        manager.release_block(bitmap_block);
+        Ok(())
     }
     /// Test whether a bit is allocated
-    pub fn test(&self, manager: &SpinMutex<BlockCacheManager>, bit: usize) -> bool {
+    pub fn test(&self, manager: &BlockCacheManager, bit: usize) -> Ext2Result<bool> {
         let mut res: bool = false;
         let (bits64_pos, inner_pos) = self.decomposition(bit);
-        let bitmap_block = manager.lock().get_block_cache(self.block_id);
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
         bitmap_block.lock()
-            .read(0, |bitmap_block: &BitmapBlock| {
+            .read_slice(|bitmap_block: &BitmapBlock| {
                 res = bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0;
             });
-        manager.lock().release_block(bitmap_block);
-        res
+        manager.release_block(bitmap_block);
+        Ok(res)
     }
-    /// Deallocate a block
-    pub fn dealloc(&self, manager: &SpinMutex<BlockCacheManager>, bit: usize) {
+    /// Deallocate a block, freeing a free bit means the bitmap is corrupted
+    pub fn dealloc(&self, manager: &BlockCacheManager, bit: usize) -> Ext2Result {
         let (bits64_pos, inner_pos) = self.decomposition(bit);
-        let bitmap_block = manager.lock().get_block_cache(self.block_id);
-        bitmap_block.lock()
-            .modify(0, |bitmap_block: &mut BitmapBlock| {
-                assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
-                bitmap_block[bits64_pos] -= 1u64 << inner_pos;
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
+        let allocated = bitmap_block.lock()
+            .modify_slice(|bitmap_block: &mut BitmapBlock| {
+                let allocated = bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0;
+                bitmap_block[bits64_pos] &= !(1u64 << inner_pos);
+                allocated
             });
-        manager.lock().release_block(bitmap_block);
+        manager.release_block(bitmap_block);
+        if allocated {
+            Ok(())
+        } else {
+            error!("Bit {} is freed twice", bit);
+            Err(Ext2Error::Corrupted)
+        }
     }
     /// Allocate a block no matter what it originally is
     #[allow(dead_code)]
-    pub fn alloc_exact(&self, manager: &SpinMutex<BlockCacheManager>, bit: usize) {
+    pub fn alloc_exact(&self, manager: &BlockCacheManager, bit: usize) -> Ext2Result {
         let (bits64_pos, inner_pos) = self.decomposition(bit);
-        let bitmap_block = manager.lock().get_block_cache(self.block_id);
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
         bitmap_block.lock()
-            .modify(0, |bitmap_block: &mut BitmapBlock| {
+            .modify_slice(|bitmap_block: &mut BitmapBlock| {
                 bitmap_block[bits64_pos] |= 1u64 << inner_pos;
             });
-        manager.lock().release_block(bitmap_block);
+        manager.release_block(bitmap_block);
+        Ok(())
     }
     
     /// Range allocation [start, end) (should only be used in creating file system)
     #[allow(dead_code)]
-    pub fn range_alloc(&self, manager: &SpinMutex<BlockCacheManager>, mut start: usize, mut end: usize) {
+    pub fn range_alloc(&self, manager: &BlockCacheManager, mut start: usize, mut end: usize) -> Ext2Result {
         debug!("range_alloc {} {}", start, end);
         assert!(start < end);
         assert!(start >= self.minimum());
@@ -87,11 +167,11 @@ impl Bitmap {
         start -= self.minimum();
         end -= self.minimum();
 
-        let bitmap_block = manager.lock().get_block_cache(self.block_id);
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
         bitmap_block.lock()
-            .modify(0, |bitmap_block: &mut BitmapBlock| {
+            .modify_slice(|bitmap_block: &mut BitmapBlock| {
                 for (pos, inner) in bitmap_block.iter_mut().enumerate() {
-                    for inner_pos in 0..64 as usize {
+                    for inner_pos in 0..64usize {
                         let idx = pos * 64 + inner_pos;
                         if idx >= start && idx < end {
                             *inner |= 1u64 << inner_pos;
@@ -99,12 +179,75 @@ impl Bitmap {
                     }
                 }
             });
-        manager.lock().release_block(bitmap_block);
+        manager.release_block(bitmap_block);
+        Ok(())
+    }
+
+    /// Set the bits past the end of the bitmap, as they can never be allocated
+    pub fn set_padding(&self, manager: &BlockCacheManager) -> Ext2Result {
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
+        bitmap_block.lock()
+            .modify_slice(|bitmap_block: &mut BitmapBlock| {
+                for (pos, inner) in bitmap_block.iter_mut().enumerate() {
+                    for inner_pos in 0..64usize {
+                        if pos * 64 + inner_pos >= self.bits {
+                            *inner |= 1u64 << inner_pos;
+                        }
+                    }
+                }
+            });
+        manager.release_block(bitmap_block);
+        Ok(())
+    }
+
+    /// Set every bit to whether `used` holds for it, return the number of bits set.
+    /// Bits past the end of the bitmap are set, as they can never be allocated.
+    pub fn rebuild(&self, manager: &BlockCacheManager, used: impl Fn(usize) -> bool) -> Ext2Result<usize> {
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
+        let count = bitmap_block.lock()
+            .modify_slice(|bitmap_block: &mut BitmapBlock| {
+                let mut count = 0;
+                for (pos, inner) in bitmap_block.iter_mut().enumerate() {
+                    *inner = 0;
+                    for inner_pos in 0..64usize {
+                        let idx = pos * 64 + inner_pos;
+                        if idx >= self.bits {
+                            *inner |= 1u64 << inner_pos;
+                        } else if used(self.offset + idx) {
+                            *inner |= 1u64 << inner_pos;
+                            count += 1;
+                        }
+                    }
+                }
+                count
+            });
+        manager.release_block(bitmap_block);
+        Ok(count)
+    }
+
+    /// Number of bits set, without the padding
+    pub fn count(&self, manager: &BlockCacheManager) -> Ext2Result<usize> {
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
+        let count = bitmap_block.lock()
+            .read_slice(|bitmap_block: &BitmapBlock| {
+                (0..self.bits).filter(|idx| bitmap_block[idx / 64] & (1u64 << (idx % 64)) != 0).count()
+            });
+        manager.release_block(bitmap_block);
+        Ok(count)
+    }
+
+    /// Checksum of the bits in use, without the padding
+    pub fn checksum(&self, manager: &BlockCacheManager, csum: &Checksum) -> Ext2Result<u16> {
+        let bitmap_block = manager.get_block_cache(self.block_id)?;
+        let checksum = bitmap_block.lock()
+            .read_slice(|bitmap_block: &[u8]| csum.bitmap(&bitmap_block[..self.bits / 8]));
+        manager.release_block(bitmap_block);
+        Ok(checksum)
     }
 
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
-        self.offset + BLOCK_BITS
+        self.offset + self.bits
     }
 
     /// Get the min number of allocatable blocks
@@ -5,9 +5,14 @@ use alloc::collections::BTreeMap;
 use fs_utils::{InListNode, ListNode, inlist_access};
 use core::marker::PhantomData;
 use core::ops::DerefMut;
-use crate::mutex::SpinMutex;
-use crate::block_dev::{BlockDevice, NullDevice};
-use crate::config::BLOCK_SIZE;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use crate::mutex::{SleepMutex, SpinMutex};
+use crate::block_dev::BlockDevice;
+use crate::journal::Journal;
+use crate::error::{Ext2Error, Ext2Result};
+use spin::Once;
+use alloc::vec::Vec;
 use log::*;
 
 inlist_access!(pub ManagerAccessBlockCache, BlockCache, lru_head);
@@ -16,18 +21,29 @@ pub struct BlockCache {
     lru_head: InListNode<BlockCache, ManagerAccessBlockCache>,
     block_id: usize,
     modified: bool,
+    /// modified as metadata, which is journaled
+    metadata: bool,
+    /// when a writeback pass first found it dirty
+    dirty_since: Option<Duration>,
     valid: bool,
+    /// blocks of the manager modified as metadata, see `BlockCacheManager::metadata_blocks`
+    metadata_blocks: Arc<AtomicUsize>,
     cache: Box<[u8]>
 }
 
+unsafe impl Send for BlockCache {}
+
 impl BlockCache {
-    pub fn new(block_id: usize, block_size: usize) -> Option<Self> {
+    pub fn new(block_id: usize, block_size: usize, metadata_blocks: &Arc<AtomicUsize>) -> Option<Self> {
         match unsafe { Box::try_new_uninit_slice(block_size) } {
             Ok(cache) => Some(Self {
                 lru_head: InListNode::new(),
                 block_id,
                 modified: false,
+                metadata: false,
+                dirty_since: None,
                 valid: false,
+                metadata_blocks: metadata_blocks.clone(),
                 cache: unsafe {cache.assume_init()}
             }),
             Err(_) => None
@@ -50,6 +66,19 @@ impl BlockCache {
     }
 
     pub fn get_mut<T>(&mut self, offset: usize) -> &mut T
+    where
+        T: Sized,
+    {
+        let type_size = core::mem::size_of::<T>();
+        assert!(offset + type_size <= self.cache.len());
+        self.mark_metadata();
+        let addr = self.addr_of_offset(offset);
+        unsafe { &mut *(addr as *mut T) }
+    }
+
+    /// Like `get_mut`, but for contents of regular files, which are written
+    /// in place before the transaction commits instead of being journaled
+    pub fn get_data_mut<T>(&mut self, offset: usize) -> &mut T
     where
         T: Sized,
     {
@@ -60,8 +89,55 @@ impl BlockCache {
         unsafe { &mut *(addr as *mut T) }
     }
 
-    pub fn zero(&mut self) {
+    /// Get the whole cached block as a slice of `T`
+    pub fn get_slice<T>(&self) -> &[T]
+    where
+        T: Sized,
+    {
+        let len = self.cache.len() / core::mem::size_of::<T>();
+        unsafe { core::slice::from_raw_parts(self.cache.as_ptr() as *const T, len) }
+    }
+
+    pub fn get_slice_mut<T>(&mut self) -> &mut [T]
+    where
+        T: Sized,
+    {
+        self.mark_metadata();
+        let len = self.cache.len() / core::mem::size_of::<T>();
+        unsafe { core::slice::from_raw_parts_mut(self.cache.as_mut_ptr() as *mut T, len) }
+    }
+
+    /// Like `get_slice_mut`, but for contents of regular files
+    pub fn get_data_slice_mut<T>(&mut self) -> &mut [T]
+    where
+        T: Sized,
+    {
         self.modified = true;
+        let len = self.cache.len() / core::mem::size_of::<T>();
+        unsafe { core::slice::from_raw_parts_mut(self.cache.as_mut_ptr() as *mut T, len) }
+    }
+
+    /// Mark the block as modified as metadata
+    fn mark_metadata(&mut self) {
+        self.modified = true;
+        if !self.metadata {
+            self.metadata = true;
+            self.metadata_blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Mark the block as written to disk
+    fn mark_clean(&mut self) {
+        self.modified = false;
+        if self.metadata {
+            self.metadata = false;
+            self.metadata_blocks.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.dirty_since = None;
+    }
+
+    pub fn zero(&mut self) {
+        self.mark_metadata();
         for byte in self.cache.as_mut() {
             *byte = 0;
         }
@@ -78,127 +154,499 @@ impl BlockCache {
     pub fn modify<T: Sized, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
         f(self.get_mut(offset))
     }
+
+    pub fn modify_data<T: Sized, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
+        f(self.get_data_mut(offset))
+    }
+
+    pub fn read_slice<T: Sized, V>(&self, f: impl FnOnce(&[T]) -> V) -> V {
+        f(self.get_slice())
+    }
+
+    pub fn modify_slice<T: Sized, V>(&mut self, f: impl FnOnce(&mut [T]) -> V) -> V {
+        f(self.get_slice_mut())
+    }
+
+    pub fn modify_data_slice<T: Sized, V>(&mut self, f: impl FnOnce(&mut [T]) -> V) -> V {
+        f(self.get_data_slice_mut())
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        // dropped without being written, as the manager invalidates its caches
+        if self.metadata {
+            self.metadata_blocks.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Number of hash buckets, blocks in different buckets are looked up and
+/// evicted without contending for a lock
+const BUCKET_NUM: usize = 7;
+
+/// Blocks whose ids hash to the same bucket, with their own LRU list
+struct Bucket {
+    blocks: BTreeMap<usize, Arc<SleepMutex<BlockCache>>>,
+    lru_head: InListNode<BlockCache, ManagerAccessBlockCache>,
 }
 
+// The LRU list only links caches owned by `blocks`, and a bucket is always
+// accessed under its lock
+unsafe impl Send for Bucket {}
+
+impl Bucket {
+    /// Drop all caches, the list head must not move afterwards
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.lru_head.init();
+    }
+}
+
+/// Counters of a block cache since it was created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups of cached blocks
+    pub hits: usize,
+    /// Lookups that had to read the block
+    pub misses: usize,
+    /// Blocks read before they were looked up
+    pub read_ahead: usize,
+    /// Read requests to the device, each for a run of adjacent blocks
+    pub device_reads: usize,
+    /// Write requests to the device, each for a run of adjacent blocks
+    pub device_writes: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    read_ahead: AtomicUsize,
+    device_reads: AtomicUsize,
+    device_writes: AtomicUsize,
+}
+
+/// A cache of blocks in the style of xv6's `bget`: a bucket lock is only held
+/// to look a block up, which is then locked on its own while it is used, so
+/// that a task may sleep on one block without blocking lookups of others.
 pub struct BlockCacheManager {
     device: Arc<dyn BlockDevice>,
+    /// Caches kept in a bucket once they are no longer used, more are kept while in use
     max_cache: usize,
-    blocks: BTreeMap<usize,Arc<SpinMutex<BlockCache>>>,
-    lru_head: InListNode<BlockCache, ManagerAccessBlockCache>
+    buckets: Vec<SpinMutex<Bucket>>,
+    journal: Once<SleepMutex<Journal>>,
+    counters: Counters,
+    /// Number of cached blocks modified as metadata since they were last
+    /// written, i.e. held by the running transaction if there is a journal
+    metadata_blocks: Arc<AtomicUsize>
 }
 
 impl BlockCacheManager {
-    pub fn new() -> Self {
+    /// `block_device` must be in units of file system blocks, see `FsBlockDevice`
+    pub fn new(block_device: Arc<dyn BlockDevice>, max_cache: usize) -> Self {
+        let buckets: Vec<_> = (0..BUCKET_NUM)
+            .map(|_| SpinMutex::new(Bucket { blocks: BTreeMap::new(), lru_head: InListNode::new() }))
+            .collect();
+        // list heads are in place once the buckets are allocated
+        for bucket in buckets.iter() {
+            bucket.lock().lru_head.init();
+        }
         Self {
-            device: Arc::new(NullDevice),
-            max_cache: 0,
-            blocks: BTreeMap::new(),
-            lru_head: InListNode::new()
+            device: block_device,
+            max_cache: max_cache / BUCKET_NUM + 1,
+            buckets,
+            journal: Once::new(),
+            counters: Counters::default(),
+            metadata_blocks: Arc::new(AtomicUsize::new(0))
         }
     }
 
-    pub fn init(&mut self, block_device: Arc<dyn BlockDevice>, max_cache: usize) {
-        // TODO: modify bitmap to adapt to variant length block
-        assert!(block_device.block_size() == BLOCK_SIZE);
-        self.device = block_device;
-        self.max_cache = max_cache;
-        self.blocks.clear();
-        self.lru_head.lazy_init();
+    pub fn stats(&self) -> CacheStats {
+        let counters = &self.counters;
+        CacheStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            read_ahead: counters.read_ahead.load(Ordering::Relaxed),
+            device_reads: counters.device_reads.load(Ordering::Relaxed),
+            device_writes: counters.device_writes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Read blocks from `block_id` on with one request
+    fn read_device(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        self.counters.device_reads.fetch_add(1, Ordering::Relaxed);
+        self.device.read_blocks(block_id, buf)
+    }
+
+    /// Write blocks from `block_id` on with one request
+    fn write_device(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        self.counters.device_writes.fetch_add(1, Ordering::Relaxed);
+        self.device.write_blocks(block_id, buf)
     }
 
-    pub fn get_block_cache(&mut self, block_id: usize) -> Arc<SpinMutex<BlockCache>> {
+    /// Drop all caches without writing them back, as they may be stale
+    pub fn invalidate(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.lock().clear();
+        }
+    }
+
+    /// Size of file system blocks
+    pub fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    /// Size of the device in file system blocks
+    pub fn block_num(&self) -> usize {
+        self.device.block_num()
+    }
+
+    fn bucket(&self, block_id: usize) -> &SpinMutex<Bucket> {
+        &self.buckets[block_id % BUCKET_NUM]
+    }
+
+    pub fn get_block_cache(&self, block_id: usize) -> Ext2Result<Arc<SleepMutex<BlockCache>>> {
         // debug!("get_block_cache {}", block_id);
-        if let Some(cache) = self.blocks.get(&block_id) {
-            return cache.clone();
-        }
-        
-        if self.blocks.len() < self.max_cache {
-            let mut new_cache = BlockCache::new(block_id, self.device.block_size()).unwrap();
-            // init
-            self.device.read_block(block_id, new_cache.cache.as_mut());
-            new_cache.valid = true;
-            let new_cache = Arc::new(SpinMutex::new(new_cache));
-            new_cache.lock().lru_head.lazy_init();
-            // debug!("&self.lru_head = {}", &self.lru_head as *const _ as usize);
-            // self.lru_head.list_check();
-            self.lru_head.push_prev(unsafe {&mut new_cache.unsafe_get_mut().lru_head});
-            self.blocks.insert(block_id, new_cache.clone());
-            // self.lru_head.list_check();
-            return new_cache;
-        };
+        let mut bucket = self.bucket(block_id).lock();
+        if let Some(cache) = bucket.blocks.get(&block_id) {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(cache.clone());
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        self.make_room(&mut bucket)?;
+        self.insert_block_cache(&mut bucket, block_id, |data| self.read_device(block_id, data))
+    }
 
-        // evict a block
-        for bk in self.lru_head.next_iter() {
-            if Arc::strong_count(self.blocks.get(&bk.block_id).unwrap()) == 1 {
-                let evict_cache = self.blocks.remove(&bk.block_id).unwrap();
-                // write dirty data to disk
-                self.write_block(&evict_cache);
-
-                let cache_ref = unsafe { evict_cache.unsafe_get_mut() };
-                // unsafe {(*ptr).lru_head.pop_self();}
-
-                // init evicted block
-                self.device.read_block(block_id, &mut cache_ref.cache);
-                // self.lru_head.list_check();
-                cache_ref.modified = false;
-                cache_ref.valid = true;
-                cache_ref.block_id = block_id;
-
-                // insert to block map
-                self.blocks.insert(block_id, evict_cache.clone());
-                // self.lru_head.list_check();
-                return evict_cache;
+    /// Load the blocks among `block_ids` that are not cached, with one request
+    /// for each run of adjacent blocks. Block 0 stands for a hole and is skipped.
+    /// The blocks must not be modified meanwhile, e.g. as they belong to an
+    /// inode that is held.
+    pub fn read_ahead(&self, block_ids: &[usize]) -> Ext2Result {
+        let block_size = self.block_size();
+        let missing: Vec<usize> = block_ids.iter()
+            .copied()
+            .filter(|block_id| *block_id != 0 && self.pin_block(*block_id).is_none())
+            .collect();
+        let mut start = 0;
+        while start < missing.len() {
+            let mut end = start + 1;
+            while end < missing.len() && missing[end] == missing[end - 1] + 1 {
+                end += 1;
             }
+            let mut buf = alloc::vec![0; (end - start) * block_size];
+            self.read_device(missing[start], &mut buf)?;
+            for (block_id, data) in missing[start..end].iter().zip(buf.chunks(block_size)) {
+                let mut bucket = self.bucket(*block_id).lock();
+                // another task may have loaded it meanwhile
+                if bucket.blocks.contains_key(block_id) {
+                    continue;
+                }
+                self.make_room(&mut bucket)?;
+                self.insert_block_cache(&mut bucket, *block_id, |cache| {
+                    cache.copy_from_slice(data);
+                    Ok(())
+                })?;
+                self.counters.read_ahead.fetch_add(1, Ordering::Relaxed);
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Evict blocks no one uses until there is room in a bucket, dirty metadata
+    /// must stay until its transaction commits. If all of them are in use the
+    /// bucket grows instead.
+    fn make_room(&self, bucket: &mut Bucket) -> Ext2Result {
+        let journaled = self.has_journal();
+        while bucket.blocks.len() >= self.max_cache {
+            let victim = bucket.lru_head.next_iter()
+                .find(|bk| {
+                    Arc::strong_count(&bucket.blocks[&bk.block_id]) == 1
+                        && !(journaled && bk.metadata)
+                })
+                .map(|bk| bk.block_id);
+            let victim = match victim {
+                Some(victim) => victim,
+                None => break
+            };
+            // write dirty data to disk, the block stays cached if it fails
+            self.write_block(&bucket.blocks[&victim])?;
+            let evict_cache = bucket.blocks.remove(&victim).unwrap();
+            unsafe { evict_cache.unsafe_get_mut() }.lru_head.pop_self();
         }
+        Ok(())
+    }
 
-        panic!("Run out of blocks");
+    /// Cache a block, whose contents `init` fills in
+    fn insert_block_cache(
+        &self,
+        bucket: &mut Bucket,
+        block_id: usize,
+        init: impl FnOnce(&mut [u8]) -> Ext2Result
+    ) -> Ext2Result<Arc<SleepMutex<BlockCache>>> {
+        let mut new_cache = BlockCache::new(block_id, self.block_size(), &self.metadata_blocks)
+            .ok_or(Ext2Error::NoSpace)?;
+        init(new_cache.cache.as_mut())?;
+        new_cache.valid = true;
+        let new_cache = Arc::new(SleepMutex::new(new_cache));
+        new_cache.lock().lru_head.lazy_init();
+        bucket.lru_head.push_prev(unsafe {&mut new_cache.unsafe_get_mut().lru_head});
+        bucket.blocks.insert(block_id, new_cache.clone());
+        Ok(new_cache)
     }
 
     /// Safety
     /// 
     /// Should drop lock of BlockCache right before calling this function to avoid dead lock
-    pub fn release_block(&mut self, bac: Arc<SpinMutex<BlockCache>>) {
+    pub fn release_block(&self, bac: Arc<SleepMutex<BlockCache>>) {
+        let block_id = unsafe { bac.unsafe_get() }.block_id;
+        let mut bucket = self.bucket(block_id).lock();
         if Arc::strong_count(&bac) == 2 {
             let ptr = unsafe { bac.unsafe_get_mut() };
             ptr.lru_head.pop_self();
-            self.lru_head.push_prev(&mut ptr.lru_head);
-            
+            bucket.lru_head.push_prev(&mut ptr.lru_head);
         }
     }
 
-    pub fn write_block(&self, block: &Arc<SpinMutex<BlockCache>>) {
+    pub fn write_block(&self, block: &Arc<SleepMutex<BlockCache>>) -> Ext2Result {
         let mut lk = block.lock();
         if lk.modified {
-            lk.modified = false;
-            self.device.write_block(lk.block_id, lk.cache.as_ref());
+            self.write_device(lk.block_id, lk.cache.as_ref())?;
+            lk.mark_clean();
         }
+        Ok(())
     }
 
     /// Use arc to record refcnt
     /// when unpin, just drop(cache)
-    pub fn pin_block(&self, block_id: usize) -> Option<Arc<SpinMutex<BlockCache>>> {
-        self.blocks.get(&block_id).map(|ac| ac.clone())
+    pub fn pin_block(&self, block_id: usize) -> Option<Arc<SleepMutex<BlockCache>>> {
+        self.bucket(block_id).lock().blocks.get(&block_id).cloned()
     }
 
     /// Move arc to this function, it will be dropped right away
-    pub fn unpin_block(&self, bac: Arc<SpinMutex<BlockCache>>) {  }
+    pub fn unpin_block(&self, bac: Arc<SleepMutex<BlockCache>>) {  }
+
+    /// All cached blocks, which can not be evicted while they are held
+    fn cached_blocks(&self) -> Vec<Arc<SleepMutex<BlockCache>>> {
+        let mut blocks = Vec::new();
+        for bucket in self.buckets.iter() {
+            blocks.extend(bucket.lock().blocks.values().cloned());
+        }
+        blocks
+    }
 
-    /// Write all dirty blocks to disk
-    pub fn sync_all_block(&self) {
+    pub fn set_journal(&self, journal: Journal) {
+        self.journal.call_once(|| SleepMutex::new(journal));
+    }
+
+    pub fn has_journal(&self) -> bool {
+        self.journal.get().is_some()
+    }
+
+    /// Whether there is no journal or it holds no transaction
+    pub fn journal_is_empty(&self) -> bool {
+        self.journal.get().is_none_or(|journal| journal.lock().is_empty())
+    }
+
+    /// Number of blocks modified as metadata by the running transaction
+    pub fn dirty_metadata(&self) -> usize {
+        self.metadata_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Whether the running transaction has anything to commit
+    pub fn has_pending(&self) -> bool {
+        self.journal.get().is_some_and(|journal| {
+            journal.lock().has_revoked() || self.dirty_metadata() > 0
+        })
+    }
+
+    /// Whether the running transaction holds enough blocks to be committed
+    pub fn should_commit(&self) -> bool {
+        self.has_journal() && self.dirty_metadata() >= self.max_cache * BUCKET_NUM / 2
+    }
+
+    /// Forget a block freed by the running transaction, so that neither the
+    /// running transaction nor any older copy in the journal overwrites it
+    pub fn revoke(&self, block_id: usize) {
+        if let Some(journal) = self.journal.get() {
+            if let Some(block) = self.pin_block(block_id) {
+                let mut lk = block.lock();
+                if lk.metadata {
+                    lk.mark_clean();
+                }
+            }
+            journal.lock().revoke(block_id);
+        }
+    }
+
+    /// Commit the running transaction in ordered mode: file contents are
+    /// written in place first, then modified metadata is logged to the
+    /// journal and checkpointed.
+    pub fn commit(&self) -> Ext2Result {
+        let mut journal = match self.journal.get() {
+            Some(journal) => journal.lock(),
+            None => return Ok(())
+        };
+        let blocks = self.cached_blocks();
+        let metadata: Vec<_> = blocks.iter()
+            .filter(|block| {
+                let lk = block.lock();
+                lk.modified && lk.metadata
+            })
+            .collect();
+        if !journal.has_revoked() && metadata.is_empty() {
+            return Ok(());
+        }
+        debug!("commit {} metadata blocks", metadata.len());
+        let mut data: Vec<_> = blocks.iter()
+            .map(|block| block.lock())
+            .filter(|lk| lk.modified && !lk.metadata)
+            .collect();
+        self.write_merged(&mut data)?;
+        drop(data);
+        let mut guards: Vec<_> = metadata.iter().map(|block| block.lock()).collect();
+        let blocks: Vec<(usize, &[u8])> = guards.iter()
+            .map(|lk| (lk.block_id, lk.cache.as_ref()))
+            .collect();
+        if !journal.commit(self.device.as_ref(), &blocks)? {
+            warn!("Write {} metadata blocks without journaling", blocks.len());
+        }
+        // checkpoint
+        self.write_merged(&mut guards)
+    }
+
+    /// Write locked blocks to disk and mark them clean, with one request for
+    /// each run of adjacent blocks
+    fn write_merged<G: DerefMut<Target = BlockCache>>(&self, blocks: &mut [G]) -> Ext2Result {
+        blocks.sort_unstable_by_key(|lk| lk.block_id);
+        let mut start = 0;
+        while start < blocks.len() {
+            let mut end = start + 1;
+            while end < blocks.len() && blocks[end].block_id == blocks[end - 1].block_id + 1 {
+                end += 1;
+            }
+            if end - start == 1 {
+                self.write_device(blocks[start].block_id, blocks[start].cache.as_ref())?;
+            } else {
+                let mut buf = Vec::with_capacity((end - start) * self.block_size());
+                for lk in blocks[start..end].iter() {
+                    buf.extend_from_slice(lk.cache.as_ref());
+                }
+                self.write_device(blocks[start].block_id, &buf)?;
+            }
+            for lk in blocks[start..end].iter_mut() {
+                lk.mark_clean();
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Empty the journal, return whether it held any transaction
+    pub fn reset_journal(&self) -> Ext2Result<bool> {
+        match self.journal.get() {
+            Some(journal) => journal.lock().reset(self.device.as_ref()),
+            None => Ok(false)
+        }
+    }
+
+    /// Modify a block on disk directly, bypassing the running transaction.
+    /// The cached copy, if any, is modified as well.
+    pub fn modify_on_disk<T: Sized>(&self, block_id: usize, offset: usize, f: impl Fn(&mut T)) -> Ext2Result {
+        if let Some(block) = self.pin_block(block_id) {
+            let mut lk = block.lock();
+            let target = lk.addr_of_offset(offset) as *mut T;
+            f(unsafe { &mut *target });
+        }
+        let mut buf: Vec<u8> = alloc::vec![0; self.device.block_size()];
+        self.read_device(block_id, &mut buf)?;
+        assert!(offset + core::mem::size_of::<T>() <= buf.len());
+        f(unsafe { &mut *(buf.as_mut_ptr().add(offset) as *mut T) });
+        self.write_device(block_id, &buf)
+    }
+
+    /// Write all dirty blocks to disk, committing the running transaction if
+    /// there is a journal
+    pub fn sync_all_block(&self) -> Ext2Result {
         debug!("sync all blocks");
-        for (_, block) in self.blocks.iter() {
+        self.commit()?;
+        let blocks = self.cached_blocks();
+        let mut dirty: Vec<_> = blocks.iter()
+            .map(|block| block.lock())
+            .filter(|lk| lk.modified)
+            .collect();
+        debug!("Write {} dirty blocks", dirty.len());
+        self.write_merged(&mut dirty)
+    }
+
+    /// Number of blocks cached before any is evicted
+    pub fn capacity(&self) -> usize {
+        self.max_cache * BUCKET_NUM
+    }
+
+    /// Number of cached blocks not written to disk yet
+    pub fn dirty_count(&self) -> usize {
+        self.cached_blocks().iter()
+            .filter(|block| block.lock().modified)
+            .count()
+    }
+
+    /// Write dirty blocks that have stayed dirty for `expire` by `now`, apart
+    /// from journaled metadata, which is left to a commit. A block counts as
+    /// dirty from the first call that finds it so, it may stay dirty for
+    /// `expire` plus the time between two calls. Return whether any journaled
+    /// metadata expired.
+    pub fn write_expired(&self, now: Duration, expire: Duration) -> Ext2Result<bool> {
+        let journaled = self.has_journal();
+        let mut expired_metadata = false;
+        for block in self.cached_blocks() {
             let mut lk = block.lock();
-            if lk.modified {
-                lk.modified = false;
-                debug!("Write to block {}", lk.block_id);
-                self.device.write_block(lk.block_id, lk.cache.as_ref());
+            if !lk.modified {
+                continue;
+            }
+            let dirty_since = *lk.dirty_since.get_or_insert(now);
+            if now.saturating_sub(dirty_since) < expire {
+                continue;
+            }
+            if journaled && lk.metadata {
+                expired_metadata = true;
+            } else {
+                self.write_device(lk.block_id, lk.cache.as_ref())?;
+                lk.mark_clean();
+            }
+        }
+        Ok(expired_metadata)
+    }
+
+    /// Write the given blocks to disk if they are cached and dirty, apart from
+    /// journaled metadata. Return whether any of them is journaled metadata,
+    /// which only a commit writes.
+    pub fn write_blocks(&self, block_ids: &[usize]) -> Ext2Result<bool> {
+        let journaled = self.has_journal();
+        let mut dirty_metadata = false;
+        for block_id in block_ids.iter() {
+            if let Some(block) = self.pin_block(*block_id) {
+                let mut lk = block.lock();
+                if !lk.modified {
+                    continue;
+                }
+                if journaled && lk.metadata {
+                    dirty_metadata = true;
+                } else {
+                    self.write_device(lk.block_id, lk.cache.as_ref())?;
+                    lk.mark_clean();
+                }
             }
         }
+        Ok(dirty_metadata)
     }
 }
 
 impl Drop for BlockCacheManager {
     fn drop(&mut self) {
-        self.sync_all_block();
+        if let Err(err) = self.sync_all_block() {
+            error!("Failed to write back cached blocks: {}", err);
+        }
     }
-}
\ No newline at end of file
+}
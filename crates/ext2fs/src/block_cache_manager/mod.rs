@@ -1,3 +1,3 @@
 mod block_cache;
 
-pub use block_cache::{BlockCache, BlockCacheManager};
\ No newline at end of file
+pub use block_cache::{BlockCache, BlockCacheManager, CacheStats};
\ No newline at end of file
@@ -1,30 +1,86 @@
 use core::any::Any;
+use alloc::{sync::Arc, vec};
+use crate::error::Ext2Result;
 /// Trait for block devices
 /// which reads and writes data in the unit of blocks
 pub trait BlockDevice: Send + Sync + Any {
     /// Read data form block to buffer
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result;
     /// Write data from buffer to block
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result;
+    /// Read consecutive blocks from `block_id` on, as many as fill the buffer.
+    /// Devices that can do it in one request should.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        for (idx, chunk) in buf.chunks_mut(self.block_size()).enumerate() {
+            self.read_block(block_id + idx, chunk)?;
+        }
+        Ok(())
+    }
+    /// Write consecutive blocks from `block_id` on, as many as the buffer holds
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        for (idx, chunk) in buf.chunks(self.block_size()).enumerate() {
+            self.write_block(block_id + idx, chunk)?;
+        }
+        Ok(())
+    }
     /// Get block size
     fn block_size(&self) -> usize;
     /// Get block num
     fn block_num(&self) -> usize;
 }
 
-pub struct NullDevice;
+/// A device seen in units of file system blocks, which may be larger than
+/// the blocks (sectors) of the underlying device
+pub struct FsBlockDevice {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+}
+
+impl FsBlockDevice {
+    pub fn new(device: Arc<dyn BlockDevice>, block_size: usize) -> Self {
+        assert!(block_size.is_multiple_of(device.block_size()),
+                "Block size {} is not a multiple of the device block size {}", block_size, device.block_size());
+        Self { device, block_size }
+    }
+
+    /// Number of device blocks in a file system block
+    fn ratio(&self) -> usize {
+        self.block_size / self.device.block_size()
+    }
+}
 
-impl BlockDevice for NullDevice {
-    fn read_block(&self, _block_id: usize, _buf: &mut [u8]) {
-        panic!("Unimplemented");
+impl BlockDevice for FsBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        self.device.read_blocks(block_id * self.ratio(), buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        self.device.write_blocks(block_id * self.ratio(), buf)
     }
-    fn write_block(&self, _block_id: usize, _buf: &[u8]) {
-        panic!("Unimplemented");
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        self.device.read_blocks(block_id * self.ratio(), buf)
+    }
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        self.device.write_blocks(block_id * self.ratio(), buf)
     }
     fn block_num(&self) -> usize {
-        panic!("Unimplemented");
+        self.device.block_num() / self.ratio()
     }
     fn block_size(&self) -> usize {
-        panic!("Unimplemented");
+        self.block_size
     }
-}
\ No newline at end of file
+}
+
+/// Read `buf.len()` bytes at byte `offset` of a device
+pub fn read_bytes(device: &dyn BlockDevice, offset: usize, buf: &mut [u8]) -> Ext2Result {
+    let block_size = device.block_size();
+    let mut block = vec![0u8; block_size];
+    let mut pos = offset;
+    while pos < offset + buf.len() {
+        device.read_block(pos / block_size, &mut block)?;
+        let start = pos % block_size;
+        let len = (block_size - start).min(offset + buf.len() - pos);
+        buf[pos - offset..pos - offset + len].copy_from_slice(&block[start..start + len]);
+        pos += len;
+    }
+    Ok(())
+}
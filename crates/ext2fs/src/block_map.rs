@@ -0,0 +1,145 @@
+//! The logical to physical block mapping of a file as cached by its inode,
+//! kept as runs of mapped blocks so that holes take no memory
+use crate::extent::Extent;
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+
+/// Mapped runs of the logical blocks below the size of a file
+#[derive(Default)]
+pub struct BlockMap {
+    /// Runs by their first logical block, as first physical block and length.
+    /// They never overlap.
+    runs: BTreeMap<usize, (u32, usize)>,
+    /// Number of logical blocks below the size
+    len: usize,
+}
+
+impl BlockMap {
+    /// A map of `len` logical blocks with the written extents `extents`,
+    /// which lie below `len` and do not overlap
+    pub fn new(extents: &[Extent], len: usize) -> Self {
+        let mut map = Self { runs: BTreeMap::new(), len };
+        for extent in extents {
+            map.insert(extent.block as usize, extent.start, extent.len as usize);
+        }
+        map
+    }
+
+    /// Number of logical blocks below the size
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Physical block of a logical block, 0 for holes and blocks past the end
+    pub fn block_id(&self, block: usize) -> u32 {
+        if block >= self.len {
+            return 0;
+        }
+        match self.runs.range(..=block).next_back() {
+            Some((first, (start, len))) if block - first < *len => start + (block - first) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Physical block of the last mapped logical block before `block`
+    pub fn last_before(&self, block: usize) -> Option<u32> {
+        let block = block.min(self.len);
+        self.runs.range(..block).next_back()
+            .map(|(first, (start, len))| start + ((block - first).min(*len) - 1) as u32)
+    }
+
+    /// The mapped logical blocks in `range` with their physical blocks
+    pub fn mapped(&self, range: Range<usize>) -> impl Iterator<Item = (usize, u32)> + '_ {
+        let end = range.end.min(self.len);
+        let begin = range.start.min(end);
+        // the run holding the first block may start before it
+        let from = match self.runs.range(..=begin).next_back() {
+            Some((first, _)) => *first,
+            None => begin,
+        };
+        self.runs.range(from..end.max(from))
+            .flat_map(move |(first, (start, len))| {
+                (begin.max(*first)..end.min(first + len))
+                    .map(move |block| (block, start + (block - first) as u32))
+            })
+    }
+
+    /// Map the logical blocks from `first` on to `blocks`, where 0 is a hole.
+    /// Blocks past the end are left out.
+    pub fn map(&mut self, first: usize, blocks: &[u32]) {
+        let end = (first + blocks.len()).min(self.len);
+        if first >= end {
+            return;
+        }
+        self.unmap(first..end);
+        let mut run: Option<(usize, u32, usize)> = None;
+        for (block, block_id) in (first..end).zip(blocks.iter().copied()) {
+            run = match run {
+                Some((first, start, len)) if block_id != 0 && start + len as u32 == block_id => Some((first, start, len + 1)),
+                _ => {
+                    if let Some((first, start, len)) = run {
+                        self.insert(first, start, len);
+                    }
+                    (block_id != 0).then_some((block, block_id, 1))
+                }
+            };
+        }
+        if let Some((first, start, len)) = run {
+            self.insert(first, start, len);
+        }
+    }
+
+    /// Unmap the logical blocks in `range`, which become holes
+    pub fn unmap(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+        self.split(range.start);
+        self.split(range.end);
+        let mut rest = self.runs.split_off(&range.start);
+        let mut after = rest.split_off(&range.end);
+        self.runs.append(&mut after);
+    }
+
+    /// Change the number of logical blocks, the blocks past a smaller one
+    /// are unmapped
+    pub fn resize(&mut self, len: usize) {
+        if len < self.len {
+            self.unmap(len..self.len);
+        }
+        self.len = len;
+    }
+
+    /// Split the run holding `block` so that a run starts there
+    fn split(&mut self, block: usize) {
+        let tail = match self.runs.range_mut(..block).next_back() {
+            Some((first, (start, len))) if block - first < *len => {
+                let tail = (*start + (block - first) as u32, *len - (block - first));
+                *len = block - first;
+                tail
+            }
+            _ => return,
+        };
+        self.runs.insert(block, tail);
+    }
+
+    /// Add a run over unmapped blocks, merged with the runs it continues
+    fn insert(&mut self, mut first: usize, mut start: u32, mut len: usize) {
+        if let Some((prev, (prev_start, prev_len))) = self.runs.range(..first).next_back() {
+            if prev + prev_len == first && *prev_start as usize + prev_len == start as usize {
+                (first, start, len) = (*prev, *prev_start, prev_len + len);
+            }
+        }
+        if let Some((next_start, next_len)) = self.runs.get(&(first + len)).copied() {
+            if start as usize + len == next_start as usize {
+                self.runs.remove(&(first + len));
+                len += next_len;
+            }
+        }
+        self.runs.insert(first, (start, len));
+    }
+}
@@ -0,0 +1,260 @@
+//! Metadata checksums, as in ext4 with `metadata_csum`.
+//!
+//! Every checksum is a crc32c, seeded with the crc32c of the UUID of the file
+//! system. The super block holds the checksum of its first 1020 bytes, group
+//! descriptors the low 16 bits of the checksum of their group number and
+//! contents, and also those of their two bitmaps. Inodes are checksummed
+//! along with their number and generation, which gives the seed of the
+//! checksums of their directory blocks, extent tree blocks and so on.
+//! Directory leaves end with an unused entry of 12 bytes holding the
+//! checksum, which older readers skip, and the index blocks of a directory
+//! give up their last index entry to make room for it.
+use crate::layout::{SuperBlock, BlockGroupDesc, DirEntryHead};
+use core::mem::size_of;
+use core::ops::Range;
+
+/// Size of the entry at the end of a directory leaf holding its checksum
+pub const DIR_TAIL_SIZE: usize = 12;
+/// Size of the tail after the index entries of a dx_root or dx_node block
+pub const DX_TAIL_SIZE: usize = 8;
+
+/// File type of the directory leaf tail, which no entry in use has
+const DIR_TAIL_FT: u8 = 0xDE;
+
+const INODE_CHECKSUM_LO: Range<usize> = 0x7C..0x7E;
+const INODE_EXTRA_ISIZE: usize = 0x80;
+const INODE_CHECKSUM_HI: Range<usize> = 0x82..0x84;
+const INODE_GENERATION: usize = 0x64;
+const XATTR_CHECKSUM: Range<usize> = 16..20;
+/// The checksum of an extent tree block follows its largest possible entries
+const EXTENT_HEADER_SIZE: usize = 12;
+const EXTENT_ENTRY_SIZE: usize = 12;
+
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// Carry a crc32c over `data`, without inverting it before or after as
+/// Linux does
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// crc32c over `data` as if the bytes in `holes`, sorted and of at most
+/// 4 bytes, were zeros
+fn crc32c_with_holes(mut crc: u32, data: &[u8], holes: &[Range<usize>]) -> u32 {
+    let mut pos = 0;
+    for hole in holes {
+        crc = crc32c(crc, &data[pos..hole.start]);
+        crc = crc32c(crc, &[0; 4][..hole.len()]);
+        pos = hole.end;
+    }
+    crc32c(crc, &data[pos..])
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Checksum of the raw super block, over everything before its checksum field
+pub fn super_block(data: &[u8]) -> u32 {
+    crc32c(!0, &data[..size_of::<SuperBlock>() - 4])
+}
+
+/// The 12 bytes ending a directory leaf, before their checksum is set
+pub fn dir_tail() -> [u8; DIR_TAIL_SIZE] {
+    let mut head = DirEntryHead::empty();
+    head.rec_len = DIR_TAIL_SIZE as u16;
+    head.file_type = DIR_TAIL_FT;
+    let mut tail = [0; DIR_TAIL_SIZE];
+    tail[..size_of::<DirEntryHead>()].copy_from_slice(head.as_bytes());
+    tail
+}
+
+/// Whether a directory block is a leaf ending with a checksum
+pub fn has_dir_tail(data: &[u8]) -> bool {
+    data.len() >= DIR_TAIL_SIZE
+        && data[data.len() - DIR_TAIL_SIZE..data.len() - 4] == dir_tail()[..DIR_TAIL_SIZE - 4]
+}
+
+/// The entries of a directory block, without the tail of a leaf
+pub fn dir_entries(data: &[u8]) -> &[u8] {
+    if has_dir_tail(data) {
+        &data[..data.len() - DIR_TAIL_SIZE]
+    } else {
+        data
+    }
+}
+
+/// Checksums of the metadata of a file system with `metadata_csum`
+#[derive(Clone, Copy, Debug)]
+pub struct Checksum {
+    seed: u32,
+    inode_size: usize,
+}
+
+impl Checksum {
+    /// The checksums of a file system, `None` if it does not keep them
+    pub fn new(super_block: &SuperBlock) -> Option<Self> {
+        if !super_block.has_metadata_csum() {
+            return None;
+        }
+        Some(Self {
+            seed: super_block.csum_seed().unwrap_or_else(|| crc32c(!0, &super_block.uuid())),
+            inode_size: super_block.inode_size(),
+        })
+    }
+
+    /// Checksum of the descriptor of group x, with its own checksum left out
+    pub fn group_desc(&self, group_id: usize, desc: &BlockGroupDesc) -> u16 {
+        let mut desc = *desc;
+        desc.bg_checksum = 0;
+        let data = unsafe {
+            core::slice::from_raw_parts(&desc as *const _ as *const u8, size_of::<BlockGroupDesc>())
+        };
+        crc32c(crc32c(self.seed, &(group_id as u32).to_le_bytes()), data) as u16
+    }
+
+    /// Checksum of the bits of a bitmap in use
+    pub fn bitmap(&self, data: &[u8]) -> u16 {
+        crc32c(self.seed, data) as u16
+    }
+
+    /// Seed of the checksums of an inode and of the blocks only it uses
+    pub fn inode_seed(&self, inode_id: u32, generation: u32) -> u32 {
+        crc32c(crc32c(self.seed, &inode_id.to_le_bytes()), &generation.to_le_bytes())
+    }
+
+    /// Checksum of a raw inode, and whether it has room for its high 16 bits
+    fn inode(&self, inode_id: u32, raw: &[u8]) -> (u32, bool) {
+        let has_hi = raw.len() > INODE_EXTRA_ISIZE
+            && read_u16(raw, INODE_EXTRA_ISIZE) as usize >= INODE_CHECKSUM_HI.end - INODE_EXTRA_ISIZE;
+        let seed = self.inode_seed(inode_id, read_u32(raw, INODE_GENERATION));
+        let crc = if has_hi {
+            crc32c_with_holes(seed, raw, &[INODE_CHECKSUM_LO, INODE_CHECKSUM_HI])
+        } else {
+            crc32c_with_holes(seed, raw, &[INODE_CHECKSUM_LO])
+        };
+        (if has_hi { crc } else { crc & 0xffff }, has_hi)
+    }
+
+    /// Set the checksum of inode x, at `offset` of a block of the inode table
+    pub fn set_inode(&self, inode_id: u32, data: &mut [u8], offset: usize) {
+        let raw = &mut data[offset..offset + self.inode_size];
+        let (crc, has_hi) = self.inode(inode_id, raw);
+        raw[INODE_CHECKSUM_LO].copy_from_slice(&(crc as u16).to_le_bytes());
+        if has_hi {
+            raw[INODE_CHECKSUM_HI].copy_from_slice(&((crc >> 16) as u16).to_le_bytes());
+        }
+    }
+
+    /// Whether the checksum of inode x matches, an inode never written is
+    /// all zeros
+    pub fn verify_inode(&self, inode_id: u32, data: &[u8], offset: usize) -> bool {
+        let raw = &data[offset..offset + self.inode_size];
+        let (crc, has_hi) = self.inode(inode_id, raw);
+        let mut recorded = read_u16(raw, INODE_CHECKSUM_LO.start) as u32;
+        if has_hi {
+            recorded |= (read_u16(raw, INODE_CHECKSUM_HI.start) as u32) << 16;
+        }
+        crc == recorded || raw.iter().all(|byte| *byte == 0)
+    }
+
+    /// Where the checksum of a directory block is and its value, `None` if
+    /// it is neither a leaf with a tail nor an index block with room for one
+    fn dir_block(&self, inode_seed: u32, data: &[u8]) -> Option<(usize, u32)> {
+        let block_size = data.len();
+        if has_dir_tail(data) {
+            let end = block_size - DIR_TAIL_SIZE;
+            return Some((block_size - 4, crc32c(inode_seed, &data[..end])));
+        }
+        // the count and limit of a dx_node follow its unused entry, those of
+        // a dx_root the `.` and `..` entries and the root info
+        let count_offset = match read_u16(data, 4) as usize {
+            len if len == block_size => 8,
+            12 if read_u16(data, 16) as usize == block_size - 12 && read_u32(data, 24) == 0 && data[29] == 8 => 32,
+            _ => return None,
+        };
+        let limit = read_u16(data, count_offset) as usize;
+        let count = read_u16(data, count_offset + 2) as usize;
+        let tail = count_offset + limit * 8;
+        if tail + DX_TAIL_SIZE > block_size || count > limit {
+            return None;
+        }
+        let crc = crc32c(inode_seed, &data[..count_offset + count * 8]);
+        let crc = crc32c(crc, &data[tail..tail + 4]);
+        Some((tail + 4, crc32c(crc, &[0; 4])))
+    }
+
+    /// Set the checksum of a directory block, return false if it has no room
+    /// for one
+    pub fn set_dir_block(&self, inode_seed: u32, data: &mut [u8]) -> bool {
+        match self.dir_block(inode_seed, data) {
+            Some((offset, crc)) => {
+                data[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn verify_dir_block(&self, inode_seed: u32, data: &[u8]) -> bool {
+        matches!(self.dir_block(inode_seed, data), Some((offset, crc)) if read_u32(data, offset) == crc)
+    }
+
+    /// Checksum of an extended attribute block, which may be shared so is
+    /// seeded with its block number
+    fn xattr_block(&self, block_id: u32, data: &[u8]) -> u32 {
+        crc32c_with_holes(crc32c(self.seed, &(block_id as u64).to_le_bytes()), data, &[XATTR_CHECKSUM])
+    }
+
+    pub fn set_xattr_block(&self, block_id: u32, data: &mut [u8]) {
+        let crc = self.xattr_block(block_id, data);
+        data[XATTR_CHECKSUM].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    pub fn verify_xattr_block(&self, block_id: u32, data: &[u8]) -> bool {
+        read_u32(data, XATTR_CHECKSUM.start) == self.xattr_block(block_id, data)
+    }
+
+    /// Where the checksum of an extent tree block is and its value
+    fn extent_block(&self, inode_seed: u32, data: &[u8]) -> Option<(usize, u32)> {
+        let tail = EXTENT_HEADER_SIZE + read_u16(data, 4) as usize * EXTENT_ENTRY_SIZE;
+        if tail + 4 > data.len() {
+            return None;
+        }
+        Some((tail, crc32c(inode_seed, &data[..tail])))
+    }
+
+    pub fn set_extent_block(&self, inode_seed: u32, data: &mut [u8]) {
+        if let Some((offset, crc)) = self.extent_block(inode_seed, data) {
+            data[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+        }
+    }
+
+    pub fn verify_extent_block(&self, inode_seed: u32, data: &[u8]) -> bool {
+        matches!(self.extent_block(inode_seed, data), Some((offset, crc)) if read_u32(data, offset) == crc)
+    }
+}
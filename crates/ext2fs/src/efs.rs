@@ -1,499 +1,2062 @@
 #![allow(unused)]
-use crate::{block_cache_manager::BlockCacheManager, layout::EXT2_FT_DIR};
-use crate::mutex::SpinMutex;
+use crate::{block_cache_manager::{BlockCache, BlockCacheManager, CacheStats}, layout::EXT2_FT_DIR};
+use crate::csum::Checksum;
+use crate::extent::{self, TreeItem};
+use crate::mutex::{self, SleepMutex, SleepRwMutex, SpinMutex};
 use crate::timer::TimeProvider;
 use crate::inode_manager::InodeCacheManager;
+use crate::journal::{Journal, default_journal_blocks};
+use crate::htree::HashInfo;
+use crate::policy::{AllocPolicy, GroupUsage, LocalityPolicy};
+use crate::xattr::{self, XattrBlock, XATTR_REFCOUNT_MAX};
+use crate::error::{Ext2Error, Ext2Result};
 use core::mem::size_of;
+use core::time::Duration;
 use fs_utils::sync::Spin;
 use log::*;
 
+use crate::block_dev::{FsBlockDevice, read_bytes};
 use super::{
     Bitmap, BlockDevice, DiskInode, BlockGroupDesc, InodeCache, Inode,
     SuperBlock, config::{
-        BLOCK_SIZE, BLOCKS_PER_GRP, RESERVED_BLOCKS_PER_GRP, EXT2_ROOT_INO,
-        FIRST_DATA_BLOCK, INODES_PER_GRP, EXT2_GOOD_OLD_FIRST_INO, SUPER_BLOCK_OFFSET
+        DEFAULT_BLOCK_SIZE, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE, EXT2_ROOT_INO,
+        EXT2_GOOD_OLD_FIRST_INO, EXT2_GOOD_OLD_INODE_SIZE, SUPER_BLOCK_OFFSET
     },
-    layout::{IMODE, EXT2_S_IFDIR, EXT2_S_IFREG}
+    layout::{
+        IMODE, EXT2_S_IFDIR, EXT2_S_IFREG, EXT2_RESIZE_INO, EXT3_JOURNAL_INO, VOLUMN_NAME_SIZE, MAX_NAME_LEN, SB_MAGIC,
+        EXT4_BG_INODE_UNINIT, EXT4_BG_BLOCK_UNINIT, LARGE_FILE_SIZE, SuperBlockParams, is_sparse_group
+    }
 };
-use alloc::{sync::Arc, vec::Vec};
-use spin::Mutex;
+use alloc::{collections::{BTreeMap, BTreeSet}, string::String, sync::Arc, vec::Vec};
+use core::ops::{DerefMut, Range};
 
 pub struct Ext2FileSystem {
     ///Real device
-    pub manager: SpinMutex<BlockCacheManager>,
+    pub manager: BlockCacheManager,
     /// manage inode cache
     pub inode_manager: SpinMutex<InodeCacheManager>,
     /// provide time
     pub timer: Arc<dyn TimeProvider>,
+    /// whether the file system must not be modified
+    read_only: bool,
+    /// checksums of metadata, if the file system keeps them
+    csum: Option<Checksum>,
+    /// when dirty blocks are written back
+    writeback: SpinMutex<WritebackPolicy>,
+    /// where new inodes and blocks go
+    alloc_policy: SpinMutex<Arc<dyn AllocPolicy>>,
+    /// preallocation windows of the inodes that allocated blocks, by inode
+    windows: SpinMutex<BTreeMap<u32, Window>>,
+    /// block groups, each locked on its own while allocating in it
+    groups: SleepRwMutex<Vec<SleepMutex<BlockGroup>>>,
+    /// extended attribute blocks that may be shared, by hash, also locked
+    /// while changing how many inodes share a block
+    xattr_blocks: SleepMutex<BTreeSet<(u32, u32)>>,
+    /// inodes without links that are still open, in the order of the orphan
+    /// list on disk
+    orphans: SleepMutex<Vec<u32>>,
+    /// held by renames between directories, see `Inode::rename`
+    pub(crate) rename_lock: SleepMutex<()>,
     /// inner meta data
-    inner: Mutex<Ext2FileSystemInner>
+    inner: SleepMutex<Ext2FileSystemInner>
+}
+
+/// Free blocks that an inode allocates from first, see [`AllocPolicy::prealloc_window`]
+struct Window {
+    blocks: Range<u32>,
+    /// Number of blocks when the window was made
+    size: usize,
+}
+
+/// A block group descriptor with the bitmaps it points to
+struct BlockGroup {
+    desc: BlockGroupDesc,
+    inode_bitmap: Bitmap,
+    data_bitmap: Bitmap,
+}
+
+impl BlockGroup {
+    fn new(super_block: &SuperBlock, group_id: usize, desc: BlockGroupDesc) -> SleepMutex<Self> {
+        let inodes_per_group = super_block.s_inodes_per_group as usize;
+        let blocks_per_group = super_block.s_blocks_per_group as usize;
+        SleepMutex::new(Self {
+            desc,
+            inode_bitmap: Bitmap::new(
+                desc.bg_inode_bitmap as usize,
+                group_id * inodes_per_group + 1,
+                inodes_per_group
+            ),
+            data_bitmap: Bitmap::new(
+                desc.bg_block_bitmap as usize,
+                super_block.s_first_data_block as usize + group_id * blocks_per_group,
+                blocks_per_group
+            )
+        })
+    }
+
+    /// Update the checksums of the bitmaps once they changed
+    fn seal_bitmaps(&mut self, manager: &BlockCacheManager, csum: Option<&Checksum>) -> Ext2Result {
+        if let Some(csum) = csum {
+            self.desc.bg_inode_bitmap_csum = self.inode_bitmap.checksum(manager, csum)?;
+            self.desc.bg_block_bitmap_csum = self.data_bitmap.checksum(manager, csum)?;
+        }
+        Ok(())
+    }
 }
 
-type DataBlock = [u8; BLOCK_SIZE];
+type DataBlock = [u8];
 
 const MAX_CACHE_NUM: usize = 50;
 
+/// Smallest number of inodes in a group, enough for the reserved inodes
+const MIN_INODES_PER_GROUP: usize = 16;
+
+/// Parameters of a new file system
+#[derive(Clone, Debug)]
+pub struct CreateOptions {
+    /// Size of a block in bytes
+    pub block_size: usize,
+    /// Bytes of space per inode, or one inode per block if `None`
+    pub bytes_per_inode: Option<usize>,
+    /// Volume label, at most 16 bytes
+    pub volume_name: String,
+    /// Map the data of new files and directories through extent trees
+    pub extents: bool,
+    /// Checksum the metadata, as ext4 does with `metadata_csum`
+    pub metadata_csum: bool,
+    /// Keep backups of the super block and group descriptors only in groups
+    /// 0, 1 and powers of 3, 5 and 7 rather than in every group
+    pub sparse_super: bool,
+    /// Reserve blocks after the group descriptor table, mapped by the resize
+    /// inode, for the file system to grow online. Needs `sparse_super`.
+    pub resize_inode: bool
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            bytes_per_inode: None,
+            volume_name: String::from("Image by hsh"),
+            extents: false,
+            metadata_csum: false,
+            sparse_super: true,
+            resize_inode: true
+        }
+    }
+}
+
+/// When dirty blocks are written back in the background, see [`Ext2FileSystem::writeback`]
+#[derive(Clone, Copy, Debug)]
+pub struct WritebackPolicy {
+    /// Time between two writebacks
+    pub interval: Duration,
+    /// Blocks that stay dirty this long are written back
+    pub dirty_expire: Duration,
+    /// All dirty blocks are written back once this percentage of the block cache is dirty
+    pub dirty_ratio: usize
+}
+
+impl Default for WritebackPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            dirty_expire: Duration::from_secs(30),
+            dirty_ratio: 20
+        }
+    }
+}
+
+/// Size and usage of the file system, see [`Ext2FileSystem::stats`]
+#[derive(Clone, Copy, Debug)]
+pub struct FsStats {
+    /// Magic number of the super block
+    pub magic: u16,
+    /// Size of blocks in bytes
+    pub block_size: usize,
+    /// Total number of blocks
+    pub blocks: u32,
+    /// Number of free blocks
+    pub free_blocks: u32,
+    /// Free blocks not reserved for root
+    pub avail_blocks: u32,
+    /// Total number of inodes
+    pub inodes: u32,
+    /// Number of free inodes
+    pub free_inodes: u32,
+    /// Identifier of the file system, folded from its UUID
+    pub fsid: u64,
+    /// Longest file name in bytes
+    pub name_len: usize
+}
+
 impl Ext2FileSystem {
-    /// Create an ext2 file system in a device
-    pub fn create(block_device: Arc<dyn BlockDevice>, timer: Arc<dyn TimeProvider>) -> Arc<Self> {
-        assert!(block_device.block_size() == BLOCK_SIZE, "Unsupported block size");
+    /// Create an ext2 file system in a device with the default block size
+    pub fn create(block_device: Arc<dyn BlockDevice>, timer: Arc<dyn TimeProvider>) -> Ext2Result<Arc<Self>> {
+        Self::create_with_block_size(block_device, timer, DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Create an ext2 file system in a device with blocks of `block_size` bytes
+    pub fn create_with_block_size(
+        block_device: Arc<dyn BlockDevice>,
+        timer: Arc<dyn TimeProvider>,
+        block_size: usize
+    ) -> Ext2Result<Arc<Self>> {
+        Self::create_with_options(block_device, timer, &CreateOptions {
+            block_size,
+            ..CreateOptions::default()
+        })
+    }
+
+    /// Create an ext2 file system in a device as described by `options`
+    pub fn create_with_options(
+        block_device: Arc<dyn BlockDevice>,
+        timer: Arc<dyn TimeProvider>,
+        options: &CreateOptions
+    ) -> Ext2Result<Arc<Self>> {
+        let block_size = options.block_size;
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            error!("Unsupported block size {}", block_size);
+            return Err(Ext2Error::InvalidInput);
+        }
+        if options.volume_name.len() > VOLUMN_NAME_SIZE {
+            error!("Volume name is too long");
+            return Err(Ext2Error::InvalidInput);
+        }
         debug!("Create ext2 file system...");
+        let block_device: Arc<dyn BlockDevice> = Arc::new(FsBlockDevice::new(block_device, block_size));
+        let blocks_per_group = 8 * block_size;
+        let first_data_block = if block_size == 1024 { 1 } else { 0 };
+
         let mut block_num = block_device.block_num();
-        let mut group_num = (block_num + BLOCKS_PER_GRP - 1)/BLOCKS_PER_GRP;
-        assert!(group_num >= 1, "Size is at least 32 MB");
-        let mut last_group_block_num = block_num - (group_num - 1) * BLOCKS_PER_GRP;
+        if block_num <= first_data_block {
+            error!("Device is too small");
+            return Err(Ext2Error::NoSpace);
+        }
+        let mut group_num = (block_num - first_data_block).div_ceil(blocks_per_group);
+        let inodes_per_group = match options.bytes_per_inode {
+            Some(bytes_per_inode) => {
+                // the inode table fills whole blocks, and the inode bitmap a single block
+                let inodes_per_block = block_size / EXT2_GOOD_OLD_INODE_SIZE;
+                let inodes = block_num * block_size / bytes_per_inode;
+                let per_group = inodes.div_ceil(group_num);
+                let per_group = per_group.div_ceil(inodes_per_block) * inodes_per_block;
+                per_group.max(MIN_INODES_PER_GROUP).min(8 * block_size)
+            }
+            None => 8 * block_size
+        };
+        // block bitmap, inode bitmap and inode table
+        let reserved_blocks_per_group = 2 + inodes_per_group * EXT2_GOOD_OLD_INODE_SIZE / block_size;
+        let mut last_group_block_num = block_num - first_data_block - (group_num - 1) * blocks_per_group;
+        // groups that start with a copy of the super block and group descriptors
+        let has_super = |group_id: usize| !options.sparse_super || is_sparse_group(group_id);
+        let gdt_blocks = |group_num: usize| (group_num * size_of::<BlockGroupDesc>()).div_ceil(block_size);
+        // as with mke2fs, enough for 1024 times the size, as far as the
+        // double indirect block of the resize inode can map
+        let reserved_gdt_blocks = if options.resize_inode && options.sparse_super {
+            let max_blocks = block_num.saturating_mul(1024).min(u32::MAX as usize);
+            let max_groups = (max_blocks - first_data_block).div_ceil(blocks_per_group);
+            (gdt_blocks(max_groups) - gdt_blocks(group_num)).min(block_size / 4)
+        } else {
+            0
+        };
+        let super_blocks = if has_super(group_num - 1) { 1 + gdt_blocks(group_num) + reserved_gdt_blocks } else { 0 };
 
-        if last_group_block_num <= RESERVED_BLOCKS_PER_GRP {
+        if last_group_block_num <= super_blocks + reserved_blocks_per_group {
             group_num -= 1;
-            last_group_block_num = BLOCKS_PER_GRP;
+            last_group_block_num = blocks_per_group;
         }
-        assert!(group_num >= 1);
-        block_num = (group_num - 1) * BLOCKS_PER_GRP + last_group_block_num;
-        let group_desc_block_num = (group_num * size_of::<BlockGroupDesc>() + BLOCK_SIZE - 1)/BLOCK_SIZE;
+        if group_num == 0 {
+            error!("Device is too small");
+            return Err(Ext2Error::NoSpace);
+        }
+        block_num = first_data_block + (group_num - 1) * blocks_per_group + last_group_block_num;
+        let group_desc_block_num = gdt_blocks(group_num);
 
         let mut group_desc_table:Vec<BlockGroupDesc> = Vec::new();
+        let mut free_blocks_count = 0;
         for group_id in 0..group_num {
-            let mut block_bitmap: usize = 0;
-            let mut free_blocks: usize = 0;
-            if group_id == 0 {
-                block_bitmap = (FIRST_DATA_BLOCK + 1) + group_desc_block_num;
-                free_blocks = if group_id == group_num - 1 {
-                    last_group_block_num - block_bitmap - RESERVED_BLOCKS_PER_GRP
-                } else {
-                    BLOCKS_PER_GRP - block_bitmap - RESERVED_BLOCKS_PER_GRP
-                }
-                // first group
-            } else if group_id == group_num - 1 {
-                // last group
-                block_bitmap = BLOCKS_PER_GRP * group_id;
-                free_blocks = last_group_block_num - RESERVED_BLOCKS_PER_GRP;
+            let group_start = first_data_block + group_id * blocks_per_group;
+            let group_block_num = if group_id == group_num - 1 {
+                last_group_block_num
             } else {
-                block_bitmap = BLOCKS_PER_GRP * group_id;
-                free_blocks = BLOCKS_PER_GRP - RESERVED_BLOCKS_PER_GRP;
-            }
+                blocks_per_group
+            };
+            // the first group holds the super block and group descriptors,
+            // other groups with a backup hold a copy of them
+            let block_bitmap = if has_super(group_id) {
+                group_start + 1 + group_desc_block_num + reserved_gdt_blocks
+            } else {
+                group_start
+            };
+            let free_blocks = group_start + group_block_num - block_bitmap - reserved_blocks_per_group;
+            let free_inodes = if group_id == 0 {
+                inodes_per_group - EXT2_GOOD_OLD_FIRST_INO + 1
+            } else {
+                inodes_per_group
+            };
+            free_blocks_count += free_blocks;
+            // the root directory is in the first group
+            let used_dirs = if group_id == 0 { 1 } else { 0 };
             group_desc_table.push(BlockGroupDesc::new(
                 block_bitmap,
                 block_bitmap + 1,
                 block_bitmap + 2,
-                free_blocks, INODES_PER_GRP, 0
+                free_blocks, free_inodes, used_dirs
             ));
         }
 
-        let super_block = SuperBlock::new(
-            INODES_PER_GRP * group_num,
-            block_num,
-            INODES_PER_GRP * group_num - EXT2_GOOD_OLD_FIRST_INO + 1,
-            block_num - group_num * RESERVED_BLOCKS_PER_GRP - (FIRST_DATA_BLOCK + 1 + group_desc_block_num),
-            group_num,
-            "Image by hsh"
-        );
-
-        let mut cache_manager = BlockCacheManager::new();
+        let mut super_block = SuperBlock::new(&SuperBlockParams {
+            inodes_count: inodes_per_group * group_num,
+            blocks_count: block_num,
+            free_inodes_count: inodes_per_group * group_num - EXT2_GOOD_OLD_FIRST_INO + 1,
+            free_blocks_count,
+            block_size,
+            blocks_per_group,
+            inodes_per_group
+        }, &options.volume_name);
+        if options.extents {
+            super_block.set_extents();
+        }
+        if options.metadata_csum {
+            super_block.set_metadata_csum();
+        }
+        if options.sparse_super {
+            super_block.set_sparse_super();
+        }
+        if reserved_gdt_blocks > 0 {
+            super_block.set_resize_inode(reserved_gdt_blocks);
+        }
 
+        let groups = group_desc_table.into_iter()
+            .enumerate()
+            .map(|(group_id, desc)| BlockGroup::new(&super_block, group_id, desc))
+            .collect();
         let fs = Arc::new(Self {
-            manager: SpinMutex::new(cache_manager),
+            manager: BlockCacheManager::new(block_device.clone(), MAX_CACHE_NUM),
             inode_manager: SpinMutex::new(InodeCacheManager::new(64)),
             timer,
-            inner: Mutex::new(Ext2FileSystemInner::new(super_block, group_desc_table))
+            read_only: false,
+            csum: Checksum::new(&super_block),
+            writeback: SpinMutex::new(WritebackPolicy::default()),
+            alloc_policy: SpinMutex::new(Arc::new(LocalityPolicy::default())),
+            windows: SpinMutex::new(BTreeMap::new()),
+            groups: SleepRwMutex::new(groups),
+            xattr_blocks: SleepMutex::new(BTreeSet::new()),
+            orphans: SleepMutex::new(Vec::new()),
+            rename_lock: SleepMutex::new(()),
+            inner: SleepMutex::new(Ext2FileSystemInner::new(super_block))
         });
-        fs.manager.lock().init(block_device.clone(), MAX_CACHE_NUM);
 
         // clear all blocks except the first 1024 bytes
         for i in 0..block_num {
-            let block = fs.manager.lock().get_block_cache(i as _);
+            let block = fs.manager.get_block_cache(i as _)?;
             block.lock()
-                .modify(0, |data_block: &mut DataBlock| {
+                .modify_slice(|data_block: &mut DataBlock| {
                     for (idx, byte) in data_block.iter_mut().enumerate() {
                         if i != 0 || idx >= 1024 {
                             *byte = 0;
                         }
                     }
                 });
-            fs.manager.lock().release_block(block);
+            fs.manager.release_block(block);
         }
 
         // TODO: mark reserved inodes and used data blocks
-        let mut inner = fs.inner.lock();
-        debug!("Super block:\n {:?}", &inner.super_block);
-        for (idx, desc) in inner.group_desc_table.iter().enumerate() {
-            debug!("Block group {:?}:\n{:?}", idx, desc);
+        debug!("Super block:\n {:?}", &fs.super_block());
+        for group_id in 0..group_num {
+            debug!("Block group {:?}:\n{:?}", group_id, fs.group_desc(group_id));
         }
-        inner.get_inode_bitmap(0)
-            .range_alloc(&fs.manager, 1, EXT2_GOOD_OLD_FIRST_INO);
+        fs.get_inode_bitmap(0)
+            .range_alloc(&fs.manager, 1, EXT2_GOOD_OLD_FIRST_INO)?;
         for group_id in 0..group_num {
+            fs.get_inode_bitmap(group_id).set_padding(&fs.manager)?;
+            let group_start = first_data_block + group_id * blocks_per_group;
             // debug!("Range alloc block in group {} {} {}", 
             //     group_id,
-            //     group_id * BLOCKS_PER_GRP,
-            //     fs.group_desc_table[group_id].bg_block_bitmap as usize + RESERVED_BLOCKS_PER_GRP + 1
+            //     group_start,
+            //     fs.group_desc_table[group_id].bg_block_bitmap as usize + reserved_blocks_per_group
             // );
-            inner.get_data_bitmap(group_id)
+            fs.get_data_bitmap(group_id)
                 .range_alloc(
                     &fs.manager, 
-                    group_id * BLOCKS_PER_GRP, 
-                    inner.group_desc_table[group_id].bg_block_bitmap as usize + RESERVED_BLOCKS_PER_GRP + 1
-                );
+                    group_start, 
+                    fs.group_desc(group_id).bg_block_bitmap as usize + reserved_blocks_per_group
+                )?;
             if group_id == group_num - 1 {
-                if block_num < (group_id + 1) * BLOCKS_PER_GRP {
-                    inner.get_data_bitmap(group_id)
+                if block_num < group_start + blocks_per_group {
+                    fs.get_data_bitmap(group_id)
                         .range_alloc(
                             &fs.manager, 
                             block_num, 
-                            (group_id + 1) * BLOCKS_PER_GRP
-                        );
+                            group_start + blocks_per_group
+                        )?;
                 }
             }
+            fs.groups.shared_lock()[group_id].lock().seal_bitmaps(&fs.manager, fs.csum.as_ref())?;
         }
 
         // TODO: init '/' inode
-        let (root_inode_block_id, root_inode_offset) = inner.get_disk_inode_pos(EXT2_ROOT_INO as u32);
-        let inode_block = fs.manager.lock().get_block_cache(root_inode_block_id as _);
-        inode_block.lock()
-            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-                *disk_inode = DiskInode::new(
-                    IMODE::from_bits_truncate(0o755), 
-                    EXT2_S_IFDIR, 0, 0);
-            });
-        fs.manager.lock().release_block(inode_block);
-
-        drop(inner);
-        // TODO: write super blocks and group description table to disk
+        let mut root = DiskInode::new(IMODE::from_bits_truncate(0o755), EXT2_S_IFDIR, 0, 0);
+        if options.extents {
+            root.init_extents();
+        }
+        fs.init_disk_inode(EXT2_ROOT_INO as u32, root)?;
 
         // TODO: create dir entry '.' and '..' for '/'
-        let root_inode = Self::root_inode_cache(&fs);
+        let root_inode = Self::root_inode_cache(&fs)?;
         // root_inode.lock().link(".", EXT2_ROOT_INO);
         // root_inode.lock().link("..", EXT2_ROOT_INO);
-        let mut lk = root_inode.lock();
-        lk.append_dir_entry(EXT2_ROOT_INO, ".", EXT2_FT_DIR);
-        lk.append_dir_entry(EXT2_ROOT_INO, "..", EXT2_FT_DIR);
-        lk.increase_nlink(2);
+        let mut lk = root_inode.unique_lock();
+        lk.add_dir_entry(EXT2_ROOT_INO, ".", EXT2_FT_DIR)?;
+        lk.add_dir_entry(EXT2_ROOT_INO, "..", EXT2_FT_DIR)?;
+        lk.increase_nlink(1)?;
+        drop(lk);
+
+        if reserved_gdt_blocks > 0 {
+            fs.create_resize_inode()?;
+        }
+        let journal_blocks = default_journal_blocks(block_num);
+        if journal_blocks > 0 {
+            fs.create_journal(journal_blocks)?;
+        }
 
-        fs.write_meta();
+        // with the backups of the super block and group descriptors
+        fs.write_meta()?;
         // fs.inner.lock().super_block.check_valid();
-        fs.manager.lock().sync_all_block();
-        fs
+        fs.manager.sync_all_block()?;
+        if let Some(journal) = fs.load_journal(&block_device)? {
+            fs.manager.set_journal(journal);
+        }
+        Ok(fs)
+    }
+
+    /// Create the resize inode, which maps the blocks reserved after the
+    /// group descriptor table and their backups for [`Self::resize`]
+    fn create_resize_inode(&self) -> Ext2Result {
+        let super_block = self.super_block();
+        let block_size = self.block_size();
+        let gdt_blocks = super_block.gdt_blocks();
+        let dind = self.alloc_data()?;
+        let dind_block = self.manager.get_block_cache(dind as _)?;
+        let mut blocks = 0;
+        for index in gdt_blocks..gdt_blocks + super_block.reserved_gdt_blocks() {
+            let primary = super_block.s_first_data_block as usize + 1 + index;
+            dind_block.lock().modify_slice(|entries: &mut [u32]| entries[index % (block_size / 4)] = primary as u32);
+            // each reserved block lists its backups in the groups that keep one
+            let backups: Vec<u32> = (1..super_block.group_count())
+                .filter(|group_id| super_block.has_super(*group_id))
+                .map(|group_id| (primary + group_id * super_block.s_blocks_per_group as usize) as u32)
+                .collect();
+            let primary_block = self.manager.get_block_cache(primary)?;
+            primary_block.lock().modify_slice(|entries: &mut [u32]| entries[..backups.len()].copy_from_slice(&backups));
+            self.manager.release_block(primary_block);
+            blocks += 1 + backups.len();
+        }
+        self.manager.release_block(dind_block);
+        let mut disk_inode = DiskInode::new(IMODE::from_bits_truncate(0o600), EXT2_S_IFREG, 0, 0);
+        disk_inode.init_resize(dind, blocks, block_size);
+        if disk_inode.size() > LARGE_FILE_SIZE as u64 {
+            self.set_large_file();
+        }
+        self.init_disk_inode(EXT2_RESIZE_INO, disk_inode)
+    }
+
+    /// Create the journal inode with `journal_blocks` blocks
+    fn create_journal(&self, journal_blocks: usize) -> Ext2Result {
+        let size = (journal_blocks * self.block_size()) as u64;
+        self.init_disk_inode(EXT3_JOURNAL_INO, DiskInode::new(
+            IMODE::from_bits_truncate(0o600),
+            EXT2_S_IFREG, 0, 0))?;
+        let (inode_block_id, inode_offset) = self.get_disk_inode_pos(EXT3_JOURNAL_INO)?;
+        let inode_block = self.manager.get_block_cache(inode_block_id as _)?;
+        let mut lk = inode_block.lock();
+        let blocks = lk.modify(inode_offset, |disk_inode: &mut DiskInode| {
+            disk_inode.increase_size(size, self.block_size())?;
+            let new_blocks = self.alloc_data_runs(None, journal_blocks)?;
+            disk_inode.map_blocks(0, &new_blocks, false, &self.manager, &mut |n| self.alloc_data_runs(None, n))?;
+            Ok(new_blocks)
+        });
+        self.seal_inode(&mut lk, EXT3_JOURNAL_INO, inode_offset);
+        drop(lk);
+        self.manager.release_block(inode_block);
+        let blocks: Vec<u32> = blocks?;
+        self.seal_extent_tree(EXT3_JOURNAL_INO, &self.read_disk_inode(EXT3_JOURNAL_INO)?)?;
+
+        let uuid = self.inner.lock().super_block.uuid();
+        let jsb_block = self.manager.get_block_cache(blocks[0] as _)?;
+        jsb_block.lock()
+            .modify_slice(|data_block: &mut DataBlock| {
+                Journal::format(data_block, journal_blocks, uuid);
+            });
+        self.manager.release_block(jsb_block);
+        self.inner.lock().super_block.set_journal(EXT3_JOURNAL_INO);
+        Ok(())
     }
 
-    /// Open a file system from disk
-    pub fn open(block_device: Arc<dyn BlockDevice>, timer: Arc<dyn TimeProvider>) -> Arc<Self> {
-        assert!(block_device.block_size() == BLOCK_SIZE, "Unsupported block size");
+    /// Load the journal of the file system, if it has one
+    fn load_journal(&self, block_device: &Arc<dyn BlockDevice>) -> Ext2Result<Option<Journal>> {
+        let inode_id = match self.inner.lock().super_block.journal_inum() {
+            Some(inode_id) => inode_id,
+            None => return Ok(None),
+        };
+        let disk_inode = self.read_disk_inode(inode_id)?;
+        let (extents, _) = disk_inode.mapped_extents(&self.manager)?;
+        let blocks: Vec<u32> = extents.iter()
+            .flat_map(|extent| extent.start..extent.start + extent.len)
+            .collect();
+        if blocks.len() != disk_inode.data_blocks(self.block_size()) as usize {
+            error!("Holes in the journal inode {}", inode_id);
+            return Err(Ext2Error::Corrupted);
+        }
+        Journal::load(block_device.as_ref(), blocks).map(Some)
+    }
+
+    /// Open a file system from disk, from the backup of the super block in
+    /// the second group if the primary one is damaged
+    pub fn open(block_device: Arc<dyn BlockDevice>, timer: Arc<dyn TimeProvider>) -> Ext2Result<Arc<Self>> {
         debug!("Open ext2 file system...");
+        let primary = Self::probe_super_block(block_device.as_ref(), SUPER_BLOCK_OFFSET)?;
+        match primary.check_valid() {
+            Ok(()) => return Self::open_at(block_device, timer, primary, SUPER_BLOCK_OFFSET),
+            // features we do not know are not damage
+            Err(Ext2Error::Corrupted) => (),
+            Err(err) => return Err(err),
+        }
+        // the geometry of a damaged super block may still be right, else
+        // try the default one of each block size
+        let mut backups = Vec::new();
+        if let Some(block_no) = primary.first_backup() {
+            backups.push(block_no);
+        }
+        let mut block_size = MIN_BLOCK_SIZE;
+        while block_size <= MAX_BLOCK_SIZE {
+            let first_data_block = if block_size == 1024 { 1 } else { 0 };
+            let block_no = first_data_block + 8 * block_size;
+            if !backups.contains(&block_no) {
+                backups.push(block_no);
+            }
+            block_size *= 2;
+        }
+        for block_no in backups {
+            warn!("Bad primary super block, try the backup in block {}", block_no);
+            match Self::open_with_superblock(block_device.clone(), timer.clone(), block_no) {
+                Ok(fs) => return Ok(fs),
+                Err(_) => continue,
+            }
+        }
+        Err(Ext2Error::Corrupted)
+    }
+
+    /// Open a file system from the copy of its super block in block x, such
+    /// as a backup at the start of a group, given in blocks of its own size
+    /// as with `e2fsck -b`. Block 0 stands for the primary super block. The
+    /// group descriptors are read from the blocks that follow, and those of
+    /// the primary copy are rewritten from them unless mounted read-only.
+    pub fn open_with_superblock(
+        block_device: Arc<dyn BlockDevice>,
+        timer: Arc<dyn TimeProvider>,
+        block_no: usize
+    ) -> Ext2Result<Arc<Self>> {
+        debug!("Open ext2 file system from the super block in block {}...", block_no);
+        let mut block_size = MIN_BLOCK_SIZE;
+        while block_size <= MAX_BLOCK_SIZE {
+            let offset = if block_no == 0 { SUPER_BLOCK_OFFSET } else { block_no * block_size };
+            if let Ok(super_block) = Self::probe_super_block(block_device.as_ref(), offset) {
+                if super_block.check_backup().is_ok() && super_block.block_size() == block_size {
+                    return Self::open_at(block_device, timer, super_block, offset);
+                }
+            }
+            block_size *= 2;
+        }
+        error!("No valid super block in block {}", block_no);
+        Err(Ext2Error::Corrupted)
+    }
+
+    /// Open a file system whose super block was read at byte `sb_offset`
+    fn open_at(
+        block_device: Arc<dyn BlockDevice>,
+        timer: Arc<dyn TimeProvider>,
+        super_block: SuperBlock,
+        sb_offset: usize
+    ) -> Ext2Result<Arc<Self>> {
+        let block_device: Arc<dyn BlockDevice> = Arc::new(FsBlockDevice::new(block_device, super_block.block_size()));
+        let read_only = super_block.is_read_only();
+        if read_only {
+            warn!("Unknown read-only compatible features, mount read-only");
+        }
         let fs = Arc::new(Self {
-            manager: SpinMutex::new(BlockCacheManager::new()),
+            manager: BlockCacheManager::new(block_device.clone(), MAX_CACHE_NUM),
             inode_manager: SpinMutex::new(InodeCacheManager::new(64)),
             timer,
-            inner: Mutex::new(Ext2FileSystemInner::new(SuperBlock::empty(), Vec::new()))
+            read_only,
+            csum: Checksum::new(&super_block),
+            writeback: SpinMutex::new(WritebackPolicy::default()),
+            alloc_policy: SpinMutex::new(Arc::new(LocalityPolicy::default())),
+            windows: SpinMutex::new(BTreeMap::new()),
+            groups: SleepRwMutex::new(Vec::new()),
+            xattr_blocks: SleepMutex::new(BTreeSet::new()),
+            orphans: SleepMutex::new(Vec::new()),
+            rename_lock: SleepMutex::new(()),
+            // the super block written back if the rest fails to load is valid
+            inner: SleepMutex::new(Ext2FileSystemInner::new(super_block))
         });
-        fs.manager.lock().init(block_device.clone(), MAX_CACHE_NUM);
-        // get_block_cache(FIRST_DATA_BLOCK, Arc::clone(&block_device))
+        // get_block_cache(first_data_block, Arc::clone(&block_device))
         //     .lock()
         //     .read(SUPER_BLOCK_OFFSET, |sb: &SuperBlock| {
         //         super_block = *sb;
         //     });
         debug!("After manager init");
-        let sb_block = fs.manager.lock().get_block_cache(FIRST_DATA_BLOCK);
-        sb_block.lock()
-            .read(SUPER_BLOCK_OFFSET, |sb: &SuperBlock| {
-                fs.inner.lock().super_block = *sb;
-            });
-        fs.manager.lock().release_block(sb_block);
-        debug!("Super block:\n {:?}", &fs.inner.lock().super_block);
-        fs.inner.lock().super_block.check_valid();
-        debug!("After superblock check valid");
-        
-        let s_block_group_nr = fs.inner.lock().super_block.s_block_group_nr;
-        let s_first_data_block = fs.inner.lock().super_block.s_first_data_block;
-
-        for group_id in 0..s_block_group_nr as usize {
-            let block_id = s_first_data_block as usize + 1 + (group_id * size_of::<BlockGroupDesc>())/BLOCK_SIZE;
-            let offset = (group_id * size_of::<BlockGroupDesc>())%BLOCK_SIZE;
-            // get_block_cache(block_id, Arc::clone(&block_device))
-            //     .lock()
-            //     .read(offset, |desc: &BlockGroupDesc| {
-            //         group_desc_table.push(*desc);
-            //     });
-            let gdt_block = fs.manager.lock().get_block_cache(block_id);
-            gdt_block.lock()
-                .read(offset, |desc: &BlockGroupDesc| {
-                    fs.inner.lock().group_desc_table.push(*desc);
-                });
-            fs.manager.lock().release_block(gdt_block);
+        fs.load_meta(sb_offset)?;
+
+        if let Some(mut journal) = fs.load_journal(&block_device)? {
+            if !journal.is_empty() {
+                let super_block = fs.inner.lock().super_block;
+                let replayed = journal.recover(block_device.as_ref(), &super_block)?;
+                info!("Replayed {} transactions from journal", replayed);
+                // blocks cached so far may be stale
+                fs.manager.invalidate();
+                fs.load_meta(sb_offset)?;
+            }
+            fs.manager.set_journal(journal);
+            let mut inner = fs.inner.lock();
+            if inner.super_block.needs_recovery() {
+                fs.mark_recover(&mut inner, false)?;
+            }
+        }
+        for group_id in 0..fs.group_count() {
+            debug!("Block group {:?}:\n{:?}", group_id, fs.group_desc(group_id));
+        }
+        // metadata is consistent once the journal is replayed, but a backup
+        // does not follow the bitmaps, which are checked against it
+        let backup = sb_offset != SUPER_BLOCK_OFFSET;
+        fs.verify_groups(!backup)?;
+        if !fs.read_only {
+            fs.init_groups()?;
+        }
+        if backup {
+            if fs.read_only {
+                warn!("The counts of free inodes and blocks may be stale");
+            } else {
+                info!("Restore the primary super block and group descriptors");
+                fs.recount_groups()?;
+                fs.write_meta()?;
+            }
         }
-        let cur_time = fs.timer.get_current_time();
 
-        {
+        if !fs.read_only {
+            Self::release_orphans(&fs)?;
+        }
+
+        if !fs.read_only {
+            let cur_time = fs.timer.get_current_time();
             let mut inner = fs.inner.lock();
             inner.super_block.s_mnt_count += 1;
             inner.super_block.s_mtime = cur_time;
+            inner.write_super_block(&fs.manager)?;
+        }
+
+        Ok(fs)
+    }
+
+    /// Read a super block at byte `offset` of a device before knowing its
+    /// block size, it is not checked yet
+    fn probe_super_block(block_device: &dyn BlockDevice, offset: usize) -> Ext2Result<SuperBlock> {
+        let mut buf = [0u8; size_of::<SuperBlock>()];
+        if offset + buf.len() > block_device.block_num() * block_device.block_size() {
+            error!("No super block past the end of the device");
+            return Err(Ext2Error::InvalidInput);
+        }
+        read_bytes(block_device, offset, &mut buf)?;
+        Ok(unsafe { (buf.as_ptr() as *const SuperBlock).read_unaligned() })
+    }
+
+    /// Read the super block at byte `sb_offset` and the group description
+    /// table that follows it from disk
+    fn load_meta(&self, sb_offset: usize) -> Ext2Result {
+        let block_size = self.block_size();
+        let sb_block = self.manager.get_block_cache(sb_offset / block_size)?;
+        let mut super_block = sb_block.lock()
+            .read(sb_offset % block_size, |sb: &SuperBlock| *sb);
+        self.manager.release_block(sb_block);
+        debug!("Super block:\n {:?}", &super_block);
+        if sb_offset == SUPER_BLOCK_OFFSET {
+            super_block.check_valid()?;
+        } else {
+            // a backup becomes the primary super block again
+            super_block.check_backup()?;
+            super_block.set_valid();
+            super_block.s_block_group_nr = 0;
         }
+        self.inner.lock().super_block = super_block;
+        debug!("After superblock check valid");
+
+        let group_count = super_block.group_count();
+        let gdt_start = sb_offset / block_size + 1;
+
+        let mut groups = Vec::new();
+        for group_id in 0..group_count {
+            let block_id = gdt_start + (group_id * size_of::<BlockGroupDesc>())/block_size;
+            let offset = (group_id * size_of::<BlockGroupDesc>())%block_size;
+            let gdt_block = self.manager.get_block_cache(block_id)?;
+            let desc = gdt_block.lock()
+                .read(offset, |desc: &BlockGroupDesc| *desc);
+            self.manager.release_block(gdt_block);
+            groups.push(BlockGroup::new(&super_block, group_id, desc));
+        }
+        *self.groups.unique_lock() = groups;
+        Ok(())
+    }
 
-        for (idx, desc) in fs.inner.lock().group_desc_table.iter().enumerate() {
-            debug!("Block group {:?}:\n{:?}", idx, desc);
+    /// Check the checksums of the group descriptors, and of the bitmaps in
+    /// use if `bitmaps` is set
+    fn verify_groups(&self, bitmaps: bool) -> Ext2Result {
+        let csum = match &self.csum {
+            Some(csum) => csum,
+            None => return Ok(()),
+        };
+        let first_data_block = self.inner.lock().super_block.s_first_data_block as usize;
+        let descs_per_block = self.block_size() / size_of::<BlockGroupDesc>();
+        for (group_id, group) in self.groups.shared_lock().iter().enumerate() {
+            let group = group.lock();
+            let desc = group.desc;
+            if csum.group_desc(group_id, &desc) != desc.bg_checksum {
+                error!("Bad checksum of group descriptor {} in block {}", group_id,
+                    first_data_block + 1 + group_id / descs_per_block);
+                return Err(Ext2Error::Corrupted);
+            }
+            if !bitmaps {
+                continue;
+            }
+            if desc.bg_flags & EXT4_BG_BLOCK_UNINIT == 0
+                && group.data_bitmap.checksum(&self.manager, csum)? != desc.bg_block_bitmap_csum
+            {
+                error!("Bad checksum of block bitmap {}", desc.bg_block_bitmap);
+                return Err(Ext2Error::Corrupted);
+            }
+            if desc.bg_flags & EXT4_BG_INODE_UNINIT == 0
+                && group.inode_bitmap.checksum(&self.manager, csum)? != desc.bg_inode_bitmap_csum
+            {
+                error!("Bad checksum of inode bitmap {}", desc.bg_inode_bitmap);
+                return Err(Ext2Error::Corrupted);
+            }
         }
+        Ok(())
+    }
+
+    /// Initialize the bitmaps that mke2fs may leave for the kernel to set up
+    /// in groups not used yet, as we keep them all up to date
+    fn init_groups(&self) -> Ext2Result {
+        let super_block = self.super_block();
+        let blocks_count = super_block.s_blocks_count as usize;
+        let inode_table_blocks = super_block.inode_table_blocks();
+        for (group_id, group) in self.groups.shared_lock().iter().enumerate() {
+            let mut group = group.lock();
+            let desc = group.desc;
+            if desc.bg_flags & (EXT4_BG_INODE_UNINIT | EXT4_BG_BLOCK_UNINIT) == 0 {
+                continue;
+            }
+            if desc.bg_flags & EXT4_BG_INODE_UNINIT != 0 {
+                group.inode_bitmap.rebuild(&self.manager, |_| false)?;
+            }
+            if desc.bg_flags & EXT4_BG_BLOCK_UNINIT != 0 {
+                // the group holds a copy of the super block and group
+                // descriptors if it has a backup, then its own metadata
+                let group_start = group.data_bitmap.minimum();
+                let super_end = if super_block.has_super(group_id) {
+                    group_start + 1 + super_block.gdt_blocks() + super_block.reserved_gdt_blocks()
+                } else {
+                    group_start
+                };
+                let inode_table = desc.bg_inode_table as usize..desc.bg_inode_table as usize + inode_table_blocks;
+                group.data_bitmap.rebuild(&self.manager, |block_id| {
+                    block_id < super_end || block_id >= blocks_count || inode_table.contains(&block_id)
+                        || block_id == desc.bg_block_bitmap as usize || block_id == desc.bg_inode_bitmap as usize
+                })?;
+            }
+            group.desc.bg_flags &= !(EXT4_BG_INODE_UNINIT | EXT4_BG_BLOCK_UNINIT);
+            group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Recount the free inodes and blocks and the directories of each group
+    /// from its bitmaps and inode table, when the counts were read from a
+    /// backup older than them
+    fn recount_groups(&self) -> Ext2Result {
+        for group_id in 0..self.group_count() {
+            let inode_bitmap = self.get_inode_bitmap(group_id);
+            let mut used_dirs = 0;
+            for inode_id in inode_bitmap.minimum()..inode_bitmap.maximum() {
+                if inode_bitmap.test(&self.manager, inode_id)? && self.read_disk_inode(inode_id as u32)?.is_dir() {
+                    used_dirs += 1;
+                }
+            }
+            let groups = self.groups.shared_lock();
+            let mut group = groups[group_id].lock();
+            let data_bitmap = group.data_bitmap;
+            group.desc.bg_free_inodes_count = (inode_bitmap.maximum() - inode_bitmap.minimum()
+                - inode_bitmap.count(&self.manager)?) as u16;
+            group.desc.bg_free_blocks_count = (data_bitmap.maximum() - data_bitmap.minimum()
+                - data_bitmap.count(&self.manager)?) as u16;
+            group.desc.bg_used_dirs_count = used_dirs;
+            group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+        }
+        let (free_inodes, free_blocks) = self.groups.shared_lock().iter()
+            .fold((0, 0), |(inodes, blocks), group| {
+                let desc = group.lock().desc;
+                (inodes + desc.bg_free_inodes_count as u32, blocks + desc.bg_free_blocks_count as u32)
+            });
+        let mut inner = self.inner.lock();
+        inner.super_block.s_free_inodes_count = free_inodes;
+        inner.super_block.s_free_blocks_count = free_blocks;
+        Ok(())
+    }
+
+    /// Size of blocks in bytes
+    pub fn block_size(&self) -> usize {
+        self.manager.block_size()
+    }
+
+    /// Whether the file system is mounted read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether directory entries record the file type
+    pub fn has_file_type(&self) -> bool {
+        self.inner.lock().super_block.has_file_type()
+    }
+
+    /// Whether new files map their data through extent trees
+    pub fn has_extents(&self) -> bool {
+        self.inner.lock().super_block.has_extents()
+    }
 
-        fs.write_super_block();
+    /// Checksums of metadata, `None` if the file system does not keep them
+    pub(crate) fn checksum(&self) -> Option<Checksum> {
+        self.csum
+    }
 
-        fs
+    /// How names are hashed in directory indexes, `None` if indexes are not kept up to date
+    pub(crate) fn hash_info(&self) -> Option<HashInfo> {
+        self.inner.lock().super_block.hash_info()
     }
 
-    pub fn root_inode(efs: &Arc<Self>) -> Inode {
-        Inode::new(Self::root_inode_cache(efs))
+    pub fn root_inode(efs: &Arc<Self>) -> Ext2Result<Inode> {
+        Self::root_inode_cache(efs).map(Inode::new)
     }
 
     /// Get root inode
-    fn root_inode_cache(efs: &Arc<Self>) -> Arc<SpinMutex<InodeCache>> {
-        Self::get_inode_cache(efs, EXT2_ROOT_INO).unwrap()
+    fn root_inode_cache(efs: &Arc<Self>) -> Ext2Result<Arc<SleepRwMutex<InodeCache>>> {
+        Self::get_inode_cache(efs, EXT2_ROOT_INO)
     }
 
-    pub fn get_inode_cache(efs: &Arc<Self>, inode_id: usize) -> Option<Arc<SpinMutex<InodeCache>>> {
-        efs.inode_manager.lock().get_or_insert(inode_id, efs)
+    /// Get the cache of an inode in use, `NotFound` if it is free or out of range
+    pub fn get_inode_cache(efs: &Arc<Self>, inode_id: usize) -> Ext2Result<Arc<SleepRwMutex<InodeCache>>> {
+        if let Some(inode_cache) = efs.inode_manager.lock().get(inode_id) {
+            return Ok(inode_cache);
+        }
+        // the inode is read without holding the manager, so another task may
+        // cache it meanwhile and then its cache is used instead
+        let cache = Self::create_inode_cache(efs, inode_id)?;
+        Ok(efs.inode_manager.lock().insert(inode_id, cache))
     } 
 
-    pub fn create_inode_cache(efs: &Arc<Self>, inode_id: usize) -> Option<InodeCache> {
-        if inode_id == 0 || !efs.inode_exists(inode_id as _) {
-            None
+    pub fn create_inode_cache(efs: &Arc<Self>, inode_id: usize) -> Ext2Result<InodeCache> {
+        let inodes_count = efs.inner.lock().super_block.s_inodes_count as usize;
+        if inode_id == 0 || inode_id > inodes_count || !efs.inode_exists(inode_id as _)?
+            || efs.is_orphan(inode_id as _)
+        {
+            Err(Ext2Error::NotFound)
         } else {
-            let (block_id, offset) = efs.inner.lock().get_disk_inode_pos(inode_id as u32);
-            Some(InodeCache::new(
+            let (block_id, offset) = efs.get_disk_inode_pos(inode_id as u32)?;
+            InodeCache::new(
                 inode_id,
                 block_id as usize,
                 offset,
                 Arc::clone(efs)
-            ))
+            )
         }
     }
 
-    /// Get inode block_id and offset from inode_id
-    pub fn get_disk_inode_pos(&self, mut inode_id: u32) -> (u32, usize) {
-        self.inner.lock().get_disk_inode_pos(inode_id)
+    /// Get inode block_id and offset from inode_id, `Corrupted` if it is out
+    /// of the file system
+    pub fn get_disk_inode_pos(&self, inode_id: u32) -> Ext2Result<(u32, usize)> {
+        let inner = self.inner.lock();
+        let group_id = inner.inode_group(inode_id)?;
+        let group_offset = (inode_id - 1)%inner.super_block.s_inodes_per_group;
+        let inode_size = inner.super_block.inode_size();
+        let inode_per_block = inner.block_size()/inode_size;
+        drop(inner);
+        let block_id = self.group_desc(group_id).bg_inode_table + group_offset/inode_per_block as u32;
+
+        Ok((block_id, (group_offset as usize%inode_per_block) * inode_size))
     }
 
-    // /// Get inode bitmap for group x
-    // pub fn get_inode_bitmap(&self, group_id: usize) -> Bitmap {
-    //     self.inner.lock().get_inode_bitmap(group_id)
-    // }
+    /// Write a new disk inode, clearing the rest of its record on disk
+    pub fn init_disk_inode(&self, inode_id: u32, new_inode: DiskInode) -> Ext2Result {
+        let inode_size = self.inner.lock().super_block.inode_size();
+        let (block_id, offset) = self.get_disk_inode_pos(inode_id)?;
+        let inode_block = self.manager.get_block_cache(block_id as _)?;
+        let mut lk = inode_block.lock();
+        lk.modify_slice(|data_block: &mut DataBlock| {
+            data_block[offset..offset + inode_size].fill(0);
+        });
+        lk.modify(offset, |disk_inode: &mut DiskInode| {
+            *disk_inode = new_inode;
+        });
+        self.seal_inode(&mut lk, inode_id, offset);
+        drop(lk);
+        self.manager.release_block(inode_block);
+        Ok(())
+    }
 
-    // /// Get data bitmap for group x
-    // pub fn get_data_bitmap(&self, group_id: usize) -> Bitmap {
-    //     self.inner.lock().get_data_bitmap(group_id)
-    // }
+    /// Read a disk inode, whether it is in use or not
+    pub(crate) fn read_disk_inode(&self, inode_id: u32) -> Ext2Result<DiskInode> {
+        let (block_id, offset) = self.get_disk_inode_pos(inode_id)?;
+        let inode_block = self.manager.get_block_cache(block_id as _)?;
+        let disk_inode = inode_block.lock()
+            .read(offset, |disk_inode: &DiskInode| *disk_inode);
+        self.manager.release_block(inode_block);
+        Ok(disk_inode)
+    }
 
-    /// Allocate inode (will modify meta data)
-    pub fn alloc_inode(&self) -> Option<u32> {
-        let mut inner = self.inner.lock();
-        for group_id in 0..inner.group_desc_table.len() {
-            if let Some(inode_id) = inner.get_inode_bitmap(group_id).alloc(&self.manager) {
-                inner.group_desc_table[group_id].bg_free_inodes_count -= 1; // still need to mantain bg_used_dir_count
-                inner.super_block.s_free_inodes_count -= 1;
-                return  Some(inode_id as u32);
+    /// Call a function over a disk inode to modify it, whether it is in use or not
+    pub(crate) fn modify_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> Ext2Result<V> {
+        let (block_id, offset) = self.get_disk_inode_pos(inode_id)?;
+        let inode_block = self.manager.get_block_cache(block_id as _)?;
+        let mut lk = inode_block.lock();
+        let ret = lk.modify(offset, f);
+        self.seal_inode(&mut lk, inode_id, offset);
+        drop(lk);
+        self.manager.release_block(inode_block);
+        Ok(ret)
+    }
+
+    /// Update the checksum of an inode at `offset` of the block of the inode
+    /// table holding it, once it changed
+    pub(crate) fn seal_inode(&self, block: &mut BlockCache, inode_id: u32, offset: usize) {
+        if let Some(csum) = &self.csum {
+            block.modify_slice(|data: &mut DataBlock| csum.set_inode(inode_id, data, offset));
+        }
+    }
+
+    /// Blocks of the extent tree of an inode below its root
+    fn extent_nodes(&self, disk_inode: &DiskInode) -> Ext2Result<Vec<u32>> {
+        let mut nodes = Vec::new();
+        if disk_inode.has_extents() {
+            extent::walk(disk_inode.extent_root(), &self.manager, &mut |item| {
+                if let TreeItem::Node(block_id) = item {
+                    nodes.push(block_id);
+                }
+                true
+            })?;
+        }
+        Ok(nodes)
+    }
+
+    /// Update the checksums of the extent tree blocks of an inode that changed
+    pub(crate) fn seal_extent_tree(&self, inode_id: u32, disk_inode: &DiskInode) -> Ext2Result {
+        let csum = match &self.csum {
+            Some(csum) => csum,
+            None => return Ok(()),
+        };
+        let seed = csum.inode_seed(inode_id, disk_inode.generation());
+        for block_id in self.extent_nodes(disk_inode)? {
+            let node_block = self.manager.get_block_cache(block_id as _)?;
+            let mut lk = node_block.lock();
+            if !lk.read_slice(|data: &DataBlock| csum.verify_extent_block(seed, data)) {
+                lk.modify_slice(|data: &mut DataBlock| csum.set_extent_block(seed, data));
             }
+            drop(lk);
+            self.manager.release_block(node_block);
         }
-        None
+        Ok(())
     }
 
-    /// Allocate data block (will modify meta data)
-    pub fn alloc_data(&self) -> Option<u32> {
-        let mut inner = self.inner.lock();
-        for group_id in 0..inner.group_desc_table.len() {
-            if let Some(block_id) = inner.get_data_bitmap(group_id).alloc(&self.manager) {
-                inner.group_desc_table[group_id].bg_free_blocks_count -= 1; // still need to mantain bg_used_dir_count
-                inner.super_block.s_free_blocks_count -= 1;
-                return Some(block_id as u32);
+    /// Check the checksums of the extent tree blocks of an inode
+    pub(crate) fn verify_extent_tree(&self, inode_id: u32, disk_inode: &DiskInode) -> Ext2Result {
+        let csum = match &self.csum {
+            Some(csum) => csum,
+            None => return Ok(()),
+        };
+        let seed = csum.inode_seed(inode_id, disk_inode.generation());
+        for block_id in self.extent_nodes(disk_inode)? {
+            let node_block = self.manager.get_block_cache(block_id as _)?;
+            let ok = node_block.lock().read_slice(|data: &DataBlock| csum.verify_extent_block(seed, data));
+            self.manager.release_block(node_block);
+            if !ok {
+                error!("Bad checksum of extent tree block {} of inode {}", block_id, inode_id);
+                return Err(Ext2Error::Corrupted);
             }
         }
-        None
+        Ok(())
+    }
+
+    /// Copy of the super block in memory
+    pub(crate) fn super_block(&self) -> SuperBlock {
+        self.inner.lock().super_block
+    }
+
+    /// Number of block groups
+    pub(crate) fn group_count(&self) -> usize {
+        self.groups.shared_lock().len()
+    }
+
+    /// Copy of the descriptor of group x in memory
+    pub(crate) fn group_desc(&self, group_id: usize) -> BlockGroupDesc {
+        self.groups.shared_lock()[group_id].lock().desc
+    }
+
+    /// Get inode bitmap for group x
+    pub(crate) fn get_inode_bitmap(&self, group_id: usize) -> Bitmap {
+        self.groups.shared_lock()[group_id].lock().inode_bitmap
+    }
+
+    /// Get data bitmap for group x
+    pub(crate) fn get_data_bitmap(&self, group_id: usize) -> Bitmap {
+        self.groups.shared_lock()[group_id].lock().data_bitmap
     }
 
-    /// Batch allocate data
-    pub fn batch_alloc_data(&self, block_num: usize) -> Vec<u32> {
+    /// Rewrite the bitmaps of group x from what is in use, and recount the
+    /// free inodes and blocks of the group and of the file system
+    pub(crate) fn rebuild_group(
+        &self,
+        group_id: usize,
+        inode_used: impl Fn(usize) -> bool,
+        block_used: impl Fn(usize) -> bool,
+        used_dirs: usize
+    ) -> Ext2Result {
         let mut inner = self.inner.lock();
+        let groups = self.groups.shared_lock();
+        let mut group = groups[group_id].lock();
+        let inode_bitmap = group.inode_bitmap;
+        let free_inodes = inode_bitmap.maximum() - inode_bitmap.minimum()
+            - inode_bitmap.rebuild(&self.manager, inode_used)?;
+        let data_bitmap = group.data_bitmap;
+        let free_blocks = data_bitmap.maximum() - data_bitmap.minimum()
+            - data_bitmap.rebuild(&self.manager, block_used)?;
+        group.desc.bg_free_inodes_count = free_inodes as u16;
+        group.desc.bg_free_blocks_count = free_blocks as u16;
+        group.desc.bg_used_dirs_count = used_dirs as u16;
+        group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+        drop(group);
+
+        let (free_inodes, free_blocks) = groups.iter()
+            .fold((0, 0), |(inodes, blocks), group| {
+                let desc = group.lock().desc;
+                (inodes + desc.bg_free_inodes_count as u32, blocks + desc.bg_free_blocks_count as u32)
+            });
+        inner.super_block.s_free_inodes_count = free_inodes;
+        inner.super_block.s_free_blocks_count = free_blocks;
+        Ok(())
+    }
+
+    /// Allocate inode (will modify meta data)
+    pub fn alloc_inode(&self, parent: u32, is_dir: bool) -> Ext2Result<u32> {
+        let usage = self.group_usage();
+        let parent_group = self.inner.lock().inode_group(parent)?;
+        let top_level = parent == EXT2_ROOT_INO as u32;
+        let first = self.alloc_policy().inode_group(&usage, parent_group, is_dir, top_level).min(usage.len() - 1);
+        let groups = self.groups.shared_lock();
+        for group_id in (first..groups.len()).chain(0..first) {
+            let mut group = groups[group_id].lock();
+            if group.desc.bg_free_inodes_count == 0 {
+                continue;
+            }
+            if let Some(inode_id) = group.inode_bitmap.alloc(&self.manager)? {
+                group.desc.bg_free_inodes_count -= 1;
+                if is_dir {
+                    group.desc.bg_used_dirs_count += 1;
+                }
+                // the inodes past the last one ever used stay unused
+                let unused_after = (group.inode_bitmap.maximum() - 1 - inode_id) as u16;
+                group.desc.bg_itable_unused = group.desc.bg_itable_unused.min(unused_after);
+                group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+                drop(group);
+                self.inner.lock().super_block.s_free_inodes_count -= 1;
+                return Ok(inode_id as u32);
+            }
+        }
+        Err(Ext2Error::NoSpace)
+    }
+
+    /// Allocate data block (will modify meta data)
+    pub fn alloc_data(&self) -> Ext2Result<u32> {
+        self.alloc_data_runs(None, 1).map(|blocks| blocks[0])
+    }
+
+    /// Batch allocate data, fewer blocks are returned if the file system is full
+    pub fn batch_alloc_data(&self, block_num: usize) -> Ext2Result<Vec<u32>> {
         let mut allocated_blocks: Vec<u32> = Vec::new();
         for _ in 0..block_num {
-            let block_id = {
-                let mut result = None;
-                for group_id in 0..inner.group_desc_table.len() {
-                    if let Some(block_id) = inner.get_data_bitmap(group_id).alloc(&self.manager) {
-                        inner.group_desc_table[group_id].bg_free_blocks_count -= 1; // still need to mantain bg_used_dir_count
-                        inner.super_block.s_free_blocks_count -= 1;
-                        result = Some(block_id as u32);
+            match self.alloc_data() {
+                Ok(block_id) => allocated_blocks.push(block_id),
+                Err(Ext2Error::NoSpace) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(allocated_blocks)
+    }
+
+    /// Batch allocate data in as few runs of consecutive blocks as possible,
+    /// starting from `goal` if it is free so that a file can grow in place.
+    /// Nothing is allocated if the file system does not have enough free blocks.
+    pub fn alloc_data_runs(&self, goal: Option<u32>, block_num: usize) -> Ext2Result<Vec<u32>> {
+        self.alloc_blocks(None, goal, block_num)
+    }
+
+    /// Batch allocate data for an inode as [`Ext2FileSystem::alloc_data_runs`]
+    /// does, taking blocks from its preallocation window first
+    pub(crate) fn alloc_inode_blocks(&self, inode_id: u32, goal: Option<u32>, block_num: usize) -> Ext2Result<Vec<u32>> {
+        self.alloc_blocks(Some(inode_id), goal, block_num)
+    }
+
+    /// Where the data of an inode starts while it has no block to follow
+    pub(crate) fn data_goal(&self, inode_id: u32) -> Option<u32> {
+        let usage = self.group_usage();
+        let inner = self.inner.lock();
+        let group_id = self.alloc_policy().data_group(&usage, inner.inode_group(inode_id).ok()?)?;
+        let super_block = &inner.super_block;
+        Some(super_block.s_first_data_block + (group_id.min(usage.len() - 1) as u32) * super_block.s_blocks_per_group)
+    }
+
+    /// Forget the preallocation window of an inode, its blocks were never
+    /// allocated
+    pub(crate) fn release_window(&self, inode_id: u32) {
+        self.windows.lock().remove(&inode_id);
+    }
+
+    /// Usage of every group, for the allocation policy
+    fn group_usage(&self) -> Vec<GroupUsage> {
+        let blocks_count = self.super_block().s_blocks_count as usize;
+        self.groups.shared_lock().iter()
+            .map(|group| {
+                let group = group.lock();
+                let data_bitmap = &group.data_bitmap;
+                GroupUsage {
+                    inodes: (group.inode_bitmap.maximum() - group.inode_bitmap.minimum()) as u32,
+                    blocks: (data_bitmap.maximum().min(blocks_count) - data_bitmap.minimum()) as u32,
+                    free_inodes: group.desc.bg_free_inodes_count as u32,
+                    free_blocks: group.desc.bg_free_blocks_count as u32,
+                    used_dirs: group.desc.bg_used_dirs_count as u32,
+                }
+            })
+            .collect()
+    }
+
+    /// Allocate blocks for `owner` if any, which gets a new preallocation
+    /// window past them unless they came from its current one. The windows
+    /// of other inodes are left alone unless there is no room elsewhere.
+    fn alloc_blocks(&self, owner: Option<u32>, goal: Option<u32>, block_num: usize) -> Ext2Result<Vec<u32>> {
+        let policy = self.alloc_policy();
+        let mut window = owner.and_then(|inode_id| self.windows.lock().remove(&inode_id));
+        let mut reserved: Vec<Range<usize>> = self.windows.lock().values()
+            .map(|window| window.blocks.start as usize..window.blocks.end as usize)
+            .collect();
+        let super_block = self.super_block();
+        let groups = self.groups.shared_lock();
+        let group_count = groups.len();
+        let mut goal = goal.filter(|goal| {
+            *goal >= super_block.s_first_data_block && *goal < super_block.s_blocks_count
+        });
+        let mut allocated_blocks: Vec<u32> = Vec::new();
+        while allocated_blocks.len() < block_num {
+            let left = block_num - allocated_blocks.len();
+            let in_window = match (&window, goal) {
+                (Some(window), Some(goal)) => window.blocks.contains(&goal),
+                _ => false,
+            };
+            let max = match (&window, goal) {
+                (Some(window), Some(goal)) if in_window => left.min((window.blocks.end - goal) as usize),
+                _ if owner.is_some() => {
+                    // the window grows while the file fills it up
+                    let used_up = match (&window, goal) {
+                        (Some(window), Some(goal)) if window.blocks.end == goal => window.size,
+                        _ => 0,
+                    };
+                    left + policy.prealloc_window(used_up)
+                }
+                _ => left,
+            };
+            let first_group = match goal {
+                Some(goal) => (goal - super_block.s_first_data_block) as usize / super_block.s_blocks_per_group as usize,
+                None => 0,
+            };
+            let mut group_goal = goal.map(|goal| goal as usize);
+            let mut run = None;
+            for group_id in (first_group..group_count).chain(0..first_group) {
+                let mut group = groups[group_id].lock();
+                if group.desc.bg_free_blocks_count > 0 {
+                    if let Some((start, len)) = group.data_bitmap.find_run(&self.manager, group_goal, max, &reserved)? {
+                        let take = len.min(left);
+                        group.data_bitmap.alloc_range(&self.manager, start, take)?;
+                        group.desc.bg_free_blocks_count -= take as u16;
+                        group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+                        run = Some((start, len, take));
                         break;
                     }
                 }
-                result
+                group_goal = None;
+            }
+            let (start, len, take) = match run {
+                Some(run) => run,
+                // take the windows of other inodes before giving up
+                None if !reserved.is_empty() => {
+                    reserved.clear();
+                    continue;
+                }
+                None => {
+                    drop(groups);
+                    let mut inner = self.inner.lock();
+                    for block_id in allocated_blocks {
+                        self.free_block(&mut inner, block_id)?;
+                    }
+                    return Err(Ext2Error::NoSpace);
+                }
             };
-            if let Some(bid) = block_id {
-                allocated_blocks.push(bid);
-            } else {
-                return allocated_blocks;
+            self.inner.lock().super_block.s_free_blocks_count -= take as u32;
+            allocated_blocks.extend(start as u32..(start + take) as u32);
+            if owner.is_some() {
+                window = match window {
+                    Some(window) if in_window && goal == Some(start as u32) => Some(Window {
+                        blocks: (start + take) as u32..window.blocks.end,
+                        size: window.size,
+                    }),
+                    _ => Some(Window {
+                        blocks: (start + take) as u32..(start + len) as u32,
+                        size: len - take,
+                    }),
+                };
             }
+            goal = Some((start + take) as u32).filter(|goal| *goal < super_block.s_blocks_count);
         }
-
-        allocated_blocks
+        drop(groups);
+        if let (Some(inode_id), Some(window)) = (owner, window) {
+            if window.size > 0 {
+                self.windows.lock().insert(inode_id, window);
+            }
+        }
+        for block_id in allocated_blocks.iter() {
+            self.zero_block(*block_id)?;
+        }
+        Ok(allocated_blocks)
     }
 
     /// Test whether an inode exists
-    pub fn inode_exists(&self, inode_id: u32) -> bool {
-        assert!(inode_id != 0);
-        let mut inner = self.inner.lock();
-        let group_id = (inode_id as usize - 1) / INODES_PER_GRP;
-        inner.get_inode_bitmap(group_id).test(&self.manager, inode_id as usize)
+    pub fn inode_exists(&self, inode_id: u32) -> Ext2Result<bool> {
+        let group_id = self.inner.lock().inode_group(inode_id)?;
+        self.get_inode_bitmap(group_id).test(&self.manager, inode_id as usize)
+    }
+
+    /// Test whether a block is allocated, the blocks past the end of the
+    /// file system are never free
+    pub fn block_exists(&self, block_id: u32) -> Ext2Result<bool> {
+        let inner = self.inner.lock();
+        if block_id >= inner.super_block.s_blocks_count {
+            return Ok(true);
+        }
+        let group_id = inner.block_group(block_id)?;
+        drop(inner);
+        self.get_data_bitmap(group_id).test(&self.manager, block_id as usize)
     }
 
     /// Dealloc inode (will modify meta data)
-    pub fn dealloc_inode(&self, inode_id: u32) {
-        assert!(inode_id != 0);
-        let mut inner = self.inner.lock();
-        let group_id = (inode_id as usize - 1) / INODES_PER_GRP;
-        inner.get_inode_bitmap(group_id).dealloc(&self.manager, inode_id as usize);
+    pub fn dealloc_inode(&self, inode_id: u32, is_dir: bool) -> Ext2Result {
+        let group_id = self.inner.lock().inode_group(inode_id)?;
+        self.release_window(inode_id);
+        let groups = self.groups.shared_lock();
+        let mut group = groups[group_id].lock();
+        if is_dir && group.desc.bg_used_dirs_count == 0 {
+            error!("Group {} has no directories left to free inode {}", group_id, inode_id);
+            return Err(Ext2Error::Corrupted);
+        }
+        group.inode_bitmap.dealloc(&self.manager, inode_id as usize)?;
+        group.desc.bg_free_inodes_count += 1;
+        if is_dir {
+            group.desc.bg_used_dirs_count -= 1;
+        }
+        group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+        drop(group);
+        self.inner.lock().super_block.s_free_inodes_count += 1;
+        Ok(())
+    }
 
-        inner.super_block.s_free_inodes_count += 1;
-        inner.group_desc_table[group_id].bg_free_inodes_count += 1;
+    /// Whether an inode has no links left but is still open
+    pub fn is_orphan(&self, inode_id: u32) -> bool {
+        self.orphans.lock().contains(&inode_id)
     }
 
-    /// Dealloc inode (will modify meta data)
-    pub fn dealloc_block(&self, block_id: u32) {
-        let target_block = self.manager.lock().get_block_cache(block_id as _);
+    /// Put an inode at the head of the orphan list, so that it is freed on
+    /// the next open if the file system is not unmounted cleanly
+    pub(crate) fn add_orphan(&self, inode_id: u32) -> Ext2Result {
+        let mut orphans = self.orphans.lock();
+        let next = orphans.first().copied().unwrap_or(0);
+        self.modify_disk_inode(inode_id, |disk_inode| disk_inode.i_dtime = next)?;
+        self.inner.lock().super_block.set_last_orphan(inode_id);
+        orphans.insert(0, inode_id);
+        Ok(())
+    }
+
+    /// Take an inode off the orphan list, its `i_dtime` is left to the caller
+    pub(crate) fn remove_orphan(&self, inode_id: u32) -> Ext2Result {
+        let mut orphans = self.orphans.lock();
+        let pos = match orphans.iter().position(|orphan| *orphan == inode_id) {
+            Some(pos) => pos,
+            None => return Ok(()),
+        };
+        let next = orphans.get(pos + 1).copied().unwrap_or(0);
+        if pos == 0 {
+            self.inner.lock().super_block.set_last_orphan(next);
+        } else {
+            self.modify_disk_inode(orphans[pos - 1], |disk_inode| disk_inode.i_dtime = next)?;
+        }
+        orphans.remove(pos);
+        Ok(())
+    }
+
+    /// Free the inodes left on the orphan list, which were still open when
+    /// the file system was last used
+    fn release_orphans(efs: &Arc<Self>) -> Ext2Result {
+        let inodes_count = efs.inner.lock().super_block.s_inodes_count;
+        let mut orphans = Vec::new();
+        let mut inode_id = efs.inner.lock().super_block.last_orphan();
+        while inode_id != 0 {
+            if inode_id > inodes_count || orphans.contains(&inode_id) {
+                error!("Orphan list is broken at inode {}", inode_id);
+                return Err(Ext2Error::Corrupted);
+            }
+            orphans.push(inode_id);
+            inode_id = efs.read_disk_inode(inode_id)?.i_dtime;
+        }
+        if orphans.is_empty() {
+            return Ok(());
+        }
+        info!("Releasing {} orphan inodes", orphans.len());
+        *efs.orphans.lock() = orphans.clone();
+        let _op = Self::begin_op(efs);
+        for inode_id in orphans {
+            if efs.read_disk_inode(inode_id)?.i_links_count > 0 {
+                // other drivers also list inodes being truncated, which are kept
+                efs.remove_orphan(inode_id)?;
+                continue;
+            }
+            let (block_id, offset) = efs.get_disk_inode_pos(inode_id)?;
+            InodeCache::new(inode_id as usize, block_id as usize, offset, Arc::clone(efs))?
+                .release()?;
+        }
+        Ok(())
+    }
+
+    /// Zero a block about to be used for data
+    pub(crate) fn zero_block(&self, block_id: u32) -> Ext2Result {
+        let target_block = self.manager.get_block_cache(block_id as _)?;
         target_block.lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                data_block.iter_mut().for_each(|p| {
-                    *p = 0;
-                })
+            .modify_data_slice(|data_block: &mut DataBlock| {
+                data_block.fill(0);
             });
-        self.manager.lock().release_block(target_block);
+        self.manager.release_block(target_block);
+        Ok(())
+    }
+
+    /// Dealloc data block (will modify meta data)
+    pub fn dealloc_block(&self, block_id: u32) -> Ext2Result {
         let mut inner = self.inner.lock();
-        let group_id = block_id as usize / BLOCKS_PER_GRP;
-        inner.get_data_bitmap(group_id).dealloc(&self.manager, block_id as usize);
-        inner.super_block.s_free_blocks_count += 1;
-        inner.group_desc_table[group_id].bg_free_blocks_count += 1;
+        self.release_block(&mut inner, block_id)
     }
 
-    pub fn batch_dealloc_block(&self, blocks: &Vec<u32>) {
+    pub fn batch_dealloc_block(&self, blocks: &Vec<u32>) -> Ext2Result {
         let mut inner = self.inner.lock();
         for block_id in blocks {
-            let target_block = self.manager.lock().get_block_cache(*block_id as _);
-            target_block.lock()
-                .modify(0, |data_block: &mut DataBlock| {
-                    data_block.iter_mut().for_each(|p| {
-                        *p = 0;
-                    })
-                });
-            self.manager.lock().release_block(target_block);
-            let group_id = *block_id as usize / BLOCKS_PER_GRP;
-            inner.get_data_bitmap(group_id).dealloc(&self.manager, *block_id as usize);
-            inner.super_block.s_free_blocks_count += 1;
-            inner.group_desc_table[group_id].bg_free_blocks_count += 1;
+            self.release_block(&mut inner, *block_id)?;
+        }
+        Ok(())
+    }
+
+    fn release_block(&self, inner: &mut Ext2FileSystemInner, block_id: u32) -> Ext2Result {
+        inner.block_group(block_id)?;
+        if self.manager.has_journal() {
+            // the block is still in use until the running transaction commits,
+            // so it must not be reallocated and overwritten in place before that
+            self.manager.revoke(block_id as _);
+            inner.pending_free.push(block_id);
+            Ok(())
+        } else {
+            self.free_block(inner, block_id)
+        }
+    }
+
+    /// Mark a data block as free
+    fn free_block(&self, inner: &mut Ext2FileSystemInner, block_id: u32) -> Ext2Result {
+        let group_id = inner.block_group(block_id)?;
+        let groups = self.groups.shared_lock();
+        let mut group = groups[group_id].lock();
+        group.data_bitmap.dealloc(&self.manager, block_id as usize)?;
+        group.desc.bg_free_blocks_count += 1;
+        group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+        inner.super_block.s_free_blocks_count += 1;
+        Ok(())
+    }
+
+    /// Attributes of an extended attribute block
+    pub(crate) fn read_xattr_block(&self, block_id: u32) -> Ext2Result<XattrBlock> {
+        let mut shared = self.xattr_blocks.lock();
+        self.load_xattr_block(&mut shared, block_id).map(|(block, _)| block)
+    }
+
+    /// Store the attributes of an inode whose attributes were in `old_block`,
+    /// return the block holding them now, 0 if there are none. A block with
+    /// the same attributes is shared if there is one, the old block is updated
+    /// in place if no other inode uses it.
+    pub(crate) fn update_xattr_block(&self, old_block: u32, mut block: XattrBlock) -> Ext2Result<u32> {
+        let mut shared = self.xattr_blocks.lock();
+        if block.entries.is_empty() {
+            if old_block != 0 {
+                self.put_xattr_block(&mut shared, old_block)?;
+            }
+            return Ok(0);
+        }
+        if !block.fits(self.block_size()) {
+            return Err(Ext2Error::NoSpace);
+        }
+        let hash = block.hash();
+        if hash != 0 {
+            let candidates: Vec<u32> = shared.range((hash, 0)..=(hash, u32::MAX))
+                .map(|(_, block_id)| *block_id)
+                .collect();
+            for block_id in candidates {
+                let (found, _) = self.load_xattr_block(&mut shared, block_id)?;
+                if found.entries != block.entries {
+                    continue;
+                }
+                if block_id == old_block {
+                    return Ok(old_block);
+                }
+                if found.refcount < XATTR_REFCOUNT_MAX {
+                    self.set_xattr_refcount(block_id, found.refcount + 1)?;
+                    if old_block != 0 {
+                        self.put_xattr_block(&mut shared, old_block)?;
+                    }
+                    return Ok(block_id);
+                }
+            }
+        }
+        block.refcount = 1;
+        if old_block != 0 {
+            let (old, old_hash) = self.load_xattr_block(&mut shared, old_block)?;
+            if old.refcount == 1 {
+                shared.remove(&(old_hash, old_block));
+                self.write_xattr_block(&mut shared, old_block, &block)?;
+                return Ok(old_block);
+            }
+        }
+        let block_id = self.alloc_data()?;
+        self.write_xattr_block(&mut shared, block_id, &block)?;
+        if old_block != 0 {
+            self.put_xattr_block(&mut shared, old_block)?;
+        }
+        self.inner.lock().super_block.set_ext_attr();
+        Ok(block_id)
+    }
+
+    /// Record that a file has grown past 2 GiB
+    pub(crate) fn set_large_file(&self) {
+        self.inner.lock().super_block.set_large_file();
+    }
+
+    /// Drop the reference of an inode being freed to its extended attribute block
+    pub(crate) fn release_xattr_block(&self, block_id: u32) -> Ext2Result {
+        let mut shared = self.xattr_blocks.lock();
+        self.put_xattr_block(&mut shared, block_id)
+    }
+
+    /// Read an extended attribute block and its recorded hash, remember it
+    /// to be shared
+    fn load_xattr_block(&self, shared: &mut BTreeSet<(u32, u32)>, block_id: u32) -> Ext2Result<(XattrBlock, u32)> {
+        let xattr_block = self.manager.get_block_cache(block_id as _)?;
+        let (block, hash, csum_ok) = xattr_block.lock()
+            .read_slice(|data: &DataBlock| (
+                XattrBlock::read(data),
+                xattr::block_hash(data),
+                match &self.csum {
+                    Some(csum) => csum.verify_xattr_block(block_id, data),
+                    None => true,
+                }
+            ));
+        self.manager.release_block(xattr_block);
+        if !csum_ok {
+            error!("Bad checksum of extended attribute block {}", block_id);
+            return Err(Ext2Error::Corrupted);
+        }
+        match block {
+            Some(block) => {
+                if hash != 0 {
+                    shared.insert((hash, block_id));
+                }
+                Ok((block, hash))
+            }
+            None => {
+                error!("Bad extended attribute block {}", block_id);
+                Err(Ext2Error::Corrupted)
+            }
+        }
+    }
+
+    fn write_xattr_block(&self, shared: &mut BTreeSet<(u32, u32)>, block_id: u32, block: &XattrBlock) -> Ext2Result {
+        let xattr_block = self.manager.get_block_cache(block_id as _)?;
+        xattr_block.lock()
+            .modify_slice(|data: &mut DataBlock| {
+                block.write(data);
+                self.seal_xattr_block(block_id, data);
+            });
+        self.manager.release_block(xattr_block);
+        let hash = block.hash();
+        if hash != 0 {
+            shared.insert((hash, block_id));
+        }
+        Ok(())
+    }
+
+    fn set_xattr_refcount(&self, block_id: u32, refcount: u32) -> Ext2Result {
+        let xattr_block = self.manager.get_block_cache(block_id as _)?;
+        xattr_block.lock()
+            .modify_slice(|data: &mut DataBlock| {
+                xattr::set_refcount(data, refcount);
+                self.seal_xattr_block(block_id, data);
+            });
+        self.manager.release_block(xattr_block);
+        Ok(())
+    }
+
+    fn seal_xattr_block(&self, block_id: u32, data: &mut DataBlock) {
+        if let Some(csum) = &self.csum {
+            csum.set_xattr_block(block_id, data);
+        }
+    }
+
+    /// Drop a reference to an extended attribute block, free it once unused
+    fn put_xattr_block(&self, shared: &mut BTreeSet<(u32, u32)>, block_id: u32) -> Ext2Result {
+        let (block, hash) = self.load_xattr_block(shared, block_id)?;
+        if block.refcount > 1 {
+            self.set_xattr_refcount(block_id, block.refcount - 1)
+        } else {
+            shared.remove(&(hash, block_id));
+            self.dealloc_block(block_id)
         }
     }
 
     /// Write super block to disk
-    pub fn write_super_block(&self) {
-        self.inner.lock().write_super_block(&self.manager);
-    }
-
-    // /// Write group description of group_id to disk
-    // pub fn write_group_desc(&self, group_id: usize) {
-    //     let block_id = self.super_block.s_first_data_block as usize + 1 + (group_id * size_of::<BlockGroupDesc>())/BLOCK_SIZE;
-    //     let offset = (group_id * size_of::<BlockGroupDesc>())%BLOCK_SIZE;
-    //     get_block_cache(block_id, Arc::clone(&self.block_device))
-    //         .lock()
-    //         .modify(offset, |desc: &mut BlockGroupDesc| {
-    //             *desc = self.group_desc_table[group_id];
-    //         });
-    // }
-
-    // /// Write all group description to disk
-    // pub fn write_all_group_desc(&self) {
-    //     for group_id in 0..self.group_desc_table.len() {
-    //         self.write_group_desc(group_id);
-    //     }
-    // }
-
-    /// Write all meta data to disk
-    pub fn write_meta(&self) {
-        self.inner.lock().write_meta(&self.manager);
+    pub fn write_super_block(&self) -> Ext2Result {
+        self.inner.lock().write_super_block(&self.manager)
+    }
+
+    /// Write group description of group_id to disk
+    fn write_group_desc(&self, inner: &Ext2FileSystemInner, group_id: usize) -> Ext2Result {
+        let block_size = inner.block_size();
+        let block_id = inner.super_block.s_first_data_block as usize + 1 + (group_id * size_of::<BlockGroupDesc>())/block_size;
+        let offset = (group_id * size_of::<BlockGroupDesc>())%block_size;
+        let desc = self.sealed_desc(group_id, self.group_desc(group_id));
+        let gd_block = self.manager.get_block_cache(block_id)?;
+        gd_block.lock()
+            .modify(offset, |disk_desc: &mut BlockGroupDesc| {
+                *disk_desc = desc;
+            });
+        self.manager.release_block(gd_block);
+        Ok(())
+    }
+
+    /// A group descriptor as written to disk, with its checksum
+    fn sealed_desc(&self, group_id: usize, mut desc: BlockGroupDesc) -> BlockGroupDesc {
+        if let Some(csum) = &self.csum {
+            desc.bg_checksum = csum.group_desc(group_id, &desc);
+        }
+        desc
+    }
+
+    /// Write all meta data to disk, backups included
+    pub fn write_meta(&self) -> Ext2Result {
+        let inner = self.inner.lock();
+        self.write_all_meta(&inner)?;
+        self.write_backups(&inner.super_block)
+    }
+
+    /// Write super block and all group descriptions to disk
+    fn write_all_meta(&self, inner: &Ext2FileSystemInner) -> Ext2Result {
+        inner.write_super_block(&self.manager)?;
+        for group_id in 0..self.group_count() {
+            self.write_group_desc(inner, group_id)?;
+        }
+        Ok(())
+    }
+
+    /// Write meta data and all dirty blocks to disk
+    pub fn sync(&self) -> Ext2Result {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut inner = self.lock_idle();
+        if self.manager.has_journal() {
+            self.commit(&mut inner)?;
+            // backups follow the committed metadata, in a transaction of their own
+            self.write_backups(&inner.super_block)?;
+            self.commit(&mut inner)?;
+            if self.manager.reset_journal()? {
+                self.mark_recover(&mut inner, false)?;
+            }
+        } else {
+            self.write_all_meta(&inner)?;
+            self.write_backups(&inner.super_block)?;
+        }
+        self.manager.sync_all_block()
+    }
+
+    /// Size and usage of the file system, from the counters of the super
+    /// block that allocation keeps up to date
+    pub fn stats(&self) -> FsStats {
+        let super_block = self.super_block();
+        let uuid = super_block.uuid();
+        let word = |i: usize| u32::from_le_bytes([uuid[i], uuid[i + 1], uuid[i + 2], uuid[i + 3]]);
+        FsStats {
+            magic: SB_MAGIC,
+            block_size: super_block.block_size(),
+            blocks: super_block.s_blocks_count,
+            free_blocks: super_block.s_free_blocks_count,
+            avail_blocks: super_block.s_free_blocks_count.saturating_sub(super_block.r_blocks_count()),
+            inodes: super_block.s_inodes_count,
+            free_inodes: super_block.s_free_inodes_count,
+            fsid: (word(0) ^ word(8)) as u64 | ((word(4) ^ word(12)) as u64) << 32,
+            name_len: MAX_NAME_LEN
+        }
+    }
+
+    /// Grow the file system to `block_count` blocks, e.g. once its device has
+    /// been enlarged. The last group is filled up and new groups are appended,
+    /// the group descriptor table growing into the blocks reserved by the
+    /// resize inode when it needs more blocks. As when creating a file system,
+    /// a last group too small for its own metadata is left out. Shrinking is
+    /// not supported.
+    pub fn resize(&self, block_count: usize) -> Ext2Result {
+        if self.read_only {
+            return Err(Ext2Error::ReadOnly);
+        }
+        if block_count > self.manager.block_num() || block_count > u32::MAX as usize {
+            error!("Can not grow to {} blocks, the device has {}", block_count, self.manager.block_num());
+            return Err(Ext2Error::NoSpace);
+        }
+        // the resize inode stays in the first group
+        let resize_inode_pos = self.get_disk_inode_pos(EXT2_RESIZE_INO)?;
+        let mut inner = self.lock_idle();
+        // the resize is a transaction of its own
+        self.commit(&mut inner)?;
+        let old = inner.super_block;
+        let old_count = old.s_blocks_count as usize;
+        if block_count < old_count {
+            error!("Shrinking is not supported");
+            return Err(Ext2Error::Unsupported);
+        }
+        let first_data_block = old.s_first_data_block as usize;
+        let blocks_per_group = old.s_blocks_per_group as usize;
+        let inodes_per_group = old.s_inodes_per_group as usize;
+        let group_start = |group_id: usize| first_data_block + group_id * blocks_per_group;
+        let old_groups = old.group_count();
+        // groups with a backup keep room for the whole table, reserved blocks included
+        let gdt_and_reserved = old.gdt_blocks() + old.reserved_gdt_blocks();
+        let super_blocks = |group_id: usize| if old.has_super(group_id) { 1 + gdt_and_reserved } else { 0 };
+        let meta_blocks = |group_id: usize| super_blocks(group_id) + 2 + old.inode_table_blocks();
+
+        let mut new = old;
+        new.s_blocks_count = block_count as u32;
+        let mut group_num = new.group_count();
+        if group_num > old_groups && block_count - group_start(group_num - 1) <= meta_blocks(group_num - 1) {
+            group_num -= 1;
+            new.s_blocks_count = group_start(group_num) as u32;
+        }
+        let block_count = new.s_blocks_count as usize;
+        if block_count == old_count {
+            return Ok(());
+        }
+        if group_num * inodes_per_group > u32::MAX as usize {
+            error!("Too many inodes for {} groups", group_num);
+            return Err(Ext2Error::InvalidInput);
+        }
+        new.s_inodes_count = (group_num * inodes_per_group) as u32;
+        let (old_gdt, new_gdt) = (old.gdt_blocks(), new.gdt_blocks());
+        if new_gdt > gdt_and_reserved {
+            error!("No reserved blocks left for the group descriptor table to grow");
+            return Err(Ext2Error::NoSpace);
+        }
+        info!("Resize from {} to {} blocks, {} to {} groups", old_count, block_count, old_groups, group_num);
+
+        for index in old_gdt..new_gdt {
+            self.take_reserved_gdt(&old, index, resize_inode_pos)?;
+        }
+        if new.has_resize_inode() {
+            new.set_reserved_gdt_blocks(gdt_and_reserved - new_gdt);
+        }
+        let mut free_blocks = 0;
+        // blocks past the end of the last group are marked in use
+        let old_end = group_start(old_groups).min(block_count);
+        if old_count < old_end {
+            let groups = self.groups.shared_lock();
+            let mut group = groups[old_groups - 1].lock();
+            for block_id in old_count..old_end {
+                group.data_bitmap.dealloc(&self.manager, block_id)?;
+            }
+            group.desc.bg_free_blocks_count += (old_end - old_count) as u16;
+            group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+            free_blocks += old_end - old_count;
+        }
+        let mut groups = Vec::new();
+        for group_id in old_groups..group_num {
+            let start = group_start(group_id);
+            let end = (start + blocks_per_group).min(block_count);
+            let block_bitmap = start + super_blocks(group_id);
+            let meta_end = start + meta_blocks(group_id);
+            for block_id in block_bitmap + 2..meta_end {
+                self.zero_block(block_id as u32)?;
+            }
+            let desc = BlockGroupDesc::new(
+                block_bitmap,
+                block_bitmap + 1,
+                block_bitmap + 2,
+                end - meta_end, inodes_per_group, 0
+            );
+            let group = BlockGroup::new(&new, group_id, desc);
+            {
+                let mut group = group.lock();
+                group.inode_bitmap.rebuild(&self.manager, |_| false)?;
+                group.data_bitmap.rebuild(&self.manager, |block_id| block_id < meta_end || block_id >= end)?;
+                group.seal_bitmaps(&self.manager, self.csum.as_ref())?;
+            }
+            if old.has_super(group_id) {
+                self.add_reserved_gdt_backups(&new, start, resize_inode_pos)?;
+            }
+            free_blocks += end - meta_end;
+            groups.push(group);
+        }
+        self.groups.unique_lock().extend(groups);
+
+        new.s_free_blocks_count += free_blocks as u32;
+        new.s_free_inodes_count += ((group_num - old_groups) * inodes_per_group) as u32;
+        // root keeps the same share of the blocks
+        new.set_r_blocks_count((old.r_blocks_count() as u64 * block_count as u64 / old_count as u64) as u32);
+        inner.super_block = new;
+        drop(inner);
+        self.sync()
+    }
+
+    /// Turn the first block reserved for the group descriptor table into its
+    /// `index`-th block, along with its backups, which the resize inode no
+    /// longer maps
+    fn take_reserved_gdt(&self, super_block: &SuperBlock, index: usize, (inode_block_id, inode_offset): (u32, usize)) -> Ext2Result {
+        let block_size = self.block_size();
+        let primary = super_block.s_first_data_block + 1 + index as u32;
+        let inode_block = self.manager.get_block_cache(inode_block_id as _)?;
+        let dind = inode_block.lock()
+            .read(inode_offset, |disk_inode: &DiskInode| disk_inode.indirect_root(2));
+        // the double indirect block lists the reserved blocks by their index in the table
+        let slot = index % (block_size / 4);
+        let mut taken = false;
+        if dind != 0 {
+            let dind_block = self.manager.get_block_cache(dind as _)?;
+            taken = dind_block.lock().read_slice(|entries: &[u32]| entries[slot] == primary);
+            if taken {
+                dind_block.lock().modify_slice(|entries: &mut [u32]| entries[slot] = 0);
+            }
+            self.manager.release_block(dind_block);
+        }
+        if !taken {
+            self.manager.release_block(inode_block);
+            error!("Reserved group descriptor block {} is not mapped by the resize inode", primary);
+            return Err(Ext2Error::Corrupted);
+        }
+        // which lists its backups, it becomes part of the table
+        let primary_block = self.manager.get_block_cache(primary as _)?;
+        let backups = primary_block.lock().modify_slice(|entries: &mut [u32]| {
+            let backups = entries.iter().take_while(|block_id| **block_id != 0).count();
+            entries.fill(0);
+            backups
+        });
+        self.manager.release_block(primary_block);
+        let mut lk = inode_block.lock();
+        lk.modify(inode_offset, |disk_inode: &mut DiskInode| {
+            disk_inode.i_blocks -= ((1 + backups) * block_size / 512) as u32;
+        });
+        self.seal_inode(&mut lk, EXT2_RESIZE_INO, inode_offset);
+        drop(lk);
+        self.manager.release_block(inode_block);
+        Ok(())
+    }
+
+    /// Map the backups of the reserved group descriptor blocks in a new group
+    /// starting at `group_start` through the resize inode
+    fn add_reserved_gdt_backups(&self, super_block: &SuperBlock, group_start: usize, (inode_block_id, inode_offset): (u32, usize)) -> Ext2Result {
+        let block_size = self.block_size();
+        let reserved = super_block.reserved_gdt_blocks();
+        let gdt_blocks = super_block.gdt_blocks();
+        for i in 0..reserved {
+            let primary = super_block.s_first_data_block as usize + 1 + gdt_blocks + i;
+            let backup = (group_start + 1 + gdt_blocks + i) as u32;
+            let primary_block = self.manager.get_block_cache(primary)?;
+            let added = primary_block.lock().modify_slice(|entries: &mut [u32]| {
+                entries.iter_mut()
+                    .find(|block_id| **block_id == 0)
+                    .map(|block_id| *block_id = backup)
+                    .is_some()
+            });
+            self.manager.release_block(primary_block);
+            if !added {
+                error!("Reserved group descriptor block {} has no room for more backups", primary);
+                return Err(Ext2Error::NoSpace);
+            }
+        }
+        let inode_block = self.manager.get_block_cache(inode_block_id as _)?;
+        let mut lk = inode_block.lock();
+        lk.modify(inode_offset, |disk_inode: &mut DiskInode| {
+            disk_inode.i_blocks += (reserved * block_size / 512) as u32;
+        });
+        self.seal_inode(&mut lk, EXT2_RESIZE_INO, inode_offset);
+        drop(lk);
+        self.manager.release_block(inode_block);
+        Ok(())
+    }
+
+    /// Copy the super block and group descriptor table to the groups that
+    /// keep a backup of them, except groups whose metadata leaves no room.
+    /// Backups already up to date are left alone.
+    fn write_backups(&self, super_block: &SuperBlock) -> Ext2Result {
+        let descs: Vec<BlockGroupDesc> = self.groups.shared_lock()
+            .iter()
+            .enumerate()
+            .map(|(group_id, group)| self.sealed_desc(group_id, group.lock().desc))
+            .collect();
+        let gdt_blocks = super_block.gdt_blocks();
+        let descs_per_block = self.block_size() / size_of::<BlockGroupDesc>();
+        for (group_id, desc) in descs.iter().enumerate().skip(1) {
+            let group_start = super_block.s_first_data_block as usize + group_id * super_block.s_blocks_per_group as usize;
+            let meta_start = desc.bg_block_bitmap.min(desc.bg_inode_bitmap).min(desc.bg_inode_table) as usize;
+            if !super_block.has_super(group_id) || meta_start < group_start + 1 + gdt_blocks {
+                continue;
+            }
+            let mut backup = *super_block;
+            backup.s_block_group_nr = group_id as u16;
+            backup.seal();
+            self.update_block(group_start, as_bytes(core::slice::from_ref(&backup)))?;
+            for (i, chunk) in descs.chunks(descs_per_block).enumerate() {
+                self.update_block(group_start + 1 + i, as_bytes(chunk))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make block x hold `data` followed by zeros, unless it already does
+    fn update_block(&self, block_id: usize, data: &[u8]) -> Ext2Result {
+        let block = self.manager.get_block_cache(block_id)?;
+        let mut lk = block.lock();
+        let same = lk.read_slice(|data_block: &DataBlock| {
+            data_block[..data.len()] == *data && data_block[data.len()..].iter().all(|byte| *byte == 0)
+        });
+        if !same {
+            lk.modify_slice(|data_block: &mut DataBlock| {
+                data_block[..data.len()].copy_from_slice(data);
+                data_block[data.len()..].fill(0);
+            });
+        }
+        drop(lk);
+        self.manager.release_block(block);
+        Ok(())
+    }
+
+    /// Hits and misses of the block cache, and requests to the device
+    pub fn cache_stats(&self) -> CacheStats {
+        self.manager.stats()
+    }
+
+    pub fn writeback_policy(&self) -> WritebackPolicy {
+        *self.writeback.lock()
+    }
+
+    pub fn set_writeback_policy(&self, policy: WritebackPolicy) {
+        *self.writeback.lock() = policy;
+    }
+
+    /// Where new inodes and blocks go
+    pub fn alloc_policy(&self) -> Arc<dyn AllocPolicy> {
+        self.alloc_policy.lock().clone()
+    }
+
+    /// Change where new inodes and blocks go, the preallocation windows of
+    /// the previous policy are dropped
+    pub fn set_alloc_policy(&self, policy: Arc<dyn AllocPolicy>) {
+        *self.alloc_policy.lock() = policy;
+        self.windows.lock().clear();
+    }
+
+    /// Write back dirty blocks as the writeback policy asks, to be called
+    /// every `interval` of it. `now` is the time since some fixed point,
+    /// e.g. boot.
+    pub fn writeback(&self, now: Duration) -> Ext2Result {
+        if self.read_only {
+            return Ok(());
+        }
+        let policy = self.writeback_policy();
+        let dirty = self.manager.dirty_count();
+        if dirty == 0 {
+            return Ok(());
+        }
+        if dirty * 100 >= self.manager.capacity() * policy.dirty_ratio {
+            debug!("{} dirty blocks, write back all of them", dirty);
+            return self.sync();
+        }
+        if !self.manager.has_journal() {
+            self.write_meta()?;
+        }
+        if self.manager.write_expired(now, policy.dirty_expire)? {
+            self.commit_idle()?;
+        }
+        Ok(())
+    }
+
+    /// Commit the running transaction once no operation is in progress
+    pub fn commit_idle(&self) -> Ext2Result {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut inner = self.lock_idle();
+        self.commit(&mut inner)
+    }
+
+    /// Start an operation, the running transaction is never committed
+    /// before all operations in progress end
+    pub fn begin_op(efs: &Arc<Self>) -> FsOp {
+        efs.inner.lock().outstanding += 1;
+        FsOp { fs: efs.clone() }
+    }
+
+    fn end_op(&self) {
+        let mut inner = self.inner.lock();
+        inner.outstanding -= 1;
+        if inner.outstanding == 0
+            && (!inner.pending_free.is_empty() || self.manager.should_commit())
+        {
+            // the transaction is committed again by the next operation or sync
+            if let Err(err) = self.commit(&mut inner) {
+                error!("Failed to commit transaction: {}", err);
+            }
+        }
+    }
+
+    /// Lock inner meta data once no operation is in progress
+    fn lock_idle(&self) -> impl DerefMut<Target = Ext2FileSystemInner> + '_ {
+        loop {
+            let inner = self.inner.lock();
+            if inner.outstanding == 0 {
+                return inner;
+            }
+            drop(inner);
+            mutex::yield_now();
+        }
+    }
+
+    /// Commit the running transaction, no operation should be in progress
+    fn commit(&self, inner: &mut Ext2FileSystemInner) -> Ext2Result {
+        for block_id in core::mem::take(&mut inner.pending_free) {
+            self.free_block(inner, block_id)?;
+        }
+        if !self.manager.has_pending() {
+            return Ok(());
+        }
+        if self.manager.journal_is_empty() {
+            self.mark_recover(inner, true)?;
+        }
+        self.write_all_meta(inner)?;
+        self.manager.commit()
+    }
+
+    /// Set or clear the recovery flag of the super block on disk right away
+    fn mark_recover(&self, inner: &mut Ext2FileSystemInner, recover: bool) -> Ext2Result {
+        inner.super_block.set_recover(recover);
+        let offset = if inner.super_block.s_first_data_block == 0 { 1024 } else { 0 };
+        self.manager
+            .modify_on_disk(inner.super_block.s_first_data_block as _, offset, |super_block: &mut SuperBlock| {
+                super_block.set_recover(recover);
+                super_block.seal();
+            })
+    }
+}
+
+/// An operation in progress, see [`Ext2FileSystem::begin_op`]
+pub struct FsOp {
+    fs: Arc<Ext2FileSystem>
+}
+
+impl Drop for FsOp {
+    fn drop(&mut self) {
+        self.fs.end_op();
     }
 }
 
 impl Drop for Ext2FileSystem {
     fn drop(&mut self) {
-        self.write_meta();
-        self.manager.lock().sync_all_block();
+        if let Err(err) = self.sync() {
+            error!("Failed to sync file system: {}", err);
+        }
     }
 }
 
 struct Ext2FileSystemInner {
     /// Super block cache
     pub super_block: SuperBlock,
-    /// Group description
-    pub group_desc_table: Vec<BlockGroupDesc>,
+    /// Number of operations in progress
+    outstanding: usize,
+    /// Blocks freed by the running transaction
+    pending_free: Vec<u32>,
 }
 
 impl Ext2FileSystemInner {
-    pub fn new(sb: SuperBlock, gdt: Vec<BlockGroupDesc>) -> Self {
-        Self { super_block: sb, group_desc_table: gdt }
+    pub fn new(sb: SuperBlock) -> Self {
+        Self { super_block: sb, outstanding: 0, pending_free: Vec::new() }
     }
 
-    /// Get inode block_id and offset from inode_id
-    pub fn get_disk_inode_pos(&self, mut inode_id: u32) -> (u32, usize) {
-        assert!(inode_id != 0); // invalid inode id
-        inode_id -= 1;
-        let group_id = inode_id/INODES_PER_GRP as u32;
-        let group_offset = inode_id%INODES_PER_GRP as u32;
-        let inode_size = size_of::<DiskInode>();
-        let inode_per_block = BLOCK_SIZE/inode_size;
-        let block_id = self.group_desc_table[group_id as usize].bg_inode_table + group_offset/inode_per_block as u32;
-
-        (block_id, (group_offset as usize%inode_per_block) * inode_size)
+    fn block_size(&self) -> usize {
+        self.super_block.block_size()
     }
 
-    /// Get inode bitmap for group x
-    pub fn get_inode_bitmap(&self, group_id: usize) -> Bitmap {
-        Bitmap::new(
-            self.group_desc_table[group_id].bg_inode_bitmap as usize,
-            group_id * INODES_PER_GRP + 1
-        )
+    /// Get the group of a block, `Corrupted` if it is out of the file system
+    pub fn block_group(&self, block_id: u32) -> Ext2Result<usize> {
+        if block_id < self.super_block.s_first_data_block || block_id >= self.super_block.s_blocks_count {
+            error!("Block {} is out of the file system", block_id);
+            return Err(Ext2Error::Corrupted);
+        }
+        Ok((block_id - self.super_block.s_first_data_block) as usize / self.super_block.s_blocks_per_group as usize)
     }
 
-    /// Get data bitmap for group x
-    pub fn get_data_bitmap(&self, group_id: usize) -> Bitmap {
-        Bitmap::new(
-            self.group_desc_table[group_id].bg_block_bitmap as usize,
-            group_id * BLOCKS_PER_GRP
-        )
+    /// Get the group of an inode, `Corrupted` if it is out of the file system
+    pub fn inode_group(&self, inode_id: u32) -> Ext2Result<usize> {
+        if inode_id == 0 || inode_id > self.super_block.s_inodes_count {
+            error!("Inode {} is out of the file system", inode_id);
+            return Err(Ext2Error::Corrupted);
+        }
+        Ok((inode_id - 1) as usize / self.super_block.s_inodes_per_group as usize)
     }
 
     /// Write super block to disk
-    pub fn write_super_block(&self, manager: &SpinMutex<BlockCacheManager>) {
+    pub fn write_super_block(&self, manager: &BlockCacheManager) -> Ext2Result {
         let offset = if self.super_block.s_first_data_block == 0 { 1024 } else { 0 };
-        let sb_block = manager.lock().get_block_cache(self.super_block.s_first_data_block as _);
+        let mut sealed = self.super_block;
+        sealed.seal();
+        let sb_block = manager.get_block_cache(self.super_block.s_first_data_block as _)?;
         sb_block.lock()
             .modify(offset, |super_block: &mut SuperBlock| {
-                *super_block = self.super_block;
-            });
-        manager.lock().release_block(sb_block);
-    }
-
-    /// Write group description of group_id to disk
-    pub fn write_group_desc(&self, group_id: usize, manager: &SpinMutex<BlockCacheManager>) {
-        let block_id = self.super_block.s_first_data_block as usize + 1 + (group_id * size_of::<BlockGroupDesc>())/BLOCK_SIZE;
-        let offset = (group_id * size_of::<BlockGroupDesc>())%BLOCK_SIZE;
-        let gd_block = manager.lock().get_block_cache(block_id);
-        gd_block.lock()
-            .modify(offset, |desc: &mut BlockGroupDesc| {
-                *desc = self.group_desc_table[group_id];
+                *super_block = sealed;
             });
-        manager.lock().release_block(gd_block);
-    }
-
-    /// Write all group description to disk
-    pub fn write_all_group_desc(&self, manager: &SpinMutex<BlockCacheManager>) {
-        for group_id in 0..self.group_desc_table.len() {
-            self.write_group_desc(group_id, manager);
-        }
+        manager.release_block(sb_block);
+        Ok(())
     }
+}
 
-    /// Write all meta data to disk
-    pub fn write_meta(&self, manager: &SpinMutex<BlockCacheManager>) {
-        self.write_super_block(manager);
-        self.write_all_group_desc(manager);
-    }
-}
\ No newline at end of file
+/// The bytes of on-disk structures
+fn as_bytes<T>(items: &[T]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(items.as_ptr() as *const u8, core::mem::size_of_val(items)) }
+}
@@ -0,0 +1,56 @@
+use core::fmt::{Display, Formatter, Result};
+
+/// Errors reported by the file system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    /// No entry of the given name, or an inode that is not in use
+    NotFound,
+    /// An entry of the given name already exists
+    AlreadyExists,
+    /// A directory was expected
+    NotADirectory,
+    /// A directory was not expected
+    IsADirectory,
+    /// Removing or replacing a directory that still has entries
+    DirectoryNotEmpty,
+    /// An argument is invalid for the operation, such as moving a directory
+    /// into itself
+    InvalidInput,
+    /// No free block or inode left
+    NoSpace,
+    /// A name longer than `MAX_NAME_LEN`
+    NameTooLong,
+    /// A file larger than what its block map or extent tree can address
+    FileTooLarge,
+    /// Writing through a read-only handle
+    ReadOnly,
+    /// A feature of the image that is not supported
+    Unsupported,
+    /// On-disk structures are inconsistent
+    Corrupted,
+    /// The block device failed
+    Io,
+}
+
+pub type Ext2Result<T = ()> = core::result::Result<T, Ext2Error>;
+
+impl Display for Ext2Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let msg = match self {
+            Ext2Error::NotFound => "No such file or directory",
+            Ext2Error::AlreadyExists => "File exists",
+            Ext2Error::NotADirectory => "Not a directory",
+            Ext2Error::IsADirectory => "Is a directory",
+            Ext2Error::DirectoryNotEmpty => "Directory not empty",
+            Ext2Error::InvalidInput => "Invalid argument",
+            Ext2Error::NoSpace => "No space left on device",
+            Ext2Error::NameTooLong => "File name too long",
+            Ext2Error::FileTooLarge => "File too large",
+            Ext2Error::ReadOnly => "Read-only file handle",
+            Ext2Error::Unsupported => "Unsupported feature",
+            Ext2Error::Corrupted => "File system is corrupted",
+            Ext2Error::Io => "I/O error",
+        };
+        f.write_str(msg)
+    }
+}
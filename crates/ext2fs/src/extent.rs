@@ -0,0 +1,593 @@
+//! Extent trees, as in ext4.
+//!
+//! An inode with `EXT4_EXTENTS_FL` maps its data through a tree rooted in the
+//! 60 bytes of its block pointers instead of indirect blocks. Every node is a
+//! header followed by sorted entries. In a leaf these are extents, each
+//! mapping a run of logical blocks to a run of physical ones, and in an index
+//! node they give the first logical block below each child node. Logical
+//! blocks outside every extent are holes, and unwritten extents are
+//! allocated but read as zeros. Mapping blocks past the end of the tree only
+//! changes its rightmost path, other changes rebuild the tree from its
+//! extents in the blocks it already takes.
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use crate::block_cache_manager::BlockCacheManager;
+use crate::error::{Ext2Error, Ext2Result};
+use log::*;
+
+const EXT4_EXT_MAGIC: u16 = 0xf30a;
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 12;
+
+/// Size of the tree root, which takes the place of the block pointers
+pub const ROOT_SIZE: usize = 60;
+const ROOT_MAX_ENTRIES: usize = (ROOT_SIZE - HEADER_SIZE) / ENTRY_SIZE;
+
+/// Deepest tree, as in ext4
+const MAX_DEPTH: u16 = 5;
+
+/// Longest initialized extent, a longer length marks an unwritten extent of
+/// that many blocks more than this
+const EXT_INIT_MAX_LEN: u32 = 1 << 15;
+
+/// Longest extent, unwritten ones are one block shorter
+fn max_len(unwritten: bool) -> u32 {
+    if unwritten { EXT_INIT_MAX_LEN - 1 } else { EXT_INIT_MAX_LEN }
+}
+
+/// A run of logical blocks mapped to consecutive physical blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    /// First logical block
+    pub block: u32,
+    pub len: u32,
+    /// First physical block
+    pub start: u32,
+    pub unwritten: bool,
+}
+
+impl Extent {
+    /// Physical block of a logical block inside the extent
+    fn map(&self, block: u32) -> Option<u32> {
+        if block >= self.block && block - self.block < self.len {
+            Some(self.start + (block - self.block))
+        } else {
+            None
+        }
+    }
+}
+
+/// What `walk` meets in a tree
+pub enum TreeItem {
+    /// A tree block, its subtree is walked if the visitor returns true
+    Node(u32),
+    Extent(Extent),
+}
+
+/// A tree node: the extents of a leaf, or the children of an index node
+/// with the first logical block below each
+#[derive(Clone)]
+struct Node {
+    depth: u16,
+    max: usize,
+    extents: Vec<Extent>,
+    children: Vec<(u32, u32)>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl Node {
+    fn leaf(max: usize, extents: Vec<Extent>) -> Self {
+        Self { depth: 0, max, extents, children: Vec::new() }
+    }
+
+    fn index(depth: u16, max: usize, children: Vec<(u32, u32)>) -> Self {
+        Self { depth, max, extents: Vec::new(), children }
+    }
+
+    /// Parse a node from the root or a tree block, None if it is malformed.
+    /// Physical blocks past 32 bits are malformed too, as we cannot address them.
+    fn parse(data: &[u8]) -> Option<Self> {
+        if read_u16(data, 0) != EXT4_EXT_MAGIC {
+            return None;
+        }
+        let entries = read_u16(data, 2) as usize;
+        let max = read_u16(data, 4) as usize;
+        let depth = read_u16(data, 6);
+        if max > (data.len() - HEADER_SIZE) / ENTRY_SIZE || entries > max || depth > MAX_DEPTH {
+            return None;
+        }
+        let mut node = Self::index(depth, max, Vec::new());
+        for i in 0..entries {
+            let entry = &data[HEADER_SIZE + i * ENTRY_SIZE..HEADER_SIZE + (i + 1) * ENTRY_SIZE];
+            let block = read_u32(entry, 0);
+            if depth == 0 {
+                let len = read_u16(entry, 4) as u32;
+                if read_u16(entry, 6) != 0 || len == 0 {
+                    return None;
+                }
+                node.extents.push(Extent {
+                    block,
+                    len: if len > EXT_INIT_MAX_LEN { len - EXT_INIT_MAX_LEN } else { len },
+                    start: read_u32(entry, 8),
+                    unwritten: len > EXT_INIT_MAX_LEN,
+                });
+            } else {
+                if read_u16(entry, 8) != 0 {
+                    return None;
+                }
+                node.children.push((block, read_u32(entry, 4)));
+            }
+        }
+        let sorted = if depth == 0 {
+            node.extents.windows(2).all(|pair| pair[0].block + pair[0].len <= pair[1].block)
+        } else {
+            node.children.windows(2).all(|pair| pair[0].0 < pair[1].0)
+        };
+        if sorted { Some(node) } else { None }
+    }
+
+    fn write(&self, data: &mut [u8]) {
+        data[0..2].copy_from_slice(&EXT4_EXT_MAGIC.to_le_bytes());
+        data[2..4].copy_from_slice(&(self.len() as u16).to_le_bytes());
+        data[4..6].copy_from_slice(&(self.max as u16).to_le_bytes());
+        data[6..8].copy_from_slice(&self.depth.to_le_bytes());
+        data[8..HEADER_SIZE].fill(0);
+        let entries = &mut data[HEADER_SIZE..HEADER_SIZE + self.max * ENTRY_SIZE];
+        entries.fill(0);
+        for (entry, extent) in entries.chunks_mut(ENTRY_SIZE).zip(self.extents.iter()) {
+            let len = if extent.unwritten { extent.len + EXT_INIT_MAX_LEN } else { extent.len };
+            entry[0..4].copy_from_slice(&extent.block.to_le_bytes());
+            entry[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+            entry[8..12].copy_from_slice(&extent.start.to_le_bytes());
+        }
+        for (entry, (block, child)) in entries.chunks_mut(ENTRY_SIZE).zip(self.children.iter()) {
+            entry[0..4].copy_from_slice(&block.to_le_bytes());
+            entry[4..8].copy_from_slice(&child.to_le_bytes());
+        }
+    }
+
+    fn len(&self) -> usize {
+        if self.depth == 0 { self.extents.len() } else { self.children.len() }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len() == self.max
+    }
+
+    /// First logical block below the node
+    fn first_block(&self) -> u32 {
+        if self.depth == 0 { self.extents[0].block } else { self.children[0].0 }
+    }
+}
+
+fn read_node(block: u32, manager: &BlockCacheManager) -> Ext2Result<Option<Node>> {
+    let cache = manager.get_block_cache(block as usize)?;
+    let node = cache.lock().read_slice(|data: &[u8]| Node::parse(data));
+    manager.release_block(cache);
+    Ok(node)
+}
+
+fn parse_root(root: &[u8]) -> Ext2Result<Node> {
+    Node::parse(root).ok_or_else(|| {
+        error!("Bad extent tree root");
+        Ext2Error::Corrupted
+    })
+}
+
+/// Read the child of an index node, which must be one level below it
+fn read_child(parent: &Node, child: u32, manager: &BlockCacheManager) -> Ext2Result<Node> {
+    read_node(child, manager)?
+        .filter(|node| node.depth + 1 == parent.depth)
+        .ok_or_else(|| {
+            error!("Bad extent tree node {}", child);
+            Ext2Error::Corrupted
+        })
+}
+
+fn write_node(node: &Node, block: u32, manager: &BlockCacheManager) -> Ext2Result {
+    let cache = manager.get_block_cache(block as usize)?;
+    cache.lock().modify_slice(|data: &mut [u8]| node.write(data));
+    manager.release_block(cache);
+    Ok(())
+}
+
+fn node_max_entries(block_size: usize) -> usize {
+    (block_size - HEADER_SIZE) / ENTRY_SIZE
+}
+
+/// Make an empty tree in the root
+pub fn init(root: &mut [u8]) {
+    Node::leaf(ROOT_MAX_ENTRIES, Vec::new()).write(root);
+}
+
+/// Physical block of a logical block, 0 for holes and unwritten blocks
+pub fn lookup(root: &[u8], block: u32, manager: &BlockCacheManager) -> Ext2Result<u32> {
+    Ok(find(root, block, manager)?
+        .filter(|extent| !extent.unwritten)
+        .and_then(|extent| extent.map(block))
+        .unwrap_or(0))
+}
+
+/// The extent holding a logical block, None if it is in a hole
+pub fn find(root: &[u8], block: u32, manager: &BlockCacheManager) -> Ext2Result<Option<Extent>> {
+    let mut node = parse_root(root)?;
+    while node.depth > 0 {
+        let pos = node.children.partition_point(|(first, _)| *first <= block);
+        if pos == 0 {
+            return Ok(None);
+        }
+        node = read_child(&node, node.children[pos - 1].1, manager)?;
+    }
+    Ok(node.extents.into_iter().find(|extent| extent.map(block).is_some()))
+}
+
+/// Visit the tree blocks and extents in order, a tree block before its subtree.
+/// Stop at the first malformed node and return its block, 0 for the root,
+/// or None if the whole tree was walked.
+pub fn walk(
+    root: &[u8],
+    manager: &BlockCacheManager,
+    f: &mut impl FnMut(TreeItem) -> bool,
+) -> Ext2Result<Option<u32>> {
+    match Node::parse(root) {
+        Some(node) => walk_node(&node, manager, f),
+        None => Ok(Some(0)),
+    }
+}
+
+fn walk_node(
+    node: &Node,
+    manager: &BlockCacheManager,
+    f: &mut impl FnMut(TreeItem) -> bool,
+) -> Ext2Result<Option<u32>> {
+    for extent in node.extents.iter() {
+        f(TreeItem::Extent(*extent));
+    }
+    for (_, child) in node.children.iter() {
+        if f(TreeItem::Node(*child)) {
+            let child_node = read_node(*child, manager)?
+                .filter(|child_node| child_node.depth + 1 == node.depth);
+            let bad = match child_node {
+                Some(child_node) => walk_node(&child_node, manager, f)?,
+                None => Some(*child),
+            };
+            if bad.is_some() {
+                return Ok(bad);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The written extents of the logical blocks below `blocks`, in order, and
+/// the tree blocks
+pub fn mapped(root: &[u8], blocks: u32, manager: &BlockCacheManager) -> Ext2Result<(Vec<Extent>, Vec<u32>)> {
+    let mut data = Vec::new();
+    let mut index = Vec::new();
+    let bad = walk(root, manager, &mut |item| {
+        match item {
+            TreeItem::Node(block) => index.push(block),
+            TreeItem::Extent(extent) if !extent.unwritten && extent.block < blocks => {
+                data.push(Extent { len: extent.len.min(blocks - extent.block), ..extent });
+            }
+            TreeItem::Extent(_) => {}
+        }
+        true
+    })?;
+    if let Some(block) = bad {
+        error!("Bad extent tree node {}", block);
+        return Err(Ext2Error::Corrupted);
+    }
+    Ok((data, index))
+}
+
+/// Map an extent past every mapped block. New tree blocks come from `alloc`,
+/// return how many were taken.
+fn append_extent(
+    root: &mut [u8],
+    extent: Extent,
+    block_size: usize,
+    manager: &BlockCacheManager,
+    alloc: &mut impl FnMut() -> Ext2Result<u32>,
+) -> Ext2Result<usize> {
+    let node_max = node_max_entries(block_size);
+    let mut added = 0;
+    loop {
+        // the rightmost path, from the root (block 0) down to a leaf
+        let mut path = vec![(0, parse_root(root)?)];
+        while let Some(&(_, child)) = path.last().unwrap().1.children.last() {
+            let child_node = read_child(&path.last().unwrap().1, child, manager)?;
+            path.push((child, child_node));
+        }
+        let store = |root: &mut [u8], block: u32, node: &Node| {
+            if block == 0 {
+                node.write(root);
+                Ok(())
+            } else {
+                write_node(node, block, manager)
+            }
+        };
+
+        let (leaf_block, leaf) = path.last_mut().unwrap();
+        if let Some(last) = leaf.extents.last_mut() {
+            if last.block + last.len > extent.block {
+                error!("Extent at block {} overlaps the end of the tree", extent.block);
+                return Err(Ext2Error::Corrupted);
+            }
+            if last.unwritten == extent.unwritten
+                && last.block + last.len == extent.block
+                && last.start + last.len == extent.start
+                && last.len + extent.len <= max_len(extent.unwritten)
+            {
+                last.len += extent.len;
+                store(root, *leaf_block, leaf)?;
+                return Ok(added);
+            }
+        }
+        if !leaf.is_full() {
+            leaf.extents.push(extent);
+            store(root, *leaf_block, leaf)?;
+            return Ok(added);
+        }
+
+        // the deepest index node with room takes a new branch down to a new leaf
+        if let Some(level) = path.iter().rposition(|(_, node)| !node.is_full()) {
+            let mut child = alloc()?;
+            added += 1;
+            write_node(&Node::leaf(node_max, vec![extent]), child, manager)?;
+            for depth in 1..path[level].1.depth {
+                let node = alloc()?;
+                added += 1;
+                write_node(&Node::index(depth, node_max, vec![(extent.block, child)]), node, manager)?;
+                child = node;
+            }
+            let (block, node) = &mut path[level];
+            node.children.push((extent.block, child));
+            store(root, *block, node)?;
+            return Ok(added);
+        }
+
+        // the whole path is full: move the root into a new block below it
+        let mut moved = path.swap_remove(0).1;
+        if moved.depth >= MAX_DEPTH {
+            return Err(Ext2Error::FileTooLarge);
+        }
+        let child = alloc()?;
+        added += 1;
+        moved.max = node_max;
+        write_node(&moved, child, manager)?;
+        Node::index(moved.depth + 1, ROOT_MAX_ENTRIES, vec![(moved.first_block(), child)]).write(root);
+    }
+}
+
+/// Add an extent after the last of `extents`, merged into it if they are
+/// contiguous, split if it is too long for one
+pub fn push_extent(extents: &mut Vec<Extent>, mut extent: Extent) {
+    if let Some(last) = extents.last_mut() {
+        if last.unwritten == extent.unwritten
+            && last.block + last.len == extent.block
+            && last.start + last.len == extent.start
+        {
+            let merged = (max_len(extent.unwritten) - last.len).min(extent.len);
+            last.len += merged;
+            extent.block += merged;
+            extent.start += merged;
+            extent.len -= merged;
+        }
+    }
+    while extent.len > 0 {
+        let len = extent.len.min(max_len(extent.unwritten));
+        extents.push(Extent { len, ..extent });
+        extent.block += len;
+        extent.start += len;
+        extent.len -= len;
+    }
+}
+
+/// What `remap` did to a tree
+pub struct Remapped {
+    /// Blocks the tree no longer takes, data and tree blocks alike
+    pub freed: Vec<u32>,
+    /// Change in the number of blocks the tree takes, data and tree blocks alike
+    pub delta: isize,
+}
+
+/// Map the logical blocks in `range` with `extents` instead, which are sorted
+/// and lie in it, or unmap them if there are none. Blocks of the old mapping
+/// that `extents` map again are kept. New tree blocks come from `alloc`, all
+/// of them before the tree changes.
+pub fn remap(
+    root: &mut [u8],
+    range: Range<u32>,
+    extents: &[Extent],
+    block_size: usize,
+    manager: &BlockCacheManager,
+    alloc: &mut impl FnMut(usize) -> Ext2Result<Vec<u32>>,
+) -> Ext2Result<Remapped> {
+    let mut old = Vec::new();
+    let mut tree = Vec::new();
+    let bad = walk(root, manager, &mut |item| {
+        match item {
+            TreeItem::Node(block) => tree.push(block),
+            TreeItem::Extent(extent) => old.push(extent),
+        }
+        true
+    })?;
+    if let Some(block) = bad {
+        error!("Bad extent tree node {}", block);
+        return Err(Ext2Error::Corrupted);
+    }
+    let mapped: isize = extents.iter().map(|extent| extent.len as isize).sum();
+    let end = old.last().map_or(0, |extent| extent.block + extent.len);
+    if range.start >= end {
+        let mut delta = mapped;
+        let mut alloc_one = || alloc(1).map(|blocks| blocks[0]);
+        for extent in extents {
+            delta += append_extent(root, *extent, block_size, manager, &mut alloc_one)? as isize;
+        }
+        return Ok(Remapped { freed: Vec::new(), delta });
+    }
+    if extents.is_empty() && range.end >= end {
+        let freed = truncate(root, range.start, manager)?;
+        return Ok(Remapped { delta: -(freed.len() as isize), freed });
+    }
+
+    // the old extents cut to outside the range, and the new ones
+    let mut unmapped = Vec::new();
+    let mut kept = Vec::new();
+    for extent in old {
+        let extent_end = extent.block + extent.len;
+        if extent_end <= range.start || extent.block >= range.end {
+            kept.push(extent);
+            continue;
+        }
+        if extent.block < range.start {
+            kept.push(Extent { len: range.start - extent.block, ..extent });
+        }
+        let cut = extent.block.max(range.start)..extent_end.min(range.end);
+        unmapped.push(extent.start + (cut.start - extent.block)..extent.start + (cut.end - extent.block));
+        if extent_end > range.end {
+            kept.push(Extent {
+                block: range.end,
+                len: extent_end - range.end,
+                start: extent.start + (range.end - extent.block),
+                unwritten: extent.unwritten,
+            });
+        }
+    }
+    kept.extend_from_slice(extents);
+    kept.sort_by_key(|extent| extent.block);
+    let mut new = Vec::new();
+    for extent in kept {
+        push_extent(&mut new, extent);
+    }
+
+    let remapped = |block: &u32| extents.iter().any(|extent| {
+        *block >= extent.start && *block - extent.start < extent.len
+    });
+    let mut freed = Vec::new();
+    let mut delta = mapped;
+    for run in unmapped {
+        delta -= run.len() as isize;
+        freed.extend(run.filter(|block| !remapped(block)));
+    }
+    delta += rebuild(root, &new, tree, block_size, manager, alloc, &mut freed)?;
+    Ok(Remapped { freed, delta })
+}
+
+/// Write a tree of `extents`, in the tree blocks `old` and more from `alloc`.
+/// Push the tree blocks left over to `freed`, return the change in how many
+/// the tree takes.
+fn rebuild(
+    root: &mut [u8],
+    extents: &[Extent],
+    old: Vec<u32>,
+    block_size: usize,
+    manager: &BlockCacheManager,
+    alloc: &mut impl FnMut(usize) -> Ext2Result<Vec<u32>>,
+    freed: &mut Vec<u32>,
+) -> Ext2Result<isize> {
+    let node_max = node_max_entries(block_size);
+    // the nodes of every level below the root, from the leaves up
+    let mut levels = Vec::new();
+    let mut entries = extents.len();
+    while entries > ROOT_MAX_ENTRIES {
+        entries = entries.div_ceil(node_max);
+        levels.push(entries);
+    }
+    if levels.len() > MAX_DEPTH as usize {
+        return Err(Ext2Error::FileTooLarge);
+    }
+    let needed: usize = levels.iter().sum();
+    let delta = needed as isize - old.len() as isize;
+    let mut blocks = old;
+    if needed > blocks.len() {
+        blocks.extend(alloc(needed - blocks.len())?);
+    } else {
+        freed.extend(blocks.drain(needed..));
+    }
+
+    let mut blocks = blocks.into_iter();
+    if levels.is_empty() {
+        Node::leaf(ROOT_MAX_ENTRIES, extents.to_vec()).write(root);
+        return Ok(delta);
+    }
+    let mut children = Vec::new();
+    for leaf in extents.chunks(node_max) {
+        let block = blocks.next().unwrap();
+        write_node(&Node::leaf(node_max, leaf.to_vec()), block, manager)?;
+        children.push((leaf[0].block, block));
+    }
+    let mut depth = 1;
+    while children.len() > ROOT_MAX_ENTRIES {
+        let mut parents = Vec::new();
+        for node in children.chunks(node_max) {
+            let block = blocks.next().unwrap();
+            write_node(&Node::index(depth, node_max, node.to_vec()), block, manager)?;
+            parents.push((node[0].0, block));
+        }
+        children = parents;
+        depth += 1;
+    }
+    Node::index(depth, ROOT_MAX_ENTRIES, children).write(root);
+    Ok(delta)
+}
+
+/// Unmap every logical block from `blocks` on, return the physical blocks
+/// freed, data and tree blocks alike
+pub fn truncate(root: &mut [u8], blocks: u32, manager: &BlockCacheManager) -> Ext2Result<Vec<u32>> {
+    let mut freed = Vec::new();
+    let mut node = parse_root(root)?;
+    trim(&mut node, blocks, manager, &mut freed)?;
+    if node.len() == 0 {
+        node = Node::leaf(ROOT_MAX_ENTRIES, Vec::new());
+    }
+    // an only child that fits in the root moves up into it
+    while node.depth > 0 && node.children.len() == 1 {
+        let child = node.children[0].1;
+        let child_node = read_child(&node, child, manager)?;
+        if child_node.len() > ROOT_MAX_ENTRIES {
+            break;
+        }
+        freed.push(child);
+        node = Node { max: ROOT_MAX_ENTRIES, ..child_node };
+    }
+    node.write(root);
+    Ok(freed)
+}
+
+/// Unmap the logical blocks from `blocks` on below a node
+fn trim(node: &mut Node, blocks: u32, manager: &BlockCacheManager, freed: &mut Vec<u32>) -> Ext2Result {
+    if node.depth == 0 {
+        node.extents.retain_mut(|extent| {
+            let keep = blocks.saturating_sub(extent.block).min(extent.len);
+            freed.extend(extent.start + keep..extent.start + extent.len);
+            extent.len = keep;
+            keep > 0
+        });
+        return Ok(());
+    }
+    while let Some(&(first, child)) = node.children.last() {
+        let mut child_node = read_child(node, child, manager)?;
+        trim(&mut child_node, blocks, manager, freed)?;
+        if child_node.len() > 0 {
+            write_node(&child_node, child, manager)?;
+        } else {
+            freed.push(child);
+            node.children.pop();
+        }
+        // the children before end below this one
+        if first < blocks {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,635 @@
+//! Offline consistency check of an ext2 file system
+//!
+//! The directory tree is walked from the root to find the inodes and blocks
+//! in use, which are then checked against link counts, bitmaps and the free
+//! counts of the groups and the super block. The file system must not be in
+//! use by anyone else while it is checked.
+use crate::config::EXT2_ROOT_INO;
+use crate::extent::{self, Extent, TreeItem};
+use crate::layout::{indirect_range, DirEntryHead, EXT2_FT_DIR, EXT2_RESIZE_INO, EXT2_S_IFDIR, INDIRECT_DEPTH};
+use crate::error::{Ext2Error, Ext2Result};
+use super::{DiskInode, Ext2FileSystem, SuperBlock};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter, Result};
+use core::ops::Range;
+use log::*;
+
+/// Directory orphans are attached to
+const LOST_AND_FOUND: &str = "lost+found";
+
+/// An inconsistency found by [`check`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// A malformed entry, the rest of its block is skipped
+    BadDirEntry { dir: u32, offset: usize },
+    /// An entry naming an inode that is not in use
+    DanglingEntry { dir: u32, name: String, inode: u32 },
+    /// `.` or `..` of a directory is missing or points to the wrong inode
+    BadDotEntry { dir: u32, name: &'static str },
+    /// A directory reached through more than one entry
+    DirLinkedTwice { inode: u32 },
+    /// A block pointer out of the file system, inode 0 stands for metadata
+    BadBlock { inode: u32, block: u32 },
+    /// A block used more than once, inode 0 stands for metadata
+    SharedBlock { inode: u32, block: u32 },
+    /// A malformed node of an extent tree, block 0 stands for the root in the
+    /// inode. The rest of the tree is skipped.
+    BadExtentTree { inode: u32, block: u32 },
+    /// A link count that differs from the number of entries naming the inode
+    LinkCount { inode: u32, recorded: u16, counted: usize },
+    /// An inode in use that no directory leads to
+    Orphan { inode: u32 },
+    InodeBitmap { inode: u32, recorded: bool },
+    BlockBitmap { block: u32, recorded: bool },
+    /// A counter of a group descriptor
+    GroupCount { group: usize, field: &'static str, recorded: u32, counted: u32 },
+    /// A counter of the super block
+    SuperCount { field: &'static str, recorded: u32, counted: u32 },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Problem::BadDirEntry { dir, offset } =>
+                write!(f, "Bad entry at {} of directory {}", offset, dir),
+            Problem::DanglingEntry { dir, name, inode } =>
+                write!(f, "Entry '{}' of directory {} points to unused inode {}", name, dir, inode),
+            Problem::BadDotEntry { dir, name } =>
+                write!(f, "Entry '{}' of directory {} is missing or wrong", name, dir),
+            Problem::DirLinkedTwice { inode } =>
+                write!(f, "Directory {} has more than one parent", inode),
+            Problem::BadBlock { inode, block } =>
+                write!(f, "Inode {} points to block {} out of the file system", inode, block),
+            Problem::SharedBlock { inode, block } =>
+                write!(f, "Block {} of inode {} is used more than once", block, inode),
+            Problem::BadExtentTree { inode, block } =>
+                write!(f, "Extent tree of inode {} is malformed at block {}", inode, block),
+            Problem::LinkCount { inode, recorded, counted } =>
+                write!(f, "Inode {} has {} links but {} entries", inode, recorded, counted),
+            Problem::Orphan { inode } =>
+                write!(f, "Inode {} is not in any directory", inode),
+            Problem::InodeBitmap { inode, recorded } =>
+                write!(f, "Inode {} is marked {} in the bitmap", inode, if *recorded { "used" } else { "free" }),
+            Problem::BlockBitmap { block, recorded } =>
+                write!(f, "Block {} is marked {} in the bitmap", block, if *recorded { "used" } else { "free" }),
+            Problem::GroupCount { group, field, recorded, counted } =>
+                write!(f, "Group {} records {} {} but has {}", group, recorded, field, counted),
+            Problem::SuperCount { field, recorded, counted } =>
+                write!(f, "Super block records {} {} but has {}", recorded, field, counted),
+        }
+    }
+}
+
+/// Result of [`check`]
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Whether the problems have been repaired
+    pub repaired: bool,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check a file system, and repair it if `repair` is set and it is writable.
+///
+/// A repair attaches orphans to `/lost+found`, drops entries pointing to unused
+/// inodes, fixes `.`, `..` and link counts, then rebuilds the bitmaps and free
+/// counts from what is in use. Blocks that are shared or out of the file system
+/// are only reported. The report describes the file system before the repair.
+pub fn check(efs: &Arc<Ext2FileSystem>, repair: bool) -> Ext2Result<Report> {
+    let checker = Checker::scan(efs)?;
+    let mut report = Report { problems: checker.problems.clone(), repaired: false };
+    if repair && !report.is_clean() {
+        if efs.is_read_only() {
+            warn!("File system is read-only, not repaired");
+        } else {
+            checker.repair()?;
+            report.repaired = true;
+        }
+    }
+    Ok(report)
+}
+
+struct Checker<'a> {
+    efs: &'a Arc<Ext2FileSystem>,
+    super_block: SuperBlock,
+    block_size: usize,
+    problems: Vec<Problem>,
+    /// Inodes in use
+    inodes: BTreeSet<u32>,
+    /// Directories in use
+    dirs: BTreeSet<u32>,
+    /// Blocks in use and the inode using them, 0 for metadata
+    blocks: BTreeMap<u32, u32>,
+    /// Extended attribute blocks, which inodes may share
+    xattr_blocks: BTreeSet<u32>,
+    /// Number of entries naming each inode
+    refs: BTreeMap<u32, usize>,
+    /// Inodes in use that no directory leads to, except other orphans
+    orphans: Vec<u32>,
+    /// Entries to drop
+    dangling: Vec<(u32, String)>,
+    /// `.` and `..` entries to point to the right inode
+    dot_entries: Vec<(u32, &'static str, u32)>,
+}
+
+impl<'a> Checker<'a> {
+    fn scan(efs: &'a Arc<Ext2FileSystem>) -> Ext2Result<Self> {
+        let mut checker = Self {
+            efs,
+            super_block: efs.super_block(),
+            block_size: efs.block_size(),
+            problems: Vec::new(),
+            inodes: BTreeSet::new(),
+            dirs: BTreeSet::new(),
+            blocks: BTreeMap::new(),
+            xattr_blocks: BTreeSet::new(),
+            refs: BTreeMap::new(),
+            orphans: Vec::new(),
+            dangling: Vec::new(),
+            dot_entries: Vec::new(),
+        };
+        checker.claim_metadata();
+        checker.claim_reserved_inodes()?;
+        checker.claim_orphan_list()?;
+        checker.walk(EXT2_ROOT_INO as u32, Some(EXT2_ROOT_INO as u32))?;
+        checker.find_orphans()?;
+        checker.check_link_counts()?;
+        checker.check_groups()?;
+        Ok(checker)
+    }
+
+    /// Claim the super block, group descriptors, bitmaps and inode table of every group
+    fn claim_metadata(&mut self) {
+        let blocks_per_group = self.super_block.s_blocks_per_group;
+        let inode_table_blocks = (self.super_block.s_inodes_per_group as usize * self.super_block.inode_size())
+            .div_ceil(self.block_size) as u32;
+        for group_id in 0..self.super_block.group_count() {
+            let desc = self.efs.group_desc(group_id);
+            let group_start = self.super_block.s_first_data_block + group_id as u32 * blocks_per_group;
+            // blocks before the bitmaps and the inode table hold the super block
+            // and group descriptors, or their backups
+            let meta_start = desc.bg_block_bitmap.min(desc.bg_inode_bitmap).min(desc.bg_inode_table);
+            if meta_start >= group_start && meta_start < group_start + blocks_per_group {
+                for block_id in group_start..meta_start {
+                    self.claim(0, block_id);
+                }
+            }
+            self.claim(0, desc.bg_block_bitmap);
+            self.claim(0, desc.bg_inode_bitmap);
+            for block_id in desc.bg_inode_table..desc.bg_inode_table + inode_table_blocks {
+                self.claim(0, block_id);
+            }
+        }
+    }
+
+    /// Claim the reserved inodes and their blocks, except the root directory
+    fn claim_reserved_inodes(&mut self) -> Ext2Result {
+        for inode_id in 1..self.super_block.first_ino() as u32 {
+            self.inodes.insert(inode_id);
+            if inode_id == EXT2_ROOT_INO as u32 {
+                continue;
+            }
+            let disk_inode = self.efs.read_disk_inode(inode_id)?;
+            if inode_id == EXT2_RESIZE_INO && self.super_block.has_resize_inode() {
+                // the reserved group descriptor blocks are found through a double
+                // indirect block, and are claimed with the other metadata
+                self.claim_pointer(inode_id, disk_inode.i_triple_block);
+            } else {
+                self.claim_blocks(inode_id, &disk_inode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Claim a block for an inode, return whether it is in the file system
+    fn claim(&mut self, inode_id: u32, block_id: u32) -> bool {
+        if block_id < self.super_block.s_first_data_block || block_id >= self.super_block.s_blocks_count {
+            self.problems.push(Problem::BadBlock { inode: inode_id, block: block_id });
+            return false;
+        }
+        if self.blocks.insert(block_id, inode_id).is_some() {
+            self.problems.push(Problem::SharedBlock { inode: inode_id, block: block_id });
+        }
+        true
+    }
+
+    /// Claim a block pointer of an inode, where 0 is a hole
+    fn claim_pointer(&mut self, inode_id: u32, block_id: u32) -> bool {
+        block_id != 0 && self.claim(inode_id, block_id)
+    }
+
+    fn read_indirect(&self, block_id: u32) -> Ext2Result<Vec<u32>> {
+        let indirect_block = self.efs.manager.get_block_cache(block_id as _)?;
+        let entries = indirect_block.lock()
+            .read_slice(|indirect: &[u32]| indirect.to_vec());
+        self.efs.manager.release_block(indirect_block);
+        Ok(entries)
+    }
+
+    /// Claim the data and index blocks of an inode, return the runs of its
+    /// data blocks in order, without holes and blocks out of the file system
+    fn claim_blocks(&mut self, inode_id: u32, disk_inode: &DiskInode) -> Ext2Result<Vec<Extent>> {
+        if disk_inode.has_extents() {
+            return self.claim_extents(inode_id, disk_inode);
+        }
+        let blocks = disk_inode.data_blocks(self.block_size) as usize;
+        let mut data = Vec::new();
+        for (i, block_id) in disk_inode.i_direct_block.iter().enumerate().take(blocks) {
+            if self.claim_pointer(inode_id, *block_id) {
+                extent::push_extent(&mut data, Extent { block: i as u32, len: 1, start: *block_id, unwritten: false });
+            }
+        }
+        for depth in 1..=INDIRECT_DEPTH {
+            let range = indirect_range(depth, self.block_size);
+            if range.start >= blocks {
+                break;
+            }
+            self.claim_indirect(inode_id, disk_inode.indirect_root(depth), depth, range.start..blocks, &mut data)?;
+        }
+        Ok(data)
+    }
+
+    /// Claim a tree of indirect blocks with `depth` levels mapping the data
+    /// blocks from `blocks.start` on, up to `blocks.end`
+    fn claim_indirect(&mut self, inode_id: u32, block_id: u32, depth: usize, blocks: Range<usize>, data: &mut Vec<Extent>) -> Ext2Result {
+        if !self.claim_pointer(inode_id, block_id) {
+            return Ok(());
+        }
+        let span = (self.block_size / 4).pow(depth as u32 - 1);
+        for (i, entry) in self.read_indirect(block_id)?.into_iter().enumerate() {
+            let start = blocks.start + i * span;
+            if start >= blocks.end {
+                break;
+            }
+            if depth > 1 {
+                self.claim_indirect(inode_id, entry, depth - 1, start..blocks.end, data)?;
+            } else if self.claim_pointer(inode_id, entry) {
+                extent::push_extent(data, Extent { block: start as u32, len: 1, start: entry, unwritten: false });
+            }
+        }
+        Ok(())
+    }
+
+    /// Claim the data and tree blocks of an inode mapped by extents, return
+    /// the runs of its data blocks in order, without holes, unwritten blocks
+    /// and blocks out of the file system
+    fn claim_extents(&mut self, inode_id: u32, disk_inode: &DiskInode) -> Ext2Result<Vec<Extent>> {
+        let blocks = disk_inode.data_blocks(self.block_size);
+        let mut data = Vec::new();
+        let efs = self.efs;
+        let walked = extent::walk(disk_inode.extent_root(), &efs.manager, &mut |item| match item {
+            TreeItem::Node(block_id) => self.claim(inode_id, block_id),
+            TreeItem::Extent(extent) => {
+                for i in 0..extent.len {
+                    let block_id = extent.start.saturating_add(i);
+                    let logical = extent.block.saturating_add(i);
+                    if self.claim(inode_id, block_id) && !extent.unwritten && logical < blocks {
+                        extent::push_extent(&mut data, Extent { block: logical, len: 1, start: block_id, unwritten: false });
+                    }
+                }
+                true
+            }
+        })?;
+        if let Some(block) = walked {
+            self.problems.push(Problem::BadExtentTree { inode: inode_id, block });
+        }
+        Ok(data)
+    }
+
+    /// Claim the inodes on the orphan list, which are in use until they are closed
+    fn claim_orphan_list(&mut self) -> Ext2Result {
+        let mut inode_id = self.super_block.last_orphan();
+        while inode_id != 0 && inode_id <= self.super_block.s_inodes_count && !self.inodes.contains(&inode_id) {
+            let disk_inode = self.efs.read_disk_inode(inode_id)?;
+            if !self.is_alive(inode_id, &disk_inode)? {
+                break;
+            }
+            self.use_inode(inode_id, &disk_inode)?;
+            inode_id = disk_inode.i_dtime;
+        }
+        Ok(())
+    }
+
+    /// Whether a directory entry may point to an inode
+    fn is_alive(&self, inode_id: u32, disk_inode: &DiskInode) -> Ext2Result<bool> {
+        Ok(disk_inode.i_mode != 0 && (disk_inode.i_links_count > 0 || self.efs.inode_exists(inode_id)?))
+    }
+
+    /// Mark an inode and its blocks as in use, return the runs of its data blocks
+    fn use_inode(&mut self, inode_id: u32, disk_inode: &DiskInode) -> Ext2Result<Vec<Extent>> {
+        self.inodes.insert(inode_id);
+        if disk_inode.is_dir() {
+            self.dirs.insert(inode_id);
+        }
+        let xattr_block = disk_inode.xattr_block();
+        if xattr_block != 0 && self.xattr_blocks.insert(xattr_block) {
+            self.claim(inode_id, xattr_block);
+        }
+        self.claim_blocks(inode_id, disk_inode)
+    }
+
+    /// Walk the tree below a directory, `parent` is what its `..` should be
+    fn walk(&mut self, root: u32, parent: Option<u32>) -> Ext2Result {
+        let disk_inode = self.efs.read_disk_inode(root)?;
+        let mut stack = vec![(root, parent, self.use_inode(root, &disk_inode)?)];
+        while let Some((dir, parent, blocks)) = stack.pop() {
+            let mut dot = None;
+            let mut dot_dot = None;
+            let blocks = blocks.iter()
+                .flat_map(|extent| (0..extent.len).map(move |i| (extent.block + i, extent.start + i)));
+            for (idx, block_id) in blocks {
+                for (inode_id, name) in self.read_dir_block(dir, idx as usize, block_id)? {
+                    if name == "." {
+                        dot = Some(inode_id);
+                    } else if name == ".." {
+                        dot_dot = Some(inode_id);
+                    }
+                    let mut disk_inode = None;
+                    if inode_id <= self.super_block.s_inodes_count {
+                        let found = self.efs.read_disk_inode(inode_id)?;
+                        if self.is_alive(inode_id, &found)? {
+                            disk_inode = Some(found);
+                        }
+                    }
+                    let disk_inode = match disk_inode {
+                        Some(disk_inode) => disk_inode,
+                        None => {
+                            // `.` and `..` are checked below
+                            if name != "." && name != ".." {
+                                self.problems.push(Problem::DanglingEntry { dir, name: name.clone(), inode: inode_id });
+                                self.dangling.push((dir, name));
+                            }
+                            continue;
+                        }
+                    };
+                    *self.refs.entry(inode_id).or_default() += 1;
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+                    if self.inodes.contains(&inode_id) {
+                        if disk_inode.is_dir() {
+                            match self.orphans.iter().position(|orphan| *orphan == inode_id) {
+                                // back to the orphan the walk started from, the
+                                // entry closing the cycle goes so that the
+                                // orphan hangs from lost+found only
+                                Some(_) if inode_id == root => self.dangling.push((dir, name)),
+                                // an orphan found below another orphan
+                                Some(pos) => {
+                                    self.orphans.remove(pos);
+                                }
+                                None => self.problems.push(Problem::DirLinkedTwice { inode: inode_id }),
+                            }
+                        }
+                        continue;
+                    }
+                    let blocks = self.use_inode(inode_id, &disk_inode)?;
+                    if disk_inode.is_dir() {
+                        stack.push((inode_id, Some(dir), blocks));
+                    }
+                }
+            }
+            if dot != Some(dir) {
+                self.problems.push(Problem::BadDotEntry { dir, name: "." });
+                self.dot_entries.push((dir, ".", dir));
+            }
+            if let Some(parent) = parent {
+                if dot_dot != Some(parent) {
+                    self.problems.push(Problem::BadDotEntry { dir, name: ".." });
+                    self.dot_entries.push((dir, "..", parent));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries in use of a directory block, as inode and name
+    fn read_dir_block(&mut self, dir: u32, idx: usize, block_id: u32) -> Ext2Result<Vec<(u32, String)>> {
+        let mut entries = Vec::new();
+        let dir_block = self.efs.manager.get_block_cache(block_id as _)?;
+        let ret = dir_block.lock()
+            .read_slice(|data_block: &[u8]| {
+                DirEntryHead::walk_block(data_block, |_, head, name| {
+                    if head.inode != 0 {
+                        entries.push((head.inode, String::from_utf8_lossy(name).to_string()));
+                    }
+                    None::<()>
+                })
+            });
+        self.efs.manager.release_block(dir_block);
+        if let Err(offset) = ret {
+            self.problems.push(Problem::BadDirEntry { dir, offset: idx * self.block_size + offset });
+        }
+        Ok(entries)
+    }
+
+    /// Find inodes in use that were not reached from the root, orphaned
+    /// directories are walked so that only the top of an orphaned tree is kept
+    fn find_orphans(&mut self) -> Ext2Result {
+        let first_ino = self.super_block.first_ino() as u32;
+        let mut files = Vec::new();
+        for inode_id in first_ino..=self.super_block.s_inodes_count {
+            if self.inodes.contains(&inode_id) {
+                continue;
+            }
+            let disk_inode = self.efs.read_disk_inode(inode_id)?;
+            if disk_inode.i_mode == 0 || disk_inode.i_links_count == 0 {
+                continue;
+            }
+            if disk_inode.is_dir() {
+                self.orphans.push(inode_id);
+                self.walk(inode_id, None)?;
+            } else {
+                files.push((inode_id, disk_inode));
+            }
+        }
+        for (inode_id, disk_inode) in files {
+            if !self.inodes.contains(&inode_id) {
+                self.orphans.push(inode_id);
+                self.use_inode(inode_id, &disk_inode)?;
+            }
+        }
+        self.orphans.sort();
+        for inode_id in self.orphans.iter() {
+            self.problems.push(Problem::Orphan { inode: *inode_id });
+        }
+        Ok(())
+    }
+
+    fn check_link_counts(&mut self) -> Ext2Result {
+        let first_ino = self.super_block.first_ino() as u32;
+        for inode_id in self.inodes.iter().copied() {
+            if inode_id < first_ino && inode_id != EXT2_ROOT_INO as u32 {
+                continue;
+            }
+            // orphans get one more link once attached
+            if self.orphans.contains(&inode_id) {
+                continue;
+            }
+            let recorded = self.efs.read_disk_inode(inode_id)?.i_links_count;
+            let counted = self.refs.get(&inode_id).copied().unwrap_or(0);
+            if recorded as usize != counted {
+                self.problems.push(Problem::LinkCount { inode: inode_id, recorded, counted });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the bitmaps and counters of every group and the super block
+    fn check_groups(&mut self) -> Ext2Result {
+        let inodes_per_group = self.super_block.s_inodes_per_group;
+        let blocks_per_group = self.super_block.s_blocks_per_group;
+        let blocks_count = self.super_block.s_blocks_count;
+        let mut free_inodes_count = 0;
+        let mut free_blocks_count = 0;
+        for group_id in 0..self.super_block.group_count() {
+            let desc = self.efs.group_desc(group_id);
+            let inode_bitmap = self.efs.get_inode_bitmap(group_id);
+            let first_inode = group_id as u32 * inodes_per_group + 1;
+            let mut free_inodes = 0;
+            let mut used_dirs = 0;
+            for inode_id in first_inode..first_inode + inodes_per_group {
+                let used = self.inodes.contains(&inode_id);
+                let recorded = inode_bitmap.test(&self.efs.manager, inode_id as usize)?;
+                if used != recorded {
+                    self.problems.push(Problem::InodeBitmap { inode: inode_id, recorded });
+                }
+                if !used {
+                    free_inodes += 1;
+                }
+                if self.dirs.contains(&inode_id) {
+                    used_dirs += 1;
+                }
+            }
+
+            let data_bitmap = self.efs.get_data_bitmap(group_id);
+            let group_start = self.super_block.s_first_data_block + group_id as u32 * blocks_per_group;
+            let mut free_blocks = 0;
+            for block_id in group_start..(group_start + blocks_per_group).min(blocks_count) {
+                let used = self.blocks.contains_key(&block_id);
+                let recorded = data_bitmap.test(&self.efs.manager, block_id as usize)?;
+                if used != recorded {
+                    self.problems.push(Problem::BlockBitmap { block: block_id, recorded });
+                }
+                if !used {
+                    free_blocks += 1;
+                }
+            }
+
+            for (field, recorded, counted) in [
+                ("free inodes", desc.bg_free_inodes_count as u32, free_inodes),
+                ("free blocks", desc.bg_free_blocks_count as u32, free_blocks),
+                ("used directories", desc.bg_used_dirs_count as u32, used_dirs),
+            ] {
+                if recorded != counted {
+                    self.problems.push(Problem::GroupCount { group: group_id, field, recorded, counted });
+                }
+            }
+            free_inodes_count += free_inodes;
+            free_blocks_count += free_blocks;
+        }
+        for (field, recorded, counted) in [
+            ("free inodes", self.super_block.s_free_inodes_count, free_inodes_count),
+            ("free blocks", self.super_block.s_free_blocks_count, free_blocks_count),
+        ] {
+            if recorded != counted {
+                self.problems.push(Problem::SuperCount { field, recorded, counted });
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite the bitmaps and counters of every group from what is in use
+    fn rebuild_groups(&self) -> Ext2Result {
+        let inodes_per_group = self.super_block.s_inodes_per_group;
+        let blocks_count = self.super_block.s_blocks_count as usize;
+        for group_id in 0..self.super_block.group_count() {
+            let _op = Ext2FileSystem::begin_op(self.efs);
+            let first_inode = group_id as u32 * inodes_per_group + 1;
+            let used_dirs = self.dirs.range(first_inode..first_inode + inodes_per_group).count();
+            self.efs.rebuild_group(
+                group_id,
+                |inode_id| self.inodes.contains(&(inode_id as u32)),
+                |block_id| block_id >= blocks_count || self.blocks.contains_key(&(block_id as u32)),
+                used_dirs
+            )?;
+        }
+        Ok(())
+    }
+
+    fn repair(self) -> Ext2Result {
+        let efs = self.efs;
+        // the inode caches and allocations below rely on the bitmaps
+        self.rebuild_groups()?;
+        {
+            let _op = Ext2FileSystem::begin_op(efs);
+            for (dir, name) in self.dangling.iter() {
+                let dir = Ext2FileSystem::get_inode_cache(efs, *dir as usize)?;
+                dir.unique_lock().remove_dir_entry(name)?;
+            }
+            for (dir, name, inode_id) in self.dot_entries.iter() {
+                let dir = Ext2FileSystem::get_inode_cache(efs, *dir as usize)?;
+                let mut lk = dir.unique_lock();
+                if !lk.set_entry_inode(name, *inode_id as usize)? {
+                    lk.add_dir_entry(*inode_id as usize, name, EXT2_FT_DIR)?;
+                }
+            }
+        }
+        if !self.orphans.is_empty() {
+            self.attach_orphans()?;
+        }
+
+        let checker = Checker::scan(efs)?;
+        for problem in checker.problems.iter() {
+            if let Problem::LinkCount { inode, counted, .. } = problem {
+                let _op = Ext2FileSystem::begin_op(efs);
+                efs.modify_disk_inode(*inode, |disk_inode| {
+                    disk_inode.i_links_count = *counted as u16;
+                })?;
+            }
+        }
+        checker.rebuild_groups()?;
+        efs.sync()
+    }
+
+    /// Attach orphans to lost+found as `#<inode>`, creating it if needed
+    fn attach_orphans(&self) -> Ext2Result {
+        let efs = self.efs;
+        let _op = Ext2FileSystem::begin_op(efs);
+        let root = Ext2FileSystem::get_inode_cache(efs, EXT2_ROOT_INO)?;
+        let mut lk = root.unique_lock();
+        let lost_found = match lk.find(LOST_AND_FOUND) {
+            Err(Ext2Error::NotFound) => lk.create(LOST_AND_FOUND, EXT2_S_IFDIR),
+            found => found,
+        };
+        drop(lk);
+        let lost_found = match lost_found {
+            Ok(lost_found) if lost_found.shared_lock().file_type() == EXT2_FT_DIR => lost_found,
+            _ => {
+                error!("Can not use /{}, orphans are left alone", LOST_AND_FOUND);
+                return Ok(());
+            }
+        };
+        let lost_found_id = lost_found.shared_lock().inode_id;
+        for inode_id in self.orphans.iter() {
+            let orphan = Ext2FileSystem::get_inode_cache(efs, *inode_id as usize)?;
+            let mut lk = orphan.unique_lock();
+            let file_type = lk.file_type();
+            if file_type == EXT2_FT_DIR && !lk.set_entry_inode("..", lost_found_id)? {
+                lk.add_dir_entry(lost_found_id, "..", EXT2_FT_DIR)?;
+            }
+            drop(lk);
+            lost_found.unique_lock().add_dir_entry(*inode_id as usize, &format!("#{}", inode_id), file_type)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+//! Hash tree indexes of directories, as in ext3.
+//!
+//! The first block of an indexed directory keeps its `.` and `..` entries,
+//! `..` spanning the rest of the block, which hides a dx_root: the hash
+//! version and depth of the tree, then index entries sorted by hash, each
+//! pointing to a logical block of the directory. In a tree of depth 1 these
+//! are dx_node blocks, made of a single unused entry hiding more index
+//! entries. Leaves are ordinary directory blocks, holding the names whose
+//! hash is at least the one of their index entry and below the next one.
+//! A hash with its lowest bit set tells that the names hashing to it go on
+//! from the leaf before. Readers ignoring the index see every name in a
+//! linear scan.
+use core::mem::size_of;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::layout::DirEntryHead;
+
+// hash versions
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+/// Deepest tree we read and write: a root and one level of dx_node blocks
+const DX_MAX_LEVELS: u8 = 1;
+
+/// The dx_root info follows the `.` and `..` entries
+const DX_ROOT_INFO_OFFSET: usize = 24;
+const DX_ROOT_INFO_LEN: u8 = 8;
+const DX_ROOT_ENTRIES_OFFSET: usize = 32;
+/// Index entries of a dx_node follow its unused directory entry
+const DX_NODE_ENTRIES_OFFSET: usize = 8;
+const DX_ENTRY_SIZE: usize = 8;
+
+/// Rec len of the `.` entry of an indexed directory
+const DOT_REC_LEN: usize = DirEntryHead::rec_len_of(1);
+
+/// How a file system hashes names, from its super block
+#[derive(Clone, Copy, Debug)]
+pub struct HashInfo {
+    /// Hash version of new indexes
+    pub def_version: u8,
+    pub seed: [u32; 4],
+    /// Whether names are hashed as unsigned chars
+    pub unsigned: bool,
+}
+
+impl HashInfo {
+    /// Hash of a name in an index of `version`, `None` if the version is unknown
+    pub fn hash(&self, name: &[u8], version: u8) -> Option<u32> {
+        let version = if version <= DX_HASH_TEA && self.unsigned {
+            version + DX_HASH_LEGACY_UNSIGNED
+        } else {
+            version
+        };
+        dx_hash(name, version, &self.seed)
+    }
+}
+
+/// Whether we can hash names for an index of `version`
+pub fn is_known_version(version: u8) -> bool {
+    version <= DX_HASH_TEA_UNSIGNED
+}
+
+/// Hash of a name as kept in an index, with the lowest bit clear.
+/// The seed is the one of the super block, a zero seed means the default one.
+pub fn dx_hash(name: &[u8], version: u8, seed: &[u32; 4]) -> Option<u32> {
+    let mut buf = if seed.iter().any(|word| *word != 0) {
+        *seed
+    } else {
+        [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476]
+    };
+    let unsigned = version >= DX_HASH_LEGACY_UNSIGNED;
+    let hash = match version {
+        DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => legacy_hash(name, unsigned),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let mut rest = name;
+            while !rest.is_empty() {
+                half_md4_transform(&mut buf, &str2hashbuf(rest, 8, unsigned));
+                rest = &rest[rest.len().min(32)..];
+            }
+            buf[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let mut rest = name;
+            while !rest.is_empty() {
+                tea_transform(&mut buf, &str2hashbuf(rest, 4, unsigned));
+                rest = &rest[rest.len().min(16)..];
+            }
+            buf[0]
+        }
+        _ => return None,
+    };
+    let hash = hash & !1;
+    // the largest hash means the end of a directory to readdir
+    Some(if hash == 0xffff_fffe { 0xffff_fffc } else { hash })
+}
+
+fn hash_char(c: u8, unsigned: bool) -> u32 {
+    if unsigned { c as u32 } else { c as i8 as i32 as u32 }
+}
+
+fn legacy_hash(name: &[u8], unsigned: bool) -> u32 {
+    let (mut hash0, mut hash1): (u32, u32) = (0x12a3fe2d, 0x37abe8f9);
+    for c in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ hash_char(*c, unsigned).wrapping_mul(7152373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Pack the first `4 * num` bytes of a name into words, padded with its length
+fn str2hashbuf(msg: &[u8], num: usize, unsigned: bool) -> [u32; 8] {
+    let mut pad = msg.len() as u32 | ((msg.len() as u32) << 8);
+    pad |= pad << 16;
+    let mut buf = [pad; 8];
+    let mut val = pad;
+    let mut words = 0;
+    for (i, c) in msg.iter().take(4 * num).enumerate() {
+        val = hash_char(*c, unsigned).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[words] = val;
+            words += 1;
+            val = pad;
+        }
+    }
+    if words < num {
+        buf[words] = val;
+    }
+    buf
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0x5a82_7999;
+    const K3: u32 = 0x6ed9_eba1;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x).rotate_left($s)
+        };
+    }
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const DELTA: u32 = 0x9e37_79b9;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = [input[0], input[1], input[2], input[3]];
+    let mut sum: u32 = 0;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b));
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d));
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// An index entry: names hashing to at least `hash` are below `block`,
+/// a logical block of the directory. The hash of the first entry is 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DxEntry {
+    pub hash: u32,
+    pub block: u32,
+}
+
+/// The index entries of a dx_root or dx_node block
+#[derive(Clone, Debug)]
+pub struct DxEntries {
+    pub limit: usize,
+    pub entries: Vec<DxEntry>,
+}
+
+impl DxEntries {
+    fn read(data: &[u8], offset: usize, tail: usize) -> Option<Self> {
+        let limit = (data.len() - tail - offset) / DX_ENTRY_SIZE;
+        let count = read_u16(data, offset + 2) as usize;
+        if read_u16(data, offset) as usize != limit || count == 0 || count > limit {
+            return None;
+        }
+        let mut entries = vec![DxEntry { hash: 0, block: read_u32(data, offset + 4) }];
+        for i in 1..count {
+            let entry_offset = offset + i * DX_ENTRY_SIZE;
+            entries.push(DxEntry {
+                hash: read_u32(data, entry_offset),
+                block: read_u32(data, entry_offset + 4),
+            });
+        }
+        Some(Self { limit, entries })
+    }
+
+    fn write(&self, data: &mut [u8], offset: usize) {
+        assert!(!self.entries.is_empty() && self.entries.len() <= self.limit);
+        data[offset..offset + 2].copy_from_slice(&(self.limit as u16).to_le_bytes());
+        data[offset + 2..offset + 4].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        data[offset + 4..offset + 8].copy_from_slice(&self.entries[0].block.to_le_bytes());
+        for (i, entry) in self.entries.iter().enumerate().skip(1) {
+            let entry_offset = offset + i * DX_ENTRY_SIZE;
+            data[entry_offset..entry_offset + 4].copy_from_slice(&entry.hash.to_le_bytes());
+            data[entry_offset + 4..entry_offset + 8].copy_from_slice(&entry.block.to_le_bytes());
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.limit
+    }
+
+    /// Position of the entry whose range holds `hash`
+    pub fn find(&self, hash: u32) -> usize {
+        self.entries.partition_point(|entry| entry.hash <= hash) - 1
+    }
+
+    /// Whether names hashing to `hash` go on below the entry at `pos`
+    pub fn continues(&self, pos: usize, hash: u32) -> bool {
+        matches!(self.entries.get(pos), Some(entry) if entry.hash & 1 != 0 && entry.hash & !1 == hash)
+    }
+}
+
+/// The index in the first block of a directory
+#[derive(Clone, Debug)]
+pub struct DxRoot {
+    pub hash_version: u8,
+    /// Levels of dx_node blocks below the root
+    pub levels: u8,
+    pub index: DxEntries,
+}
+
+impl DxRoot {
+    /// A root with room for entries in the first `room` bytes of its block
+    pub fn new(hash_version: u8, room: usize, first_leaf: u32) -> Self {
+        Self {
+            hash_version,
+            levels: 0,
+            index: DxEntries {
+                limit: (room - DX_ROOT_ENTRIES_OFFSET) / DX_ENTRY_SIZE,
+                entries: vec![DxEntry { hash: 0, block: first_leaf }],
+            },
+        }
+    }
+
+    /// Parse the first block of an indexed directory, ending with `tail`
+    /// bytes after the entries, `None` if it does not hold an index we can use
+    pub fn read(data: &[u8], tail: usize) -> Option<Self> {
+        let dot = DirEntryHead::from_bytes(data);
+        let dot_dot = DirEntryHead::from_bytes(&data[DOT_REC_LEN..]);
+        if dot.rec_len as usize != DOT_REC_LEN || dot.name_len != 1 ||
+            dot_dot.rec_len as usize != data.len() - DOT_REC_LEN || dot_dot.name_len != 2 {
+            return None;
+        }
+        let info = &data[DX_ROOT_INFO_OFFSET..DX_ROOT_ENTRIES_OFFSET];
+        let (hash_version, info_len, levels) = (info[4], info[5], info[6]);
+        if read_u32(info, 0) != 0 || info_len != DX_ROOT_INFO_LEN || levels > DX_MAX_LEVELS ||
+            !is_known_version(hash_version) {
+            return None;
+        }
+        Some(Self { hash_version, levels, index: DxEntries::read(data, DX_ROOT_ENTRIES_OFFSET, tail)? })
+    }
+
+    /// Build the first block of an indexed directory out of its `.` and `..` entries
+    pub fn to_block(&self, mut dot: DirEntryHead, mut dot_dot: DirEntryHead, block_size: usize) -> Vec<u8> {
+        let mut data = vec![0; block_size];
+        dot.rec_len = DOT_REC_LEN as u16;
+        dot.name_len = 1;
+        dot_dot.rec_len = (block_size - DOT_REC_LEN) as u16;
+        dot_dot.name_len = 2;
+        data[..size_of::<DirEntryHead>()].copy_from_slice(dot.as_bytes());
+        data[size_of::<DirEntryHead>()] = b'.';
+        data[DOT_REC_LEN..DOT_REC_LEN + size_of::<DirEntryHead>()].copy_from_slice(dot_dot.as_bytes());
+        data[DOT_REC_LEN + size_of::<DirEntryHead>()..][..2].copy_from_slice(b"..");
+        self.write(&mut data);
+        data
+    }
+
+    /// Write the index into the first block of the directory
+    pub fn write(&self, data: &mut [u8]) {
+        let info = &mut data[DX_ROOT_INFO_OFFSET..DX_ROOT_ENTRIES_OFFSET];
+        info.fill(0);
+        info[4] = self.hash_version;
+        info[5] = DX_ROOT_INFO_LEN;
+        info[6] = self.levels;
+        self.index.write(data, DX_ROOT_ENTRIES_OFFSET);
+    }
+
+    /// Move the entries of a full root to a dx_node block, to be written at
+    /// logical block `block` with `room` bytes for them, and point the root to it
+    pub fn add_level(&mut self, block: u32, room: usize) -> DxEntries {
+        assert!(self.levels < DX_MAX_LEVELS);
+        let node = DxEntries {
+            limit: (room - DX_NODE_ENTRIES_OFFSET) / DX_ENTRY_SIZE,
+            entries: core::mem::replace(&mut self.index.entries, vec![DxEntry { hash: 0, block }]),
+        };
+        self.levels += 1;
+        node
+    }
+
+    /// Whether the tree can hold more leaves
+    pub fn can_grow(&self) -> bool {
+        !self.index.is_full() || self.levels < DX_MAX_LEVELS
+    }
+}
+
+/// Parse a dx_node block, ending with `tail` bytes after the entries
+pub fn read_node(data: &[u8], tail: usize) -> Option<DxEntries> {
+    let head = DirEntryHead::from_bytes(data);
+    if head.inode != 0 || head.rec_len as usize != data.len() || head.name_len != 0 {
+        return None;
+    }
+    DxEntries::read(data, DX_NODE_ENTRIES_OFFSET, tail)
+}
+
+/// Build a dx_node block
+pub fn node_block(node: &DxEntries, block_size: usize) -> Vec<u8> {
+    let mut data = vec![0; block_size];
+    let mut head = DirEntryHead::empty();
+    head.rec_len = block_size as u16;
+    data[..size_of::<DirEntryHead>()].copy_from_slice(head.as_bytes());
+    node.write(&mut data, DX_NODE_ENTRIES_OFFSET);
+    data
+}
+
+/// Split the entries of a dx_node in two halves, the upper half is
+/// returned with the hash it starts at
+pub fn split_node(node: &mut DxEntries) -> (u32, DxEntries) {
+    let upper = node.entries.split_off(node.entries.len() / 2);
+    let hash = upper[0].hash;
+    (hash, DxEntries { limit: node.limit, entries: upper })
+}
+
+/// An entry in use of a leaf block
+pub struct LeafEntry {
+    pub hash: u32,
+    pub head: DirEntryHead,
+    pub name: Vec<u8>,
+}
+
+impl LeafEntry {
+    fn size(&self) -> usize {
+        DirEntryHead::rec_len_of(self.name.len())
+    }
+}
+
+/// Split the entries of a full leaf by hash, about half of their size
+/// moving to a new leaf. Returns both halves and the hash of the index
+/// entry of the new one.
+pub fn split_leaf(mut entries: Vec<LeafEntry>, block_size: usize) -> (Vec<LeafEntry>, Vec<LeafEntry>, u32) {
+    assert!(entries.len() >= 2);
+    entries.sort_by_key(|entry| entry.hash);
+    let mut moved_size = 0;
+    let mut split = entries.len();
+    while split > 1 {
+        let size = entries[split - 1].size();
+        if moved_size + size / 2 > block_size / 2 {
+            break;
+        }
+        moved_size += size;
+        split -= 1;
+    }
+    let split = split.min(entries.len() - 1);
+    let upper = entries.split_off(split);
+    let hash = upper[0].hash;
+    let continued = hash == entries[split - 1].hash;
+    (entries, upper, hash | continued as u32)
+}
+
+/// Build a leaf block out of entries
+pub fn leaf_block(entries: &[LeafEntry], block_size: usize) -> Vec<u8> {
+    let mut data = vec![0; block_size];
+    let mut offset = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let mut head = entry.head;
+        head.rec_len = if i + 1 == entries.len() { block_size - offset } else { entry.size() } as u16;
+        data[offset..offset + size_of::<DirEntryHead>()].copy_from_slice(head.as_bytes());
+        data[offset + size_of::<DirEntryHead>()..][..entry.name.len()].copy_from_slice(&entry.name);
+        offset += entry.size();
+    }
+    if entries.is_empty() {
+        let mut head = DirEntryHead::empty();
+        head.rec_len = block_size as u16;
+        data[..size_of::<DirEntryHead>()].copy_from_slice(head.as_bytes());
+    }
+    data
+}
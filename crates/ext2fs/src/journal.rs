@@ -0,0 +1,487 @@
+//! Journal of ext3 in the JBD2 on-disk format
+//!
+//! Metadata blocks modified by a transaction are first logged to the journal
+//! inode, then written in place (checkpointed) right after the commit block
+//! reaches the disk. Since every committed transaction is checkpointed before
+//! the next one starts, the log is never wrapped: it is simply emptied once
+//! it runs out of space. Freed blocks that were logged since the log was last
+//! emptied get revoke records, so that stale copies are not replayed over
+//! their new contents.
+//!
+//! All fields of JBD2 structures are big-endian.
+use crate::block_dev::BlockDevice;
+use crate::error::{Ext2Error, Ext2Result};
+use crate::layout::{self, SuperBlock};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use log::*;
+
+const JBD2_MAGIC_NUMBER: u32 = 0xC03B3998;
+
+// h_blocktype
+const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+const JBD2_COMMIT_BLOCK: u32 = 2;
+const JBD2_SUPERBLOCK_V1: u32 = 3;
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+const JBD2_REVOKE_BLOCK: u32 = 5;
+
+// s_feature_incompat
+const JBD2_FEATURE_INCOMPAT_REVOKE: u32 = 1;
+
+// t_flags
+const JBD2_FLAG_ESCAPE: u16 = 1;
+const JBD2_FLAG_SAME_UUID: u16 = 2;
+const JBD2_FLAG_LAST_TAG: u16 = 8;
+
+/// journal_header_s: h_magic, h_blocktype, h_sequence
+const HEADER_SIZE: usize = 12;
+/// journal_block_tag_s without 64bit and checksum features
+const TAG_SIZE: usize = 8;
+const UUID_SIZE: usize = 16;
+/// jbd2_journal_revoke_header_s: header and r_count
+const REVOKE_HEADER_SIZE: usize = HEADER_SIZE + 4;
+const REVOKE_RECORD_SIZE: usize = 4;
+
+// offsets inside journal_superblock_s
+const S_BLOCKSIZE: usize = 0x0C;
+const S_MAXLEN: usize = 0x10;
+const S_FIRST: usize = 0x14;
+const S_SEQUENCE: usize = 0x18;
+const S_START: usize = 0x1C;
+const S_FEATURE_INCOMPAT: usize = 0x28;
+const S_UUID: usize = 0x30;
+const S_NR_USERS: usize = 0x40;
+const S_USERS: usize = 0x100;
+
+/// Offsets in the super block of the file system
+const SB_BLOCKS_COUNT: usize = 0x04;
+const SB_MAGIC: usize = 0x38;
+
+/// Journal size (in blocks) that mke2fs would choose for a file system
+pub fn default_journal_blocks(block_num: usize) -> usize {
+    if block_num < 2048 {
+        0
+    } else if block_num < 32768 {
+        1024
+    } else if block_num < 256 * 1024 {
+        4096
+    } else if block_num < 512 * 1024 {
+        8192
+    } else {
+        16384
+    }
+}
+
+pub struct Journal {
+    /// Blocks of the journal inode, indexed by journal block number
+    blocks: Vec<u32>,
+    block_size: usize,
+    uuid: [u8; 16],
+    /// First journal block of the log
+    first: u32,
+    /// Number of blocks in the journal
+    maxlen: u32,
+    /// Journal block of the first transaction, or 0 if the log is empty
+    start: u32,
+    /// Sequence number of the first transaction in the log
+    start_sequence: u32,
+    /// Next free journal block
+    head: u32,
+    /// Sequence number of the running transaction
+    sequence: u32,
+    /// Blocks logged since the log was last emptied
+    logged: BTreeSet<usize>,
+    /// Blocks revoked by the running transaction
+    revoked: BTreeSet<usize>,
+}
+
+#[derive(Clone, Copy)]
+enum Pass<'a> {
+    /// Find the end of the log, where targets are checked against the file
+    /// system of this super block
+    Scan(&'a SuperBlock),
+    Revoke,
+    Replay,
+}
+
+impl Journal {
+    /// Write an empty journal super block into the first block of a new journal
+    pub fn format(buf: &mut [u8], maxlen: usize, uuid: [u8; 16]) {
+        buf.fill(0);
+        write_header(buf, JBD2_SUPERBLOCK_V2, 0);
+        set_be32(buf, S_BLOCKSIZE, buf.len() as u32);
+        set_be32(buf, S_MAXLEN, maxlen as u32);
+        set_be32(buf, S_FIRST, 1);
+        set_be32(buf, S_SEQUENCE, 1);
+        set_be32(buf, S_FEATURE_INCOMPAT, JBD2_FEATURE_INCOMPAT_REVOKE);
+        buf[S_UUID..S_UUID + UUID_SIZE].copy_from_slice(&uuid);
+        set_be32(buf, S_NR_USERS, 1);
+        buf[S_USERS..S_USERS + UUID_SIZE].copy_from_slice(&uuid);
+    }
+
+    /// Load the journal stored in `blocks`
+    pub fn load(device: &dyn BlockDevice, blocks: Vec<u32>) -> Ext2Result<Self> {
+        if blocks.is_empty() {
+            error!("Empty journal");
+            return Err(Ext2Error::Corrupted);
+        }
+        let block_size = device.block_size();
+        let mut buf = vec![0u8; block_size];
+        device.read_block(blocks[0] as _, &mut buf)?;
+        let blocktype = be32(&buf, 4);
+        if be32(&buf, 0) != JBD2_MAGIC_NUMBER
+            || !(blocktype == JBD2_SUPERBLOCK_V1 || blocktype == JBD2_SUPERBLOCK_V2)
+            || be32(&buf, S_BLOCKSIZE) as usize != block_size
+        {
+            error!("Bad journal super block");
+            return Err(Ext2Error::Corrupted);
+        }
+        if blocktype == JBD2_SUPERBLOCK_V2
+            && be32(&buf, S_FEATURE_INCOMPAT) & !JBD2_FEATURE_INCOMPAT_REVOKE != 0
+        {
+            error!("Journal feature incompat not supported");
+            return Err(Ext2Error::Unsupported);
+        }
+        let first = be32(&buf, S_FIRST);
+        let maxlen = be32(&buf, S_MAXLEN);
+        if !(first >= 1 && first < maxlen && maxlen as usize <= blocks.len()) {
+            error!("Bad journal size");
+            return Err(Ext2Error::Corrupted);
+        }
+        let start = be32(&buf, S_START);
+        let sequence = be32(&buf, S_SEQUENCE);
+        Ok(Self {
+            blocks,
+            block_size,
+            uuid: buf[S_UUID..S_UUID + UUID_SIZE].try_into().unwrap(),
+            first,
+            maxlen,
+            start,
+            start_sequence: sequence,
+            head: first,
+            sequence,
+            logged: BTreeSet::new(),
+            revoked: BTreeSet::new(),
+        })
+    }
+
+    /// Whether the log holds no transaction
+    pub fn is_empty(&self) -> bool {
+        self.start == 0
+    }
+
+    /// Replay all committed transactions in the log and empty it,
+    /// return the number of replayed transactions. The log ends before a
+    /// transaction that logs a block out of the file system of `super_block`
+    /// or in the journal, or anything but a super block over the super block,
+    /// as a corrupted one.
+    pub fn recover(&mut self, device: &dyn BlockDevice, super_block: &SuperBlock) -> Ext2Result<usize> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        let mut revoked = BTreeMap::new();
+        let end = self.do_one_pass(device, Pass::Scan(super_block), u32::MAX, &mut revoked)?;
+        self.do_one_pass(device, Pass::Revoke, end, &mut revoked)?;
+        self.do_one_pass(device, Pass::Replay, end, &mut revoked)?;
+        let replayed = end.wrapping_sub(self.start_sequence) as usize;
+        debug!("journal: replayed {} transactions, {} revoked blocks", replayed, revoked.len());
+        self.sequence = end.wrapping_add(1);
+        self.reset(device)?;
+        Ok(replayed)
+    }
+
+    /// Record that `block_id` was freed by the running transaction
+    pub fn revoke(&mut self, block_id: usize) {
+        if self.logged.contains(&block_id) {
+            self.revoked.insert(block_id);
+        }
+    }
+
+    pub fn has_revoked(&self) -> bool {
+        !self.revoked.is_empty()
+    }
+
+    /// Log metadata blocks as one transaction and wait for its commit block.
+    /// Return false if the transaction does not fit in the journal, in which
+    /// case the journal is emptied and the blocks should be written in place.
+    pub fn commit(&mut self, device: &dyn BlockDevice, blocks: &[(usize, &[u8])]) -> Ext2Result<bool> {
+        let needed = self.blocks_needed(blocks.len(), self.revoked.len());
+        if needed > (self.maxlen - self.first) as usize {
+            warn!("journal: transaction of {} blocks is too large", blocks.len());
+            self.reset(device)?;
+            return Ok(false);
+        }
+        if self.head as usize + needed > self.maxlen as usize {
+            // all logged transactions are checkpointed, so the log can be reused
+            self.reset(device)?;
+        }
+        // a block logged again by this transaction is no longer revoked
+        let revoked: Vec<u32> = self.revoked.iter()
+            .filter(|block_id| blocks.iter().all(|(id, _)| id != *block_id))
+            .map(|block_id| *block_id as u32)
+            .collect();
+        if self.is_empty() {
+            self.start = self.head;
+            self.start_sequence = self.sequence;
+            self.write_super_block(device)?;
+        }
+
+        let mut buf = vec![0u8; self.block_size];
+        // revoke records
+        for records in revoked.chunks(self.records_per_revoke()) {
+            buf.fill(0);
+            write_header(&mut buf, JBD2_REVOKE_BLOCK, self.sequence);
+            set_be32(&mut buf, HEADER_SIZE, (REVOKE_HEADER_SIZE + records.len() * REVOKE_RECORD_SIZE) as u32);
+            for (i, block_id) in records.iter().enumerate() {
+                set_be32(&mut buf, REVOKE_HEADER_SIZE + i * REVOKE_RECORD_SIZE, *block_id);
+            }
+            self.write_next(device, &buf)?;
+        }
+        // descriptors followed by the logged blocks
+        for chunk in blocks.chunks(self.tags_per_descriptor()) {
+            buf.fill(0);
+            write_header(&mut buf, JBD2_DESCRIPTOR_BLOCK, self.sequence);
+            let mut offset = HEADER_SIZE;
+            for (i, (block_id, data)) in chunk.iter().enumerate() {
+                let mut flags = 0;
+                if i > 0 {
+                    flags |= JBD2_FLAG_SAME_UUID;
+                }
+                if i == chunk.len() - 1 {
+                    flags |= JBD2_FLAG_LAST_TAG;
+                }
+                if be32(data, 0) == JBD2_MAGIC_NUMBER {
+                    flags |= JBD2_FLAG_ESCAPE;
+                }
+                set_be32(&mut buf, offset, *block_id as u32);
+                set_be16(&mut buf, offset + 6, flags);
+                offset += TAG_SIZE;
+                if i == 0 {
+                    buf[offset..offset + UUID_SIZE].copy_from_slice(&self.uuid);
+                    offset += UUID_SIZE;
+                }
+            }
+            self.write_next(device, &buf)?;
+            let mut escaped = vec![0u8; self.block_size];
+            for (_, data) in chunk {
+                if be32(data, 0) == JBD2_MAGIC_NUMBER {
+                    escaped.copy_from_slice(data);
+                    set_be32(&mut escaped, 0, 0);
+                    self.write_next(device, &escaped)?;
+                } else {
+                    self.write_next(device, data)?;
+                }
+            }
+        }
+        // commit block
+        buf.fill(0);
+        write_header(&mut buf, JBD2_COMMIT_BLOCK, self.sequence);
+        self.write_next(device, &buf)?;
+
+        self.logged.extend(blocks.iter().map(|(block_id, _)| *block_id));
+        self.revoked.clear();
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(true)
+    }
+
+    /// Empty the log, all committed transactions must have been checkpointed.
+    /// Return whether the log held any transaction.
+    pub fn reset(&mut self, device: &dyn BlockDevice) -> Ext2Result<bool> {
+        self.logged.clear();
+        self.revoked.clear();
+        self.head = self.first;
+        if self.is_empty() {
+            return Ok(false);
+        }
+        self.start = 0;
+        self.start_sequence = self.sequence;
+        self.write_super_block(device)?;
+        Ok(true)
+    }
+
+    fn tags_per_descriptor(&self) -> usize {
+        (self.block_size - HEADER_SIZE - UUID_SIZE) / TAG_SIZE
+    }
+
+    fn records_per_revoke(&self) -> usize {
+        (self.block_size - REVOKE_HEADER_SIZE) / REVOKE_RECORD_SIZE
+    }
+
+    fn blocks_needed(&self, logged: usize, revoked: usize) -> usize {
+        let tags = self.tags_per_descriptor();
+        let records = self.records_per_revoke();
+        logged.div_ceil(tags) + logged + revoked.div_ceil(records) + 1
+    }
+
+    /// Update s_start and s_sequence of the journal super block on disk
+    fn write_super_block(&self, device: &dyn BlockDevice) -> Ext2Result {
+        let mut buf = vec![0u8; self.block_size];
+        device.read_block(self.blocks[0] as _, &mut buf)?;
+        set_be32(&mut buf, S_SEQUENCE, self.start_sequence);
+        set_be32(&mut buf, S_START, self.start);
+        device.write_block(self.blocks[0] as _, &buf)
+    }
+
+    fn write_next(&mut self, device: &dyn BlockDevice, buf: &[u8]) -> Ext2Result {
+        device.write_block(self.blocks[self.head as usize] as _, buf)?;
+        self.head += 1;
+        Ok(())
+    }
+
+    fn next(&self, block: u32) -> u32 {
+        if block + 1 >= self.maxlen {
+            self.first
+        } else {
+            block + 1
+        }
+    }
+
+    /// Walk through the log from its start, as `do_one_pass` of JBD2 does.
+    /// Return the sequence number following the last committed transaction.
+    fn do_one_pass(
+        &self,
+        device: &dyn BlockDevice,
+        pass: Pass,
+        end: u32,
+        revoked: &mut BTreeMap<u32, u32>
+    ) -> Ext2Result<u32> {
+        let mut sequence = self.start_sequence;
+        let mut block = self.start;
+        let mut buf = vec![0u8; self.block_size];
+        let mut data = vec![0u8; self.block_size];
+        let journal_blocks: BTreeSet<u32> = match pass {
+            Pass::Scan(_) => self.blocks.iter().copied().collect(),
+            _ => BTreeSet::new(),
+        };
+        // targets of the transaction being scanned, with their log blocks
+        let mut targets = Vec::new();
+        let mut blocks_count = match pass {
+            Pass::Scan(super_block) => super_block.s_blocks_count,
+            _ => 0,
+        };
+        // a corrupted log must not make us loop forever
+        for _ in 0..self.maxlen {
+            if !matches!(pass, Pass::Scan(_)) && sequence == end {
+                break;
+            }
+            device.read_block(self.blocks[block as usize] as _, &mut buf)?;
+            if be32(&buf, 0) != JBD2_MAGIC_NUMBER || be32(&buf, 8) != sequence {
+                break;
+            }
+            block = self.next(block);
+            match be32(&buf, 4) {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    for (block_id, flags) in parse_tags(&buf) {
+                        targets.push((block_id, block));
+                        let revoked = revoked.get(&block_id)
+                            .is_some_and(|revoke_sequence| *revoke_sequence >= sequence);
+                        if matches!(pass, Pass::Replay) && !revoked {
+                            device.read_block(self.blocks[block as usize] as _, &mut data)?;
+                            if flags & JBD2_FLAG_ESCAPE != 0 {
+                                set_be32(&mut data, 0, JBD2_MAGIC_NUMBER);
+                            }
+                            device.write_block(block_id as _, &data)?;
+                        }
+                        block = self.next(block);
+                    }
+                }
+                JBD2_COMMIT_BLOCK => {
+                    if let Pass::Scan(super_block) = pass {
+                        let first_data_block = super_block.s_first_data_block;
+                        if let Some(block_id) = self.check_targets(device, first_data_block, &mut blocks_count, &journal_blocks, &targets)? {
+                            error!("journal: transaction {} logs block {} it can not replay", sequence, block_id);
+                            return Ok(sequence);
+                        }
+                        targets.clear();
+                    }
+                    sequence = sequence.wrapping_add(1);
+                }
+                JBD2_REVOKE_BLOCK => {
+                    if matches!(pass, Pass::Revoke) {
+                        let count = (be32(&buf, HEADER_SIZE) as usize).min(self.block_size);
+                        for offset in (REVOKE_HEADER_SIZE..count).step_by(REVOKE_RECORD_SIZE) {
+                            let revoke_sequence = revoked.entry(be32(&buf, offset)).or_insert(sequence);
+                            *revoke_sequence = (*revoke_sequence).max(sequence);
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok(sequence)
+    }
+
+    /// Check the targets of a transaction, as file system and log blocks,
+    /// return one it can not replay. Targets must be in the file system,
+    /// which is `blocks_count` blocks long or as long as the super block the
+    /// transaction logs says, and out of the journal. The super block is only
+    /// replaced by another one.
+    fn check_targets(
+        &self,
+        device: &dyn BlockDevice,
+        first_data_block: u32,
+        blocks_count: &mut u32,
+        journal_blocks: &BTreeSet<u32>,
+        targets: &[(u32, u32)]
+    ) -> Ext2Result<Option<u32>> {
+        let mut data = vec![0u8; self.block_size];
+        if let Some((block_id, block)) = targets.iter().find(|(block_id, _)| *block_id == first_data_block) {
+            device.read_block(self.blocks[*block as usize] as _, &mut data)?;
+            let offset = if first_data_block == 0 { 1024 } else { 0 };
+            if u16::from_le_bytes([data[offset + SB_MAGIC], data[offset + SB_MAGIC + 1]]) != layout::SB_MAGIC {
+                return Ok(Some(*block_id));
+            }
+            // a resize logs the blocks of the new groups with the super block
+            *blocks_count = (*blocks_count).max(le32(&data, offset + SB_BLOCKS_COUNT));
+        }
+        Ok(targets.iter()
+            .map(|(block_id, _)| *block_id)
+            .find(|block_id| *block_id < first_data_block || *block_id >= *blocks_count || journal_blocks.contains(block_id)))
+    }
+}
+
+/// Get (t_blocknr, t_flags) of all tags in a descriptor block
+fn parse_tags(buf: &[u8]) -> Vec<(u32, u16)> {
+    let mut tags = Vec::new();
+    let mut offset = HEADER_SIZE;
+    while offset + TAG_SIZE <= buf.len() {
+        let block_id = be32(buf, offset);
+        let flags = be16(buf, offset + 6);
+        offset += TAG_SIZE;
+        if flags & JBD2_FLAG_SAME_UUID == 0 {
+            offset += UUID_SIZE;
+        }
+        tags.push((block_id, flags));
+        if flags & JBD2_FLAG_LAST_TAG != 0 {
+            break;
+        }
+    }
+    tags
+}
+
+fn write_header(buf: &mut [u8], blocktype: u32, sequence: u32) {
+    set_be32(buf, 0, JBD2_MAGIC_NUMBER);
+    set_be32(buf, 4, blocktype);
+    set_be32(buf, 8, sequence);
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn set_be32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn set_be16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
@@ -1,23 +1,47 @@
 #![allow(unused)]
 use super::{config::*};
 use crate::block_cache_manager::{BlockCacheManager};
-use crate::mutex::SpinMutex;
+use crate::block_map::BlockMap;
+use crate::htree::{HashInfo, DX_HASH_HALF_MD4};
+use crate::extent::{self, Extent};
+use crate::csum;
+use crate::error::{Ext2Error, Ext2Result};
 use _core::mem::size_of;
 use bitflags::*;
-use alloc::{string::String, vec::Vec};
+use alloc::{string::String, vec, vec::Vec};
 use core::fmt::{Debug, Formatter, Result};
+use core::ops::Range;
 use log::*;
 
-const VOLUMN_NAME_SIZE: usize = 16;
+pub(crate) const VOLUMN_NAME_SIZE: usize = 16;
 const MOUNT_SIZE: usize = 64;
 const HASH_SEED_SIZE: usize = 4;
-const SB_RESERVED_SIZE: usize = 760;
+const JNL_BLOCKS_SIZE: usize = 17;
+
+pub const DIRECT_BLOCK_NUM: usize = 12;
+
+/// Targets shorter than this are stored in the inode as fast symlinks
+pub const FAST_SYMLINK_MAX: usize = (DIRECT_BLOCK_NUM + 3) * 4;
+
+/// Number of block ids in an indirect block
+pub const fn double_block_num(block_size: usize) -> usize {
+    block_size / 4
+}
+
+/// Levels of the deepest tree of indirect blocks
+pub const INDIRECT_DEPTH: usize = 3;
+
+/// The logical blocks mapped through the tree of indirect blocks with
+/// `depth` levels, which follow the direct blocks and the shallower trees
+pub fn indirect_range(depth: usize, block_size: usize) -> Range<usize> {
+    let per_block = double_block_num(block_size);
+    let start = (1..depth).fold(DIRECT_BLOCK_NUM, |start, level| start + per_block.pow(level as u32));
+    start..start + per_block.pow(depth as u32)
+}
+
+/// Files larger than this need `EXT2_FEATURE_RO_COMPAT_LARGE_FILE`
+pub const LARGE_FILE_SIZE: usize = 0x7fff_ffff;
 
-pub const DIRECT_BLOCK_NUM: usize = 13;
-pub const DOUBLE_BLOCK_NUM: usize = BLOCK_SIZE/4;
-pub const DOUBLE_BLOCK_BOUND: usize = DIRECT_BLOCK_NUM + DOUBLE_BLOCK_NUM;
-pub const TRIPLE_BLOCK_NUM: usize = (BLOCK_SIZE/4) * (BLOCK_SIZE/4);
-// pub const TRIPLE_BLOCK_BOUND: usize = TRIPLE_BLOCK_NUM + DOUBLE_BLOCK_BOUND;
 pub const SB_MAGIC: u16 = 0xEF53;
 
 #[derive(Clone, Copy)]
@@ -31,9 +55,9 @@ pub struct SuperBlock {
     pub s_first_data_block: u32,
     s_log_block_size: u32,
     s_log_frag_size: u32,
-    s_blocks_per_group: u32,
+    pub s_blocks_per_group: u32,
     s_frags_per_group: u32,
-    s_inodes_per_group: u32,
+    pub s_inodes_per_group: u32,
     pub s_mtime: u32,
     pub s_wtime: u32,
     pub s_mnt_count: u16,
@@ -55,16 +79,16 @@ pub struct SuperBlock {
     s_feature_compat: FeatureCompat,
     s_feature_incompat: FeatureIncompat,
     s_feature_ro_compat: FeatureRocompat,
-    s_uuid: u128,
+    s_uuid: [u8; 16],
     s_volume_name: [u8; VOLUMN_NAME_SIZE],
     s_last_mounted: [u8; MOUNT_SIZE],
     s_algo_bitmap: u32,
     // Performance hints
     s_prealloc_blocks: u8,
     s_prealloc_dir_blocks: u8,
-    p_padding: [u8; 2],
+    s_reserved_gdt_blocks: u16,
     // Journaling Support
-    s_journal_uuid: u128,
+    s_journal_uuid: [u8; 16],
     s_journal_inum: u32,
     s_journal_dev: u32,
     s_last_orphan: u32,
@@ -75,7 +99,23 @@ pub struct SuperBlock {
     // Other options
     s_default_mount_option: u32,
     s_first_meta_bg: u32,
-    reserved: [u8; SB_RESERVED_SIZE]
+    // Ext3 and later
+    s_mkfs_time: u32,
+    s_jnl_blocks: [u32; JNL_BLOCKS_SIZE],
+    s_blocks_count_hi: u32,
+    s_r_blocks_count_hi: u32,
+    s_free_blocks_count_hi: u32,
+    s_min_extra_isize: u16,
+    s_want_extra_isize: u16,
+    s_flags: u32,
+    reserved: [u8; 17],
+    s_checksum_type: u8,
+    reserved_2: [u8; 250],
+    /// Seed of metadata checksums, if `EXT4_FEATURE_INCOMPAT_CSUM_SEED`
+    s_checksum_seed: u32,
+    reserved_3: [u8; 392],
+    /// crc32c of the super block before this field
+    s_checksum: u32
 }
 
 // s_state
@@ -87,6 +127,13 @@ const EXT2_ERRORS_CONTINUE: u16 = 1;
 const EXT2_ERRORS_RO: u16 = 2;
 const EXT2_ERRORS_PANIC: u16 = 3;
 
+// s_flags
+const EXT2_FLAGS_SIGNED_HASH: u32 = 1;
+const EXT2_FLAGS_UNSIGNED_HASH: u32 = 2;
+
+// s_checksum_type
+const EXT4_CRC32C_CHKSUM: u8 = 1;
+
 // s_creator_os
 const EXT2_OS_LINUX: u32 = 0;
 const EXT2_OS_HURD: u32 = 1;
@@ -124,6 +171,8 @@ bitflags! {
         const EXT3_FEATURE_INCOMPAT_RECOVER = 1 << 2;
         const EXT3_FEATURE_INCOMPAT_JOURNAL_DEV = 1 << 3;
         const EXT2_FEATURE_INCOMPAT_META_BG = 1 << 4;
+        const EXT3_FEATURE_INCOMPAT_EXTENTS = 1 << 6;
+        const EXT4_FEATURE_INCOMPAT_CSUM_SEED = 1 << 13;
     }
 }
 
@@ -132,9 +181,26 @@ bitflags! {
         const EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER = 1;
         const EXT2_FEATURE_RO_COMPAT_LARGE_FILE = 1 << 1;
         const EXT2_FEATURE_RO_COMPAT_BTREE_DIR = 1 << 2;
+        const EXT4_FEATURE_RO_COMPAT_METADATA_CSUM = 1 << 10;
     }
 }
 
+/// Incompatible features we know of, a file system using any other can not be opened
+const SUPPORTED_INCOMPAT: FeatureIncompat = FeatureIncompat::from_bits_truncate(
+    FeatureIncompat::EXT2_FEATURE_INCOMPAT_FILETYPE.bits()
+    | FeatureIncompat::EXT3_FEATURE_INCOMPAT_RECOVER.bits()
+    | FeatureIncompat::EXT3_FEATURE_INCOMPAT_EXTENTS.bits()
+    | FeatureIncompat::EXT4_FEATURE_INCOMPAT_CSUM_SEED.bits()
+);
+
+/// Read-only compatible features we know of, a file system using any other is
+/// opened read-only
+const SUPPORTED_RO_COMPAT: FeatureRocompat = FeatureRocompat::from_bits_truncate(
+    FeatureRocompat::EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER.bits()
+    | FeatureRocompat::EXT2_FEATURE_RO_COMPAT_LARGE_FILE.bits()
+    | FeatureRocompat::EXT4_FEATURE_RO_COMPAT_METADATA_CSUM.bits()
+);
+
 bitflags! {
     pub struct AlgoBitmap: u32 {
         const EXT2_LZV1_ALG = 1;
@@ -154,40 +220,55 @@ pub struct BlockGroupDesc {
     pub bg_free_blocks_count: u16,
     pub bg_free_inodes_count: u16,
     pub bg_used_dirs_count: u16,
-    bg_pad: u16,
-    bg_reserved: [u8; 12]
+    pub bg_flags: u16,
+    bg_exclude_bitmap: u32,
+    /// Low 16 bits of the checksums of the bitmaps, with `metadata_csum`
+    pub bg_block_bitmap_csum: u16,
+    pub bg_inode_bitmap_csum: u16,
+    /// Inodes at the end of the inode table never used, with `metadata_csum`
+    pub bg_itable_unused: u16,
+    pub bg_checksum: u16
 }
 
+// bg_flags
+/// The inode bitmap and inode table are not initialized
+pub const EXT4_BG_INODE_UNINIT: u16 = 1;
+/// The block bitmap is not initialized
+pub const EXT4_BG_BLOCK_UNINIT: u16 = 2;
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct DiskInode {
     pub i_mode: u16,
     pub i_uid: u16,
-    pub i_size: u32,
+    /// Low 32 bits of the size, see [`DiskInode::size`]
+    i_size: u32,
     pub i_atime: u32,
     pub i_ctime: u32,
     pub i_mtime: u32,
     pub i_dtime: u32,
     pub i_gid: u16,
     pub i_links_count: u16,
-    // the total number of 512-bytes blocks
+    // the total number of 512-bytes blocks, including indirect blocks
     pub i_blocks: u32,
-    i_flags: u32,
+    pub i_flags: u32,
     i_osd1: u32,
-    i_direct_block: [u32; DIRECT_BLOCK_NUM],
-    i_double_block: u32,
-    i_triple_block: u32,
+    pub(crate) i_direct_block: [u32; DIRECT_BLOCK_NUM],
+    pub(crate) i_double_block: u32,
+    pub(crate) i_triple_block: u32,
+    pub(crate) i_quadruple_block: u32,
     i_generation: u32,
     i_file_acl: u32,
-    i_dir_acl: u32,
+    /// High 32 bits of the size of regular files, the ACL of directories
+    i_size_high: u32,
     i_faddr: u32,
     i_osd2: LinuxOSD
 }
 
 /// A indirect block
-type IndirectBlock = [u32; BLOCK_SIZE / 4];
+type IndirectBlock = [u32];
 /// A data block
-type DataBlock = [u8; BLOCK_SIZE];
+type DataBlock = [u8];
 
 // Defined Reserved Inodes
 const EXT2_BAD_INO: u32 = 1;
@@ -196,6 +277,14 @@ const EXT2_ACL_IDX_INO: u32 = 3;
 const EXT2_ACL_DATA_INO: u32 = 4;
 const EXT2_BOOT_LOADER_INO: u32 = 5;
 const EXT2_UNDEL_DIR_INO: u32 = 6;
+pub const EXT2_RESIZE_INO: u32 = 7;
+pub const EXT3_JOURNAL_INO: u32 = 8;
+
+// i_flags
+/// Directory with a hash index
+pub const EXT2_INDEX_FL: u32 = 0x1000;
+/// Inode mapping its data through an extent tree
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;
 
 bitflags! {
     pub struct IMODE: u16 {
@@ -216,6 +305,20 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// How `fallocate` changes a range of a file, the values are those of Linux.
+    /// Without flags the range is allocated and the file grows to cover it.
+    pub struct FallocMode: u32 {
+        /// Do not change the size, blocks past the end stay allocated. Only
+        /// files mapped by extents can have blocks past the end
+        const KEEP_SIZE = 0x01;
+        /// Unmap the blocks of the range, which reads as zeros, with `KEEP_SIZE`
+        const PUNCH_HOLE = 0x02;
+        /// Make the range read as zeros, with its blocks allocated
+        const ZERO_RANGE = 0x10;
+    }
+}
+
 pub const DEFAULT_IMODE: IMODE = IMODE::from_bits_truncate(0o755); // rwxrw-rw-
 
 // IMODE -> file format
@@ -245,7 +348,8 @@ pub struct LinuxOSD {
     reserved: [u8; 2],
     l_i_uid_high: u16,
     l_i_gid_high: u16,
-    reserved_2: [u8; 4]
+    l_i_checksum_lo: u16,
+    l_i_reserved: u16
 }
 
 impl LinuxOSD {
@@ -256,7 +360,8 @@ impl LinuxOSD {
             reserved: [0; 2],
             l_i_uid_high: 0,
             l_i_gid_high: 0,
-            reserved_2: [0; 4]
+            l_i_checksum_lo: 0,
+            l_i_reserved: 0
         }
     }
 }
@@ -273,28 +378,41 @@ pub struct DirEntryHead {
     // name is variable length
 }
 
+/// Sizes and counts of a new file system, see [`SuperBlock::new`]
+#[derive(Clone, Copy, Default)]
+pub struct SuperBlockParams {
+    pub inodes_count: usize,
+    pub blocks_count: usize,
+    pub free_inodes_count: usize,
+    pub free_blocks_count: usize,
+    pub block_size: usize,
+    pub blocks_per_group: usize,
+    pub inodes_per_group: usize
+}
+
 impl SuperBlock {
-    pub fn new(
-        inodes_count: usize,
-        blocks_count: usize,
-        free_inodes_count: usize,
-        free_blocks_count: usize,
-        block_group_num: usize,
-        volumn_name: &str
-    ) -> SuperBlock 
-    {
+    pub fn new(params: &SuperBlockParams, volumn_name: &str) -> SuperBlock {
+        let SuperBlockParams {
+            inodes_count,
+            blocks_count,
+            free_inodes_count,
+            free_blocks_count,
+            block_size,
+            blocks_per_group,
+            inodes_per_group
+        } = *params;
         let mut sb = SuperBlock {
             s_inodes_count: inodes_count as u32,
             s_blocks_count: blocks_count as u32,
             s_r_blocks_count: 0,
             s_free_blocks_count: free_blocks_count as u32,
             s_free_inodes_count: free_inodes_count as u32,
-            s_first_data_block: FIRST_DATA_BLOCK as u32,
-            s_log_block_size: LOG_BLOCK_SIZE as u32,
-            s_log_frag_size: LOG_FRAG_SIZE as u32,
-            s_blocks_per_group: BLOCKS_PER_GRP as u32,
-            s_frags_per_group: BLOCKS_PER_GRP as u32,
-            s_inodes_per_group: INODES_PER_GRP as u32,
+            s_first_data_block: if block_size == 1024 { 1 } else { 0 },
+            s_log_block_size: (block_size / MIN_BLOCK_SIZE).trailing_zeros(),
+            s_log_frag_size: (block_size / MIN_BLOCK_SIZE).trailing_zeros(),
+            s_blocks_per_group: blocks_per_group as u32,
+            s_frags_per_group: blocks_per_group as u32,
+            s_inodes_per_group: inodes_per_group as u32,
             s_mtime: FAKE_CREATE_TIME as u32,
             s_wtime: FAKE_CREATE_TIME as u32,
             s_mnt_count: 0,
@@ -306,64 +424,355 @@ impl SuperBlock {
             s_lastcheck: FAKE_CREATE_TIME as u32,
             s_checkinterval: CHECK_INTERVAL as u32,
             s_creator_os: EXT2_OS_LINUX,
-            s_rev_level: EXT2_GOOD_OLD_REV,
+            s_rev_level: EXT2_DYNAMIC_REV,
             s_def_resuid: EXT2_DEF_RESUID,
             s_def_resgid: EXT2_DEF_RESGID,
             s_first_ino: EXT2_GOOD_OLD_FIRST_INO as u32,
             s_inode_size: EXT2_GOOD_OLD_INODE_SIZE as u16,
-            s_block_group_nr: block_group_num as u16,
-            s_feature_compat: FeatureCompat::from_bits_truncate(0),
-            s_feature_incompat: FeatureIncompat::from_bits_truncate(0),
+            s_block_group_nr: 0,
+            s_feature_compat: FeatureCompat::EXT2_FEATURE_COMPAT_DIR_INDEX,
+            s_feature_incompat: FeatureIncompat::EXT2_FEATURE_INCOMPAT_FILETYPE,
             s_feature_ro_compat: FeatureRocompat::from_bits_truncate(0),
-            s_uuid: FAKE_UUID,
+            s_uuid: FAKE_UUID.to_le_bytes(),
             s_volume_name: [0; VOLUMN_NAME_SIZE],
             s_algo_bitmap: 0, // we don't use compression
             s_prealloc_blocks: 0,
             s_last_mounted: [0; MOUNT_SIZE],
             i_padding: [0; 3],
             s_prealloc_dir_blocks: 0,
-            p_padding: [0; 2],
-            s_journal_uuid: FAKE_JOURNAL_UUID,
+            s_reserved_gdt_blocks: 0,
+            s_journal_uuid: FAKE_JOURNAL_UUID.to_le_bytes(),
             s_journal_inum: 0,
             s_journal_dev: 0,
             s_last_orphan: 0,
-            s_hash_seed: [0; HASH_SEED_SIZE],
-            s_def_hash_version: 0,
+            s_hash_seed: FAKE_HASH_SEED,
+            s_def_hash_version: DX_HASH_HALF_MD4,
             s_default_mount_option: 0,
             s_first_meta_bg: 0,
-            reserved: [0; SB_RESERVED_SIZE]
+            s_mkfs_time: FAKE_CREATE_TIME as u32,
+            s_jnl_blocks: [0; JNL_BLOCKS_SIZE],
+            s_blocks_count_hi: 0,
+            s_r_blocks_count_hi: 0,
+            s_free_blocks_count_hi: 0,
+            s_min_extra_isize: 0,
+            s_want_extra_isize: 0,
+            s_flags: EXT2_FLAGS_SIGNED_HASH,
+            reserved: [0; 17],
+            s_checksum_type: 0,
+            reserved_2: [0; 250],
+            s_checksum_seed: 0,
+            reserved_3: [0; 392],
+            s_checksum: 0
         };
         sb.s_volume_name[..volumn_name.len()].copy_from_slice(volumn_name.as_bytes());
         sb
     }
 
     pub fn empty() -> Self {
-        Self::new(0, 0, 0, 0, 0,  "Null")
-    }
-
-    pub fn check_valid(&self) {
-        assert_eq!(self.s_magic, SB_MAGIC, "Bad magic num");
-        assert!(self.s_first_data_block == FIRST_DATA_BLOCK as u32, "Wrong first data block");
-        assert!(self.s_log_block_size == LOG_BLOCK_SIZE as u32 
-                && self.s_log_frag_size == LOG_FRAG_SIZE as u32, 
-                "Bad log block size");
-        assert!(self.s_blocks_per_group == BLOCKS_PER_GRP as u32 &&
-                self.s_frags_per_group == BLOCKS_PER_GRP as u32 &&
-                self.s_inodes_per_group == INODES_PER_GRP as u32,
-                "Bad inodes and blocks per group");
-        assert!(self.s_rev_level == EXT2_GOOD_OLD_REV as u32 &&
-                self.s_first_ino == EXT2_GOOD_OLD_FIRST_INO as u32,
-                "Bad rev level");
-        assert!(self.s_feature_incompat == FeatureIncompat::from_bits_truncate(0),
-                "Feature incompat not supported");
-        assert!(self.s_feature_ro_compat == FeatureRocompat::from_bits_truncate(0),
-                "Feature rocompat not supported");
-        assert!(self.s_state == EXT2_VALID_FS,
-                "Not a valid state");
+        Self::new(&SuperBlockParams { block_size: MIN_BLOCK_SIZE, ..SuperBlockParams::default() }, "Null")
+    }
+
+    pub fn check_valid(&self) -> Ext2Result {
+        self.check_backup()?;
+        check(self.s_state == EXT2_VALID_FS, "Not a valid state")
+    }
+
+    /// Check a backup super block, whose state mke2fs leaves unset
+    pub fn check_backup(&self) -> Ext2Result {
+        check(self.s_magic == SB_MAGIC, "Bad magic num")?;
+        check(self.s_log_block_size <= (MAX_BLOCK_SIZE / MIN_BLOCK_SIZE).trailing_zeros()
+              && self.s_log_frag_size == self.s_log_block_size,
+              "Bad log block size")?;
+        let block_size = self.block_size();
+        check(self.s_first_data_block == if block_size == 1024 { 1 } else { 0 }, "Wrong first data block")?;
+        check(self.s_blocks_per_group > 0 && self.s_blocks_per_group as usize <= 8 * block_size &&
+              self.s_frags_per_group == self.s_blocks_per_group &&
+              self.s_inodes_per_group > 0 && self.s_inodes_per_group as usize <= 8 * block_size,
+              "Bad inodes and blocks per group")?;
+        check(self.s_blocks_count > self.s_first_data_block &&
+              self.s_inodes_count as usize == self.group_count() * self.s_inodes_per_group as usize,
+              "Bad inodes and blocks count")?;
+        check(self.s_rev_level == EXT2_GOOD_OLD_REV || self.s_rev_level == EXT2_DYNAMIC_REV,
+              "Bad rev level")?;
+        check(self.inode_size().is_power_of_two() &&
+              self.inode_size() >= EXT2_GOOD_OLD_INODE_SIZE &&
+              self.inode_size() <= block_size &&
+              self.first_ino() >= EXT2_GOOD_OLD_FIRST_INO,
+              "Bad inode size or first inode")?;
+        if !(self.s_feature_incompat - SUPPORTED_INCOMPAT).is_empty() {
+            error!("Feature incompat not supported");
+            return Err(Ext2Error::Unsupported);
+        }
+        if self.has_metadata_csum() {
+            if self.s_checksum_type != EXT4_CRC32C_CHKSUM {
+                error!("Unknown checksum type {}", self.s_checksum_type);
+                return Err(Ext2Error::Unsupported);
+            }
+            check(self.s_checksum == self.checksum(), "Bad super block checksum")?;
+        }
+        Ok(())
+    }
+
+    /// Mark the file system as valid, once loaded from a backup
+    pub fn set_valid(&mut self) {
+        self.s_state = EXT2_VALID_FS;
+    }
+
+    pub fn block_size(&self) -> usize {
+        MIN_BLOCK_SIZE << self.s_log_block_size
+    }
+
+    /// Size of an inode on disk
+    pub fn inode_size(&self) -> usize {
+        if self.s_rev_level == EXT2_GOOD_OLD_REV {
+            EXT2_GOOD_OLD_INODE_SIZE
+        } else {
+            self.s_inode_size as usize
+        }
+    }
+
+    /// First inode that is not reserved
+    pub fn first_ino(&self) -> usize {
+        if self.s_rev_level == EXT2_GOOD_OLD_REV {
+            EXT2_GOOD_OLD_FIRST_INO
+        } else {
+            self.s_first_ino as usize
+        }
+    }
+
+    /// Number of block groups
+    pub fn group_count(&self) -> usize {
+        let blocks_per_group = self.s_blocks_per_group as usize;
+        ((self.s_blocks_count - self.s_first_data_block) as usize).div_ceil(blocks_per_group)
+    }
+
+    /// Whether directory entries record the file type
+    pub fn has_file_type(&self) -> bool {
+        self.s_feature_incompat.contains(FeatureIncompat::EXT2_FEATURE_INCOMPAT_FILETYPE)
+    }
+
+    /// Whether new files map their data through extent trees
+    pub fn has_extents(&self) -> bool {
+        self.s_feature_incompat.contains(FeatureIncompat::EXT3_FEATURE_INCOMPAT_EXTENTS)
+    }
+
+    /// How names are hashed in directory indexes, if they are kept up to date
+    pub fn hash_info(&self) -> Option<HashInfo> {
+        if !self.s_feature_compat.contains(FeatureCompat::EXT2_FEATURE_COMPAT_DIR_INDEX) {
+            return None;
+        }
+        Some(HashInfo {
+            def_version: self.s_def_hash_version,
+            seed: self.s_hash_seed,
+            // without a flag, Linux hashes names as signed chars on x86
+            unsigned: self.s_flags & EXT2_FLAGS_UNSIGNED_HASH != 0,
+        })
+    }
+
+    /// Whether inode 7 reserves blocks to grow the group descriptor table
+    pub fn has_resize_inode(&self) -> bool {
+        self.s_feature_compat.contains(FeatureCompat::EXT2_FEATURE_COMPAT_RESIZE_INO)
+    }
+
+    /// Blocks reserved after the group descriptor table for it to grow into
+    pub fn reserved_gdt_blocks(&self) -> usize {
+        if self.has_resize_inode() {
+            self.s_reserved_gdt_blocks as usize
+        } else {
+            0
+        }
+    }
+
+    pub fn set_reserved_gdt_blocks(&mut self, blocks: usize) {
+        self.s_reserved_gdt_blocks = blocks as u16;
+    }
+
+    /// Reserve `blocks` blocks after the group descriptor table for it to
+    /// grow into, mapped by the resize inode
+    pub fn set_resize_inode(&mut self, blocks: usize) {
+        self.s_feature_compat.insert(FeatureCompat::EXT2_FEATURE_COMPAT_RESIZE_INO);
+        self.set_reserved_gdt_blocks(blocks);
+    }
+
+    /// Number of blocks of the group descriptor table
+    pub fn gdt_blocks(&self) -> usize {
+        let block_size = self.block_size();
+        (self.group_count() * size_of::<BlockGroupDesc>()).div_ceil(block_size)
+    }
+
+    /// Number of blocks of the inode table of a group
+    pub fn inode_table_blocks(&self) -> usize {
+        let block_size = self.block_size();
+        (self.s_inodes_per_group as usize * self.inode_size()).div_ceil(block_size)
+    }
+
+    /// Whether group x starts with a copy of the super block and group
+    /// descriptors, with sparse super blocks only groups 0, 1 and powers of
+    /// 3, 5 and 7 do
+    pub fn has_super(&self, group_id: usize) -> bool {
+        !self.s_feature_ro_compat.contains(FeatureRocompat::EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER)
+            || is_sparse_group(group_id)
+    }
+
+    /// Block where the second group starts with a backup of the super
+    /// block, if the geometry of this one looks sane even when it is damaged
+    pub fn first_backup(&self) -> Option<usize> {
+        if self.s_log_block_size > (MAX_BLOCK_SIZE / MIN_BLOCK_SIZE).trailing_zeros()
+            || self.s_first_data_block != (self.s_log_block_size == 0) as u32
+            || self.s_blocks_per_group == 0
+            || self.s_blocks_per_group as usize > 8 * self.block_size()
+        {
+            return None;
+        }
+        Some(self.s_first_data_block as usize + self.s_blocks_per_group as usize)
+    }
+
+    pub fn set_sparse_super(&mut self) {
+        self.s_feature_ro_compat.insert(FeatureRocompat::EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER);
+    }
+
+    /// Whether the file system uses features that we can read but not write
+    pub fn is_read_only(&self) -> bool {
+        !(self.s_feature_ro_compat - SUPPORTED_RO_COMPAT).is_empty()
+    }
+
+    /// Number of blocks only root may allocate
+    pub fn r_blocks_count(&self) -> u32 {
+        self.s_r_blocks_count
+    }
+
+    pub fn set_r_blocks_count(&mut self, blocks: u32) {
+        self.s_r_blocks_count = blocks;
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.s_uuid
+    }
+
+    /// Volume label, without the trailing zeros
+    pub fn volume_name(&self) -> &[u8] {
+        let len = self.s_volume_name.iter().position(|b| *b == 0).unwrap_or(VOLUMN_NAME_SIZE);
+        &self.s_volume_name[..len]
+    }
+
+    /// Inode of the journal, if the file system has one
+    pub fn journal_inum(&self) -> Option<u32> {
+        if self.s_feature_compat.contains(FeatureCompat::EXT3_FEATURE_COMPAT_HAS_JOURNAL) {
+            Some(self.s_journal_inum)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_journal(&mut self, inode_id: u32) {
+        self.s_feature_compat.insert(FeatureCompat::EXT3_FEATURE_COMPAT_HAS_JOURNAL);
+        self.s_journal_inum = inode_id;
+        self.s_journal_uuid = [0; 16]; // internal journal
+    }
+
+    /// Whether the journal may contain transactions to replay
+    pub fn needs_recovery(&self) -> bool {
+        self.s_feature_incompat.contains(FeatureIncompat::EXT3_FEATURE_INCOMPAT_RECOVER)
+    }
+
+    /// Whether inodes may have extended attribute blocks
+    pub fn has_ext_attr(&self) -> bool {
+        self.s_feature_compat.contains(FeatureCompat::EXT2_FEATURE_COMPAT_EXT_ATTR)
+    }
+
+    pub fn has_large_file(&self) -> bool {
+        self.s_feature_ro_compat.contains(FeatureRocompat::EXT2_FEATURE_RO_COMPAT_LARGE_FILE)
+    }
+
+    pub fn set_ext_attr(&mut self) {
+        self.s_feature_compat.insert(FeatureCompat::EXT2_FEATURE_COMPAT_EXT_ATTR);
+    }
+
+    pub fn set_extents(&mut self) {
+        self.s_feature_incompat.insert(FeatureIncompat::EXT3_FEATURE_INCOMPAT_EXTENTS);
+    }
+
+    /// Mark the filesystem as holding files of 2 GiB or more, the flag lives
+    /// in the dynamic revision fields, so an old revision superblock is upgraded
+    pub fn set_large_file(&mut self) {
+        if self.s_rev_level == EXT2_GOOD_OLD_REV {
+            self.s_rev_level = EXT2_DYNAMIC_REV;
+            self.s_first_ino = EXT2_GOOD_OLD_FIRST_INO as u32;
+            self.s_inode_size = EXT2_GOOD_OLD_INODE_SIZE as u16;
+        }
+        self.s_feature_ro_compat.insert(FeatureRocompat::EXT2_FEATURE_RO_COMPAT_LARGE_FILE);
+    }
+
+    pub fn set_recover(&mut self, recover: bool) {
+        self.s_feature_incompat.set(FeatureIncompat::EXT3_FEATURE_INCOMPAT_RECOVER, recover);
+    }
+
+    /// First inode of the orphan list, the others are chained through `i_dtime`
+    pub fn last_orphan(&self) -> u32 {
+        self.s_last_orphan
+    }
+
+    pub fn set_last_orphan(&mut self, inode_id: u32) {
+        self.s_last_orphan = inode_id;
+    }
+
+    /// Whether metadata is checksummed, as in ext4
+    pub fn has_metadata_csum(&self) -> bool {
+        self.s_feature_ro_compat.contains(FeatureRocompat::EXT4_FEATURE_RO_COMPAT_METADATA_CSUM)
+    }
+
+    pub fn set_metadata_csum(&mut self) {
+        self.s_feature_ro_compat.insert(FeatureRocompat::EXT4_FEATURE_RO_COMPAT_METADATA_CSUM);
+        self.s_checksum_type = EXT4_CRC32C_CHKSUM;
+    }
+
+    /// Seed of metadata checksums if the super block records it, otherwise
+    /// it comes from the UUID
+    pub fn csum_seed(&self) -> Option<u32> {
+        if self.s_feature_incompat.contains(FeatureIncompat::EXT4_FEATURE_INCOMPAT_CSUM_SEED) {
+            Some(self.s_checksum_seed)
+        } else {
+            None
+        }
+    }
+
+    fn checksum(&self) -> u32 {
+        let data = unsafe {
+            core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>())
+        };
+        csum::super_block(data)
+    }
+
+    /// Update the checksum of the super block before writing it
+    pub fn seal(&mut self) {
+        if self.has_metadata_csum() {
+            self.s_checksum = self.checksum();
+        }
     }
 
 }
 
+fn check(valid: bool, msg: &str) -> Ext2Result {
+    if valid {
+        Ok(())
+    } else {
+        error!("{}", msg);
+        Err(Ext2Error::Corrupted)
+    }
+}
+
+/// Whether group x keeps a copy of the super block with sparse super
+/// blocks: groups 0, 1 and powers of 3, 5 and 7
+pub fn is_sparse_group(group_id: usize) -> bool {
+    group_id <= 1 || [3, 5, 7].iter().any(|base| {
+        let mut power = *base;
+        while power < group_id {
+            power *= base;
+        }
+        power == group_id
+    })
+}
+
 impl Debug for SuperBlock {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("SuperBlock")
@@ -394,8 +803,12 @@ impl BlockGroupDesc {
             bg_free_blocks_count: free_blocks as u16,
             bg_free_inodes_count: free_inodes as u16,
             bg_used_dirs_count: used_dirs as u16,
-            bg_pad: 0,
-            bg_reserved: [0; 12]
+            bg_flags: 0,
+            bg_exclude_bitmap: 0,
+            bg_block_bitmap_csum: 0,
+            bg_inode_bitmap_csum: 0,
+            bg_itable_unused: 0,
+            bg_checksum: 0
         }
     }
 }
@@ -425,8 +838,9 @@ impl DiskInode {
             i_direct_block: [0; DIRECT_BLOCK_NUM],
             i_double_block: 0,
             i_triple_block: 0,
+            i_quadruple_block: 0,
             i_generation: 0,
-            i_dir_acl: 0,
+            i_size_high: 0,
             i_file_acl: 0,
             i_faddr: 0,
             i_osd2: LinuxOSD::empty()
@@ -462,312 +876,418 @@ impl DiskInode {
         self.file_type() == EXT2_S_IFDIR
     }
 
-    pub fn data_blocks(&self) -> u32 {
-        self.i_blocks * 512 / BLOCK_SIZE as u32
+    /// Number of 512-byte blocks taken by the extended attribute block
+    pub fn acl_blocks(&self, block_size: usize) -> u32 {
+        if self.i_file_acl != 0 { block_size as u32 / 512 } else { 0 }
     }
 
-    fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32
+    pub fn generation(&self) -> u32 {
+        self.i_generation
+    }
+
+    /// The extended attribute block, 0 if there is none
+    pub fn xattr_block(&self) -> u32 {
+        self.i_file_acl
+    }
+
+    /// Point to another extended attribute block, or to none with 0
+    pub fn set_xattr_block(&mut self, block_id: u32, block_size: usize) {
+        self.i_blocks = self.i_blocks.saturating_sub(self.acl_blocks(block_size));
+        self.i_file_acl = block_id;
+        self.i_blocks += self.acl_blocks(block_size);
+    }
+
+    /// Whether the target of a symlink is stored in the block pointers
+    pub fn is_fast_symlink(&self, block_size: usize) -> bool {
+        self.file_type() == EXT2_S_IFLNK && self.i_blocks == self.acl_blocks(block_size)
+    }
+
+    /// Target of a fast symlink, stored in place of the block pointers
+    pub fn fast_symlink_target(&self) -> &[u8] {
+        let pointers = self.block_pointers();
+        &pointers[..(self.i_size as usize).min(pointers.len())]
+    }
+
+    /// Store the target of a fast symlink in place of the block pointers
+    pub fn set_fast_symlink_target(&mut self, target: &[u8]) {
+        assert!(self.i_size == 0 && target.len() < FAST_SYMLINK_MAX);
+        self.block_pointers_mut()[..target.len()].copy_from_slice(target);
+        self.i_size = target.len() as u32;
+    }
+
+    /// The block pointers as bytes, which hold the target of a fast symlink
+    /// or the root of an extent tree instead
+    fn block_pointers(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self.i_direct_block.as_ptr() as *const u8,
+                (DIRECT_BLOCK_NUM + 3) * size_of::<u32>()
+            )
+        }
     }
 
-    /// Return number of blocks needed include indirect1/2.
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > DIRECT_BLOCK_NUM {
-            total += 1;
+    fn block_pointers_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.i_direct_block.as_mut_ptr() as *mut u8,
+                (DIRECT_BLOCK_NUM + 3) * size_of::<u32>()
+            )
         }
-        // indirect2
-        if data_blocks > DOUBLE_BLOCK_BOUND {
-            total += 1;
-            // sub indirect1
-            total +=
-                (data_blocks - DOUBLE_BLOCK_BOUND + DOUBLE_BLOCK_NUM - 1) / DOUBLE_BLOCK_NUM;
+    }
+
+    /// Size in bytes, regular files keep its high 32 bits in `i_size_high`
+    pub fn size(&self) -> u64 {
+        if self.is_file() {
+            self.i_size as u64 | (self.i_size_high as u64) << 32
+        } else {
+            self.i_size as u64
         }
-        total as u32
     }
 
-    /// Get the number of data blocks that have to be allocated given the new size of data
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        let allocated_size = 512 * self.i_blocks;
-        if new_size <= allocated_size {
-            return 0;
+    /// Set the size, only regular files may be larger than 4 GiB
+    fn set_size(&mut self, size: u64) {
+        debug_assert!(self.is_file() || size <= u32::MAX as u64);
+        self.i_size = size as u32;
+        if self.is_file() {
+            self.i_size_high = (size >> 32) as u32;
         }
-        let new_block_num = Self::total_blocks(new_size);
-        let old_block_num = Self::total_blocks(allocated_size);
-        if old_block_num >= new_block_num {
+    }
+
+    /// Make a new inode the resize inode, whose double indirect block `dind`
+    /// lists the reserved group descriptor blocks, each of them listing its
+    /// backups. `blocks` counts them all. As mke2fs does, the size spans all
+    /// the blocks a double indirect block reaches.
+    pub fn init_resize(&mut self, dind: u32, blocks: usize, block_size: usize) {
+        let per_block = block_size / 4;
+        self.i_triple_block = dind;
+        self.i_blocks = ((1 + blocks) * block_size / 512) as u32;
+        self.set_size(((DIRECT_BLOCK_NUM + per_block + per_block * per_block) * block_size) as u64);
+    }
+
+    /// Whether the data is mapped through an extent tree
+    pub fn has_extents(&self) -> bool {
+        self.i_flags & EXT4_EXTENTS_FL != 0
+    }
+
+    /// Map the data of an empty inode through an extent tree
+    pub fn init_extents(&mut self) {
+        assert!(self.size() == 0);
+        self.i_flags |= EXT4_EXTENTS_FL;
+        extent::init(self.block_pointers_mut());
+    }
+
+    /// Root of the extent tree
+    pub fn extent_root(&self) -> &[u8] {
+        assert!(self.has_extents());
+        self.block_pointers()
+    }
+
+    /// Number of logical blocks below the size
+    pub fn data_blocks(&self, block_size: usize) -> u32 {
+        if self.is_fast_symlink(block_size) {
             0
         } else {
-            new_block_num - old_block_num
+            Self::_data_blocks(self.size(), block_size) as u32
         }
     }
 
-    /// Get id of block given inner id
-    pub fn get_block_id(&self, inner_id: u32, manager: &SpinMutex<BlockCacheManager>) -> u32 {
+    fn _data_blocks(size: u64, block_size: usize) -> u64 {
+        size.div_ceil(block_size as u64)
+    }
+
+    /// Number of 512-byte sectors in a block, the unit of `i_blocks`
+    fn block_sectors(block_size: usize) -> u32 {
+        (block_size / 512) as u32
+    }
+
+    /// Most logical blocks of a file, that the block pointers can map and
+    /// that fit with their index blocks in `i_blocks`, as Linux computes it
+    pub fn max_blocks(&self, block_size: usize) -> u32 {
+        let limit = u32::MAX as u64 / Self::block_sectors(block_size) as u64;
+        if self.has_extents() {
+            // extent trees are not counted
+            return limit as u32;
+        }
+        let per_block = double_block_num(block_size) as u64;
+        let mapped = indirect_range(INDIRECT_DEPTH, block_size).end as u64;
+        let index = 1 + (1 + per_block) + (1 + per_block + per_block * per_block);
+        if mapped + index <= limit {
+            return mapped as u32;
+        }
+        // the triple indirect tree is cut short, count the index blocks
+        // mapping `limit` blocks
+        let mut left = limit - DIRECT_BLOCK_NUM as u64 - per_block;
+        let mut index = 1;
+        if left < per_block * per_block {
+            index += 1 + left.div_ceil(per_block);
+        } else {
+            index += 1 + per_block;
+            left -= per_block * per_block;
+            index += 1 + left.div_ceil(per_block) + left.div_ceil(per_block * per_block);
+        }
+        (limit - index) as u32
+    }
+
+    /// The root of the tree of indirect blocks with `depth` levels
+    pub(crate) fn indirect_root(&self, depth: usize) -> u32 {
+        [self.i_double_block, self.i_triple_block, self.i_quadruple_block][depth - 1]
+    }
+
+    fn indirect_root_mut(&mut self, depth: usize) -> &mut u32 {
+        match depth {
+            1 => &mut self.i_double_block,
+            2 => &mut self.i_triple_block,
+            _ => &mut self.i_quadruple_block,
+        }
+    }
+
+    /// Get id of block given inner id, 0 for holes
+    pub fn get_block_id(&self, inner_id: u32, manager: &BlockCacheManager) -> Ext2Result<u32> {
         debug!("get block id of index {}", inner_id);
+        if self.has_extents() {
+            return extent::lookup(self.extent_root(), inner_id, manager);
+        }
+        let block_size = manager.block_size();
+        let per_block = double_block_num(block_size);
         let inner_id = inner_id as usize;
         if inner_id < DIRECT_BLOCK_NUM {
-            self.i_direct_block[inner_id]
-        } else if inner_id < DOUBLE_BLOCK_BOUND {
-            // get_block_cache(self.i_double_block as usize, Arc::clone(block_device))
-            //     .lock()
-            //     .read(0, |indirect_block: &IndirectBlock| {
-            //         indirect_block[inner_id - DIRECT_BLOCK_NUM]
-            //     })
-            let double_block = manager.lock().get_block_cache(self.i_double_block as _);
-            let block_id = double_block.lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                            indirect_block[inner_id - DIRECT_BLOCK_NUM]
-                });
-            block_id
-        } else {
-            let last = inner_id - DOUBLE_BLOCK_BOUND;
-            // let indirect1 = get_block_cache(self.i_triple_block as usize, Arc::clone(block_device))
-            //     .lock()
-            //     .read(0, |indirect2: &IndirectBlock| {
-            //         indirect2[last / DOUBLE_BLOCK_NUM]
-            //     });
-            let indirect1_block = manager.lock().get_block_cache(self.i_triple_block as _);
-            let indirect1 = indirect1_block.lock()
-                .read(0, |indirect2: &IndirectBlock| {
-                    indirect2[last / DOUBLE_BLOCK_NUM]
-                });
-            drop(indirect1_block);
-            // get_block_cache(indirect1 as usize, Arc::clone(block_device))
-            //     .lock()
-            //     .read(0, |indirect1: &IndirectBlock| {
-            //         indirect1[last % DOUBLE_BLOCK_NUM]
-            //     })
-            let indirect2_block = manager.lock().get_block_cache(indirect1 as _);
-            let block_id = indirect2_block.lock()
-                .read(0, |indirect1: &IndirectBlock| {
-                    indirect1[last % DOUBLE_BLOCK_NUM]
-                });
-            block_id
+            return Ok(self.i_direct_block[inner_id]);
+        }
+        if inner_id >= self.max_blocks(block_size) as usize {
+            return Ok(0);
+        }
+        let (depth, pos) = locate_indirect(inner_id, block_size);
+        let mut block_id = self.indirect_root(depth);
+        for level in (0..depth as u32).rev() {
+            block_id = read_entry(manager, block_id, pos / per_block.pow(level) % per_block)?;
         }
+        Ok(block_id)
     }
 
-    /// Inncrease the size of current disk inode
-    pub fn increase_size(
+    /// The extent holding a logical block of an inode mapped through an
+    /// extent tree, None if the block is in a hole
+    pub fn find_extent(&self, inner_id: u32, manager: &BlockCacheManager) -> Ext2Result<Option<Extent>> {
+        extent::find(self.extent_root(), inner_id, manager)
+    }
+
+    /// Grow the size, the blocks past the old end are holes until written
+    pub fn increase_size(&mut self, new_size: u64, block_size: usize) -> Ext2Result {
+        if new_size <= self.size() {
+            return Ok(());
+        }
+        if (!self.is_file() && new_size > u32::MAX as u64)
+            || Self::_data_blocks(new_size, block_size) > self.max_blocks(block_size) as u64
+        {
+            return Err(Ext2Error::FileTooLarge);
+        }
+        self.set_size(new_size);
+        Ok(())
+    }
+
+    /// Map the logical blocks from `first` on to `blocks`, as unwritten if
+    /// `unwritten`, which only extent trees can. Indirect and tree blocks
+    /// come from `alloc`. Return the blocks mapped before that are no longer
+    /// mapped.
+    pub fn map_blocks(
         &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
-        manager: &SpinMutex<BlockCacheManager>
-    ) -> Vec<u32> {
-        if new_size <= self.i_size {
-            return Vec::new();
-        }
-        if new_size <= self.i_blocks * 512 {
-            self.i_size = new_size;
-            return Vec::new();
-        }
-        
-        let mut extra_blocks: Vec<u32> = Vec::new();
-
-        let mut current_blocks = self.data_blocks();
-        self.i_size = new_size;
-        let mut total_blocks = Self::_data_blocks(new_size);
-        self.i_blocks = total_blocks * (BLOCK_SIZE/512) as u32;
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(DIRECT_BLOCK_NUM as u32) {
-            self.i_direct_block[current_blocks as usize] = new_blocks.next().unwrap();
-            extra_blocks.push(self.i_direct_block[current_blocks as usize]);
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > DIRECT_BLOCK_NUM as u32 {
-            if current_blocks == DIRECT_BLOCK_NUM as u32 {
-                self.i_double_block = new_blocks.next().unwrap();
+        first: u32,
+        blocks: &[u32],
+        unwritten: bool,
+        manager: &BlockCacheManager,
+        alloc: &mut impl FnMut(usize) -> Ext2Result<Vec<u32>>
+    ) -> Ext2Result<Vec<u32>> {
+        let block_size = manager.block_size();
+        let end = first as u64 + blocks.len() as u64;
+        if end > self.max_blocks(block_size) as u64 {
+            return Err(Ext2Error::FileTooLarge);
+        }
+        if self.has_extents() {
+            let mut extents = Vec::new();
+            for (i, block_id) in blocks.iter().enumerate() {
+                extent::push_extent(&mut extents, Extent { block: first + i as u32, len: 1, start: *block_id, unwritten });
             }
-            current_blocks -= DIRECT_BLOCK_NUM as u32;
-            total_blocks -= DIRECT_BLOCK_NUM as u32;
-        } else {
-            return extra_blocks;
-        }
-        // fill indirect1
-        // get_block_cache(self.i_double_block as usize, Arc::clone(block_device))
-        //     .lock()
-        //     .modify(0, |indirect1: &mut IndirectBlock| {
-        //         while current_blocks < total_blocks.min(DOUBLE_BLOCK_NUM as u32) {
-        //             indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-        //             current_blocks += 1;
-        //         }
-        //     });
-        let double_block = manager.lock().get_block_cache(self.i_double_block as _);
-        double_block.lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < total_blocks.min(DOUBLE_BLOCK_NUM as u32) {
-                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                    extra_blocks.push(indirect1[current_blocks as usize]);
-                    current_blocks += 1;
-                }
-            });
-        manager.lock().release_block(double_block);
-        // alloc indirect2
-        if total_blocks > DOUBLE_BLOCK_NUM as u32 {
-            if current_blocks == DOUBLE_BLOCK_NUM as u32 {
-                self.i_triple_block = new_blocks.next().unwrap();
+            let remapped = extent::remap(
+                self.block_pointers_mut(), first..end as u32, &extents, block_size, manager, alloc
+            )?;
+            self.i_blocks = (self.i_blocks as isize + remapped.delta * Self::block_sectors(block_size) as isize) as u32;
+            return Ok(remapped.freed);
+        }
+        assert!(!unwritten);
+        // the indirect blocks are all taken first, so that nothing is mapped
+        // if there are not enough
+        let range = first as usize..end as usize;
+        let mut indirect = alloc(self.indirect_needed(range.clone(), manager)?)?.into_iter();
+        let mut freed = Vec::new();
+        for (inner_id, block_id) in range.zip(blocks.iter()) {
+            match self.set_block_id(inner_id, *block_id, manager, &mut indirect)? {
+                0 => self.i_blocks += Self::block_sectors(block_size),
+                old => freed.push(old),
             }
-            current_blocks -= DOUBLE_BLOCK_NUM as u32;
-            total_blocks -= DOUBLE_BLOCK_NUM as u32;
-        } else {
-            return extra_blocks;
-        }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let a0 = current_blocks as usize / DOUBLE_BLOCK_NUM;
-        let b0 = current_blocks as usize % DOUBLE_BLOCK_NUM;
-        let a1 = total_blocks as usize / DOUBLE_BLOCK_NUM;
-        let b1 = total_blocks as usize % DOUBLE_BLOCK_NUM;
-        // alloc low-level indirect1
-        let indirect1_block = manager.lock().get_block_cache(self.i_triple_block as _);
-        indirect1_block.lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                for a in a0..=a1 {
-                    // if b0 == 0 {
-                    //     indirect2[a0] = new_blocks.next().unwrap();
-                    // }
-                    // // fill current
-                    // get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
-                    //     .lock()
-                    //     .modify(0, |indirect1: &mut IndirectBlock| {
-                    //         indirect1[b0] = new_blocks.next().unwrap();
-                    //     });
-                    // // move to next
-                    // b0 += 1;
-                    // if b0 == DOUBLE_BLOCK_NUM {
-                    //     b0 = 0;
-                    //     a0 += 1;
-                    // }
-                    let start = if a == a0 { b0 } else { 0 };
-                    let end = if a == a1 { b1 } else { DOUBLE_BLOCK_NUM };
-                    if start == 0 && end > 0 {
-                        indirect1[a] = new_blocks.next().unwrap();
-                    }
-                    let indirect2_block = manager.lock().get_block_cache(indirect1[a] as _);
-                    indirect2_block.lock()
-                        .modify(0, |indirect2: &mut IndirectBlock| {
-                            for b in start..end {
-                                indirect2[b] = new_blocks.next().unwrap();
-                                extra_blocks.push(indirect2[b]);
-                            }
-                        });
-                    manager.lock().release_block(indirect2_block);
+        }
+        Ok(freed)
+    }
 
-                }
-            });
-        manager.lock().release_block(indirect1_block);
-        return extra_blocks;
+    /// Number of indirect blocks missing to map the logical blocks in `range`
+    fn indirect_needed(&self, range: Range<usize>, manager: &BlockCacheManager) -> Ext2Result<usize> {
+        let block_size = manager.block_size();
+        let mut needed = 0;
+        for depth in 1..=INDIRECT_DEPTH {
+            let tree = indirect_range(depth, block_size);
+            let (start, end) = (range.start.max(tree.start), range.end.min(tree.end));
+            if start < end {
+                let root = self.indirect_root(depth);
+                needed += missing_indirect(manager, root, depth, start - tree.start..end - tree.start)?;
+            }
+        }
+        Ok(needed)
+    }
+
+    /// Point logical block `inner_id` to `block_id`, the indirect blocks
+    /// missing on the way are taken from `indirect`. Return the block it
+    /// pointed to.
+    fn set_block_id(
+        &mut self,
+        inner_id: usize,
+        block_id: u32,
+        manager: &BlockCacheManager,
+        indirect: &mut impl Iterator<Item = u32>
+    ) -> Ext2Result<u32> {
+        let block_size = manager.block_size();
+        let per_block = double_block_num(block_size);
+        if inner_id < DIRECT_BLOCK_NUM {
+            return Ok(core::mem::replace(&mut self.i_direct_block[inner_id], block_id));
+        }
+        let (depth, pos) = locate_indirect(inner_id, block_size);
+        let mut parent = self.indirect_root(depth);
+        if parent == 0 {
+            parent = self.take_indirect(block_size, indirect)?;
+            *self.indirect_root_mut(depth) = parent;
+        }
+        for level in (1..depth as u32).rev() {
+            let idx = pos / per_block.pow(level) % per_block;
+            let mut child = read_entry(manager, parent, idx)?;
+            if child == 0 {
+                child = self.take_indirect(block_size, indirect)?;
+                write_entry(manager, parent, idx, child)?;
+            }
+            parent = child;
+        }
+        write_entry(manager, parent, pos % per_block, block_id)
+    }
+
+    /// Take a zeroed indirect block to use, `Corrupted` if more are missing
+    /// than were counted, which a tree sharing blocks with others can do
+    fn take_indirect(&mut self, block_size: usize, indirect: &mut impl Iterator<Item = u32>) -> Ext2Result<u32> {
+        let block_id = indirect.next().ok_or_else(|| {
+            error!("Indirect blocks changed while they were mapped");
+            Ext2Error::Corrupted
+        })?;
+        self.i_blocks += Self::block_sectors(block_size);
+        Ok(block_id)
+    }
+
+    /// Unmap the logical blocks in `range`, leaving holes. New tree blocks
+    /// come from `alloc`. Return the blocks freed, with the indirect and tree
+    /// blocks no longer needed.
+    pub fn unmap_blocks(
+        &mut self,
+        range: Range<u32>,
+        manager: &BlockCacheManager,
+        alloc: &mut impl FnMut(usize) -> Ext2Result<Vec<u32>>
+    ) -> Ext2Result<Vec<u32>> {
+        let block_size = manager.block_size();
+        if range.is_empty() || self.is_fast_symlink(block_size) {
+            return Ok(Vec::new());
+        }
+        if self.has_extents() {
+            let remapped = extent::remap(self.block_pointers_mut(), range, &[], block_size, manager, alloc)?;
+            self.i_blocks = (self.i_blocks as isize + remapped.delta * Self::block_sectors(block_size) as isize) as u32;
+            return Ok(remapped.freed);
+        }
+        let freed = self.unmap_indirect(range.start as usize..range.end as usize, manager)?;
+        // a corrupted count must not wrap
+        self.i_blocks = self.i_blocks.saturating_sub(freed.len() as u32 * Self::block_sectors(block_size));
+        Ok(freed)
+    }
+
+    /// Clear the block pointers to the logical blocks in `range`, return the
+    /// blocks they pointed to and the indirect blocks left empty
+    fn unmap_indirect(&mut self, range: Range<usize>, manager: &BlockCacheManager) -> Ext2Result<Vec<u32>> {
+        let block_size = manager.block_size();
+        let end = range.end.min(self.max_blocks(block_size) as usize);
+        let mut freed = Vec::new();
+        for block_id in self.i_direct_block.iter_mut().take(end).skip(range.start) {
+            if *block_id != 0 {
+                freed.push(*block_id);
+                *block_id = 0;
+            }
+        }
+        for depth in 1..=INDIRECT_DEPTH {
+            let tree = indirect_range(depth, block_size);
+            let (start, stop) = (range.start.max(tree.start), end.min(tree.end));
+            let root = self.indirect_root(depth);
+            if start < stop
+                && root != 0
+                && clear_indirect(manager, root, depth, start - tree.start..stop - tree.start, &mut freed)?
+            {
+                freed.push(root);
+                *self.indirect_root_mut(depth) = 0;
+            }
+        }
+        Ok(freed)
     }
 
     /// Clear size to zero and return blocks that should be deallocated.
     /// We will clear the block contents to zero later.
-    pub fn clear_size(&mut self, manager: &SpinMutex<BlockCacheManager>) -> Vec<u32> {
+    pub fn clear_size(&mut self, manager: &BlockCacheManager) -> Ext2Result<Vec<u32>> {
         self.decrease_size(0, manager)
     }
 
-    /// Get all data blocks of current inode
-    pub fn all_data_blocks(&self, manager: &SpinMutex<BlockCacheManager>, include_index: bool) -> Vec<u32> {
-        let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        // debug!("all_data_blocks called on {} blocks", data_blocks);
-        // self.size = 0;
-        let mut current_blocks = 0usize;
-        // direct
-        while current_blocks < data_blocks.min(DIRECT_BLOCK_NUM) {
-            v.push(self.i_direct_block[current_blocks]);
-            // self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // debug!("after direct: {}", v.len());
-        // indirect1 block
-        if data_blocks > DIRECT_BLOCK_NUM {
-            if include_index {
-                v.push(self.i_double_block);
+    /// Get the written extents of the logical blocks below the size, in
+    /// order, and the indirect or tree blocks
+    pub fn mapped_extents(&self, manager: &BlockCacheManager) -> Ext2Result<(Vec<Extent>, Vec<u32>)> {
+        let block_size = manager.block_size();
+        let blocks = self.data_blocks(block_size);
+        if self.has_extents() {
+            return extent::mapped(self.extent_root(), blocks, manager);
+        }
+        let mut data = Vec::new();
+        let mut index = Vec::new();
+        for (block, block_id) in self.i_direct_block.iter().enumerate().take(blocks as usize) {
+            if *block_id != 0 {
+                extent::push_extent(&mut data, Extent { block: block as u32, len: 1, start: *block_id, unwritten: false });
             }
-            data_blocks -= DIRECT_BLOCK_NUM;
-            current_blocks = 0;
-        } else {
-            return v;
-        }
-        // indirect1
-        let double_block = manager.lock().get_block_cache(self.i_double_block as _);
-        double_block.lock()
-            .read(0, |indirect1: &IndirectBlock| {
-                while current_blocks < data_blocks.min(DOUBLE_BLOCK_NUM) {
-                    v.push(indirect1[current_blocks]);
-                    current_blocks += 1;
-                }
-            });
-        manager.lock().release_block(double_block);
-        // debug!("after double block: {}", v.len());
-        // indirect2 block
-        if data_blocks > DOUBLE_BLOCK_NUM {
-            if include_index {
-                v.push(self.i_triple_block);
+        }
+        for depth in 1..=INDIRECT_DEPTH {
+            let first = indirect_range(depth, block_size).start;
+            let root = self.indirect_root(depth);
+            if (blocks as usize) > first && root != 0 {
+                collect_indirect(manager, root, depth, first..blocks as usize, &mut data, &mut index)?;
             }
-            data_blocks -= DOUBLE_BLOCK_NUM;
-        } else {
-            return v;
-        }
-        // indirect2
-        assert!(data_blocks <= TRIPLE_BLOCK_NUM);
-        let a1 = data_blocks / DOUBLE_BLOCK_NUM;
-        let b1 = data_blocks % DOUBLE_BLOCK_NUM;
-        let indirect1_block = manager.lock().get_block_cache(self.i_triple_block as _);
-        indirect1_block.lock()
-            .read(0, |indirect2: &IndirectBlock| {
-                // full indirect1 blocks
-                for entry in indirect2.iter().take(a1) {
-                    if include_index {
-                        v.push(*entry);
-                    }
-                    let indirect2_block = manager.lock().get_block_cache(*entry as _);
-                    indirect2_block.lock()
-                        .read(0, |indirect1: &IndirectBlock| {
-                            for entry in indirect1.iter() {
-                                v.push(*entry);
-                            }
-                        });
-                    manager.lock().release_block(indirect2_block);
-                }
-                // last indirect1 block
-                if b1 > 0 {
-                    if include_index {
-                        v.push(indirect2[a1]);
-                    }
-                    let indirect2_block = manager.lock().get_block_cache(indirect2[a1] as _);
-                    indirect2_block.lock()
-                        .read(0, |indirect1: &IndirectBlock| {
-                            for entry in indirect1.iter().take(b1) {
-                                v.push(*entry);
-                            }
-                        });
-                    manager.lock().release_block(indirect2_block);
-                }
-            });
-        manager.lock().release_block(indirect1_block);
-        v
+        }
+        Ok((data, index))
     }
 
-    /// Decrease size
-    pub fn decrease_size(&mut self, new_size: u32, manager: &SpinMutex<BlockCacheManager>) -> Vec<u32> {
-        // debug!("decrease size from {} to {}", self.i_size, new_size);
-        if new_size >= self.i_size {
-            return Vec::new();
+    /// Shrink the size, unmapping the blocks past the new end, allocated
+    /// beyond the old one too, and return the blocks freed
+    pub fn decrease_size(&mut self, new_size: u64, manager: &BlockCacheManager) -> Ext2Result<Vec<u32>> {
+        if new_size > self.size() {
+            return Ok(Vec::new());
         }
-        if Self::total_blocks(new_size) >= Self::total_blocks(self.i_blocks * 512) {
-            self.i_size = new_size;
-            return Vec::new();
+        let block_size = manager.block_size();
+        if self.is_fast_symlink(block_size) {
+            self.set_size(new_size);
+            return Ok(Vec::new());
         }
-        let mut all_blocks = self.all_data_blocks(manager, true);
-        self.i_size = new_size;
-        self.i_blocks = Self::_data_blocks(new_size) * BLOCK_SIZE as u32 / 512;
-        let remain_block_num = Self::total_blocks(new_size);
-        all_blocks.drain(0..remain_block_num as usize);
-        
-        all_blocks
+        let first = Self::_data_blocks(new_size, block_size);
+        let freed = if self.has_extents() {
+            extent::truncate(self.block_pointers_mut(), first as u32, manager)?
+        } else {
+            self.unmap_indirect(first as usize..self.max_blocks(block_size) as usize, manager)?
+        };
+        self.set_size(new_size);
+        self.i_blocks = self.i_blocks.saturating_sub(freed.len() as u32 * Self::block_sectors(block_size));
+        Ok(freed)
     }
 
     /// Read data from current disk inode
@@ -775,35 +1295,41 @@ impl DiskInode {
         &self,
         offset: usize,
         buf: &mut [u8],
-        manager: &SpinMutex<BlockCacheManager>,
-        cache: Option<&Vec<u32>>
-    ) -> usize {
+        manager: &BlockCacheManager,
+        cache: Option<&BlockMap>
+    ) -> Ext2Result<usize> {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.i_size as usize);
+        let end = (offset + buf.len()).min(self.size() as usize);
         if start >= end {
-            return 0;
+            return Ok(0);
         }
-        let mut start_block = start / BLOCK_SIZE;
+        let block_size = manager.block_size();
+        let mut start_block = start / block_size;
         let mut read_size = 0usize;
         loop {
             // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SIZE + 1) * BLOCK_SIZE;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
             let block_id = if let Some(blocks) = cache.as_ref() {
-                blocks[start_block]
+                blocks.block_id(start_block)
             } else {
-                self.get_block_id(start_block as _, manager)
+                self.get_block_id(start_block as _, manager)?
             };
-            let data_block = manager.lock().get_block_cache(block_id as _);
-            data_block.lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SIZE..start % BLOCK_SIZE + block_read_size];
-                dst.copy_from_slice(src);
-            });
-            manager.lock().release_block(data_block);
+            if block_id == 0 {
+                // a hole, or an unwritten extent
+                dst.fill(0);
+            } else {
+                let data_block = manager.get_block_cache(block_id as _)?;
+                data_block.lock()
+                .read_slice(|data_block: &DataBlock| {
+                    let src = &data_block[start % block_size..start % block_size + block_read_size];
+                    dst.copy_from_slice(src);
+                });
+                manager.release_block(data_block);
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end {
@@ -812,41 +1338,51 @@ impl DiskInode {
             start_block += 1;
             start = end_current_block;
         }
-        read_size
+        Ok(read_size)
     }
     /// Write data into current disk inode
     /// size must be adjusted properly beforehand
     pub fn write_at(
-        &mut self,
+        &self,
         offset: usize,
         buf: &[u8],
-        manager: &SpinMutex<BlockCacheManager>,
-        cache: Option<&Vec<u32>>
-    ) -> usize {
+        manager: &BlockCacheManager,
+        cache: Option<&BlockMap>
+    ) -> Ext2Result<usize> {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.i_size as usize);
+        let end = (offset + buf.len()).min(self.size() as usize);
         assert!(start <= end);
-        let mut start_block = start / BLOCK_SIZE;
+        let block_size = manager.block_size();
+        let mut start_block = start / block_size;
         let mut write_size = 0usize;
         loop {
             // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SIZE + 1) * BLOCK_SIZE;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
             let block_id = if let Some(blocks) = cache.as_ref() {
-                blocks[start_block]
+                blocks.block_id(start_block)
             } else {
-                self.get_block_id(start_block as _, manager)
+                self.get_block_id(start_block as _, manager)?
             };
-            let data_block = manager.lock().get_block_cache(block_id as _);
-            data_block.lock()
-            .modify(0, |data_block: &mut DataBlock| {
+            if block_id == 0 {
+                error!("Write to a hole at block {}", start_block);
+                return Err(Ext2Error::Corrupted);
+            }
+            let data_block = manager.get_block_cache(block_id as _)?;
+            let copy = |data_block: &mut DataBlock| {
                 let src = &buf[write_size..write_size + block_write_size];
-                let dst = &mut data_block[start % BLOCK_SIZE..start % BLOCK_SIZE + block_write_size];
+                let dst = &mut data_block[start % block_size..start % block_size + block_write_size];
                 dst.copy_from_slice(src);
-            });
-            manager.lock().release_block(data_block);
+            };
+            // contents of regular files are not journaled
+            if self.is_file() {
+                data_block.lock().modify_data_slice(copy);
+            } else {
+                data_block.lock().modify_slice(copy);
+            }
+            manager.release_block(data_block);
             write_size += block_write_size;
             // move to next block
             if end_current_block == end {
@@ -855,15 +1391,176 @@ impl DiskInode {
             start_block += 1;
             start = end_current_block;
         }
-        write_size
+        Ok(write_size)
+    }
+}
+
+
+/// The depth of the tree of indirect blocks mapping logical block
+/// `inner_id`, past the direct ones, and the position of the block in it
+fn locate_indirect(inner_id: usize, block_size: usize) -> (usize, usize) {
+    let depth = (1..INDIRECT_DEPTH)
+        .find(|depth| inner_id < indirect_range(*depth, block_size).end)
+        .unwrap_or(INDIRECT_DEPTH);
+    (depth, inner_id - indirect_range(depth, block_size).start)
+}
+
+/// Number of indirect blocks missing in the tree with `depth` levels under
+/// `block_id`, 0 if there is none yet, to map the entries in `range` of it
+fn missing_indirect(
+    manager: &BlockCacheManager,
+    block_id: u32,
+    depth: usize,
+    range: Range<usize>
+) -> Ext2Result<usize> {
+    let missing = (block_id == 0) as usize;
+    if depth == 1 {
+        return Ok(missing);
+    }
+    let span = double_block_num(manager.block_size()).pow(depth as u32 - 1);
+    let entries = if block_id == 0 { None } else { Some(read_indirect(manager, block_id)?) };
+    let mut needed = missing;
+    for idx in range.start / span..range.end.div_ceil(span) {
+        let first = idx * span;
+        let entry = entries.as_ref().map_or(0, |entries| entries[idx]);
+        let sub = range.start.max(first) - first..range.end.min(first + span) - first;
+        needed += missing_indirect(manager, entry, depth - 1, sub)?;
+    }
+    Ok(needed)
+}
+
+/// Clear the entries in `range` of the tree with `depth` levels under
+/// `block_id`, add the blocks they pointed to and the indirect blocks left
+/// empty to `freed`. Return whether `block_id` is left empty.
+fn clear_indirect(
+    manager: &BlockCacheManager,
+    block_id: u32,
+    depth: usize,
+    range: Range<usize>,
+    freed: &mut Vec<u32>
+) -> Ext2Result<bool> {
+    if depth == 1 {
+        return clear_entries(manager, block_id, range, freed);
+    }
+    let span = double_block_num(manager.block_size()).pow(depth as u32 - 1);
+    let mut entries = read_indirect(manager, block_id)?;
+    let mut emptied = Vec::new();
+    for (idx, entry) in entries.iter().enumerate()
+        .take(range.end.div_ceil(span))
+        .skip(range.start / span)
+    {
+        let first = idx * span;
+        let sub = range.start.max(first) - first..range.end.min(first + span) - first;
+        if *entry != 0 && clear_indirect(manager, *entry, depth - 1, sub, freed)? {
+            freed.push(*entry);
+            emptied.push(idx);
+        }
+    }
+    for idx in emptied.iter() {
+        entries[*idx] = 0;
+    }
+    let empty = entries.iter().all(|entry| *entry == 0);
+    if !empty && !emptied.is_empty() {
+        let indirect_block = manager.get_block_cache(block_id as _)?;
+        indirect_block.lock()
+            .modify_slice(|entries: &mut IndirectBlock| {
+                for idx in emptied {
+                    entries[idx] = 0;
+                }
+            });
+        manager.release_block(indirect_block);
+    }
+    Ok(empty)
+}
+
+/// Add the blocks the tree with `depth` levels under `block_id` maps, from
+/// `blocks.start` on, to `data` up to `blocks.end`, and its indirect blocks
+/// to `index`
+fn collect_indirect(
+    manager: &BlockCacheManager,
+    block_id: u32,
+    depth: usize,
+    blocks: Range<usize>,
+    data: &mut Vec<Extent>,
+    index: &mut Vec<u32>
+) -> Ext2Result {
+    index.push(block_id);
+    let entries = read_indirect(manager, block_id)?;
+    let span = entries.len().pow(depth as u32 - 1);
+    for (idx, entry) in entries.into_iter().enumerate() {
+        let start = blocks.start + idx * span;
+        if start >= blocks.end {
+            break;
+        }
+        if entry == 0 {
+            continue;
+        }
+        if depth == 1 {
+            extent::push_extent(data, Extent { block: start as u32, len: 1, start: entry, unwritten: false });
+        } else {
+            collect_indirect(manager, entry, depth - 1, start..blocks.end, data, index)?;
+        }
     }
+    Ok(())
 }
 
+/// Read the entries of an indirect block
+fn read_indirect(manager: &BlockCacheManager, block_id: u32) -> Ext2Result<Vec<u32>> {
+    let indirect_block = manager.get_block_cache(block_id as _)?;
+    let entries = indirect_block.lock()
+        .read_slice(|entries: &IndirectBlock| entries.to_vec());
+    manager.release_block(indirect_block);
+    Ok(entries)
+}
+
+/// Entry `idx` of an indirect block, 0 if the block is a hole as well
+fn read_entry(manager: &BlockCacheManager, block_id: u32, idx: usize) -> Ext2Result<u32> {
+    if block_id == 0 {
+        return Ok(0);
+    }
+    let indirect_block = manager.get_block_cache(block_id as _)?;
+    let entry = indirect_block.lock()
+        .read_slice(|entries: &IndirectBlock| entries[idx]);
+    manager.release_block(indirect_block);
+    Ok(entry)
+}
+
+/// Set entry `idx` of an indirect block, return what it was
+fn write_entry(manager: &BlockCacheManager, block_id: u32, idx: usize, value: u32) -> Ext2Result<u32> {
+    let indirect_block = manager.get_block_cache(block_id as _)?;
+    let old = indirect_block.lock()
+        .modify_slice(|entries: &mut IndirectBlock| core::mem::replace(&mut entries[idx], value));
+    manager.release_block(indirect_block);
+    Ok(old)
+}
+
+/// Clear the entries in `range` of an indirect block, pushing the blocks they
+/// pointed to to `freed`. Return whether the block is left empty, then it is
+/// not written as it is to be freed.
+fn clear_entries(
+    manager: &BlockCacheManager,
+    block_id: u32,
+    range: Range<usize>,
+    freed: &mut Vec<u32>
+) -> Ext2Result<bool> {
+    let mut entries = read_indirect(manager, block_id)?;
+    let cleared = freed.len();
+    freed.extend(entries[range.clone()].iter().filter(|entry| **entry != 0));
+    entries[range.clone()].fill(0);
+    let empty = entries.iter().all(|entry| *entry == 0);
+    if !empty && freed.len() > cleared {
+        let indirect_block = manager.get_block_cache(block_id as _)?;
+        indirect_block.lock()
+            .modify_slice(|entries: &mut IndirectBlock| entries[range].fill(0));
+        manager.release_block(indirect_block);
+    }
+    Ok(empty)
+}
 
 impl DirEntryHead {
     pub fn create(inode: usize, name: &str, file_type: u8) -> DirEntryHead {
         let name_len = name.as_bytes().len().min(MAX_NAME_LEN);
-        let rec_len = size_of::<DirEntryHead>() + name_len;
+        let rec_len = Self::rec_len_of(name_len);
 
         DirEntryHead {
             inode: inode as u32,
@@ -877,6 +1574,41 @@ impl DirEntryHead {
         DirEntryHead { inode: 0, rec_len: 0, name_len: 0, file_type: 0 }
     }
 
+    /// Space taken by an entry with a name of `name_len` bytes, entries are 4-byte aligned
+    pub const fn rec_len_of(name_len: usize) -> usize {
+        (size_of::<DirEntryHead>() + name_len + 3) & !3
+    }
+
+    /// Call `f` over the entries of a directory block with their offsets and names,
+    /// until it returns something. Fails with the offset of a malformed entry.
+    pub fn walk_block<V>(
+        data_block: &[u8],
+        mut f: impl FnMut(usize, &DirEntryHead, &[u8]) -> Option<V>
+    ) -> core::result::Result<Option<V>, usize> {
+        let block_size = data_block.len();
+        let mut offset = 0;
+        while offset + size_of::<DirEntryHead>() <= block_size {
+            let head = Self::from_bytes(&data_block[offset..]);
+            let rec_len = head.rec_len as usize;
+            let name_end = offset + size_of::<DirEntryHead>() + head.name_len as usize;
+            if rec_len < size_of::<DirEntryHead>() || offset + rec_len > block_size || name_end > offset + rec_len {
+                return Err(offset);
+            }
+            let name = &data_block[offset + size_of::<DirEntryHead>()..name_end];
+            if let Some(v) = f(offset, &head, name) {
+                return Ok(Some(v));
+            }
+            offset += rec_len;
+        }
+        Ok(None)
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        assert!(bytes.len() >= size_of::<DirEntryHead>());
+        unsafe { (bytes.as_ptr() as *const DirEntryHead).read_unaligned() }
+    }
+
     /// Serialize into bytes
     pub fn as_bytes(&self) -> &[u8] {
         unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, size_of::<DirEntryHead>()) }
@@ -1,24 +1,40 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(allocator_api)]
 #![feature(new_uninit)]
 extern crate alloc;
 mod layout;
+mod error;
 mod config;
 mod block_dev;
 mod bitmap;
 mod efs;
 mod vfs;
+mod htree;
+mod xattr;
+mod extent;
+mod csum;
 mod timer;
 mod block_cache_manager;
+mod journal;
 mod inode_manager;
+mod readahead;
+mod block_map;
 mod mutex;
+mod policy;
+pub mod fsck;
+#[cfg(test)]
+mod tests;
 
 pub use block_dev::BlockDevice;
-pub use efs::Ext2FileSystem;
+pub use block_cache_manager::CacheStats;
+pub use error::{Ext2Error, Ext2Result};
+pub use efs::{Ext2FileSystem, CreateOptions, FsStats, WritebackPolicy};
 pub use vfs::Inode;
 use vfs::InodeCache;
 pub use timer::{TimeProvider, ZeroTimeProvider};
-pub use config::{BLOCK_SIZE, BLOCKS_PER_GRP};
-pub use layout::{EXT2_S_IFREG, EXT2_S_IFDIR};
+pub use mutex::set_yield_now;
+pub use policy::{AllocPolicy, GroupUsage, LocalityPolicy, FirstFitPolicy};
+pub use config::DEFAULT_BLOCK_SIZE;
+pub use layout::{EXT2_S_IFREG, EXT2_S_IFDIR, EXT2_FT_REG_FILE, EXT2_FT_DIR, EXT2_FT_SYMLINK, IMODE, FallocMode};
 use bitmap::Bitmap;
 use layout::{SuperBlock, DiskInode, BlockGroupDesc};
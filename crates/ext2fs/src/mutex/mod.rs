@@ -1,5 +1,42 @@
 #![allow(unused)]
-use fs_utils::sync::{self, Spin};
+use fs_utils::sync::{self, MutexSupport, Spin};
+use spin::Once;
 
 pub type RwSpinMutex<T> = sync::rw_spin_mutex::RwSpinMutex<T, Spin>;
-pub type SpinMutex<T> = sync::spin_mutex::SpinMutex<T, Spin>;
\ No newline at end of file
+pub type SpinMutex<T> = sync::spin_mutex::SpinMutex<T, Spin>;
+
+/// A lock that may be held across disk I/O and other locks, waiters give up the CPU
+pub type SleepMutex<T> = sync::spin_mutex::SpinMutex<T, Sleep>;
+/// A readers-writer lock that may be held across disk I/O and other locks
+pub type SleepRwMutex<T> = sync::rw_spin_mutex::RwSpinMutex<T, Sleep>;
+
+static YIELD_NOW: Once<fn()> = Once::new();
+
+/// Set how a task waiting for a sleep lock gives up the CPU, it spins until this is set.
+/// Only the first call has an effect.
+pub fn set_yield_now(yield_now: fn()) {
+    YIELD_NOW.call_once(|| yield_now);
+}
+
+/// Give up the CPU while waiting for something another task does
+pub fn yield_now() {
+    match YIELD_NOW.get() {
+        Some(yield_now) => yield_now(),
+        None => core::hint::spin_loop(),
+    }
+}
+
+/// Waits by yielding to other tasks, see [`set_yield_now`]
+pub struct Sleep;
+
+impl MutexSupport for Sleep {
+    type GuardData = ();
+    #[inline(always)]
+    fn before_lock() -> Self::GuardData {}
+    #[inline(always)]
+    fn after_unlock(_: &mut Self::GuardData) {}
+    #[inline(always)]
+    fn relax() {
+        yield_now();
+    }
+}
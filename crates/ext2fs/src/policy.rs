@@ -0,0 +1,146 @@
+//! Allocation policies, deciding where new inodes and data blocks go.
+//!
+//! The file system asks its policy for the group of a new inode and for the
+//! group where the data of a file starts, then looks for free bits from
+//! there. A file that appends blocks may also keep a preallocation window:
+//! free blocks past its last ones that other files leave alone as long as
+//! they find room elsewhere. Windows live in memory only, the bitmaps never
+//! see them.
+
+/// Usage of a block group, as seen when placing a new inode
+#[derive(Clone, Copy, Debug)]
+pub struct GroupUsage {
+    /// Number of inodes of the group
+    pub inodes: u32,
+    /// Number of blocks of the group
+    pub blocks: u32,
+    pub free_inodes: u32,
+    pub free_blocks: u32,
+    /// Number of directories in the group
+    pub used_dirs: u32
+}
+
+/// Decides where new inodes and data blocks go, see
+/// [`Ext2FileSystem::set_alloc_policy`]. The file system still falls back to
+/// any group with room when the one chosen is full.
+///
+/// [`Ext2FileSystem::set_alloc_policy`]: crate::Ext2FileSystem::set_alloc_policy
+pub trait AllocPolicy: Send + Sync {
+    /// Group where a new inode is looked for first, the others being tried
+    /// in order after it. `parent` is the group of its directory, which is
+    /// the root directory if `top_level`.
+    fn inode_group(&self, groups: &[GroupUsage], parent: usize, is_dir: bool, top_level: bool) -> usize;
+
+    /// Group where the data of an inode of group `inode_group` starts while
+    /// it has no block to follow, or `None` for the first free blocks
+    fn data_group(&self, groups: &[GroupUsage], inode_group: usize) -> Option<usize>;
+
+    /// Free blocks kept in memory for a file past the ones it allocates, so
+    /// that its next appends stay contiguous even if other files allocate
+    /// in between. `used_up` is the size of its previous window if it
+    /// filled it, 0 otherwise. No window is kept if 0.
+    fn prealloc_window(&self, used_up: usize) -> usize;
+}
+
+/// The first free inode and blocks of the file system, regardless of locality
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirstFitPolicy;
+
+impl AllocPolicy for FirstFitPolicy {
+    fn inode_group(&self, _groups: &[GroupUsage], _parent: usize, _is_dir: bool, _top_level: bool) -> usize {
+        0
+    }
+
+    fn data_group(&self, _groups: &[GroupUsage], _inode_group: usize) -> Option<usize> {
+        None
+    }
+
+    fn prealloc_window(&self, _used_up: usize) -> usize {
+        0
+    }
+}
+
+/// Keeps files near their directory and their data in their inode's group,
+/// and spreads directories across groups as the Orlov allocator of Linux
+/// does: directories in the root go to the emptiest groups, and other
+/// directories stay near their parent unless its group is crowded.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalityPolicy {
+    /// Size of the first preallocation window of a file
+    pub prealloc_window: usize,
+    /// A window used up is doubled up to this size
+    pub max_prealloc_window: usize
+}
+
+impl Default for LocalityPolicy {
+    fn default() -> Self {
+        Self {
+            prealloc_window: 8,
+            max_prealloc_window: 1024
+        }
+    }
+}
+
+impl AllocPolicy for LocalityPolicy {
+    fn inode_group(&self, groups: &[GroupUsage], parent: usize, is_dir: bool, top_level: bool) -> usize {
+        let group_num = groups.len();
+        let avg_free_inodes = groups.iter().map(|group| group.free_inodes as usize).sum::<usize>() / group_num;
+        let avg_free_blocks = groups.iter().map(|group| group.free_blocks as usize).sum::<usize>() / group_num;
+        // starting from `first`, wrapping around
+        let from = |first: usize| (0..group_num).map(move |i| (first + i) % group_num);
+
+        if is_dir && top_level {
+            // the group with the fewest directories among those with more
+            // free inodes and blocks than average
+            return from(parent + 1)
+                .filter(|group_id| {
+                    let group = &groups[*group_id];
+                    group.free_inodes as usize >= avg_free_inodes.max(1)
+                        && group.free_blocks as usize >= avg_free_blocks
+                })
+                .min_by_key(|group_id| groups[*group_id].used_dirs)
+                .unwrap_or(parent);
+        }
+        if is_dir {
+            // the first group from the parent's that neither holds too many
+            // directories nor has too few free inodes or blocks
+            let avg_dirs = groups.iter().map(|group| group.used_dirs as usize).sum::<usize>() / group_num;
+            return from(parent)
+                .find(|group_id| {
+                    let group = &groups[*group_id];
+                    let max_dirs = avg_dirs + group.inodes as usize / 16;
+                    let min_inodes = avg_free_inodes.saturating_sub(group.inodes as usize / 4).max(1);
+                    let min_blocks = avg_free_blocks.saturating_sub(group.blocks as usize / 4);
+                    (group.used_dirs as usize) < max_dirs
+                        && group.free_inodes as usize >= min_inodes
+                        && group.free_blocks as usize >= min_blocks
+                })
+                .unwrap_or(parent);
+        }
+        // files stay with their directory while its group has room, else
+        // the groups are probed at growing distances
+        let has_room = |group_id: usize| groups[group_id].free_inodes > 0 && groups[group_id].free_blocks > 0;
+        let mut group_id = parent;
+        let mut step = 1;
+        while step < group_num * 2 {
+            if has_room(group_id) {
+                return group_id;
+            }
+            group_id = (group_id + step) % group_num;
+            step *= 2;
+        }
+        parent
+    }
+
+    fn data_group(&self, _groups: &[GroupUsage], inode_group: usize) -> Option<usize> {
+        Some(inode_group)
+    }
+
+    fn prealloc_window(&self, used_up: usize) -> usize {
+        if used_up == 0 {
+            self.prealloc_window
+        } else {
+            (used_up * 2).min(self.max_prealloc_window)
+        }
+    }
+}
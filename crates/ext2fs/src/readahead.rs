@@ -0,0 +1,42 @@
+use core::ops::Range;
+
+/// Blocks read ahead once a file is found to be read sequentially
+const MIN_WINDOW: usize = 4;
+/// Most blocks read ahead at once, the window doubles up to it
+const MAX_WINDOW: usize = 16;
+
+/// Sequential access detection of an open file, in the spirit of Linux's
+/// `file_ra_state`: reads that go on where the last one ended read ahead a
+/// window of blocks, which grows while they stay sequential.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadAhead {
+    /// Where a sequential read would start
+    next_offset: usize,
+    /// Blocks read ahead last time, 0 while reads are random
+    window: usize,
+    /// End of the blocks read ahead so far
+    ahead: usize,
+}
+
+impl ReadAhead {
+    /// Record a read of `len` bytes at `offset`, return the logical blocks to
+    /// load for it: those it reads, and those after them to read ahead
+    pub fn on_read(&mut self, offset: usize, len: usize, block_size: usize) -> Range<usize> {
+        let start = offset / block_size;
+        let end = if len == 0 { start } else { (offset + len - 1) / block_size + 1 };
+        let sequential = offset == self.next_offset;
+        self.next_offset = offset + len;
+        if !sequential {
+            self.window = 0;
+            self.ahead = 0;
+            return start..end;
+        }
+        // read ahead again once the reader gets within half a window of the end
+        if self.ahead > end + self.window / 2 {
+            return start..end;
+        }
+        self.window = if self.window == 0 { MIN_WINDOW } else { (self.window * 2).min(MAX_WINDOW) };
+        self.ahead = end + self.window;
+        start..self.ahead
+    }
+}
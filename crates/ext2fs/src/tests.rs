@@ -0,0 +1,2044 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{EXT2_GOOD_OLD_FIRST_INO, SUPER_BLOCK_OFFSET};
+use crate::extent::{self, TreeItem};
+use crate::htree::{self, DxRoot};
+use crate::block_dev::FsBlockDevice;
+use crate::journal::Journal;
+use crate::layout::{EXT2_INDEX_FL, EXT2_RESIZE_INO, EXT3_JOURNAL_INO};
+use crate::*;
+
+/// Number of file system blocks, which gets a journal of 1024 blocks
+const BLOCK_NUM: usize = 4096;
+const SECTOR_SIZE: usize = 512;
+
+/// Images built by Linux mke2fs, see `testdata/mkimages.sh`
+const IMAGE_1K: &[u8] = include_bytes!("../testdata/ext2_1k.img");
+const IMAGE_4K: &[u8] = include_bytes!("../testdata/ext2_4k.img");
+const IMAGE_EXTENTS: &[u8] = include_bytes!("../testdata/ext2_extents.img");
+const IMAGE_CSUM: &[u8] = include_bytes!("../testdata/ext2_csum.img");
+
+/// A disk of 512-byte sectors that loses power after `budget` writes: later
+/// writes are still seen by the running file system, but never reach the
+/// persistent image.
+struct CrashDisk {
+    live: Mutex<Vec<u8>>,
+    persisted: Mutex<Vec<u8>>,
+    budget: usize,
+    writes: AtomicUsize,
+}
+
+impl CrashDisk {
+    fn new(image: Vec<u8>, budget: usize) -> Arc<Self> {
+        Arc::new(Self {
+            live: Mutex::new(image.clone()),
+            persisted: Mutex::new(image),
+            budget,
+            writes: AtomicUsize::new(0),
+        })
+    }
+
+    fn image(&self) -> Vec<u8> {
+        self.persisted.lock().unwrap().clone()
+    }
+
+    /// Detach the disk and return its persistent image, the file system
+    /// still holding it can no longer access it
+    fn power_off(&self) -> Vec<u8> {
+        *self.live.lock().unwrap() = Vec::new();
+        core::mem::take(&mut *self.persisted.lock().unwrap())
+    }
+}
+
+impl BlockDevice for CrashDisk {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        let data = self.live.lock().unwrap();
+        if data.is_empty() {
+            buf.fill(0);
+            return Ok(());
+        }
+        buf.copy_from_slice(&data[block_id * SECTOR_SIZE..(block_id + 1) * SECTOR_SIZE]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        let range = block_id * SECTOR_SIZE..(block_id + 1) * SECTOR_SIZE;
+        let mut live = self.live.lock().unwrap();
+        if live.is_empty() {
+            return Ok(());
+        }
+        live[range.clone()].copy_from_slice(buf);
+        if self.writes.fetch_add(1, Ordering::SeqCst) < self.budget {
+            self.persisted.lock().unwrap()[range].copy_from_slice(buf);
+        }
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_num(&self) -> usize {
+        self.live.lock().unwrap().len() / SECTOR_SIZE
+    }
+}
+
+fn fresh_image(block_size: usize) -> Vec<u8> {
+    let disk = CrashDisk::new(vec![0; BLOCK_NUM * block_size], usize::MAX);
+    let efs = Ext2FileSystem::create_with_block_size(disk.clone(), Arc::new(ZeroTimeProvider), block_size).unwrap();
+    efs.sync().unwrap();
+    disk.image()
+}
+
+/// Run a few transactions: the first logs an indirect block, the second
+/// frees and revokes it, and the last may reuse it for file contents.
+fn populate(efs: &Arc<Ext2FileSystem>) {
+    let block_size = efs.block_size();
+    let root = Ext2FileSystem::root_inode(efs).unwrap();
+    let dir = root.create("dir", EXT2_S_IFDIR).unwrap();
+    let file = dir.create("file", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &vec![1; 40 * block_size]).unwrap();
+    let tmp = root.create("tmp", EXT2_S_IFREG).unwrap();
+    tmp.write_at(0, &vec![2; 3 * block_size]).unwrap();
+    drop(tmp);
+    root.rm_file("tmp").unwrap();
+
+    dir.link("hard", file.inode_id().unwrap()).unwrap();
+    file.ftruncate(5 * block_size).unwrap();
+
+    let data = root.create("data", EXT2_S_IFREG).unwrap();
+    data.write_at(0, &vec![3; 20 * block_size]).unwrap();
+    efs.sync().unwrap();
+}
+
+/// The physical blocks an inode maps below its size, followed by its index
+/// blocks if `include_index`
+fn mapped_blocks(efs: &Arc<Ext2FileSystem>, inode: &Inode, include_index: bool) -> Vec<u32> {
+    let (extents, index) = inode.disk_inode().unwrap().mapped_extents(&efs.manager).unwrap();
+    let mut blocks: Vec<u32> = extents.iter()
+        .flat_map(|extent| extent.start..extent.start + extent.len)
+        .collect();
+    if include_index {
+        blocks.extend(index);
+    }
+    blocks
+}
+
+fn collect(
+    efs: &Arc<Ext2FileSystem>,
+    dir: &Inode,
+    refs: &mut BTreeMap<usize, usize>,
+    blocks: &mut Vec<u32>,
+) {
+    for name in dir.ls().unwrap() {
+        let child = dir.find(&name).unwrap();
+        let id = child.inode_id().unwrap();
+        *refs.entry(id).or_default() += 1;
+        if name == "." || name == ".." {
+            continue;
+        }
+        if refs[&id] == 1 {
+            blocks.extend(mapped_blocks(efs, &child, true));
+            if child.file_type() == EXT2_FT_DIR {
+                collect(efs, &child, refs, blocks);
+            }
+        }
+    }
+}
+
+fn check_contents(inode: &Inode, byte: u8) {
+    let size = inode.disk_inode().unwrap().size() as usize;
+    let mut buf = vec![0; size];
+    assert_eq!(inode.read_at(0, &mut buf), Ok(size));
+    assert!(buf.iter().all(|b| *b == byte));
+}
+
+/// Check that the bitmaps agree with the directory tree, and that link
+/// counts agree with the directory entries.
+fn check_consistency(efs: &Arc<Ext2FileSystem>) {
+    let root = Ext2FileSystem::root_inode(efs).unwrap();
+    let mut refs = BTreeMap::new();
+    let mut blocks = mapped_blocks(efs, &root, true);
+    collect(efs, &root, &mut refs, &mut blocks);
+    let journal = Inode::new(Ext2FileSystem::get_inode_cache(efs, EXT3_JOURNAL_INO as usize).unwrap());
+    blocks.extend(mapped_blocks(efs, &journal, true));
+
+    for (&id, &count) in refs.iter() {
+        let inode = Inode::new(Ext2FileSystem::get_inode_cache(efs, id).unwrap());
+        assert_eq!(inode.disk_inode().unwrap().i_links_count as usize, count, "inode {}", id);
+    }
+    let block_size = efs.block_size();
+    for id in 1..=(8 * block_size) as u32 {
+        let used = (id as usize) < EXT2_GOOD_OLD_FIRST_INO || refs.contains_key(&(id as usize));
+        assert_eq!(efs.inode_exists(id).unwrap(), used, "inode {}", id);
+    }
+
+    // the resize inode maps reserved group descriptor blocks, not data
+    blocks.push(efs.read_disk_inode(EXT2_RESIZE_INO).unwrap().i_triple_block);
+
+    let mut used = BTreeSet::new();
+    for block_id in blocks {
+        assert!(used.insert(block_id), "block {} is shared", block_id);
+    }
+    // blocks before the first free one hold the super block, the group
+    // descriptors and the blocks reserved for them, the bitmaps and the
+    // inode table
+    let first_data_block = if block_size == 1024 { 1 } else { 0 };
+    let first_free_block = efs.group_desc(0).bg_inode_table + 8 * 128;
+    for block_id in first_data_block..first_data_block + 8 * block_size as u32 {
+        let expected = block_id < first_free_block
+            || block_id as usize >= BLOCK_NUM
+            || used.contains(&block_id);
+        assert_eq!(efs.block_exists(block_id).unwrap(), expected, "block {}", block_id);
+    }
+
+    if let Ok(dir) = root.find("dir") {
+        if let Ok(file) = dir.find("file") {
+            check_contents(&file, 1);
+        }
+    }
+    if let Ok(data) = root.find("data") {
+        check_contents(&data, 3);
+    }
+}
+
+#[test]
+fn block_sizes() {
+    for block_size in [1024, 2048, 4096] {
+        let disk = CrashDisk::new(fresh_image(block_size), usize::MAX);
+        let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+        assert_eq!(efs.block_size(), block_size);
+        populate(&efs);
+
+        let disk = CrashDisk::new(disk.image(), usize::MAX);
+        let efs = Ext2FileSystem::open(disk, Arc::new(ZeroTimeProvider)).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let data = root.find("data").unwrap();
+        assert_eq!(data.disk_inode().unwrap().size() as usize, 20 * block_size);
+        check_consistency(&efs);
+    }
+}
+
+#[test]
+fn journal_replay() {
+    let image = fresh_image(DEFAULT_BLOCK_SIZE);
+
+    let disk = CrashDisk::new(image.clone(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    populate(&efs);
+    let writes = disk.writes.load(Ordering::SeqCst);
+    let names: Vec<String> = Ext2FileSystem::root_inode(&efs).unwrap().ls().unwrap();
+    assert!(names.contains(&"data".into()) && !names.contains(&"tmp".into()));
+
+    for budget in 0..=writes {
+        let disk = CrashDisk::new(image.clone(), budget);
+        let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+        populate(&efs);
+
+        let disk = CrashDisk::new(disk.power_off(), usize::MAX);
+        let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+        check_consistency(&efs);
+        disk.power_off();
+    }
+}
+
+#[test]
+fn journal_replay_bad_tags() {
+    let disk = CrashDisk::new(fresh_image(1024), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let journal = Inode::new(Ext2FileSystem::get_inode_cache(&efs, EXT3_JOURNAL_INO as usize).unwrap());
+    let journal_blocks = mapped_blocks(&efs, &journal, false);
+    let blocks_count = efs.super_block().s_blocks_count as usize;
+    drop((journal, efs));
+    let image = disk.image();
+
+    // the transaction with a bad tag and the ones after it are not replayed
+    let (first, middle, last) = (blocks_count - 1, blocks_count - 2, blocks_count - 3);
+    for bad in [blocks_count + 1, journal_blocks[5] as usize, 0, 1] {
+        let disk = CrashDisk::new(image.clone(), usize::MAX);
+        let device = FsBlockDevice::new(disk.clone(), 1024);
+        let mut journal = Journal::load(&device, journal_blocks.clone()).unwrap();
+        journal.commit(&device, &[(first, &[1; 1024])]).unwrap();
+        // garbage over the super block as well
+        journal.commit(&device, &[(middle, &[2; 1024]), (bad, &[2; 1024])]).unwrap();
+        journal.commit(&device, &[(last, &[3; 1024])]).unwrap();
+        let image = disk.image();
+        let disk = CrashDisk::new(image, usize::MAX);
+        let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        drop(efs);
+        let image = disk.image();
+        assert_eq!(image[first * 1024..][..1024], [1; 1024], "bad target {}", bad);
+        assert_eq!(image[middle * 1024..][..1024], [0; 1024], "bad target {}", bad);
+        assert_eq!(image[last * 1024..][..1024], [0; 1024], "bad target {}", bad);
+    }
+}
+
+/// Files reach the disk when they are synced, or written back once they
+/// stay dirty long enough or too much of the cache is dirty.
+#[test]
+fn fsync_and_writeback() {
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    efs.set_writeback_policy(WritebackPolicy {
+        interval: Duration::from_secs(5),
+        dirty_expire: Duration::from_secs(30),
+        dirty_ratio: 100,
+    });
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    // contents of a file if the power went off now
+    let persisted = |name: &str| {
+        let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        root.find(name).ok().map(|file| read_all(&file))
+    };
+
+    let file = root.create("fsynced", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &pattern(3 * DEFAULT_BLOCK_SIZE)).unwrap();
+    assert_eq!(persisted("fsynced"), None);
+    file.fsync().unwrap();
+    assert_eq!(persisted("fsynced"), Some(pattern(3 * DEFAULT_BLOCK_SIZE)));
+
+    let file = root.create("aged", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &[1; 100]).unwrap();
+    efs.writeback(Duration::from_secs(100)).unwrap();
+    efs.writeback(Duration::from_secs(125)).unwrap();
+    assert_eq!(persisted("aged"), None);
+    efs.writeback(Duration::from_secs(130)).unwrap();
+    assert_eq!(persisted("aged"), Some(vec![1; 100]));
+
+    efs.set_writeback_policy(WritebackPolicy { dirty_ratio: 10, ..efs.writeback_policy() });
+    let file = root.create("bulk", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &pattern(10 * DEFAULT_BLOCK_SIZE)).unwrap();
+    efs.writeback(Duration::from_secs(135)).unwrap();
+    assert_eq!(persisted("bulk"), Some(pattern(10 * DEFAULT_BLOCK_SIZE)));
+    efs.sync().unwrap();
+    assert_eq!(efs.manager.dirty_metadata(), 0);
+}
+
+/// Sequential reads load the blocks ahead of them in a few requests, other
+/// reads only the blocks they read. Adjacent dirty blocks are written together.
+#[test]
+fn read_ahead() {
+    const BLOCKS: usize = 64;
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    root.create("file", EXT2_S_IFREG).unwrap().write_at(0, &pattern(BLOCKS * DEFAULT_BLOCK_SIZE)).unwrap();
+    let before = efs.cache_stats();
+    efs.sync().unwrap();
+    assert!(efs.cache_stats().device_writes - before.device_writes < BLOCKS / 4);
+
+    let read_blocks = |order: &mut dyn Iterator<Item = usize>| {
+        let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        let file = Ext2FileSystem::root_inode(&efs).unwrap().find("file").unwrap();
+        let before = efs.cache_stats();
+        let mut contents = vec![0; BLOCKS * DEFAULT_BLOCK_SIZE];
+        for idx in order {
+            let block = &mut contents[idx * DEFAULT_BLOCK_SIZE..(idx + 1) * DEFAULT_BLOCK_SIZE];
+            assert_eq!(file.read_at(idx * DEFAULT_BLOCK_SIZE, block), Ok(DEFAULT_BLOCK_SIZE));
+        }
+        assert_eq!(contents, pattern(BLOCKS * DEFAULT_BLOCK_SIZE));
+        let stats = efs.cache_stats();
+        (stats.device_reads - before.device_reads, stats.read_ahead - before.read_ahead)
+    };
+    let (requests, read_ahead) = read_blocks(&mut (0..BLOCKS));
+    assert!(requests < BLOCKS / 4, "{} requests", requests);
+    assert!(read_ahead >= BLOCKS, "{} blocks read ahead", read_ahead);
+    let (requests, read_ahead) = read_blocks(&mut (0..BLOCKS).rev());
+    assert!(requests >= BLOCKS, "{} requests", requests);
+    assert_eq!(read_ahead, BLOCKS);
+}
+
+#[test]
+fn journal_reset_when_full() {
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    for i in 0..500 {
+        let name = format!("file{}", i);
+        let file = root.create(&name, EXT2_S_IFREG).unwrap();
+        file.write_at(0, &vec![i as u8; 2 * DEFAULT_BLOCK_SIZE]).unwrap();
+        drop(file);
+        if i % 2 == 0 {
+            root.rm_file(&name).unwrap();
+        }
+        // committed once it holds half of the cache
+        assert!(efs.manager.dirty_metadata() < efs.manager.capacity() / 2);
+    }
+    drop(root);
+
+    // crash without syncing, the committed transactions must survive
+    let disk = CrashDisk::new(disk.image(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk, Arc::new(ZeroTimeProvider)).unwrap();
+    check_consistency(&efs);
+}
+
+/// Contents of the pattern files in the golden images
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn read_all(inode: &Inode) -> Vec<u8> {
+    let size = inode.disk_inode().unwrap().size() as usize;
+    let mut buf = vec![0; size];
+    assert_eq!(inode.read_at(0, &mut buf), Ok(size));
+    buf
+}
+
+fn sorted_ls(dir: &Inode) -> Vec<String> {
+    let mut names = dir.ls().unwrap();
+    names.sort();
+    names
+}
+
+/// Set feature bits in the super block of an image
+fn set_features(image: &mut [u8], field: usize, bits: u32) {
+    let offset = SUPER_BLOCK_OFFSET + field;
+    let features = u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap()) | bits;
+    image[offset..offset + 4].copy_from_slice(&features.to_le_bytes());
+}
+
+#[test]
+fn mke2fs_images() {
+    for (image, block_size, pattern_file, pattern_len) in [
+        (IMAGE_1K, 1024, "big.bin", 300 * 1024),
+        (IMAGE_4K, 4096, "mid.bin", 60 * 1024),
+    ] {
+        let disk = CrashDisk::new(image.to_vec(), usize::MAX);
+        let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+        assert_eq!(efs.block_size(), block_size);
+        assert!(!efs.is_read_only());
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let mut expected = vec![".", "..", "dir", "hello.txt", "long", "lost+found", "short", pattern_file];
+        if block_size == 1024 {
+            expected.push("many");
+        }
+        expected.sort();
+        assert_eq!(sorted_ls(&root), expected);
+
+        let hello = root.find("hello.txt").unwrap();
+        assert_eq!(read_all(&hello), b"Hello, ext2!\n");
+        let dir = root.find("dir").unwrap();
+        let link = dir.find("hello.link").unwrap();
+        assert_eq!(link.inode_id(), hello.inode_id());
+        assert_eq!(hello.disk_inode().unwrap().i_links_count, 2);
+        assert_eq!(read_all(&dir.find("sub").unwrap().find("deep.txt").unwrap()), b"deep\n");
+        assert_eq!(dir.find("..").unwrap().inode_id(), root.inode_id());
+        let short = root.find("short").unwrap();
+        assert_eq!(short.file_type(), EXT2_FT_SYMLINK);
+        assert!(short.disk_inode().unwrap().is_fast_symlink(block_size));
+        assert_eq!(short.readlink().unwrap(), "hello.txt");
+        let long = root.find("long").unwrap();
+        assert!(!long.disk_inode().unwrap().is_fast_symlink(block_size));
+        assert_eq!(long.readlink().unwrap(), format!("dir/{}", "x".repeat(80)));
+        let big = root.find(pattern_file).unwrap();
+        assert_eq!(read_all(&big), pattern(pattern_len));
+        // types come from the entries, or from the inodes on revision 0
+        let mut entries = Vec::new();
+        loop {
+            let chunk = root.read_dir(entries.len(), 3).unwrap();
+            if chunk.is_empty() {
+                break;
+            }
+            entries.extend(chunk);
+        }
+        assert_eq!(entries.len(), expected.len());
+        for (name, file_type) in entries {
+            assert_eq!(file_type, root.find(&name).unwrap().file_type(), "{}", name);
+        }
+
+        // write to the image
+        let new_dir = root.create("new", EXT2_S_IFDIR).unwrap();
+        let new_file = new_dir.create("file", EXT2_S_IFREG).unwrap();
+        new_file.write_at(0, &pattern(20 * block_size)).unwrap();
+        big.ftruncate(pattern_len / 2).unwrap();
+        root.rm_file("hello.txt").unwrap();
+        root.rm_file("short").unwrap();
+        assert_eq!(root.rm_file("dir"), Err(Ext2Error::IsADirectory));
+        if let Ok(many) = root.find("many") {
+            assert!(many.disk_inode().unwrap().i_flags & EXT2_INDEX_FL != 0);
+            for i in 0..50 {
+                many.rm_file(&format!("file-with-a-long-name-{:02}", i)).unwrap();
+            }
+            for i in 0..10 {
+                many.create(&format!("added-{}", i), EXT2_S_IFREG).unwrap();
+            }
+            // the index is kept up to date
+            assert!(many.disk_inode().unwrap().i_flags & EXT2_INDEX_FL != 0);
+        }
+        efs.sync().unwrap();
+
+        let disk = CrashDisk::new(disk.image(), usize::MAX);
+        let efs = Ext2FileSystem::open(disk, Arc::new(ZeroTimeProvider)).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        assert!(root.find("hello.txt").is_err() && root.find("short").is_err());
+        let link = root.find("dir").unwrap().find("hello.link").unwrap();
+        assert_eq!(link.disk_inode().unwrap().i_links_count, 1);
+        assert_eq!(read_all(&link), b"Hello, ext2!\n");
+        let new_dir = root.find("new").unwrap();
+        assert_eq!(sorted_ls(&new_dir), [".", "..", "file"]);
+        assert_eq!(new_dir.disk_inode().unwrap().size() as usize, block_size);
+        assert_eq!(read_all(&new_dir.find("file").unwrap()), pattern(20 * block_size));
+        assert_eq!(read_all(&root.find(pattern_file).unwrap()), pattern(pattern_len / 2));
+        if let Ok(many) = root.find("many") {
+            let names = many.ls().unwrap();
+            assert_eq!(names.len(), 2 + 50 + 10);
+            for name in names {
+                assert!(many.find(&name).is_ok(), "{}", name);
+            }
+            assert_eq!(many.find("file-with-a-long-name-00").err(), Some(Ext2Error::NotFound));
+        }
+    }
+}
+
+#[test]
+fn unknown_ro_compat_features() {
+    let mut image = IMAGE_1K.to_vec();
+    // s_feature_ro_compat
+    set_features(&mut image, 0x64, 1 << 12);
+    let disk = CrashDisk::new(image.clone(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    assert!(efs.is_read_only());
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(read_all(&root.find("hello.txt").unwrap()), b"Hello, ext2!\n");
+    assert_eq!(root.create("new", EXT2_S_IFREG).err(), Some(Ext2Error::ReadOnly));
+    assert_eq!(root.rm_file("hello.txt"), Err(Ext2Error::ReadOnly));
+    efs.sync().unwrap();
+    assert!(disk.image() == image);
+}
+
+#[test]
+fn unknown_incompat_features() {
+    let mut image = IMAGE_1K.to_vec();
+    // s_feature_incompat
+    set_features(&mut image, 0x60, 1 << 16);
+    let efs = Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider));
+    assert_eq!(efs.err(), Some(Ext2Error::Unsupported));
+}
+
+#[test]
+fn fsck_clean_images() {
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    populate(&Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap());
+    for image in [IMAGE_1K.to_vec(), IMAGE_4K.to_vec(), disk.image()] {
+        let efs = Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}
+
+#[test]
+fn fsck_repair() {
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    populate(&efs);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let dir_id = root.find("dir").unwrap().inode_id().unwrap() as u32;
+    let file = root.find("dir").unwrap().find("file").unwrap();
+    let file_id = file.inode_id().unwrap() as u32;
+    let file_block = mapped_blocks(&efs, &file, false)[0];
+    let data_id = root.find("data").unwrap().inode_id().unwrap() as u32;
+    {
+        let _op = Ext2FileSystem::begin_op(&efs);
+        let root_cache = Ext2FileSystem::get_inode_cache(&efs, 2).unwrap();
+        let mut lk = root_cache.unique_lock();
+        lk.remove_dir_entry("dir").unwrap();
+        lk.remove_dir_entry("data").unwrap();
+        lk.add_dir_entry(100, "ghost", EXT2_FT_REG_FILE).unwrap();
+        drop(lk);
+        Ext2FileSystem::get_inode_cache(&efs, file_id as usize).unwrap().unique_lock().increase_nlink(1).unwrap();
+        efs.dealloc_block(file_block).unwrap();
+    }
+    efs.sync().unwrap();
+
+    let disk = CrashDisk::new(disk.image(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, true).unwrap();
+    assert!(report.repaired);
+    for problem in [
+        fsck::Problem::Orphan { inode: dir_id },
+        fsck::Problem::Orphan { inode: data_id },
+        fsck::Problem::DanglingEntry { dir: 2, name: "ghost".into(), inode: 100 },
+        fsck::Problem::LinkCount { inode: file_id, recorded: 3, counted: 2 },
+        fsck::Problem::BlockBitmap { block: file_block, recorded: false },
+    ] {
+        assert!(report.problems.contains(&problem), "{} not in {:?}", problem, report.problems);
+    }
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    check_consistency(&efs);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert!(root.find("ghost").is_err());
+    let lost_found = root.find("lost+found").unwrap();
+    assert_eq!(sorted_ls(&lost_found), [&format!("#{}", dir_id), &format!("#{}", data_id), ".", ".."]);
+    check_contents(&lost_found.find(&format!("#{}", data_id)).unwrap(), 3);
+    let dir = lost_found.find(&format!("#{}", dir_id)).unwrap();
+    assert_eq!(dir.find("..").unwrap().inode_id(), lost_found.inode_id());
+    let file = dir.find("file").unwrap();
+    assert_eq!(file.disk_inode().unwrap().i_links_count, 2);
+    check_contents(&file, 1);
+}
+
+#[test]
+fn fsck_orphan_cycle() {
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let a = root.create("a", EXT2_S_IFDIR).unwrap();
+    let b = a.create("b", EXT2_S_IFDIR).unwrap();
+    b.create("file", EXT2_S_IFREG).unwrap().write_at(0, &[4; 100]).unwrap();
+    let (a_id, b_id) = (a.inode_id().unwrap(), b.inode_id().unwrap());
+    // a/b and b/a, neither reachable from the root
+    {
+        let _op = Ext2FileSystem::begin_op(&efs);
+        let b_cache = Ext2FileSystem::get_inode_cache(&efs, b_id).unwrap();
+        b_cache.unique_lock().add_dir_entry(a_id, "a", EXT2_FT_DIR).unwrap();
+        let a_cache = Ext2FileSystem::get_inode_cache(&efs, a_id).unwrap();
+        a_cache.unique_lock().set_entry_inode("..", b_id).unwrap();
+        let root_cache = Ext2FileSystem::get_inode_cache(&efs, 2).unwrap();
+        root_cache.unique_lock().remove_dir_entry("a").unwrap();
+    }
+    efs.sync().unwrap();
+
+    let disk = CrashDisk::new(disk.image(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, true).unwrap();
+    assert!(report.repaired);
+    assert!(report.problems.contains(&fsck::Problem::Orphan { inode: a_id as u32 }), "{:?}", report.problems);
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    check_consistency(&efs);
+    let lost_found = Ext2FileSystem::root_inode(&efs).unwrap().find("lost+found").unwrap();
+    let b = lost_found.find(&format!("#{}", a_id)).unwrap().find("b").unwrap();
+    assert_eq!(sorted_ls(&b), [".", "..", "file"]);
+    check_contents(&b.find("file").unwrap(), 4);
+}
+
+#[test]
+fn create_options() {
+    // three groups of 1K blocks, the last one partial
+    let block_num = 20000;
+    let disk = CrashDisk::new(vec![0; block_num * 1024], usize::MAX);
+    let options = CreateOptions {
+        block_size: 1024,
+        bytes_per_inode: Some(4096),
+        volume_name: String::from("rootfs"),
+        extents: false,
+        metadata_csum: false,
+        sparse_super: true,
+        resize_inode: true,
+    };
+    let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+    populate(&efs);
+    Ext2FileSystem::root_inode(&efs).unwrap().find("data").unwrap().chmod(IMODE::from_bits_truncate(0o4750)).unwrap();
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let sb = efs.super_block();
+    assert_eq!(sb.s_blocks_count as usize, block_num);
+    assert_eq!(sb.s_inodes_per_group, 1672);
+    assert_eq!(sb.s_inodes_count, 3 * 1672);
+    assert_eq!(sb.volume_name(), b"rootfs");
+    // the inode bitmaps are padded up to a whole block
+    for group_id in 0..3 {
+        let block = efs.manager.get_block_cache(efs.group_desc(group_id).bg_inode_bitmap as usize).unwrap();
+        block.lock().read_slice(|bits: &[u8]| {
+            assert!(bits[1672 / 8..].iter().all(|b| *b == 0xff));
+        });
+        efs.manager.release_block(block);
+    }
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let data = root.find("data").unwrap();
+    assert_eq!(data.disk_inode().unwrap().i_mode, EXT2_S_IFREG | 0o4750);
+    check_contents(&data, 3);
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn rename() {
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    populate(&efs);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let dir = root.find("dir").unwrap();
+    let links = |inode: &Inode| inode.disk_inode().unwrap().i_links_count;
+
+    // in the same directory, and over an existing file
+    dir.rename("hard", &dir, "moved").unwrap();
+    assert_eq!(sorted_ls(&dir), [".", "..", "file", "moved"]);
+    root.rename("data", &dir, "file").unwrap();
+    assert_eq!(sorted_ls(&root), [".", "..", "dir"]);
+    check_contents(&dir.find("file").unwrap(), 3);
+    assert_eq!(links(&dir.find("moved").unwrap()), 1);
+
+    // a directory to another one, which fixes '..' and the link counts
+    let sub = dir.create("sub", EXT2_S_IFDIR).unwrap();
+    let other = root.create("other", EXT2_S_IFDIR).unwrap();
+    assert_eq!(links(&dir), 3);
+    dir.rename("sub", &other, "sub").unwrap();
+    assert_eq!(links(&dir), 2);
+    assert_eq!(links(&other), 3);
+    assert_eq!(sub.find("..").unwrap().inode_id(), other.inode_id());
+
+    // over an empty directory, but not a file or a directory that is not empty
+    let empty = root.create("empty", EXT2_S_IFDIR).unwrap();
+    assert_eq!(other.rename("sub", &dir, "file"), Err(Ext2Error::NotADirectory));
+    assert_eq!(root.rename("other", &root, "dir"), Err(Ext2Error::DirectoryNotEmpty));
+    root.rename("dir", &root, "empty").unwrap();
+    // the replaced directory is freed
+    assert_eq!(empty.disk_inode().err(), Some(Ext2Error::NotFound));
+    assert_eq!(links(&root), 4);
+
+    // not into itself or its subtree
+    assert_eq!(root.rename("other", &other, "x"), Err(Ext2Error::InvalidInput));
+    assert_eq!(root.rename("other", &sub, "x"), Err(Ext2Error::InvalidInput));
+    assert_eq!(other.rename("sub", &sub, "x"), Err(Ext2Error::InvalidInput));
+    assert_eq!(root.rename("missing", &root, "x"), Err(Ext2Error::NotFound));
+    assert_eq!(root.rename(".", &other, "x"), Err(Ext2Error::InvalidInput));
+    assert_eq!(sorted_ls(&root), [".", "..", "empty", "other"]);
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn errors() {
+    let disk = CrashDisk::new(fresh_image(1024), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let dir = root.create("dir", EXT2_S_IFDIR).unwrap();
+    let file = dir.create("file", EXT2_S_IFREG).unwrap();
+
+    assert_eq!(root.create(&"x".repeat(256), EXT2_S_IFREG).err(), Some(Ext2Error::NameTooLong));
+    assert_eq!(root.create("dir", EXT2_S_IFREG).err(), Some(Ext2Error::AlreadyExists));
+    assert_eq!(root.find("missing").err(), Some(Ext2Error::NotFound));
+    assert_eq!(file.find("x").err(), Some(Ext2Error::NotADirectory));
+    assert_eq!(dir.write_at(0, b"x"), Err(Ext2Error::IsADirectory));
+    assert_eq!(root.rm_dir("dir", false), Err(Ext2Error::DirectoryNotEmpty));
+    assert_eq!(dir.rm_dir("file", false), Err(Ext2Error::NotADirectory));
+
+    // a write that does not fit fails without leaking blocks
+    let free_blocks = efs.super_block().s_free_blocks_count as usize;
+    assert_eq!(file.write_at(0, &vec![1; (free_blocks + 1) * 1024]), Err(Ext2Error::NoSpace));
+    assert_eq!(efs.super_block().s_free_blocks_count as usize, free_blocks);
+    assert_eq!(file.write_at(0, &vec![1; 100 * 1024]), Ok(100 * 1024));
+    let mut filled = 0;
+    loop {
+        match file.append(&[1; 1024]) {
+            Ok(_) => filled += 1,
+            Err(err) => {
+                assert_eq!(err, Ext2Error::NoSpace);
+                break;
+            }
+        }
+    }
+    assert!(filled > 0);
+    check_contents(&file, 1);
+    dir.rm_file("file").unwrap();
+    root.rm_dir("dir", false).unwrap();
+    // the file is freed once its handle is dropped
+    assert_eq!(file.read_at(0, &mut [0; 1]), Ok(1));
+    drop(file);
+    efs.sync().unwrap();
+
+    let disk = CrashDisk::new(disk.image(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    // with the block of the removed directory
+    assert_eq!(efs.super_block().s_free_blocks_count as usize, free_blocks + 1);
+
+    // a directory whose entries can not be parsed is not searched nor changed
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let bad = root.create("bad", EXT2_S_IFDIR).unwrap();
+    let bad_block = mapped_blocks(&efs, &bad, false)[0] as usize;
+    efs.sync().unwrap();
+    let mut image = disk.image();
+    // rec_len of `..`, shorter than an entry
+    image[bad_block * 1024 + 12 + 4..][..2].copy_from_slice(&3u16.to_le_bytes());
+    let efs = Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let bad = Ext2FileSystem::root_inode(&efs).unwrap().find("bad").unwrap();
+    assert_eq!(bad.find("missing").err(), Some(Ext2Error::Corrupted));
+    assert_eq!(bad.create("new", EXT2_S_IFREG).err(), Some(Ext2Error::Corrupted));
+    assert_eq!(bad.ls().err(), Some(Ext2Error::Corrupted));
+}
+
+#[test]
+fn corrupted_ids() {
+    let disk = CrashDisk::new(fresh_image(1024), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    efs.set_alloc_policy(Arc::new(FirstFitPolicy));
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let dir = root.create("dir", EXT2_S_IFDIR).unwrap();
+    let file = root.create("file", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &[1; 1024]).unwrap();
+    // the first blocks the double indirect block maps, right after which
+    // it is allocated
+    let big = root.create("big", EXT2_S_IFREG).unwrap();
+    big.write_at(268 * 1024, &[2; 1024]).unwrap();
+    big.write_at(270 * 1024, &[2; 1024]).unwrap();
+    let orphan = root.create("orphan", EXT2_S_IFREG).unwrap();
+    {
+        let _op = Ext2FileSystem::begin_op(&efs);
+        Ext2FileSystem::get_inode_cache(&efs, 2).unwrap().unique_lock().remove_dir_entry("orphan").unwrap();
+    }
+    efs.sync().unwrap();
+    let super_block = efs.super_block();
+    let inode_pos = |inode_id: usize| {
+        let (block_id, offset) = efs.get_disk_inode_pos(inode_id as u32).unwrap();
+        block_id as usize * 1024 + offset
+    };
+    let dir_id = dir.inode_id().unwrap();
+    let orphan_id = orphan.inode_id().unwrap() as u32;
+    let dir_desc = 2 * 1024 + (dir_id - 1) / super_block.s_inodes_per_group as usize * 32;
+    let double = big.disk_inode().unwrap().indirect_root(2);
+    let bitmap = efs.group_desc(0).bg_block_bitmap as usize * 1024;
+    let (file_pos, root_pos) = (inode_pos(file.inode_id().unwrap()), inode_pos(2));
+    let image = disk.image();
+    let open = |image: Vec<u8>| {
+        let efs = Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        efs.set_alloc_policy(Arc::new(FirstFitPolicy));
+        efs
+    };
+    drop((dir, file, big, orphan));
+
+    // inodes out of the file system
+    assert_eq!(efs.get_disk_inode_pos(0), Err(Ext2Error::Corrupted));
+    assert_eq!(efs.get_disk_inode_pos(super_block.s_inodes_count + 1), Err(Ext2Error::Corrupted));
+
+    // a block pointer past the end of the file system is not freed
+    let mut bad = image.clone();
+    bad[file_pos + 40..][..4].copy_from_slice(&(super_block.s_blocks_count + 5).to_le_bytes());
+    let efs = open(bad);
+    let file = Ext2FileSystem::root_inode(&efs).unwrap().find("file").unwrap();
+    assert_eq!(file.ftruncate(0), Err(Ext2Error::Corrupted));
+
+    // a group that counts no directories can not lose one
+    let mut bad = image.clone();
+    bad[dir_desc + 16..][..2].copy_from_slice(&0u16.to_le_bytes());
+    let efs = open(bad);
+    assert_eq!(Ext2FileSystem::root_inode(&efs).unwrap().rm_dir("dir", false), Err(Ext2Error::Corrupted));
+
+    // the double indirect block of a file, marked free, is taken and zeroed
+    // for a new indirect block of that file, which then misses more of them
+    let mut bad = image.clone();
+    bad[bitmap + (double as usize - 1) / 8] &= !(1 << ((double - 1) % 8));
+    let efs = open(bad);
+    let big = Ext2FileSystem::root_inode(&efs).unwrap().find("big").unwrap();
+    assert_eq!(big.write_at(271 * 1024, &[3; 256 * 1024]), Err(Ext2Error::Corrupted));
+
+    // lost+found is looked up in a root that is no directory, and orphans
+    // are left alone
+    let mut bad = image.clone();
+    bad[root_pos..][..2].copy_from_slice(&(EXT2_S_IFREG | 0o644).to_le_bytes());
+    let efs = open(bad);
+    let report = fsck::check(&efs, true).unwrap();
+    assert!(report.problems.contains(&fsck::Problem::Orphan { inode: orphan_id }), "{:?}", report.problems);
+    assert_eq!(Ext2FileSystem::get_inode_cache(&efs, 2).unwrap().shared_lock().ls().err(), Some(Ext2Error::Corrupted));
+
+    // an extent starting before the first data block
+    let disk = CrashDisk::new(vec![0; BLOCK_NUM * 1024], usize::MAX);
+    let options = CreateOptions { block_size: 1024, extents: true, ..CreateOptions::default() };
+    let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+    let file = Ext2FileSystem::root_inode(&efs).unwrap().create("file", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &[1; 1024]).unwrap();
+    efs.sync().unwrap();
+    let mut bad = disk.image();
+    // the low 32 bits of the start of the first extent in the root
+    let file_pos = {
+        let (block_id, offset) = efs.get_disk_inode_pos(file.inode_id().unwrap() as u32).unwrap();
+        block_id as usize * 1024 + offset
+    };
+    bad[file_pos + 40 + 12 + 8..][..4].copy_from_slice(&0u32.to_le_bytes());
+    let efs = open(bad);
+    let file = Ext2FileSystem::root_inode(&efs).unwrap().find("file").unwrap();
+    assert_eq!(file.ftruncate(0), Err(Ext2Error::Corrupted));
+}
+
+#[test]
+fn symlinks() {
+    let disk = CrashDisk::new(fresh_image(DEFAULT_BLOCK_SIZE), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let free_blocks = efs.super_block().s_free_blocks_count;
+
+    // up to 59 bytes of target are kept in the inode
+    let fast_target = "a".repeat(59);
+    let slow_target = "b".repeat(60);
+    root.symlink("fast", &fast_target).unwrap();
+    assert_eq!(efs.super_block().s_free_blocks_count, free_blocks);
+    root.symlink("slow", &slow_target).unwrap();
+    assert_eq!(efs.super_block().s_free_blocks_count, free_blocks - 1);
+    assert_eq!(root.symlink("fast", "x"), Err(Ext2Error::AlreadyExists));
+    let fast = root.find("fast").unwrap();
+    assert_eq!(fast.file_type(), EXT2_FT_SYMLINK);
+    assert!(fast.disk_inode().unwrap().is_fast_symlink(DEFAULT_BLOCK_SIZE));
+    assert_eq!(fast.disk_inode().unwrap().size(), 59);
+    assert_eq!(fast.readlink().unwrap(), fast_target);
+    assert!(!root.find("slow").unwrap().disk_inode().unwrap().is_fast_symlink(DEFAULT_BLOCK_SIZE));
+    assert_eq!(root.find("slow").unwrap().readlink().unwrap(), slow_target);
+    assert_eq!(root.readlink(), Err(Ext2Error::InvalidInput));
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(root.find("fast").unwrap().readlink().unwrap(), fast_target);
+    assert_eq!(root.find("slow").unwrap().readlink().unwrap(), slow_target);
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    root.rm_file("fast").unwrap();
+    root.rm_file("slow").unwrap();
+    assert_eq!(efs.super_block().s_free_blocks_count, free_blocks);
+    check_consistency(&efs);
+}
+
+#[test]
+fn dx_hash_versions() {
+    // from debugfs dx_hash, with the hash seed of IMAGE_1K
+    let seed = [0x3e2d1c0b, 0x1746504f, 0xb5a49382, 0xf9e8d7c6];
+    let long_name = b"a-name-longer-than-thirty-two-bytes-to-hash-in-several-chunks";
+    for (name, hashes) in [
+        (&b"file-with-a-long-name-10"[..], [0x786a96d8, 0x01aae162, 0x2bbb12b8, 0x786a96d8, 0x01aae162, 0x2bbb12b8]),
+        ("\u{e9}t\u{e9}".as_bytes(), [0x70d7b7fc, 0x0663a056, 0x9de4c618, 0x40d0f40c, 0x47d4d9cc, 0x0d58fcd8]),
+        (&long_name[..], [0xd2bb05b6, 0xa29a8182, 0x49facf24, 0xd2bb05b6, 0xa29a8182, 0x49facf24]),
+    ] {
+        for (version, hash) in hashes.into_iter().enumerate() {
+            assert_eq!(htree::dx_hash(name, version as u8, &seed), Some(hash), "version {}", version);
+        }
+    }
+    // a zero seed stands for the default one
+    assert_eq!(htree::dx_hash(b"file-with-a-long-name-10", 1, &[0; 4]), Some(0x4148956e));
+    assert_eq!(htree::dx_hash(b"x", 6, &seed), None);
+}
+
+#[test]
+fn htree() {
+    let disk = CrashDisk::new(fresh_image(1024), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let dir = root.create("dir", EXT2_S_IFDIR).unwrap();
+    let name = |i: usize| format!("a-fairly-long-file-name-{:05}", i);
+    // enough names for the root to be full, adding a level of dx_node blocks
+    for i in 0..3000 {
+        dir.create(&name(i), EXT2_S_IFREG).unwrap();
+        if i == 40 {
+            assert!(dir.disk_inode().unwrap().i_flags & EXT2_INDEX_FL != 0);
+        }
+    }
+    let first_block = mapped_blocks(&efs, &dir, false)[0];
+    let block = efs.manager.get_block_cache(first_block as usize).unwrap();
+    let dx_root = block.lock().read_slice(|data: &[u8]| DxRoot::read(data, 0).unwrap());
+    efs.manager.release_block(block);
+    assert_eq!(dx_root.levels, 1);
+    for i in (0..3000).step_by(3) {
+        dir.rm_file(&name(i)).unwrap();
+    }
+    assert_eq!(dir.create(&name(1), EXT2_S_IFREG).err(), Some(Ext2Error::AlreadyExists));
+    for i in 3000..3500 {
+        dir.create(&name(i), EXT2_S_IFREG).unwrap();
+    }
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let dir = Ext2FileSystem::root_inode(&efs).unwrap().find("dir").unwrap();
+    assert!(dir.disk_inode().unwrap().i_flags & EXT2_INDEX_FL != 0);
+    assert_eq!(dir.ls().unwrap().len(), 2 + 2000 + 500);
+    for i in 0..3500 {
+        assert_eq!(dir.find(&name(i)).is_ok(), i >= 3000 || i % 3 != 0, "{}", name(i));
+    }
+    assert_eq!(dir.find("..").unwrap().inode_id(), Ok(2));
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    // emptied, it can be removed
+    for i in 0..3500 {
+        if i >= 3000 || i % 3 != 0 {
+            dir.rm_file(&name(i)).unwrap();
+        }
+    }
+    assert_eq!(dir.is_empty_dir(), Ok(true));
+    Ext2FileSystem::root_inode(&efs).unwrap().rm_dir("dir", false).unwrap();
+    efs.sync().unwrap();
+    check_consistency(&efs);
+}
+
+/// Extents of an inode, and the number of blocks of its extent tree
+fn extents_of(efs: &Arc<Ext2FileSystem>, inode: &Inode) -> (Vec<extent::Extent>, usize) {
+    let disk_inode = inode.disk_inode().unwrap();
+    let mut extents = Vec::new();
+    let mut nodes = 0;
+    let walked = extent::walk(disk_inode.extent_root(), &efs.manager, &mut |item| {
+        match item {
+            TreeItem::Node(_) => nodes += 1,
+            TreeItem::Extent(extent) => extents.push(extent),
+        }
+        true
+    });
+    assert_eq!(walked, Ok(None));
+    (extents, nodes)
+}
+
+/// The first blocks of the sparse file of the images with extents: block
+/// 2i holds block i of the pattern, the others are holes
+fn sparse_contents(blocks: usize) -> Vec<u8> {
+    let pattern = pattern(24 * 1024);
+    let mut expected = vec![0; blocks * 1024];
+    for i in (0..blocks).step_by(2) {
+        expected[i * 1024..(i + 1) * 1024].copy_from_slice(&pattern[i / 2 * 1024..(i / 2 + 1) * 1024]);
+    }
+    expected
+}
+
+#[test]
+fn mke2fs_extents_image() {
+    let disk = CrashDisk::new(IMAGE_EXTENTS.to_vec(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    assert!(efs.has_extents());
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert!(root.disk_inode().unwrap().has_extents());
+    assert_eq!(sorted_ls(&root), [".", "..", "big.bin", "hello.txt", "long", "lost+found", "sparse.bin"]);
+    assert_eq!(read_all(&root.find("hello.txt").unwrap()), b"Hello, extents!\n");
+    assert_eq!(root.find("long").unwrap().readlink().unwrap(), "y".repeat(90));
+    assert_eq!(read_all(&root.find("big.bin").unwrap()), pattern(200 * 1024));
+    let sparse = root.find("sparse.bin").unwrap();
+    assert_eq!(read_all(&sparse), sparse_contents(48));
+    assert_eq!(extents_of(&efs, &sparse).1, 1);
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    // the leaf is kept while it holds more extents than the root can
+    sparse.ftruncate(21 * 1024).unwrap();
+    assert_eq!(read_all(&sparse), sparse_contents(21));
+    assert_eq!(extents_of(&efs, &sparse).0.len(), 11);
+    assert_eq!(extents_of(&efs, &sparse).1, 1);
+    sparse.ftruncate(5 * 1024).unwrap();
+    assert_eq!(extents_of(&efs, &sparse), (vec![
+        extent::Extent { block: 0, len: 1, start: 230, unwritten: false },
+        extent::Extent { block: 2, len: 1, start: 231, unwritten: false },
+        extent::Extent { block: 4, len: 1, start: 232, unwritten: false },
+    ], 0));
+    assert_eq!(sparse.disk_inode().unwrap().i_blocks, 3 * 2);
+    let big = root.find("big.bin").unwrap();
+    big.write_at(200 * 1024, &pattern(30 * 1024)).unwrap();
+    let new_file = root.create("new", EXT2_S_IFREG).unwrap();
+    assert!(new_file.disk_inode().unwrap().has_extents());
+    new_file.write_at(0, &pattern(10 * 1024)).unwrap();
+    root.rm_file("long").unwrap();
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(read_all(&root.find("sparse.bin").unwrap()), sparse_contents(5));
+    let mut expected = pattern(200 * 1024);
+    expected.extend(pattern(30 * 1024));
+    assert_eq!(read_all(&root.find("big.bin").unwrap()), expected);
+    assert_eq!(read_all(&root.find("new").unwrap()), pattern(10 * 1024));
+    assert!(root.find("long").is_err());
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn extents() {
+    let disk = CrashDisk::new(vec![0; BLOCK_NUM * 1024], usize::MAX);
+    let options = CreateOptions { block_size: 1024, extents: true, ..CreateOptions::default() };
+    let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert!(root.disk_inode().unwrap().has_extents());
+
+    // a file written at once takes a single run
+    let seq = root.create("seq", EXT2_S_IFREG).unwrap();
+    seq.write_at(0, &pattern(1000 * 1024)).unwrap();
+    let (extents, nodes) = extents_of(&efs, &seq);
+    assert_eq!((extents.len(), nodes), (1, 0));
+    assert_eq!(seq.disk_inode().unwrap().i_blocks, 1000 * 2);
+    // files growing in turns are scattered without preallocation, needing
+    // a deeper tree
+    efs.set_alloc_policy(Arc::new(FirstFitPolicy));
+    let a = root.create("a", EXT2_S_IFREG).unwrap();
+    let b = root.create("b", EXT2_S_IFREG).unwrap();
+    for i in 0..500 {
+        a.write_at(i * 1024, &[i as u8; 1024]).unwrap();
+        b.write_at(i * 1024, &[!(i as u8); 1024]).unwrap();
+    }
+    let (extents, nodes) = extents_of(&efs, &a);
+    assert!(extents.len() > 100 && nodes > 1, "{} extents in {} nodes", extents.len(), nodes);
+    assert_eq!(a.disk_inode().unwrap().i_blocks as usize, (500 + nodes) * 2);
+    let contents = |len: usize, flip: bool| -> Vec<u8> {
+        (0..len).flat_map(|i| [if flip { !(i as u8) } else { i as u8 }; 1024]).collect()
+    };
+    assert_eq!(read_all(&a), contents(500, false));
+    assert_eq!(read_all(&b), contents(500, true));
+
+    a.ftruncate(100 * 1024).unwrap();
+    assert_eq!(read_all(&a), contents(100, false));
+    a.ftruncate(2 * 1024 + 10).unwrap();
+    assert_eq!(extents_of(&efs, &a).1, 0);
+    assert_eq!(a.disk_inode().unwrap().i_blocks, 3 * 2);
+    a.write_at(2 * 1024 + 10, &[7; 2000]).unwrap();
+    let mut expected = contents(3, false);
+    expected.truncate(2 * 1024 + 10);
+    expected.extend([7; 2000]);
+    assert_eq!(read_all(&a), expected);
+    root.rm_file("b").unwrap();
+    let dir = root.create("dir", EXT2_S_IFDIR).unwrap();
+    assert!(dir.disk_inode().unwrap().has_extents());
+    dir.create("file", EXT2_S_IFREG).unwrap().write_at(0, &[1; 3000]).unwrap();
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(read_all(&root.find("a").unwrap()), expected);
+    assert_eq!(read_all(&root.find("seq").unwrap()), pattern(1000 * 1024));
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    check_consistency(&efs);
+}
+
+/// Tasks creating, writing, renaming and removing files side by side, while
+/// reading a shared file, leave a consistent file system behind.
+#[test]
+fn concurrent_tasks() {
+    const TASKS: usize = 8;
+    const ROUNDS: usize = 30;
+    set_yield_now(std::thread::yield_now);
+    // three block groups, so that tasks allocate from more than one
+    let disk = CrashDisk::new(vec![0; 3 * 8192 * 1024], usize::MAX);
+    let efs = Ext2FileSystem::create_with_block_size(disk.clone(), Arc::new(ZeroTimeProvider), 1024).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    root.create("shared", EXT2_S_IFREG).unwrap().write_at(0, &pattern(64 * 1024)).unwrap();
+
+    let contents = |task: usize, round: usize| vec![(task * ROUNDS + round) as u8; (round + 1) * 700];
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|task| {
+            let efs = efs.clone();
+            std::thread::spawn(move || {
+                let root = Ext2FileSystem::root_inode(&efs).unwrap();
+                let dir = root.create(&format!("task{}", task), EXT2_S_IFDIR).unwrap();
+                let shared = root.find("shared").unwrap();
+                for round in 0..ROUNDS {
+                    let name = format!("file{}", round);
+                    let file = dir.create(&name, EXT2_S_IFREG).unwrap();
+                    file.write_at(0, &contents(task, round)).unwrap();
+                    file.append(&contents(task, round)).unwrap();
+                    assert_eq!(read_all(&file), contents(task, round).repeat(2));
+                    assert_eq!(read_all(&shared), pattern(64 * 1024));
+                    match round % 3 {
+                        0 => dir.rename(&name, &root, &format!("moved{}_{}", task, round)).unwrap(),
+                        1 => dir.rm_file(&name).unwrap(),
+                        _ => {}
+                    }
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.join().unwrap();
+    }
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    for task in 0..TASKS {
+        let dir = root.find(&format!("task{}", task)).unwrap();
+        for round in 0..ROUNDS {
+            let file = match round % 3 {
+                0 => root.find(&format!("moved{}_{}", task, round)),
+                1 => {
+                    assert_eq!(dir.find(&format!("file{}", round)).err(), Some(Ext2Error::NotFound));
+                    continue;
+                }
+                _ => dir.find(&format!("file{}", round)),
+            };
+            assert_eq!(read_all(&file.unwrap()), contents(task, round).repeat(2));
+        }
+    }
+}
+
+#[test]
+fn concurrent_renames() {
+    const ROUNDS: usize = 1000;
+    set_yield_now(std::thread::yield_now);
+    // room for two directories a round
+    let disk = CrashDisk::new(vec![0; 2 * BLOCK_NUM * 1024], usize::MAX);
+    let efs = Ext2FileSystem::create_with_block_size(disk.clone(), Arc::new(ZeroTimeProvider), 1024).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    for round in 0..ROUNDS {
+        let (a, b) = (format!("ra{}", round), format!("rb{}", round));
+        root.create(&a, EXT2_S_IFDIR).unwrap();
+        root.create(&b, EXT2_S_IFDIR).unwrap();
+        // each moves a directory into the other, only one of them can
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let renames: Vec<_> = [(a.clone(), b.clone(), "x"), (b, a, "y")]
+            .into_iter()
+            .map(|(name, new_dir, new_name)| {
+                let root = root.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let new_dir = root.find(&new_dir).unwrap();
+                    barrier.wait();
+                    root.rename(&name, &new_dir, new_name)
+                })
+            })
+            .collect();
+        let mut results: Vec<Ext2Result> = renames.into_iter().map(|task| task.join().unwrap()).collect();
+        results.sort_by_key(|res| res.is_err());
+        assert_eq!(results, [Ok(()), Err(Ext2Error::InvalidInput)]);
+    }
+    efs.sync().unwrap();
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!(sorted_ls(&Ext2FileSystem::root_inode(&efs).unwrap()).len(), 2 + ROUNDS);
+}
+
+#[test]
+fn xattrs() {
+    let disk = CrashDisk::new(fresh_image(1024), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let free_blocks = efs.super_block().s_free_blocks_count;
+    let a = root.create("a", EXT2_S_IFREG).unwrap();
+    let b = root.create("b", EXT2_S_IFREG).unwrap();
+    assert_eq!(a.listxattr(), Ok(Vec::new()));
+    assert_eq!(a.getxattr("user.tag"), Err(Ext2Error::NotFound));
+    assert_eq!(a.setxattr("other.tag", b"x"), Err(Ext2Error::Unsupported));
+    assert_eq!(a.setxattr("user.", b"x"), Err(Ext2Error::Unsupported));
+    assert_eq!(a.setxattr(&format!("user.{}", "n".repeat(256)), b"x"), Err(Ext2Error::NameTooLong));
+
+    let attrs: [(&str, &[u8]); 3] = [
+        ("security.selinux", b"system_u:object_r:etc_t:s0\0"),
+        ("user.tag", b"red"),
+        ("system.posix_acl_access", &[2, 0, 0, 0]),
+    ];
+    for (name, value) in attrs {
+        a.setxattr(name, value).unwrap();
+        b.setxattr(name, value).unwrap();
+    }
+    assert_eq!(a.getxattr("user.tag"), Ok(b"red".to_vec()));
+    // listed by name index
+    assert_eq!(a.listxattr().unwrap(), ["user.tag", "system.posix_acl_access", "security.selinux"]);
+    // the same attributes share a block
+    let shared = a.disk_inode().unwrap().xattr_block();
+    assert_ne!(shared, 0);
+    assert_eq!(b.disk_inode().unwrap().xattr_block(), shared);
+    assert_eq!(a.disk_inode().unwrap().i_blocks, 2);
+    efs.sync().unwrap();
+    assert_eq!(efs.super_block().s_free_blocks_count, free_blocks - 1);
+
+    // a shared block is copied before it changes
+    b.setxattr("user.tag", b"blue").unwrap();
+    assert_ne!(b.disk_inode().unwrap().xattr_block(), shared);
+    assert_eq!(a.getxattr("user.tag"), Ok(b"red".to_vec()));
+    assert_eq!(b.getxattr("user.tag"), Ok(b"blue".to_vec()));
+    assert_eq!(a.setxattr("user.big", &[0; 1024]), Err(Ext2Error::NoSpace));
+    assert_eq!(a.listxattr().unwrap().len(), 3);
+    efs.sync().unwrap();
+    assert_eq!(efs.super_block().s_free_blocks_count, free_blocks - 2);
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    assert!(efs.super_block().has_ext_attr());
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let a = root.find("a").unwrap();
+    let b = root.find("b").unwrap();
+    assert_eq!(a.getxattr("security.selinux"), Ok(attrs[0].1.to_vec()));
+    assert_eq!(b.getxattr("user.tag"), Ok(b"blue".to_vec()));
+    // back to the attributes of `a`, `b` shares its block again and frees its own
+    b.setxattr("user.tag", b"red").unwrap();
+    assert_eq!(b.disk_inode().unwrap().xattr_block(), shared);
+    efs.sync().unwrap();
+    assert_eq!(efs.super_block().s_free_blocks_count, free_blocks - 1);
+
+    for (name, _) in attrs {
+        b.removexattr(name).unwrap();
+    }
+    assert_eq!(b.removexattr("user.tag"), Err(Ext2Error::NotFound));
+    assert_eq!(b.disk_inode().unwrap().xattr_block(), 0);
+    assert_eq!(b.disk_inode().unwrap().i_blocks, 0);
+    assert_eq!(a.getxattr("user.tag"), Ok(b"red".to_vec()));
+    drop((a, b));
+    root.rm_file("a").unwrap();
+    root.rm_file("b").unwrap();
+    efs.sync().unwrap();
+    assert_eq!(efs.super_block().s_free_blocks_count, free_blocks);
+    check_consistency(&efs);
+}
+
+/// Writes far past the end leave holes, which read as zeros and take no
+/// blocks, and `fallocate` allocates, punches and zeroes ranges.
+#[test]
+fn sparse_files() {
+    for extents in [false, true] {
+        let disk = CrashDisk::new(vec![0; BLOCK_NUM * 1024], usize::MAX);
+        let options = CreateOptions { block_size: 1024, extents, ..CreateOptions::default() };
+        let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        efs.sync().unwrap();
+        let free_blocks = efs.super_block().s_free_blocks_count;
+
+        // a byte 40 MiB in, on a disk of 4 MiB, takes a single data block
+        let file = root.create("file", EXT2_S_IFREG).unwrap();
+        file.write_at(40 << 20, b"x").unwrap();
+        let index_blocks = if extents { 0 } else { 2 };
+        assert_eq!(file.disk_inode().unwrap().size(), (40 << 20) + 1);
+        assert_eq!(file.disk_inode().unwrap().i_blocks, (1 + index_blocks) * 2);
+        let mut buf = vec![1; 5000];
+        assert_eq!(file.read_at((40 << 20) - 4999, &mut buf), Ok(5000));
+        assert!(buf[..4999].iter().all(|b| *b == 0) && buf[4999] == b'x');
+        file.write_at(0, &pattern(4 * 1024)).unwrap();
+        assert_eq!(file.disk_inode().unwrap().i_blocks, (5 + index_blocks) * 2);
+
+        // punching unmaps whole blocks and zeroes the rest
+        let punch = FallocMode::KEEP_SIZE | FallocMode::PUNCH_HOLE;
+        assert_eq!(file.fallocate(FallocMode::PUNCH_HOLE, 0, 1024), Err(Ext2Error::InvalidInput));
+        file.fallocate(punch, 1000, 2100).unwrap();
+        assert_eq!(file.disk_inode().unwrap().i_blocks, (3 + index_blocks) * 2);
+        let mut expected = pattern(4 * 1024);
+        expected[1000..3100].fill(0);
+        let mut buf = vec![0; 4 * 1024];
+        file.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+        file.fallocate(punch, 40 << 20, 1024).unwrap();
+        assert_eq!(file.disk_inode().unwrap().i_blocks, 2 * 2);
+        // zeroing a range allocates its blocks
+        file.fallocate(FallocMode::ZERO_RANGE, 3500, 1000).unwrap();
+        expected[3500..].fill(0);
+        file.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(file.disk_inode().unwrap().i_blocks, 3 * 2);
+
+        // allocating grows the file unless told not to
+        let small = root.create("small", EXT2_S_IFREG).unwrap();
+        small.write_at(0, b"abc").unwrap();
+        small.fallocate(FallocMode::empty(), 1024, 10 * 1024).unwrap();
+        assert_eq!(small.disk_inode().unwrap().size(), 11 * 1024);
+        assert_eq!(small.disk_inode().unwrap().i_blocks, 11 * 2);
+        let res = small.fallocate(FallocMode::KEEP_SIZE, 0, 20 * 1024);
+        if extents {
+            // blocks past the end are unwritten, until written or truncated away
+            res.unwrap();
+            assert_eq!(small.disk_inode().unwrap().size(), 11 * 1024);
+            assert_eq!(small.disk_inode().unwrap().i_blocks, 20 * 2);
+            small.write_at(15 * 1024 + 10, b"def").unwrap();
+            assert_eq!(small.disk_inode().unwrap().i_blocks, 20 * 2);
+            let mut expected = b"abc".to_vec();
+            expected.resize(15 * 1024 + 10, 0);
+            expected.extend(b"def");
+            assert_eq!(read_all(&small), expected);
+            small.ftruncate(3).unwrap();
+            assert_eq!(small.disk_inode().unwrap().i_blocks, 2);
+        } else {
+            // block pointers past the end would be taken for a corrupted size
+            assert_eq!(res, Err(Ext2Error::Unsupported));
+            assert_eq!(small.disk_inode().unwrap().i_blocks, 11 * 2);
+            // but holes inside the size are filled
+            small.fallocate(punch, 2048, 1024).unwrap();
+            small.fallocate(FallocMode::KEEP_SIZE, 0, 11 * 1024).unwrap();
+            assert_eq!(small.disk_inode().unwrap().size(), 11 * 1024);
+            assert_eq!(small.disk_inode().unwrap().i_blocks, 11 * 2);
+            small.ftruncate(3).unwrap();
+        }
+        small.ftruncate(2000).unwrap();
+        assert_eq!(read_all(&small), [b"abc".as_slice(), &[0; 1997]].concat());
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        efs.sync().unwrap();
+        // which does not count the blocks of unwritten extents as used
+        if !extents {
+            check_consistency(&efs);
+        }
+
+        let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let file = root.find("file").unwrap();
+        file.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+        drop(file);
+        root.rm_file("file").unwrap();
+        root.rm_file("small").unwrap();
+        efs.sync().unwrap();
+        assert_eq!(efs.super_block().s_free_blocks_count, free_blocks);
+    }
+}
+
+#[test]
+fn huge_sparse_files() {
+    for extents in [false, true] {
+        let disk = CrashDisk::new(vec![0; BLOCK_NUM * 4096], usize::MAX);
+        let options = CreateOptions { block_size: 4096, extents, ..CreateOptions::default() };
+        let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        efs.sync().unwrap();
+        let free_blocks = efs.super_block().s_free_blocks_count;
+
+        // the largest file `i_blocks` can count, almost 2 TiB, costs nothing
+        // until written
+        let file = root.create("file", EXT2_S_IFREG).unwrap();
+        let max_size = file.disk_inode().unwrap().max_blocks(4096) as usize * 4096;
+        assert!(max_size > (1 << 40));
+        assert_eq!(file.ftruncate(max_size + 1), Err(Ext2Error::FileTooLarge));
+        file.ftruncate(max_size).unwrap();
+        file.write_at(max_size - 3, b"end").unwrap();
+        file.write_at(1 << 40, b"middle").unwrap();
+        let mut buf = vec![1; 8192];
+        assert_eq!(file.read_at((1 << 40) - 4096, &mut buf), Ok(8192));
+        assert!(buf[..4096].iter().all(|b| *b == 0));
+        assert_eq!(&buf[4096..4102], b"middle");
+        assert!(buf[4102..].iter().all(|b| *b == 0));
+
+        // punching and truncating across the holes
+        let punch = FallocMode::KEEP_SIZE | FallocMode::PUNCH_HOLE;
+        file.fallocate(punch, 4096, (1 << 40) - 4096).unwrap();
+        assert_eq!(file.read_at(max_size - 3, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"end");
+        file.ftruncate((1 << 40) + 3).unwrap();
+        file.ftruncate(max_size).unwrap();
+        assert_eq!(file.read_at(1 << 40, &mut buf[..6]), Ok(6));
+        assert_eq!(&buf[..6], b"mid\0\0\0");
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        efs.sync().unwrap();
+
+        let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let file = root.find("file").unwrap();
+        assert_eq!(file.disk_inode().unwrap().size(), max_size as u64);
+        assert_eq!(file.read_at(1 << 40, &mut buf[..6]), Ok(6));
+        assert_eq!(&buf[..6], b"mid\0\0\0");
+        // nor do its holes cost fsck anything
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        drop(file);
+        root.rm_file("file").unwrap();
+        efs.sync().unwrap();
+        assert_eq!(efs.super_block().s_free_blocks_count, free_blocks);
+    }
+}
+
+#[test]
+fn stats() {
+    // the counters of the image, as dumpe2fs reports them
+    let efs = Ext2FileSystem::open(CrashDisk::new(IMAGE_1K.to_vec(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let stats = efs.stats();
+    assert_eq!(stats.magic, 0xEF53);
+    assert_eq!(stats.block_size, 1024);
+    assert_eq!((stats.blocks, stats.free_blocks, stats.avail_blocks), (640, 186, 186 - 32));
+    assert_eq!((stats.inodes, stats.free_inodes), (160, 41));
+    assert_eq!(stats.fsid, 0x07031536_efadf6f6);
+    assert_eq!(stats.name_len, 255);
+
+    // and they follow allocation
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let file = root.create("file", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &vec![1; 10 * 1024]).unwrap();
+    let used = efs.stats();
+    assert_eq!(used.free_inodes, stats.free_inodes - 1);
+    assert_eq!(used.free_blocks, stats.free_blocks - 10);
+    drop(file);
+    root.rm_file("file").unwrap();
+    efs.sync().unwrap();
+    let freed = efs.stats();
+    assert_eq!((freed.free_blocks, freed.free_inodes), (stats.free_blocks, stats.free_inodes));
+}
+
+#[test]
+fn large_files() {
+    // files of a few GiB on disks of a few MiB, only the written blocks and
+    // their index blocks are allocated
+    for (block_size, extents) in [(1024, false), (4096, false), (1024, true)] {
+        let disk = CrashDisk::new(vec![0; BLOCK_NUM * block_size], usize::MAX);
+        let options = CreateOptions { block_size, extents, ..CreateOptions::default() };
+        let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        efs.sync().unwrap();
+        let free_blocks = efs.super_block().s_free_blocks_count;
+        assert!(!efs.super_block().has_large_file());
+
+        // across 4 GiB, then far into the triple indirect blocks
+        let file = root.create("file", EXT2_S_IFREG).unwrap();
+        file.write_at((4 << 30) - 2, b"abcd").unwrap();
+        assert_eq!(file.disk_inode().unwrap().size(), (4 << 30) + 2);
+        assert!(efs.super_block().has_large_file());
+        file.write_at(6 << 30, b"efgh").unwrap();
+        assert_eq!(file.disk_inode().unwrap().size(), (6 << 30) + 4);
+        let index_blocks = if extents { 0 } else { 5 };
+        assert_eq!(file.disk_inode().unwrap().i_blocks as usize, (3 + index_blocks) * block_size / 512);
+
+        // up to the largest size the block count of the inode can describe
+        let max_blocks = file.disk_inode().unwrap().max_blocks(block_size) as usize;
+        if block_size == 1024 && !extents {
+            assert_eq!(max_blocks, 12 + 256 + 256 * 256 + 256 * 256 * 256);
+        }
+        assert_eq!(file.write_at(max_blocks * block_size, b"x"), Err(Ext2Error::FileTooLarge));
+        assert_eq!(file.ftruncate(max_blocks * block_size + 1), Err(Ext2Error::FileTooLarge));
+        assert_eq!(file.disk_inode().unwrap().size(), (6 << 30) + 4);
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        efs.sync().unwrap();
+        check_consistency(&efs);
+
+        let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        assert!(efs.super_block().has_large_file());
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let file = root.find("file").unwrap();
+        assert_eq!(file.disk_inode().unwrap().size(), (6 << 30) + 4);
+        let mut buf = [1; 8];
+        assert_eq!(file.read_at((4 << 30) - 4, &mut buf), Ok(8));
+        assert_eq!(&buf, b"\0\0abcd\0\0");
+        assert_eq!(file.read_at((6 << 30) - 4, &mut buf), Ok(8));
+        assert_eq!(&buf, b"\0\0\0\0efgh");
+
+        // truncating frees the indirect blocks at every depth, 4 GiB is mapped
+        // through the triple indirect blocks with 1 KiB blocks only
+        file.ftruncate(4 << 30).unwrap();
+        let index_blocks = match (extents, block_size) {
+            (true, _) => 0,
+            (false, 1024) => 3,
+            (false, _) => 2,
+        };
+        assert_eq!(file.disk_inode().unwrap().i_blocks as usize, (1 + index_blocks) * block_size / 512);
+        file.ftruncate(0).unwrap();
+        assert_eq!(file.disk_inode().unwrap().i_blocks, 0);
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        efs.sync().unwrap();
+        assert_eq!(efs.super_block().s_free_blocks_count, free_blocks);
+    }
+
+    // the flag needs the dynamic revision, the image of the old revision is upgraded
+    let disk = CrashDisk::new(IMAGE_4K.to_vec(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let file = root.create("large", EXT2_S_IFREG).unwrap();
+    file.write_at(1 << 31, b"x").unwrap();
+    efs.sync().unwrap();
+    let image = disk.image();
+    let field = |offset: usize| u32::from_le_bytes(image[SUPER_BLOCK_OFFSET + offset..][..4].try_into().unwrap());
+    // s_rev_level, s_first_ino and s_feature_ro_compat
+    assert_eq!((field(76), field(84), field(100)), (1, EXT2_GOOD_OLD_FIRST_INO as u32, 2));
+    assert_eq!(u16::from_le_bytes([image[SUPER_BLOCK_OFFSET + 88], image[SUPER_BLOCK_OFFSET + 89]]), 128);
+    let efs = Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(root.find("large").unwrap().disk_inode().unwrap().size(), (1 << 31) + 1);
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn resize() {
+    // the image has two groups of 512 blocks, the last one partial, and a
+    // group descriptor block with 39 reserved blocks after it
+    let mut image = IMAGE_1K.to_vec();
+    image.resize((1 + 40 * 512) * 1024, 0);
+    let disk = CrashDisk::new(image, usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let stats = efs.stats();
+    assert_eq!(efs.resize(600), Err(Ext2Error::Unsupported));
+    assert_eq!(efs.resize(1 + 41 * 512), Err(Ext2Error::NoSpace));
+
+    // filling up the last group, a third group of 10 blocks is too small
+    efs.resize(1 + 2 * 512 + 10).unwrap();
+    assert_eq!(efs.stats().blocks, 1 + 2 * 512);
+    assert_eq!(efs.stats().free_blocks, stats.free_blocks + 1 + 2 * 512 - 640);
+    assert_eq!(efs.stats().inodes, stats.inodes);
+
+    // 34 groups need a second group descriptor block
+    efs.resize(1 + 33 * 512 + 300).unwrap();
+    let grown = efs.stats();
+    assert_eq!(grown.blocks, 1 + 33 * 512 + 300);
+    assert_eq!(grown.inodes, 34 * 80);
+    assert_eq!(grown.free_inodes, stats.free_inodes + 32 * 80);
+    // root keeps the same share of the blocks
+    let reserved = 32 * (1 + 2 * 512) / 640;
+    assert_eq!(grown.free_blocks - grown.avail_blocks, reserved * grown.blocks / (1 + 2 * 512));
+    let super_block = efs.super_block();
+    assert_eq!((super_block.gdt_blocks(), super_block.reserved_gdt_blocks()), (2, 38));
+    // groups 3, 5, 7, 9, 25 and 27 keep a backup
+    let desc = efs.group_desc(3);
+    assert_eq!(desc.bg_block_bitmap, 1 + 3 * 512 + 1 + 40);
+    assert_eq!(desc.bg_free_blocks_count as usize, 512 - 41 - 2 - 20);
+    let desc = efs.group_desc(33);
+    assert_eq!(desc.bg_block_bitmap, 1 + 33 * 512);
+    assert_eq!(desc.bg_free_blocks_count as usize, 300 - 2 - 20);
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    // the new groups are used
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let file = root.create("large", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &pattern(4 << 20)).unwrap();
+    for i in 0..200 {
+        root.create(&format!("file-{}", i), EXT2_S_IFREG).unwrap();
+    }
+    efs.sync().unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    let image = disk.image();
+    // the backup of group 3 follows the resize
+    let backup = 1 + 3 * 512;
+    let field = |block: usize, offset: usize| u32::from_le_bytes(image[block * 1024 + offset..][..4].try_into().unwrap());
+    assert_eq!(field(backup, 4), grown.blocks);
+    assert_eq!(field(backup + 2, 0), efs.group_desc(32).bg_block_bitmap);
+    let efs = Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    assert_eq!(efs.stats().blocks, grown.blocks);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(read_all(&root.find("large").unwrap()), pattern(4 << 20));
+    assert_eq!(read_all(&root.find("big.bin").unwrap()), pattern(300 * 1024));
+    assert!(root.find("file-199").is_ok());
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    // our own file systems reserve blocks for the table to grow into
+    let disk = CrashDisk::new(vec![0; BLOCK_NUM * 1024], usize::MAX);
+    let options = CreateOptions { block_size: 1024, bytes_per_inode: Some(64 * 1024), ..CreateOptions::default() };
+    let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+    let super_block = efs.super_block();
+    assert_eq!((super_block.gdt_blocks(), super_block.reserved_gdt_blocks()), (1, 15));
+    populate(&efs);
+    efs.sync().unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    drop(efs);
+    let mut image = disk.image();
+    let block_count = 1 + 33 * 8192 + 1000;
+    image.resize(block_count * 1024, 0);
+    let disk = CrashDisk::new(image, usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    efs.resize(block_count).unwrap();
+    let super_block = efs.super_block();
+    assert_eq!(super_block.s_blocks_count as usize, block_count);
+    assert_eq!((super_block.gdt_blocks(), super_block.reserved_gdt_blocks()), (2, 14));
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let file = root.create("large", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &pattern(8 << 20)).unwrap();
+    efs.sync().unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    assert_eq!(efs.stats().blocks as usize, block_count);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(read_all(&root.find("large").unwrap()), pattern(8 << 20));
+    check_contents(&root.find("data").unwrap(), 3);
+
+    // without sparse super blocks every new group keeps a backup, and the
+    // table has to fit in its blocks without reserved ones
+    let disk = CrashDisk::new(vec![0; BLOCK_NUM * 1024], usize::MAX);
+    let options = CreateOptions { block_size: 1024, sparse_super: false, ..CreateOptions::default() };
+    let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+    populate(&efs);
+    efs.sync().unwrap();
+    drop(efs);
+    let mut image = disk.image();
+    image.resize((1 + 3 * 8192) * 1024, 0);
+    let disk = CrashDisk::new(image, usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    efs.resize(1 + 3 * 8192).unwrap();
+    assert_eq!(efs.stats().blocks, 1 + 3 * 8192);
+    assert_eq!(efs.group_desc(2).bg_block_bitmap, 1 + 2 * 8192 + 2);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let file = root.create("large", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &pattern(8 << 20)).unwrap();
+    efs.sync().unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(read_all(&root.find("large").unwrap()), pattern(8 << 20));
+    check_contents(&root.find("data").unwrap(), 3);
+}
+
+#[test]
+fn orphans() {
+    let disk = CrashDisk::new(fresh_image(1024), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let stats = efs.stats();
+
+    // a removed file lives on while it is open
+    let file = root.create("file", EXT2_S_IFREG).unwrap();
+    file.write_at(0, &pattern(10 * 1024)).unwrap();
+    let other = root.find("file").unwrap();
+    let inode_id = file.inode_id().unwrap();
+    root.rm_file("file").unwrap();
+    assert!(root.find("file").is_err());
+    assert!(Ext2FileSystem::get_inode_cache(&efs, inode_id).is_err());
+    assert_eq!(root.link("again", inode_id), Err(Ext2Error::NotFound));
+    assert_eq!(read_all(&other), pattern(10 * 1024));
+    file.write_at(10 * 1024, b"tail").unwrap();
+    assert_eq!(file.disk_inode().unwrap().i_links_count, 0);
+    assert_eq!(efs.super_block().last_orphan(), inode_id as u32);
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    drop(file);
+    assert_eq!(&read_all(&other)[10 * 1024..], b"tail");
+    drop(other);
+    efs.sync().unwrap();
+    assert_eq!(efs.super_block().last_orphan(), 0);
+    let freed = efs.stats();
+    assert_eq!((freed.free_blocks, freed.free_inodes), (stats.free_blocks, stats.free_inodes));
+
+    // orphans left by a crash are freed on the next open, wherever they are
+    // on the list
+    let files: Vec<Inode> = (0..3).map(|i| {
+        let file = root.create(&format!("file-{}", i), EXT2_S_IFREG).unwrap();
+        file.write_at(0, &pattern(5 * 1024)).unwrap();
+        file
+    }).collect();
+    for i in 0..3 {
+        root.rm_file(&format!("file-{}", i)).unwrap();
+    }
+    drop(files.into_iter().nth(1));
+    efs.sync().unwrap();
+    let image = disk.image();
+    let efs = Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    assert_eq!(efs.super_block().last_orphan(), 0);
+    let reopened = efs.stats();
+    assert_eq!((reopened.free_blocks, reopened.free_inodes), (stats.free_blocks, stats.free_inodes));
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn mke2fs_csum_image() {
+    let disk = CrashDisk::new(IMAGE_CSUM.to_vec(), usize::MAX);
+    let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+    assert!(!efs.is_read_only() && efs.super_block().has_metadata_csum());
+    // the groups mke2fs left uninitialized are set up on mount
+    for group_id in 1..3 {
+        assert_eq!(efs.group_desc(group_id).bg_flags, 0);
+    }
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(sorted_ls(&root), [".", "..", "hello.txt", "lost+found", "many", "sparse.bin"]);
+    let hello = root.find("hello.txt").unwrap();
+    assert_eq!(read_all(&hello), b"Hello, checksums!\n");
+    let sparse = root.find("sparse.bin").unwrap();
+    assert_eq!(read_all(&sparse), sparse_contents(48));
+    assert_eq!(extents_of(&efs, &sparse).1, 1);
+    let many = root.find("many").unwrap();
+    assert!(many.disk_inode().unwrap().i_flags & EXT2_INDEX_FL != 0);
+
+    // touch every kind of checksummed metadata, with inodes and blocks of
+    // the groups that were uninitialized
+    for i in (0..100).step_by(2) {
+        many.rm_file(&format!("file-with-a-long-name-{:02}", i)).unwrap();
+    }
+    for i in 0..200 {
+        many.create(&format!("added-{}", i), EXT2_S_IFREG).unwrap();
+    }
+    assert!(many.disk_inode().unwrap().i_flags & EXT2_INDEX_FL != 0);
+    sparse.ftruncate(21 * 1024).unwrap();
+    let big = root.create("big.bin", EXT2_S_IFREG).unwrap();
+    big.write_at(0, &pattern(600 * 1024)).unwrap();
+    hello.setxattr("user.tag", b"checked").unwrap();
+    efs.sync().unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let many = root.find("many").unwrap();
+    assert_eq!(many.ls().unwrap().len(), 2 + 50 + 200);
+    assert!(many.find("added-199").is_ok() && many.find("file-with-a-long-name-01").is_ok());
+    assert_eq!(read_all(&root.find("sparse.bin").unwrap()), sparse_contents(21));
+    assert_eq!(read_all(&root.find("big.bin").unwrap()), pattern(600 * 1024));
+    assert_eq!(root.find("hello.txt").unwrap().getxattr("user.tag"), Ok(b"checked".to_vec()));
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn metadata_csum() {
+    for (block_size, extents) in [(1024, true), (4096, false)] {
+        let disk = CrashDisk::new(vec![0; BLOCK_NUM * block_size], usize::MAX);
+        let options = CreateOptions { block_size, extents, metadata_csum: true, ..CreateOptions::default() };
+        let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+        populate(&efs);
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let dir = root.create("many", EXT2_S_IFDIR).unwrap();
+        let name = |i: usize| format!("a-fairly-long-file-name-{:05}", i);
+        for i in 0..3000 {
+            dir.create(&name(i), EXT2_S_IFREG).unwrap();
+        }
+        for i in (0..3000).step_by(3) {
+            dir.rm_file(&name(i)).unwrap();
+        }
+        // files growing in turns, with extent tree blocks
+        let a = root.create("a", EXT2_S_IFREG).unwrap();
+        let b = root.create("b", EXT2_S_IFREG).unwrap();
+        for i in 0..200 {
+            a.write_at(i * block_size, &[i as u8; 16]).unwrap();
+            b.write_at(i * block_size, &[!(i as u8); 16]).unwrap();
+        }
+        if extents {
+            assert!(extents_of(&efs, &a).1 > 0);
+        }
+        a.ftruncate(150 * block_size).unwrap();
+        drop(b);
+        root.rm_file("b").unwrap();
+        a.setxattr("user.tag", b"checked").unwrap();
+        efs.sync().unwrap();
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let dir = root.find("many").unwrap();
+        assert!(dir.disk_inode().unwrap().i_flags & EXT2_INDEX_FL != 0);
+        assert_eq!(dir.ls().unwrap().len(), 2 + 2000);
+        for i in 0..3000 {
+            assert_eq!(dir.find(&name(i)).is_ok(), i % 3 != 0, "{}", name(i));
+        }
+        let a = root.find("a").unwrap();
+        let mut buf = [0; 16];
+        a.read_at(149 * block_size, &mut buf).unwrap();
+        assert_eq!(buf, [149; 16]);
+        assert_eq!(a.getxattr("user.tag"), Ok(b"checked".to_vec()));
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}
+
+#[test]
+fn metadata_csum_errors() {
+    let disk = CrashDisk::new(vec![0; BLOCK_NUM * 1024], usize::MAX);
+    let options = CreateOptions { block_size: 1024, metadata_csum: true, ..CreateOptions::default() };
+    let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+    populate(&efs);
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let data = root.find("data").unwrap();
+    let (inode_block, inode_offset) = efs.get_disk_inode_pos(data.inode_id().unwrap() as u32).unwrap();
+    let dir_block = mapped_blocks(&efs, &root.find("dir").unwrap(), false)[0];
+    let image = disk.image();
+    let open = |pos: usize| {
+        let mut image = image.clone();
+        image[pos] ^= 1;
+        Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider))
+    };
+    assert!(open(0).is_ok());
+    // the mount time in the super block
+    assert_eq!(open(SUPER_BLOCK_OFFSET + 44).err(), Some(Ext2Error::Corrupted));
+    // the free blocks of the first group descriptor
+    assert_eq!(open(2 * 1024 + 12).err(), Some(Ext2Error::Corrupted));
+    // the modification time of an inode
+    let efs = open(inode_block as usize * 1024 + inode_offset + 16).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    assert_eq!(root.find("data").err(), Some(Ext2Error::Corrupted));
+    assert!(root.find("dir").is_ok());
+    // a name in a directory
+    let efs = open(dir_block as usize * 1024 + 8).unwrap();
+    let dir = Ext2FileSystem::root_inode(&efs).unwrap().find("dir").unwrap();
+    assert_eq!(dir.find("file").err(), Some(Ext2Error::Corrupted));
+    assert_eq!(dir.ls().err(), Some(Ext2Error::Corrupted));
+}
+
+#[test]
+fn backup_super_blocks() {
+    let field16 = |image: &[u8], block: usize, offset: usize| {
+        u16::from_le_bytes(image[block * 1024 + offset..][..2].try_into().unwrap())
+    };
+    let field32 = |image: &[u8], block: usize, offset: usize| {
+        u32::from_le_bytes(image[block * 1024 + offset..][..4].try_into().unwrap())
+    };
+    // four groups of 1K blocks, sparse backups in groups 1 and 3
+    let group_start = |group_id: usize| 1 + group_id * 8192;
+    for metadata_csum in [false, true] {
+        let disk = CrashDisk::new(vec![0; group_start(4) * 1024], usize::MAX);
+        let options = CreateOptions { block_size: 1024, metadata_csum, ..CreateOptions::default() };
+        let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+        populate(&efs);
+        let synced = disk.image();
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        root.create("late", EXT2_S_IFREG).unwrap().write_at(0, &pattern(100 * 1024)).unwrap();
+        efs.sync().unwrap();
+        let stats = efs.stats();
+
+        let image = disk.image();
+        for group_id in [1, 3] {
+            assert_eq!(field16(&image, group_start(group_id), 56), 0xEF53);
+            assert_eq!(field16(&image, group_start(group_id), 90), group_id as u16);
+            // free blocks follow the last sync
+            assert_eq!(field32(&image, group_start(group_id), 12), stats.free_blocks);
+        }
+        assert_ne!(field16(&image, group_start(2), 56), 0xEF53);
+        assert_eq!(
+            Ext2FileSystem::open_with_superblock(CrashDisk::new(image.clone(), usize::MAX), Arc::new(ZeroTimeProvider), group_start(2)).err(),
+            Some(Ext2Error::Corrupted)
+        );
+
+        // a damaged primary super block falls back to the first backup, and
+        // is written again from it
+        let mut damaged = image.clone();
+        damaged[SUPER_BLOCK_OFFSET + 56] ^= 1;
+        let disk = CrashDisk::new(damaged, usize::MAX);
+        let efs = Ext2FileSystem::open(disk.clone(), Arc::new(ZeroTimeProvider)).unwrap();
+        assert_eq!(efs.stats().free_blocks, stats.free_blocks);
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        check_contents(&root.find("data").unwrap(), 3);
+        assert_eq!(read_all(&root.find("late").unwrap()), pattern(100 * 1024));
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        efs.sync().unwrap();
+        assert_eq!(field16(&disk.image(), 1, 56), 0xEF53);
+        assert_eq!(field16(&disk.image(), 1, 90), 0);
+
+        // a backup older than the bitmaps has its counts redone
+        let mut stale = image.clone();
+        let backup = group_start(3) * 1024;
+        stale[backup..backup + 2 * 1024].copy_from_slice(&synced[backup..backup + 2 * 1024]);
+        assert_ne!(field32(&stale, group_start(3), 12), stats.free_blocks);
+        let disk = CrashDisk::new(stale, usize::MAX);
+        let efs = Ext2FileSystem::open_with_superblock(disk.clone(), Arc::new(ZeroTimeProvider), group_start(3)).unwrap();
+        assert_eq!(efs.stats().free_blocks, stats.free_blocks);
+        assert_eq!(efs.stats().free_inodes, stats.free_inodes);
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        efs.sync().unwrap();
+        assert_eq!(field32(&disk.image(), 1, 12), stats.free_blocks);
+    }
+
+    // mke2fs keeps a backup at the start of the second group, in block 513
+    let mut image = IMAGE_1K.to_vec();
+    image[SUPER_BLOCK_OFFSET + 56] ^= 1;
+    for efs in [
+        Ext2FileSystem::open(CrashDisk::new(image, usize::MAX), Arc::new(ZeroTimeProvider)).unwrap(),
+        Ext2FileSystem::open_with_superblock(CrashDisk::new(IMAGE_1K.to_vec(), usize::MAX), Arc::new(ZeroTimeProvider), 513).unwrap(),
+    ] {
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        assert_eq!(read_all(&root.find("hello.txt").unwrap()), b"Hello, ext2!\n");
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}
+
+/// Four groups of 1K blocks with extents, so that runs are easy to count
+fn four_groups() -> (Arc<CrashDisk>, Arc<Ext2FileSystem>) {
+    let disk = CrashDisk::new(vec![0; (1 + 4 * 8192) * 1024], usize::MAX);
+    let options = CreateOptions { block_size: 1024, extents: true, ..CreateOptions::default() };
+    let efs = Ext2FileSystem::create_with_options(disk.clone(), Arc::new(ZeroTimeProvider), &options).unwrap();
+    (disk, efs)
+}
+
+#[test]
+fn alloc_policy() {
+    let (disk, efs) = four_groups();
+    let super_block = efs.super_block();
+    let inode_group = |inode: &Inode| (inode.inode_id().unwrap() - 1) / super_block.s_inodes_per_group as usize;
+    let block_group = |block: u32| ((block - 1) / super_block.s_blocks_per_group) as usize;
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+
+    // directories in the root are spread, the others stay near their parent
+    let dirs: Vec<Inode> = (0..3).map(|i| root.create(&format!("dir{}", i), EXT2_S_IFDIR).unwrap()).collect();
+    let groups: BTreeSet<usize> = dirs.iter().map(inode_group).collect();
+    assert_eq!(groups.len(), 3);
+    assert!(!groups.contains(&0));
+    let sub = dirs[0].create("sub", EXT2_S_IFDIR).unwrap();
+    assert_eq!(inode_group(&sub), inode_group(&dirs[0]));
+    // files and their data stay in the group of their directory
+    for dir in dirs.iter() {
+        let file = dir.create("file", EXT2_S_IFREG).unwrap();
+        file.write_at(0, &pattern(20 * 1024)).unwrap();
+        assert_eq!(inode_group(&file), inode_group(dir));
+        let (extents, _) = extents_of(&efs, &file);
+        assert_eq!(extents.len(), 1);
+        assert_eq!(block_group(extents[0].start), inode_group(dir));
+    }
+
+    // files appending in turns keep their data contiguous in their windows
+    let a = dirs[1].create("a", EXT2_S_IFREG).unwrap();
+    let b = dirs[1].create("b", EXT2_S_IFREG).unwrap();
+    for i in 0..200 {
+        a.write_at(i * 1024, &[i as u8; 1024]).unwrap();
+        b.write_at(i * 1024, &[!(i as u8); 1024]).unwrap();
+    }
+    for file in [&a, &b] {
+        let (extents, _) = extents_of(&efs, file);
+        assert!(extents.len() < 10, "{} extents", extents.len());
+    }
+    // windows are not allocated, and are given up when space runs out
+    let free_blocks = efs.stats().free_blocks as usize;
+    let big = root.create("big", EXT2_S_IFREG).unwrap();
+    big.write_at(0, &pattern(free_blocks * 1024 / 2)).unwrap();
+    let left = efs.stats().free_blocks as usize;
+    let rest = dirs[2].create("rest", EXT2_S_IFREG).unwrap();
+    assert_eq!(rest.write_at(0, &pattern((left + 1) * 1024)), Err(Ext2Error::NoSpace));
+    // the tree of a file with many runs takes a few blocks too
+    rest.write_at(0, &pattern((left - 8) * 1024)).unwrap();
+    efs.sync().unwrap();
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    let efs = Ext2FileSystem::open(CrashDisk::new(disk.image(), usize::MAX), Arc::new(ZeroTimeProvider)).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let a = root.find("dir1").unwrap().find("a").unwrap();
+    assert_eq!(read_all(&a), (0..200).flat_map(|i| [i as u8; 1024]).collect::<Vec<u8>>());
+    let report = fsck::check(&efs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+#[test]
+fn bench_fragmentation() {
+    const DIRS: usize = 4;
+    const FILES: usize = 8;
+    const ROUNDS: usize = 64;
+
+    let policies: [(&str, Arc<dyn AllocPolicy>); 2] = [
+        ("FirstFitPolicy", Arc::new(FirstFitPolicy)),
+        ("LocalityPolicy", Arc::new(LocalityPolicy::default())),
+    ];
+    let mut runs = Vec::new();
+    for (name, policy) in policies {
+        let (_disk, efs) = four_groups();
+        efs.set_alloc_policy(policy);
+        let root = Ext2FileSystem::root_inode(&efs).unwrap();
+        let mut files = Vec::new();
+        for i in 0..DIRS {
+            let dir = root.create(&format!("dir{}", i), EXT2_S_IFDIR).unwrap();
+            for j in 0..FILES {
+                files.push(dir.create(&format!("file{}", j), EXT2_S_IFREG).unwrap());
+            }
+        }
+        // files growing together, a few blocks at a time
+        let mut sizes = vec![0; files.len()];
+        let t0 = std::time::Instant::now();
+        for round in 0..ROUNDS {
+            for (i, file) in files.iter().enumerate() {
+                let len = (1 + (i + round) % 4) * 1024;
+                file.write_at(sizes[i], &pattern(len)).unwrap();
+                sizes[i] += len;
+            }
+        }
+        let t1 = std::time::Instant::now();
+        let extents: usize = files.iter().map(|file| extents_of(&efs, file).0.len()).sum();
+        println!(
+            "  {}: {:.1} runs/file, {:?}/write",
+            name,
+            extents as f64 / files.len() as f64,
+            (t1 - t0) / (ROUNDS * files.len()) as u32
+        );
+        efs.sync().unwrap();
+        let report = fsck::check(&efs, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        runs.push(extents);
+    }
+    assert!(runs[1] * 4 < runs[0], "{:?}", runs);
+}
@@ -1,4 +1,4 @@
-pub trait TimeProvider {
+pub trait TimeProvider: Send + Sync {
     fn get_current_time(&self) -> u32;
 }
 
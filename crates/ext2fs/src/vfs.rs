@@ -1,38 +1,94 @@
-use core::mem::size_of;
 use log::*;
 
-use crate::mutex::SpinMutex;
+use crate::mutex::{SleepRwMutex, SpinMutex};
+use crate::readahead::ReadAhead;
+use crate::block_map::BlockMap;
+use crate::xattr::{XattrBlock, XattrEntry};
+use crate::error::{Ext2Error, Ext2Result};
+use crate::csum::{self, Checksum, DIR_TAIL_SIZE, DX_TAIL_SIZE};
 
 use super::{
     DiskInode, 
-    Ext2FileSystem, layout::{
-        MAX_NAME_LEN, DirEntryHead, EXT2_FT_UNKNOWN, EXT2_FT_DIR, EXT2_FT_REG_FILE,
-        DEFAULT_IMODE, EXT2_S_IFDIR, EXT2_S_IFLNK, IMODE
+    Ext2FileSystem, efs::FsOp, config::EXT2_ROOT_INO, htree::{
+        self, DxEntries, DxEntry, DxRoot, HashInfo, LeafEntry
+    }, layout::{
+        DirEntryHead, EXT2_FT_UNKNOWN, EXT2_FT_DIR, EXT2_FT_REG_FILE, EXT2_FT_SYMLINK,
+        DEFAULT_IMODE, EXT2_S_IFDIR, EXT2_S_IFLNK, EXT2_S_IFREG, EXT2_INDEX_FL, FAST_SYMLINK_MAX, IMODE,
+        MAX_NAME_LEN, FallocMode, LARGE_FILE_SIZE
     }
 };
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::ops::Range;
 
+type DataBlock = [u8];
+/// A directory entry and its offset in the directory
+type DirEntryPos = (DirEntryHead, usize);
+
+/// A handle of an inode, which reads ahead on its own
 pub struct Inode {
     file_type: u8,
-    inner: Arc<SpinMutex<InodeCache>>
+    inner: Arc<SleepRwMutex<InodeCache>>,
+    read_ahead: SpinMutex<ReadAhead>
+}
+
+impl Clone for Inode {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
 }
 
 impl Inode {
-    pub fn new(inner: Arc<SpinMutex<InodeCache>>) -> Inode {
-        let file_type = inner.lock().file_type();
+    pub fn new(inner: Arc<SleepRwMutex<InodeCache>>) -> Inode {
+        let file_type = inner.shared_lock().file_type();
         Self {
             file_type,
-            inner
+            inner,
+            read_ahead: SpinMutex::new(ReadAhead::default())
+        }
+    }
+
+    /// Start an operation on the file system of this inode
+    fn begin_op(&self) -> FsOp {
+        let fs = self.inner.shared_lock().fs.clone();
+        Ext2FileSystem::begin_op(&fs)
+    }
+
+    /// Start an operation that modifies the file system, fails if it is read-only
+    fn begin_write(&self) -> Ext2Result<FsOp> {
+        let fs = self.inner.shared_lock().fs.clone();
+        if fs.is_read_only() {
+            Err(Ext2Error::ReadOnly)
+        } else {
+            Ok(Ext2FileSystem::begin_op(&fs))
         }
     }
 
-    fn access(&self) -> Option<&Arc<SpinMutex<InodeCache>>> {
-        if self.inner.lock().valid {
-            Some(&self.inner)
+    fn access(&self) -> Ext2Result<&Arc<SleepRwMutex<InodeCache>>> {
+        if self.inner.shared_lock().valid {
+            Ok(&self.inner)
         } else {
-            None
+            Err(Ext2Error::NotFound)
+        }
+    }
+
+    /// Fail unless this is a regular file
+    fn expect_file(&self) -> Ext2Result {
+        match self.file_type {
+            EXT2_FT_REG_FILE => Ok(()),
+            EXT2_FT_DIR => Err(Ext2Error::IsADirectory),
+            _ => Err(Ext2Error::InvalidInput),
+        }
+    }
+
+    /// Fail unless this is a directory
+    fn expect_dir(&self) -> Ext2Result {
+        if self.file_type == EXT2_FT_DIR {
+            Ok(())
+        } else {
+            Err(Ext2Error::NotADirectory)
         }
     }
 
@@ -42,117 +98,236 @@ impl Inode {
         self.file_type
     }
 
-    pub fn inode_id(&self) -> Option<usize> {
-        Some(self.access()?.lock().inode_id)
+    pub fn inode_id(&self) -> Ext2Result<usize> {
+        Ok(self.access()?.shared_lock().inode_id)
     }
 
-    pub fn chown(&self, uid: Option<usize>, gid:Option<usize>) -> Option<()> {
-        Some(self.access()?.lock().chown(uid, gid))
+    pub fn chown(&self, uid: Option<usize>, gid:Option<usize>) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.access()?.unique_lock().chown(uid, gid)
     }
 
-    pub fn chmod(&self, access: IMODE) -> Option<()> {
-        Some(self.access()?.lock().chmod(access))
+    pub fn chmod(&self, access: IMODE) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.access()?.unique_lock().chmod(access)
     }
 
-    pub fn disk_inode(&self) -> Option<DiskInode> {
-        Some(self.access()?.lock().disk_inode())
+    /// Set the access and modification times, in seconds since the epoch
+    pub fn set_times(&self, atime: Option<u32>, mtime: Option<u32>) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.access()?.unique_lock().set_times(atime, mtime)
+    }
+
+    pub fn disk_inode(&self) -> Ext2Result<DiskInode> {
+        self.access()?.shared_lock().disk_inode()
+    }
+
+    /// Write the contents and metadata of this inode to disk, committing the
+    /// running transaction if it holds any of the metadata
+    pub fn fsync(&self) -> Ext2Result {
+        let lk = self.access()?.shared_lock();
+        let fs = lk.fs.clone();
+        let commit = lk.write_blocks()?;
+        // a commit waits for operations in progress, which may wait for us
+        drop(lk);
+        if commit {
+            fs.commit_idle()?;
+        }
+        Ok(())
+    }
+
+    // extended attributes
+
+    /// Value of the extended attribute `name`, such as `user.comment`
+    pub fn getxattr(&self, name: &str) -> Ext2Result<Vec<u8>> {
+        let _op = self.begin_op();
+        self.access()?.shared_lock().getxattr(name)
+    }
+
+    /// Set the extended attribute `name`, creating it if it does not exist
+    pub fn setxattr(&self, name: &str, value: &[u8]) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.access()?.unique_lock().setxattr(name, value)
+    }
+
+    /// Names of the extended attributes, with their prefixes
+    pub fn listxattr(&self) -> Ext2Result<Vec<String>> {
+        let _op = self.begin_op();
+        self.access()?.shared_lock().listxattr()
+    }
+
+    pub fn removexattr(&self, name: &str) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.access()?.unique_lock().removexattr(name)
     }
 
     // file operation
 
-    pub fn ftruncate(&self, new_size: usize) -> Option<bool> {
-        Some(self.access()?.lock().ftruncate(new_size as _))
-        
+    pub fn ftruncate(&self, new_size: usize) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.expect_file()?;
+        self.access()?.unique_lock().ftruncate(new_size)
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
-        let lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_REG_FILE {
-            None
-        } else {
-            Some(lk.read_at(offset, buf))
-        }
+    /// Allocate, punch or zero the `len` bytes from `offset` on, as `mode` says.
+    ///
+    /// Blocks past the size are only kept for files mapped by extents, as
+    /// unwritten extents: for other files `KEEP_SIZE` past the end fails with
+    /// [`Ext2Error::Unsupported`], since e2fsck would take their block
+    /// pointers for a wrong size.
+    pub fn fallocate(&self, mode: FallocMode, offset: usize, len: usize) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.expect_file()?;
+        self.access()?.unique_lock().fallocate(mode, offset, len)
     }
 
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Option<usize> {
-        let mut lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_REG_FILE {
-            None
-        } else {
-            Some(lk.write_at(offset, buf))
-        }
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Ext2Result<usize> {
+        let _op = self.begin_op();
+        let lk = self.access()?.shared_lock();
+        self.expect_file()?;
+        let blocks = self.read_ahead.lock().on_read(offset, buf.len(), lk.fs.block_size());
+        lk.load_blocks(blocks)?;
+        lk.read_at(offset, buf)
     }
 
-    pub fn append(&self, buf: &[u8]) ->Option<usize> {
-        let mut lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_REG_FILE {
-            None
-        } else {
-            Some(lk.append(buf))
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Ext2Result<usize> {
+        let _op = self.begin_write()?;
+        let mut lk = self.access()?.unique_lock();
+        self.expect_file()?;
+        lk.write_at(offset, buf)
+    }
+
+    /// Target of a symlink
+    pub fn readlink(&self) -> Ext2Result<String> {
+        let _op = self.begin_op();
+        let lk = self.access()?.shared_lock();
+        if self.file_type != EXT2_FT_SYMLINK {
+            return Err(Ext2Error::InvalidInput);
         }
+        lk.readlink()
+    }
+
+    pub fn append(&self, buf: &[u8]) -> Ext2Result<usize> {
+        let _op = self.begin_write()?;
+        let mut lk = self.access()?.unique_lock();
+        self.expect_file()?;
+        lk.append(buf)
     }
 
     // dir operation
 
-    pub fn find(&self, name: &str) -> Option<Self> {
-        let lk = self.access()?.lock();
-        lk.find(name)
-            .map(|inner| Self::new(inner))
+    pub fn find(&self, name: &str) -> Ext2Result<Self> {
+        self.expect_dir()?;
+        // release our lock first: "." and ".." may resolve to this inode
+        let inner = self.access()?.shared_lock().find(name)?;
+        Ok(Self::new(inner))
     }
 
-    pub fn create(&self, name: &str, file_type: u16) -> Option<Self> {
-        let mut lk = self.access()?.lock();
-        lk.create(name, file_type)
-            .map(|inner| Self::new(inner))
+    pub fn create(&self, name: &str, file_type: u16) -> Ext2Result<Self> {
+        let _op = self.begin_write()?;
+        self.expect_dir()?;
+        let mut lk = self.access()?.unique_lock();
+        lk.create(name, file_type).map(Self::new)
     }
 
-    pub fn ls(&self) -> Option<Vec<String>> {
-        let lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_DIR {
-            None
-        } else {
-            Some(lk.ls())
-        }
+    pub fn ls(&self) -> Ext2Result<Vec<String>> {
+        let lk = self.access()?.shared_lock();
+        self.expect_dir()?;
+        lk.ls()
     }
 
-    pub fn is_empty_dir(&self) -> Option<bool> {
-        let lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_DIR {
-            None
-        } else {
-            Some(lk.is_empty_dir())
-        }
+    /// At most `max` entries of this directory from the `start`-th one, with
+    /// their types as `EXT2_FT_*`
+    pub fn read_dir(&self, start: usize, max: usize) -> Ext2Result<Vec<(String, u8)>> {
+        let lk = self.access()?.shared_lock();
+        self.expect_dir()?;
+        lk.read_dir(start, max)
     }
 
-    pub fn link(&self, name: &str, inode_id: usize) -> Option<bool> {
-        let mut lk = self.access()?.lock();
-        Some(lk.link(name, inode_id))
+    pub fn is_empty_dir(&self) -> Ext2Result<bool> {
+        let lk = self.access()?.shared_lock();
+        self.expect_dir()?;
+        lk.is_empty_dir()
     }
 
-    pub fn symlink(&self, name: &str, path_name: &str) -> Option<bool> {
-        let mut lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_DIR {
-            None
-        } else {
-            Some(lk.symlink(name, path_name))
-        }
+    pub fn link(&self, name: &str, inode_id: usize) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.expect_dir()?;
+        let mut lk = self.access()?.unique_lock();
+        lk.link(name, inode_id)
     }
 
-    pub fn rm_file(&self, file_name: &str) -> Option<bool> {
-        let mut lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_DIR {
-            None
-        } else {
-            Some(lk.unlink(file_name, EXT2_FT_REG_FILE, false))
-        }
+    pub fn symlink(&self, name: &str, path_name: &str) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.expect_dir()?;
+        let mut lk = self.access()?.unique_lock();
+        lk.symlink(name, path_name)
     }
 
-    pub fn rm_dir(&self, dir_name: &str, recursive: bool) -> Option<bool> {
-        let mut lk = self.access()?.lock();
-        if self.file_type != EXT2_FT_DIR {
-            None
-        } else {
-            Some(lk.unlink(dir_name, EXT2_FT_DIR, recursive))
+    /// Remove an entry that is not a directory
+    pub fn rm_file(&self, file_name: &str) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.expect_dir()?;
+        let mut lk = self.access()?.unique_lock();
+        lk.unlink(file_name, EXT2_FT_REG_FILE, false)
+    }
+
+    pub fn rm_dir(&self, dir_name: &str, recursive: bool) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.expect_dir()?;
+        let mut lk = self.access()?.unique_lock();
+        lk.unlink(dir_name, EXT2_FT_DIR, recursive)
+    }
+
+    /// Move the entry `name` of this directory to `new_name` in `new_dir`,
+    /// replacing the file or empty directory `new_name` refers to.
+    /// A directory can not be moved into itself or its subdirectories.
+    pub fn rename(&self, name: &str, new_dir: &Inode, new_name: &str) -> Ext2Result {
+        let _op = self.begin_write()?;
+        self.expect_dir()?;
+        new_dir.expect_dir()?;
+        if Arc::ptr_eq(&self.inner, &new_dir.inner) {
+            let mut lk = self.access()?.unique_lock();
+            return lk.rename(name, None, new_name);
+        }
+
+        // renames between directories take turns, so that no other one moves
+        // the source above the new directory between the check and the move
+        let fs = self.access()?.shared_lock().fs.clone();
+        let _rename = fs.rename_lock.lock();
+        loop {
+            let src = self.find(name)?;
+            let src_id = src.inode_id()?;
+            if src.file_type == EXT2_FT_DIR {
+                // walk up from the new directory, which must not be below the source
+                let mut cur = new_dir.clone();
+                loop {
+                    let cur_id = cur.inode_id()?;
+                    if cur_id == src_id {
+                        return Err(Ext2Error::InvalidInput);
+                    }
+                    if cur_id == EXT2_ROOT_INO {
+                        break;
+                    }
+                    cur = cur.find("..")?;
+                }
+            }
+            // lock the directories in the order of their inode numbers, so that
+            // two renames between them in opposite directions can not deadlock
+            let (mut lk, mut new_lk) = if self.inode_id()? < new_dir.inode_id()? {
+                let lk = self.access()?.unique_lock();
+                (lk, new_dir.access()?.unique_lock())
+            } else {
+                let new_lk = new_dir.access()?.unique_lock();
+                (self.access()?.unique_lock(), new_lk)
+            };
+            // the entry may have been replaced by an unlink and a create meanwhile
+            match lk.get_inode_id(name)? {
+                Some(((de, _), _)) if de.inode as usize == src_id => {
+                    return lk.rename(name, Some(&mut new_lk), new_name);
+                }
+                _ => continue,
+            }
         }
     }
 
@@ -168,8 +343,10 @@ pub struct InodeCache {
     // cache part
     file_type: u8,
     size: usize,
-    blocks: Vec<u32>,
+    blocks: BlockMap,
     pub valid: bool,
+    /// The last link is gone, the inode is freed once the cache is dropped
+    orphan: bool,
 }
 
 impl InodeCache {
@@ -178,7 +355,7 @@ impl InodeCache {
         block_id: usize,
         block_offset: usize,
         fs: Arc<Ext2FileSystem>
-    ) -> Self {
+    ) -> Ext2Result<Self> {
         let mut inode = Self {
             inode_id,
             block_id,
@@ -186,319 +363,632 @@ impl InodeCache {
             fs,
             file_type: EXT2_FT_UNKNOWN,
             size: 0,
-            blocks: Vec::new(),
-            valid: true
+            blocks: BlockMap::default(),
+            valid: true,
+            orphan: false
         };
-        inode.read_cache();
-        inode
+        inode.read_cache()?;
+        Ok(inode)
     }
 
     /// Call a function over a disk inode to read it
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
-        let inode_block = self.fs.manager.lock().get_block_cache(self.block_id);
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> Ext2Result<V> {
+        let inode_block = self.fs.manager.get_block_cache(self.block_id)?;
         let ret = inode_block.lock()
             .read(self.block_offset, f);
-        self.fs.manager.lock().release_block(inode_block);
-        ret
+        self.fs.manager.release_block(inode_block);
+        Ok(ret)
     }
     /// Call a function over a disk inode to modify it
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
-        let inode_block = self.fs.manager.lock().get_block_cache(self.block_id);
-        let ret = inode_block.lock()
-            .modify(self.block_offset, f);
-        self.fs.manager.lock().release_block(inode_block);
-        ret
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> Ext2Result<V> {
+        let inode_block = self.fs.manager.get_block_cache(self.block_id)?;
+        let mut lk = inode_block.lock();
+        let ret = lk.modify(self.block_offset, f);
+        self.fs.seal_inode(&mut lk, self.inode_id as u32, self.block_offset);
+        drop(lk);
+        self.fs.manager.release_block(inode_block);
+        Ok(ret)
     }
 
-    pub fn read_cache(&mut self) {
-        let mut file_type: u8 = 0;
-        let mut file_size: usize = 0;
-        let mut blocks: Vec<u32> = Vec::new();
-
-        self.read_disk_inode(|disk_inode| {
-            file_type = disk_inode.file_code();
-            file_size = disk_inode.i_size as usize;
-            blocks = disk_inode.all_data_blocks(&self.fs.manager, false);
-        });
+    /// The checksums of the file system and the seed of those of the blocks
+    /// of this inode, if the file system keeps them
+    fn checksum(&self) -> Ext2Result<Option<(Checksum, u32)>> {
+        match self.fs.checksum() {
+            Some(csum) => {
+                let generation = self.read_disk_inode(|disk_inode| disk_inode.generation())?;
+                Ok(Some((csum, csum.inode_seed(self.inode_id as u32, generation))))
+            }
+            None => Ok(None),
+        }
+    }
 
-        self.file_type = file_type;
-        self.size = file_size;
-        self.blocks = blocks;
+    pub fn read_cache(&mut self) -> Ext2Result {
+        if let Some(csum) = self.fs.checksum() {
+            let inode_block = self.fs.manager.get_block_cache(self.block_id)?;
+            let ok = inode_block.lock()
+                .read_slice(|data: &DataBlock| csum.verify_inode(self.inode_id as u32, data, self.block_offset));
+            self.fs.manager.release_block(inode_block);
+            if !ok {
+                error!("Bad checksum of inode {} in block {}", self.inode_id, self.block_id);
+                return Err(Ext2Error::Corrupted);
+            }
+        }
+        let disk_inode = self.disk_inode()?;
+        self.fs.verify_extent_tree(self.inode_id as u32, &disk_inode)?;
+        self.file_type = disk_inode.file_code();
+        self.size = disk_inode.size() as usize;
+        let (extents, _) = disk_inode.mapped_extents(&self.fs.manager)?;
+        self.blocks = BlockMap::new(&extents, disk_inode.data_blocks(self.fs.block_size()) as usize);
+        Ok(())
     }
 
     pub fn file_type(&self) -> u8 {
         self.file_type
     }
 
-    pub fn disk_inode(&self) -> DiskInode {
+    pub fn disk_inode(&self) -> Ext2Result<DiskInode> {
         self.read_disk_inode(|disk_inode| *disk_inode)
     }
 
-    /// Find inode under a disk inode by name (DirEntry, pos, prev_offset)
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<(DirEntryHead, usize, usize)> {
-        // debug!("find_inode_id");
-        // assert it is a directory
-        assert!(disk_inode.is_dir());
-        let mut buffer = [0 as u8; MAX_NAME_LEN];
-        let mut dir_entry_head = DirEntryHead::empty();
-        let mut offset: usize = 0;
-        let mut pos: usize = 0;
-        let mut prev_offset: usize = 0;
-
-        while offset + size_of::<DirEntryHead>() < disk_inode.i_size as usize {
-            assert_eq!(disk_inode.read_at(offset, dir_entry_head.as_bytes_mut(), &self.fs.manager, Some(&self.blocks)),
-                        size_of::<DirEntryHead>());
-            let name_len = dir_entry_head.name_len as usize;
-            let name_buffer = &mut buffer[0..name_len];
-            let name_offset = offset + size_of::<DirEntryHead>();
-            assert_eq!(disk_inode.read_at(name_offset, name_buffer, &self.fs.manager, Some(&self.blocks)),
-                        name_len);
-            if name_buffer == name.as_bytes() {
-                return Some((dir_entry_head, pos, prev_offset));
-            }
-            prev_offset = offset;
-            offset += dir_entry_head.rec_len as usize;
-            pos += 1;
-        }
+    /// Write the data, index and inode table blocks of this inode to disk,
+    /// return whether some of them are journaled metadata left to a commit
+    fn write_blocks(&self) -> Ext2Result<bool> {
+        let (extents, index) = self.disk_inode()?.mapped_extents(&self.fs.manager)?;
+        let mut blocks: Vec<usize> = extents.iter()
+            .flat_map(|extent| extent.start as usize..(extent.start + extent.len) as usize)
+            .chain(index.into_iter().map(|block_id| block_id as usize))
+            .collect();
+        blocks.push(self.block_id);
+        self.fs.manager.write_blocks(&blocks)
+    }
 
-        None
+    /// Call `f` over the entries of this directory with their offsets and names,
+    /// until it returns something. Unused entries (with inode 0) are included.
+    fn walk_dir<V>(&self, mut f: impl FnMut(usize, &DirEntryHead, &[u8]) -> Option<V>) -> Ext2Result<Option<V>> {
+        if self.file_type() != EXT2_FT_DIR {
+            error!("Inode {} is walked as a directory but is not one", self.inode_id);
+            return Err(Ext2Error::Corrupted);
+        }
+        for idx in 0..self.blocks.len() {
+            if let Some(v) = self.walk_dir_block(idx, &mut f)? {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
     }
 
-    fn get_inode_id(&self, name: &str) -> Option<(DirEntryHead, usize, usize)> {
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
-        })
+    /// Call `f` over the entries of the logical block `idx` of this directory,
+    /// as `walk_dir` does
+    fn walk_dir_block<V>(&self, idx: usize, mut f: impl FnMut(usize, &DirEntryHead, &[u8]) -> Option<V>) -> Ext2Result<Option<V>> {
+        let block_size = self.fs.block_size();
+        let checksum = self.checksum()?;
+        let dir_block = self.fs.manager.get_block_cache(self.blocks.block_id(idx) as _)?;
+        let ret = dir_block.lock()
+            .read_slice(|data_block: &DataBlock| {
+                if let Some((csum, seed)) = checksum {
+                    if !csum.verify_dir_block(seed, data_block) {
+                        return Err(self.bad_dir_block(idx));
+                    }
+                }
+                // the tail holding the checksum is never used for entries
+                DirEntryHead::walk_block(csum::dir_entries(data_block), |offset, head, name| {
+                    f(idx * block_size + offset, head, name)
+                }).map_err(|offset| {
+                    error!("Bad dir entry at {} of inode {}", idx * block_size + offset, self.inode_id);
+                    Ext2Error::Corrupted
+                })
+            });
+        self.fs.manager.release_block(dir_block);
+        ret
     }
 
-    pub fn find(&self, name: &str) -> Option<Arc<SpinMutex<InodeCache>>> {
-        if let Some(de) = self.get_inode_id(name)
-                                .map(|(de, _, _)| de)
-        {
-            Some(Ext2FileSystem::get_inode_cache(&self.fs, de.inode as _).unwrap())
-        } else {
-            None
+    /// Contents of the logical block `idx` of this directory
+    fn read_dir_block(&self, idx: usize) -> Ext2Result<Vec<u8>> {
+        let checksum = self.checksum()?;
+        let dir_block = self.fs.manager.get_block_cache(self.blocks.block_id(idx) as _)?;
+        let data = dir_block.lock()
+            .read_slice(|data_block: &DataBlock| data_block.to_vec());
+        self.fs.manager.release_block(dir_block);
+        match checksum {
+            Some((csum, seed)) if !csum.verify_dir_block(seed, &data) => Err(self.bad_dir_block(idx)),
+            _ => Ok(data),
         }
     }
 
-    pub fn create(&mut self, name: &str, mut file_type: u16) -> Option<Arc<SpinMutex<InodeCache>>> {
-        assert!(self.file_type() == EXT2_FT_DIR);
-        if self.get_inode_id(name).is_some() {
-            error!("Try to create a file already exists");
-            return None;
+    fn bad_dir_block(&self, idx: usize) -> Ext2Error {
+        error!("Bad checksum of dir block {} of inode {}", self.blocks.block_id(idx), self.inode_id);
+        Ext2Error::Corrupted
+    }
+
+    /// Update the checksums of the logical blocks in `range` of this directory
+    fn seal_dir_blocks(&self, range: Range<usize>) -> Ext2Result {
+        if let Some((csum, seed)) = self.checksum()? {
+            for idx in range {
+                let dir_block = self.fs.manager.get_block_cache(self.blocks.block_id(idx) as _)?;
+                dir_block.lock()
+                    .modify_slice(|data_block: &mut DataBlock| csum.set_dir_block(seed, data_block));
+                self.fs.manager.release_block(dir_block);
+            }
         }
-        file_type &= 0xF000;
-        let new_inode_id = self.fs.alloc_inode().unwrap();
-        let (new_inode_block_id, new_inode_block_offset) = self.fs.get_disk_inode_pos(new_inode_id);
-        let inode_block = self.fs.manager.lock().get_block_cache(new_inode_block_id as _);
-        inode_block.lock()
-            .modify(new_inode_block_offset, |disk_inode: &mut DiskInode| {
-                *disk_inode = DiskInode::new(DEFAULT_IMODE, file_type, 0, 0);
-                let cur_time = self.fs.timer.get_current_time();
-                disk_inode.i_atime = cur_time;
-                disk_inode.i_ctime = cur_time;
-            });
-        self.fs.manager.lock().release_block(inode_block);
+        Ok(())
+    }
 
-        let new_inode = Ext2FileSystem::get_inode_cache(&self.fs, new_inode_id as usize).unwrap();
-        self.append_dir_entry(new_inode_id as usize, name, new_inode.lock().file_type());
+    /// Bytes of a leaf block before the tail holding its checksum
+    fn leaf_room(&self) -> usize {
+        match self.fs.checksum() {
+            Some(_) => self.fs.block_size() - DIR_TAIL_SIZE,
+            None => self.fs.block_size(),
+        }
+    }
 
-        if file_type == EXT2_S_IFDIR {
-            // new_inode.lock().link(".", new_inode_id as usize);
-            // new_inode.lock().link("..", self.inode_id);
-            let mut lk = new_inode.lock();
-            lk.append_dir_entry(new_inode_id as usize, ".", EXT2_FT_DIR);
-            lk.append_dir_entry(self.inode_id, "..", EXT2_FT_DIR);
-            lk.increase_nlink(1);
+    /// Bytes after the entries of an index block, for its checksum
+    fn dx_tail(&self) -> usize {
+        match self.fs.checksum() {
+            Some(_) => DX_TAIL_SIZE,
+            None => 0,
+        }
+    }
 
-            self.increase_nlink(1);
+    /// Build a leaf block out of entries, with room for its checksum
+    fn leaf_block(&self, entries: &[LeafEntry]) -> Vec<u8> {
+        let mut data = htree::leaf_block(entries, self.leaf_room());
+        if self.fs.checksum().is_some() {
+            data.extend_from_slice(&csum::dir_tail());
         }
+        data
+    }
 
-        self.fs.write_meta();
-        Some(new_inode)
+    /// Find an entry by name, return it with its offset and the entry before
+    /// it in the same block
+    fn get_inode_id(&self, name: &str) -> Ext2Result<Option<(DirEntryPos, Option<DirEntryPos>)>> {
+        let block_size = self.fs.block_size();
+        let mut prev = None;
+        let mut f = |offset, head: &DirEntryHead, entry_name: &[u8]| {
+            if offset % block_size == 0 {
+                prev = None;
+            }
+            if head.inode != 0 && entry_name == name.as_bytes() {
+                return Some(((*head, offset), prev));
+            }
+            prev = Some((*head, offset));
+            None
+        };
+        if let Some((root, hash_info)) = self.dx_root()? {
+            // `.` and `..` are always in the first block
+            let leaves = if name == "." || name == ".." {
+                Some(vec![0])
+            } else {
+                self.dx_leaves(&root, hash_info.hash(name.as_bytes(), root.hash_version).unwrap())?
+            };
+            match leaves {
+                Some(leaves) => {
+                    for idx in leaves {
+                        if let Some(found) = self.walk_dir_block(idx, &mut f)? {
+                            return Ok(Some(found));
+                        }
+                    }
+                    return Ok(None);
+                }
+                None => warn!("Bad hash index of inode {}, search it linearly", self.inode_id),
+            }
+        }
+        self.walk_dir(f)
     }
 
-    /// can only link to file
-    pub fn link(&mut self, name: &str, inode_id: usize) -> bool {
-        assert!(self.file_type() == EXT2_FT_DIR);
-        debug!("link {} to {}", name, inode_id);
-        if inode_id == 0 {
-            return false;
+    /// The hash index of this directory and how names are hashed, if it has
+    /// an index that we can use and keep up to date
+    fn dx_root(&self) -> Ext2Result<Option<(DxRoot, HashInfo)>> {
+        let hash_info = match self.fs.hash_info() {
+            Some(hash_info) => hash_info,
+            None => return Ok(None),
+        };
+        if self.blocks.is_empty() || self.read_disk_inode(|disk_inode| disk_inode.i_flags & EXT2_INDEX_FL == 0)? {
+            return Ok(None);
         }
+        match DxRoot::read(&self.read_dir_block(0)?, self.dx_tail()) {
+            Some(root) => Ok(Some((root, hash_info))),
+            None => {
+                warn!("Bad hash index root of inode {}", self.inode_id);
+                Ok(None)
+            }
+        }
+    }
 
-        // link to self
-        if self.inode_id == inode_id {
-            return false;
+    /// Read the dx_node at logical block `block`
+    fn read_dx_node(&self, block: u32) -> Ext2Result<Option<DxEntries>> {
+        if block == 0 || block as usize >= self.blocks.len() {
+            return Ok(None);
         }
+        Ok(htree::read_node(&self.read_dir_block(block as usize)?, self.dx_tail()))
+    }
 
-        if let Some(inode) = Ext2FileSystem::get_inode_cache(&self.fs, inode_id) {
-            if self.get_inode_id(name).is_some() {
-                // already exists
-                false
-            } else {
-                let lk = inode.lock();
-                if lk.file_type() != EXT2_FT_REG_FILE {
-                    return false;
+    /// Logical blocks of the leaves that may hold names hashing to `hash`,
+    /// `None` if the index is broken
+    fn dx_leaves(&self, root: &DxRoot, hash: u32) -> Ext2Result<Option<Vec<usize>>> {
+        let mut leaves = Vec::new();
+        let mut root_pos = root.index.find(hash);
+        let mut node = if root.levels == 0 {
+            root.index.clone()
+        } else {
+            match self.read_dx_node(root.index.entries[root_pos].block)? {
+                Some(node) => node,
+                None => return Ok(None),
+            }
+        };
+        let mut pos = node.find(hash);
+        loop {
+            leaves.push(node.entries[pos].block as usize);
+            pos += 1;
+            if pos < node.entries.len() {
+                if !node.continues(pos, hash) {
+                    break;
                 }
-                self.append_dir_entry(inode_id, name, EXT2_FT_REG_FILE);
-                lk.increase_nlink(1);
-                self.fs.write_meta();
-                true
+            } else if root.levels > 0 && root.index.continues(root_pos + 1, hash) {
+                // the names go on below the next dx_node
+                root_pos += 1;
+                node = match self.read_dx_node(root.index.entries[root_pos].block)? {
+                    Some(node) => node,
+                    None => return Ok(None),
+                };
+                pos = 0;
+            } else {
+                break;
             }
-        } else {
-            false
         }
+        if leaves.iter().any(|leaf| *leaf == 0 || *leaf >= self.blocks.len()) {
+            return Ok(None);
+        }
+        Ok(Some(leaves))
+    }
+
+    pub fn find(&self, name: &str) -> Ext2Result<Arc<SleepRwMutex<InodeCache>>> {
+        let ((de, _), _) = self.get_inode_id(name)?.ok_or(Ext2Error::NotFound)?;
+        self.entry_inode(&de)
+    }
+
+    /// Cache of the inode an entry points to, which must be in use
+    fn entry_inode(&self, de: &DirEntryHead) -> Ext2Result<Arc<SleepRwMutex<InodeCache>>> {
+        Ext2FileSystem::get_inode_cache(&self.fs, de.inode as _).map_err(|err| match err {
+            Ext2Error::NotFound => {
+                error!("Entry of directory {} points to unused inode {}", self.inode_id, de.inode);
+                Ext2Error::Corrupted
+            }
+            err => err,
+        })
+    }
+
+    /// Fail if `name` can not be added to this directory
+    fn check_new_name(&self, name: &str) -> Ext2Result {
+        if name.len() > MAX_NAME_LEN {
+            return Err(Ext2Error::NameTooLong);
+        }
+        if name.is_empty() || name.contains('/') {
+            return Err(Ext2Error::InvalidInput);
+        }
+        if self.get_inode_id(name)?.is_some() {
+            return Err(Ext2Error::AlreadyExists);
+        }
+        Ok(())
     }
 
-    pub fn symlink(&mut self, name: &str, path_name: &str) -> bool {
+    pub fn create(&mut self, name: &str, mut file_type: u16) -> Ext2Result<Arc<SleepRwMutex<InodeCache>>> {
         assert!(self.file_type() == EXT2_FT_DIR);
-        debug!("symlink {} to {}", name, path_name);
-        if let Some(inode) = self.create(name, EXT2_S_IFLNK) {
-            inode.lock().append(path_name.as_bytes());
-            true
+        self.check_new_name(name)?;
+        file_type &= 0xF000;
+        let is_dir = file_type == EXT2_S_IFDIR;
+        let new_inode_id = self.fs.alloc_inode(self.inode_id as u32, is_dir)?;
+        let mut disk_inode = DiskInode::new(DEFAULT_IMODE, file_type, 0, 0);
+        if self.fs.has_extents() && (file_type == EXT2_S_IFREG || file_type == EXT2_S_IFDIR) {
+            disk_inode.init_extents();
+        }
+        let cur_time = self.fs.timer.get_current_time();
+        disk_inode.i_atime = cur_time;
+        disk_inode.i_ctime = cur_time;
+        disk_inode.i_mtime = cur_time;
+        self.fs.init_disk_inode(new_inode_id, disk_inode)?;
+
+        let new_inode = Ext2FileSystem::get_inode_cache(&self.fs, new_inode_id as usize)?;
+        let added = if is_dir {
+            // new_inode.lock().link(".", new_inode_id as usize);
+            // new_inode.lock().link("..", self.inode_id);
+            let mut lk = new_inode.unique_lock();
+            lk.add_dir_entry(new_inode_id as usize, ".", EXT2_FT_DIR)
+                .and_then(|_| lk.add_dir_entry(self.inode_id, "..", EXT2_FT_DIR))
         } else {
-            false
+            Ok(())
+        };
+        let file_code = new_inode.shared_lock().file_type();
+        if let Err(err) = added.and_then(|_| self.add_dir_entry(new_inode_id as usize, name, file_code)) {
+            new_inode.unique_lock().discard()?;
+            return Err(err);
+        }
+        if is_dir {
+            new_inode.unique_lock().increase_nlink(1)?;
+            self.increase_nlink(1)?;
         }
-    }
 
-    fn ls_disk(&self, disk_inode: &DiskInode) -> Vec<String> {
-        assert!(disk_inode.is_dir());
-        let mut buffer = [0 as u8; MAX_NAME_LEN];
-        let mut names: Vec<String> = Vec::new();
+        self.fs.write_meta()?;
+        Ok(new_inode)
+    }
 
-        let mut dir_entry_head = DirEntryHead::empty();
-        let mut offset: usize = 0;
-
-        while offset + size_of::<DirEntryHead>() < disk_inode.i_size as usize {
-            assert_eq!(disk_inode.read_at(offset, dir_entry_head.as_bytes_mut(), &self.fs.manager, Some(&self.blocks)),
-                        size_of::<DirEntryHead>());
-            let name_len = dir_entry_head.name_len as usize;
-            let name_buffer = &mut buffer[0..name_len];
-            let name_offset = offset + size_of::<DirEntryHead>();
-            assert_eq!(disk_inode.read_at(name_offset, name_buffer, &self.fs.manager, Some(&self.blocks)),
-                        name_len);
-            names.push(String::from_utf8_lossy(name_buffer).to_string());
-            offset += dir_entry_head.rec_len as usize;
-        };
+    /// Free a new inode that could not be linked into its directory
+    fn discard(&mut self) -> Ext2Result {
+        self.modify_disk_inode(|disk_inode| disk_inode.i_links_count = 0)?;
+        self.fs.inode_manager.lock().try_to_remove(self.inode_id);
+        self.release()
+    }
 
-        names
+    /// Free an inode without links along with its blocks, it should be out of
+    /// the cache manager
+    pub(crate) fn release(&mut self) -> Ext2Result {
+        self.valid = false;
+        self.orphan = false;
+        self.clear()?;
+        self.fs.remove_orphan(self.inode_id as u32)?;
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.i_dtime = self.fs.timer.get_current_time();
+        })?;
+        self.fs.dealloc_inode(self.inode_id as u32, self.file_type == EXT2_FT_DIR)
     }
 
-    pub fn ls(&self) -> Vec<String> {
+    /// can only link to file
+    pub fn link(&mut self, name: &str, inode_id: usize) -> Ext2Result {
         assert!(self.file_type() == EXT2_FT_DIR);
-        self.read_disk_inode(|disk_inode| {
-            self.ls_disk(disk_inode)
-        })
-    }
+        debug!("link {} to {}", name, inode_id);
+        // link to self
+        if inode_id == 0 || self.inode_id == inode_id {
+            return Err(Ext2Error::InvalidInput);
+        }
 
-    fn is_empty_dir_disk(&self, disk_inode: &DiskInode) -> bool {
-        assert!(disk_inode.is_dir());
+        let inode = Ext2FileSystem::get_inode_cache(&self.fs, inode_id)?;
+        self.check_new_name(name)?;
+        let lk = inode.unique_lock();
+        match lk.file_type() {
+            EXT2_FT_REG_FILE => {}
+            EXT2_FT_DIR => return Err(Ext2Error::IsADirectory),
+            _ => return Err(Ext2Error::InvalidInput),
+        }
+        self.add_dir_entry(inode_id, name, EXT2_FT_REG_FILE)?;
+        lk.increase_nlink(1)?;
+        self.fs.write_meta()
+    }
 
-        let mut dir_entry_head = DirEntryHead::empty();
-        let mut offset: usize = 0;
-        let mut file_num = 0;
+    pub fn symlink(&mut self, name: &str, path_name: &str) -> Ext2Result {
+        assert!(self.file_type() == EXT2_FT_DIR);
+        debug!("symlink {} to {}", name, path_name);
+        let inode = self.create(name, EXT2_S_IFLNK)?;
+        let mut lk = inode.unique_lock();
+        if path_name.len() < FAST_SYMLINK_MAX {
+            lk.modify_disk_inode(|disk_inode| disk_inode.set_fast_symlink_target(path_name.as_bytes()))?;
+            lk.size = path_name.len();
+        } else {
+            lk.append(path_name.as_bytes())?;
+        }
+        Ok(())
+    }
 
-        while offset + size_of::<DirEntryHead>() < disk_inode.i_size as usize {
-            assert_eq!(disk_inode.read_at(offset, dir_entry_head.as_bytes_mut(), &self.fs.manager, Some(&self.blocks)),
-                        size_of::<DirEntryHead>());
-            offset += dir_entry_head.rec_len as usize;
-            file_num += 1;
-            if file_num > 2 {
-                return false;
+    pub fn ls(&self) -> Ext2Result<Vec<String>> {
+        let mut names: Vec<String> = Vec::new();
+        self.walk_dir(|_, head, name| {
+            if head.inode != 0 {
+                names.push(String::from_utf8_lossy(name).to_string());
             }
-        };
+            None::<()>
+        })?;
+        Ok(names)
+    }
 
-        true
+    pub fn read_dir(&self, start: usize, max: usize) -> Ext2Result<Vec<(String, u8)>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        let mut entries: Vec<(DirEntryHead, String)> = Vec::new();
+        let mut skipped = 0;
+        self.walk_dir(|_, head, name| {
+            if head.inode == 0 {
+                return None;
+            }
+            if skipped < start {
+                skipped += 1;
+                return None;
+            }
+            entries.push((*head, String::from_utf8_lossy(name).to_string()));
+            if entries.len() == max { Some(()) } else { None }
+        })?;
+        // revision 0 entries have no type, it is in the inode
+        let has_file_type = self.fs.has_file_type();
+        entries.into_iter()
+            .map(|(head, name)| {
+                let file_type = if has_file_type {
+                    head.file_type
+                } else if name == "." || name == ".." {
+                    EXT2_FT_DIR
+                } else {
+                    self.entry_inode(&head)?.shared_lock().file_type()
+                };
+                Ok((name, file_type))
+            })
+            .collect()
     }
 
-    pub fn is_empty_dir(&self) -> bool {
-        assert!(self.file_type() == EXT2_FT_DIR);
-        self.read_disk_inode(|disk_inode| {
-            self.is_empty_dir_disk(disk_inode)
-        })
+    pub fn is_empty_dir(&self) -> Ext2Result<bool> {
+        Ok(self.walk_dir(|_, head, name| {
+            if head.inode != 0 && name != b"." && name != b".." {
+                Some(())
+            } else {
+                None
+            }
+        })?.is_none())
     }
 
-    fn unlink_below(&mut self) {
+    fn unlink_below(&mut self) -> Ext2Result {
         assert!(self.file_type() == EXT2_FT_DIR);
-        let names = self.ls();
+        let names = self.ls()?;
 
         for file_name in names.iter() {
             if file_name.as_str() == "." || file_name.as_str() == ".." {
                 // special case
                 continue;
             }
-            let child_inode = self.find(file_name.as_str()).unwrap();
-            let mut lk = child_inode.lock();
+            let child_inode = self.find(file_name.as_str())?;
+            let mut lk = child_inode.unique_lock();
             if lk.file_type() == EXT2_FT_DIR {
-                lk.unlink_below();
-                lk.decrease_nlink(1);
-                self.decrease_nlink(1);
+                lk.unlink_below()?;
+                lk.decrease_nlink(1)?;
+                self.decrease_nlink(1)?;
             }
             drop(lk);
-            self.unlink_single(file_name.as_str());
+            self.unlink_single(file_name.as_str())?;
         }
 
         // when reaching here, it is assumed to be an empty directory
-        self.read_disk_inode(|disk_inode| {
-            assert!(disk_inode.i_links_count == 2);
-        });
+        let links = self.read_disk_inode(|disk_inode| disk_inode.i_links_count)?;
+        if links != 2 {
+            error!("Empty directory {} has {} links", self.inode_id, links);
+            return Err(Ext2Error::Corrupted);
+        }
+        Ok(())
     }
 
     /// unlink recursively
-    pub fn unlink(&mut self, name: &str, expect: u8, recursive: bool) -> bool {
+    pub fn unlink(&mut self, name: &str, expect: u8, recursive: bool) -> Ext2Result {
         assert!(self.file_type() == EXT2_FT_DIR);
         debug!("unlink {}", name);
         if name == "." || name == ".." {
             error!("Can not unlink . or ..");
-            return false;
+            return Err(Ext2Error::InvalidInput);
         }
 
-        if let Some(inode) = self.find(name) {
-            let mut lk = inode.lock();
-            if lk.file_type() != expect {
-                return false;
-            }
-            if lk.file_type() == EXT2_FT_DIR {
-                let file_under_dir = lk.ls();
-                debug!("under this dir: {:?}", file_under_dir);
-                if !lk.is_empty_dir() && !recursive {
-                    return false;
-                }
-                lk.unlink_below();
-                lk.decrease_nlink(1);
-                self.decrease_nlink(1);
+        let inode = self.find(name)?;
+        let mut lk = inode.unique_lock();
+        // directories and other files are removed apart
+        match (lk.file_type() == EXT2_FT_DIR, expect == EXT2_FT_DIR) {
+            (true, false) => return Err(Ext2Error::IsADirectory),
+            (false, true) => return Err(Ext2Error::NotADirectory),
+            _ => {}
+        }
+        if lk.file_type() == EXT2_FT_DIR {
+            if !recursive && !lk.is_empty_dir()? {
+                return Err(Ext2Error::DirectoryNotEmpty);
             }
-            drop(lk);
-            self.unlink_single(name);
-            true
+            lk.unlink_below()?;
+            lk.decrease_nlink(1)?;
+            self.decrease_nlink(1)?;
+        }
+        drop(lk);
+        self.unlink_single(name)
+    }
+
+    fn unlink_single(&mut self, name: &str) -> Ext2Result {
+        assert!(self.file_type() == EXT2_FT_DIR);
+        let de = self.remove_dir_entry(name)?.ok_or(Ext2Error::NotFound)?;
+        self.entry_inode(&de)?.unique_lock().decrease_nlink(1)
+    }
+
+    /// Remove an entry from a directory, leaving the link count of its inode alone
+    pub fn remove_dir_entry(&mut self, name: &str) -> Ext2Result<Option<DirEntryHead>> {
+        assert!(self.file_type() == EXT2_FT_DIR);
+        let ((de, offset), prev) = match self.get_inode_id(name)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if let Some((mut prev, prev_offset)) = prev {
+            // merge into the entry before it
+            prev.rec_len += de.rec_len;
+            self.write_at(prev_offset, prev.as_bytes())?;
         } else {
-            false
+            // the first entry of a block is marked unused instead
+            let mut unused = de;
+            unused.inode = 0;
+            self.write_at(offset, unused.as_bytes())?;
         }
+        Ok(Some(de))
     }
 
-    fn unlink_single(&mut self, name: &str) -> bool {
+    /// Point an entry of a directory to another inode, leaving link counts alone
+    pub fn set_entry_inode(&mut self, name: &str, inode: usize) -> Ext2Result<bool> {
         assert!(self.file_type() == EXT2_FT_DIR);
-        if name == "." || name == ".." {
-            return false;
-        }
-        if let Some((de, pos, prev_offset)) = self.get_inode_id(name) {
-            assert!(pos != 0);
-            let mut buf = [0 as u8; size_of::<DirEntryHead>()];
-            self.read_at(prev_offset, &mut buf);
-            unsafe {
-                (*(&mut buf as *mut u8 as *mut DirEntryHead)).rec_len += de.rec_len;
-            }
-            self.write_at(prev_offset, &buf);
-            
-            let target_inode = Ext2FileSystem::get_inode_cache(&self.fs, de.inode as usize).unwrap();
-            target_inode.lock().decrease_nlink(1);
-            true
+        if let Some(((mut de, offset), _)) = self.get_inode_id(name)? {
+            de.inode = inode as u32;
+            self.write_at(offset, de.as_bytes())?;
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
+    /// Move the entry `name` of this directory to `new_name` in `new_dir`,
+    /// or in this directory if `new_dir` is `None`. An existing `new_name`
+    /// is replaced if it is of the same kind, and empty for a directory.
+    pub fn rename(&mut self, name: &str, mut new_dir: Option<&mut InodeCache>, new_name: &str) -> Ext2Result {
+        assert!(self.file_type() == EXT2_FT_DIR);
+        debug!("rename {} to {}", name, new_name);
+        /// The directory the entry moves to
+        fn dst<'a>(src: &'a mut InodeCache, new_dir: &'a mut Option<&mut InodeCache>) -> &'a mut InodeCache {
+            match new_dir {
+                Some(dir) => dir,
+                None => src,
+            }
+        }
+
+        if name == "." || name == ".." || new_name == "." || new_name == ".." {
+            return Err(Ext2Error::InvalidInput);
+        }
+        if new_name.len() > MAX_NAME_LEN {
+            return Err(Ext2Error::NameTooLong);
+        }
+        let ((de, _), _) = self.get_inode_id(name)?.ok_or(Ext2Error::NotFound)?;
+        if new_dir.is_none() && name == new_name {
+            return Ok(());
+        }
+        let src_inode = self.entry_inode(&de)?;
+        let src_type = src_inode.shared_lock().file_type();
+
+        if let Some(((target, _), _)) = dst(self, &mut new_dir).get_inode_id(new_name)? {
+            if target.inode == de.inode {
+                // both are links to the same file
+                return Ok(());
+            }
+            if target.inode as usize == self.inode_id {
+                // this directory holds the source, it is not empty
+                return Err(Ext2Error::DirectoryNotEmpty);
+            }
+            let target_inode = self.entry_inode(&target)?;
+            let mut target_lk = target_inode.unique_lock();
+            let target_is_dir = target_lk.file_type() == EXT2_FT_DIR;
+            match (src_type == EXT2_FT_DIR, target_is_dir) {
+                (true, false) => return Err(Ext2Error::NotADirectory),
+                (false, true) => return Err(Ext2Error::IsADirectory),
+                _ => {}
+            }
+            if target_is_dir {
+                if !target_lk.is_empty_dir()? {
+                    return Err(Ext2Error::DirectoryNotEmpty);
+                }
+                // its entry, '.', and '..' in the directory holding it
+                target_lk.decrease_nlink(2)?;
+                dst(self, &mut new_dir).decrease_nlink(1)?;
+            } else {
+                target_lk.decrease_nlink(1)?;
+            }
+            drop(target_lk);
+            dst(self, &mut new_dir).remove_dir_entry(new_name)?;
+        }
+
+        dst(self, &mut new_dir).add_dir_entry(de.inode as usize, new_name, src_type)?;
+        self.remove_dir_entry(name)?;
+        if let Some(new_dir) = new_dir {
+            if src_type == EXT2_FT_DIR {
+                src_inode.unique_lock().set_entry_inode("..", new_dir.inode_id)?;
+                self.decrease_nlink(1)?;
+                new_dir.increase_nlink(1)?;
+            }
+        }
+        self.fs.write_meta()
+    }
+
     // ----- ACL ------
-    pub fn chown(&self, uid: Option<usize>, gid: Option<usize>) {
+    pub fn chown(&self, uid: Option<usize>, gid: Option<usize>) -> Ext2Result {
         self.modify_disk_inode(|disk_inode| {
             if let Some(uid) = uid {
                 disk_inode.i_uid = uid as _;
@@ -506,153 +996,659 @@ impl InodeCache {
             if let Some(gid) = gid {
                 disk_inode.i_gid = gid as _;
             }
-            disk_inode.i_mtime = self.fs.timer.get_current_time();
+            disk_inode.i_ctime = self.fs.timer.get_current_time();
         })
     }
-    pub fn chmod(&self, access: IMODE) {
+    pub fn chmod(&self, access: IMODE) -> Ext2Result {
         self.modify_disk_inode(|disk_inode| {
-            disk_inode.i_mode = (disk_inode.i_mode & 0o7000) | access.bits();
+            disk_inode.i_mode = (disk_inode.i_mode & 0xF000) | access.bits();
             let cur_time = self.fs.timer.get_current_time();
             disk_inode.i_ctime = cur_time;
             disk_inode.i_atime = cur_time;
-        });
+        })
+    }
+
+    pub fn set_times(&self, atime: Option<u32>, mtime: Option<u32>) -> Ext2Result {
+        self.modify_disk_inode(|disk_inode| {
+            if let Some(atime) = atime {
+                disk_inode.i_atime = atime;
+            }
+            if let Some(mtime) = mtime {
+                disk_inode.i_mtime = mtime;
+            }
+            disk_inode.i_ctime = self.fs.timer.get_current_time();
+        })
+    }
+
+    // ----- Extended attributes -----
+    fn xattrs(&self) -> Ext2Result<XattrBlock> {
+        match self.read_disk_inode(|disk_inode| disk_inode.xattr_block())? {
+            0 => Ok(XattrBlock::default()),
+            block_id => self.fs.read_xattr_block(block_id),
+        }
+    }
+
+    pub fn getxattr(&self, name: &str) -> Ext2Result<Vec<u8>> {
+        let (index, name) = XattrEntry::split_name(name)?;
+        self.xattrs()?
+            .get(index, name)
+            .map(|value| value.to_vec())
+            .ok_or(Ext2Error::NotFound)
+    }
+
+    pub fn setxattr(&mut self, name: &str, value: &[u8]) -> Ext2Result {
+        let (index, name) = XattrEntry::split_name(name)?;
+        let mut xattrs = self.xattrs()?;
+        xattrs.set(index, name, value);
+        self.update_xattrs(xattrs)
+    }
+
+    pub fn listxattr(&self) -> Ext2Result<Vec<String>> {
+        Ok(self.xattrs()?.entries.iter().filter_map(XattrEntry::full_name).collect())
+    }
+
+    pub fn removexattr(&mut self, name: &str) -> Ext2Result {
+        let (index, name) = XattrEntry::split_name(name)?;
+        let mut xattrs = self.xattrs()?;
+        if !xattrs.remove(index, name) {
+            return Err(Ext2Error::NotFound);
+        }
+        self.update_xattrs(xattrs)
+    }
+
+    /// Store the extended attributes, in a block shared with other inodes if
+    /// they have the same
+    fn update_xattrs(&self, xattrs: XattrBlock) -> Ext2Result {
+        let old_block = self.read_disk_inode(|disk_inode| disk_inode.xattr_block())?;
+        let block_id = self.fs.update_xattr_block(old_block, xattrs)?;
+        let block_size = self.fs.block_size();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.set_xattr_block(block_id, block_size);
+            disk_inode.i_ctime = self.fs.timer.get_current_time();
+        })
     }
 
     // ----- Basic operation -----
-    pub fn ftruncate(&mut self, new_size: u32) -> bool {
+    pub fn ftruncate(&mut self, new_size: usize) -> Ext2Result {
         assert!(self.file_type() == EXT2_FT_REG_FILE);
         debug!("ftruncate from {} to {}", self.size, new_size);
-        if self.size < new_size as _ {
-            self.cache_increase_size(new_size);
-        } else if self.size > new_size as _ {
-            self.cache_decrease_size(new_size);
+        if self.size < new_size {
+            self.cache_increase_size(new_size)
+        } else if self.size > new_size {
+            self.cache_decrease_size(new_size)
+        } else {
+            Ok(())
         }
-        true
     }
 
-    fn decrease_nlink(&mut self, by: usize) {
-        let mut clean = false;
+    /// Allocate, punch or zero the bytes in `offset..offset + len`, see [`FallocMode`]
+    pub fn fallocate(&mut self, mode: FallocMode, offset: usize, len: usize) -> Ext2Result {
+        assert!(self.file_type() == EXT2_FT_REG_FILE);
+        let punch = mode.contains(FallocMode::PUNCH_HOLE);
+        if len == 0 || (punch && (!mode.contains(FallocMode::KEEP_SIZE) || mode.contains(FallocMode::ZERO_RANGE))) {
+            return Err(Ext2Error::InvalidInput);
+        }
+        let end = offset.checked_add(len).ok_or(Ext2Error::FileTooLarge)?;
+        if punch {
+            self.punch_hole(offset, end)?;
+        } else {
+            let old_size = self.size;
+            if !mode.contains(FallocMode::KEEP_SIZE) {
+                self.cache_increase_size(end)?;
+            }
+            let mut res = Ok(());
+            if mode.contains(FallocMode::ZERO_RANGE) {
+                res = self.punch_hole(offset, end);
+            }
+            if res.is_ok() {
+                res = self.preallocate(offset, end);
+            }
+            if let Err(err) = res {
+                self.cache_decrease_size(old_size)?;
+                return Err(err);
+            }
+        }
         self.modify_disk_inode(|disk_inode| {
-            assert!(disk_inode.i_links_count >= by as u16);
+            let cur_time = self.fs.timer.get_current_time();
+            disk_inode.i_mtime = cur_time;
+            disk_inode.i_ctime = cur_time;
+        })
+    }
+
+    /// Make the bytes in `offset..end` read as zeros, unmapping the blocks
+    /// wholly among them
+    fn punch_hole(&mut self, offset: usize, end: usize) -> Ext2Result {
+        let block_size = self.fs.block_size();
+        // no block is mapped past the largest file
+        let max_blocks = self.read_disk_inode(|disk_inode| disk_inode.max_blocks(block_size))? as usize;
+        let first = offset.div_ceil(block_size).min(max_blocks);
+        let last = (end / block_size).min(max_blocks);
+        self.zero_bytes(offset, end.min(first * block_size))?;
+        if last >= first {
+            self.zero_bytes((last * block_size).max(offset), end)?;
+        }
+        if first < last {
+            let freed = self.modify_disk_inode(|disk_inode| {
+                disk_inode.unmap_blocks(first as u32..last as u32, &self.fs.manager, &mut |n| self.fs.alloc_data_runs(None, n))
+            })??;
+            self.fs.batch_dealloc_block(&freed)?;
+            self.fs.seal_extent_tree(self.inode_id as u32, &self.disk_inode()?)?;
+            self.blocks.unmap(first..last);
+        }
+        Ok(())
+    }
+
+    /// Allocate the blocks holding the bytes in `offset..end`. The new blocks
+    /// of an extent tree are unwritten, block pointers only map blocks below
+    /// the size.
+    fn preallocate(&mut self, offset: usize, end: usize) -> Ext2Result {
+        let block_size = self.fs.block_size();
+        let blocks = offset / block_size..end.div_ceil(block_size);
+        let (unwritten, max_blocks) = self.read_disk_inode(|disk_inode| {
+            (disk_inode.has_extents(), disk_inode.max_blocks(block_size))
+        })?;
+        if blocks.end > max_blocks as usize {
+            return Err(Ext2Error::FileTooLarge);
+        }
+        if !unwritten && blocks.end > self.blocks.len() {
+            return Err(Ext2Error::Unsupported);
+        }
+        self.map_holes(blocks.start as u32..blocks.end as u32, unwritten)
+    }
+
+    /// Zero the bytes in `start..end` below the size, in the blocks mapped
+    fn zero_bytes(&self, start: usize, end: usize) -> Ext2Result {
+        let block_size = self.fs.block_size();
+        let end = end.min(self.size);
+        let mut pos = start;
+        while pos < end {
+            let block_end = ((pos / block_size + 1) * block_size).min(end);
+            let block_id = self.blocks.block_id(pos / block_size);
+            if block_id != 0 {
+                let data_block = self.fs.manager.get_block_cache(block_id as _)?;
+                data_block.lock()
+                    .modify_data_slice(|data_block: &mut DataBlock| {
+                        data_block[pos % block_size..(block_end - 1) % block_size + 1].fill(0);
+                    });
+                self.fs.manager.release_block(data_block);
+            }
+            pos = block_end;
+        }
+        Ok(())
+    }
+
+    /// Map the holes among the logical blocks in `range` to new zeroed blocks,
+    /// or to new unwritten ones if `unwritten`. The blocks of unwritten
+    /// extents are zeroed and marked written, unless `unwritten` too.
+    fn map_holes(&mut self, range: Range<u32>, unwritten: bool) -> Ext2Result {
+        let disk_inode = self.disk_inode()?;
+        // runs of logical blocks to map, to the blocks of an unwritten extent
+        // from the one given or to new ones
+        let mut runs: Vec<(u32, u32, Option<u32>)> = Vec::new();
+        let mut block = range.start;
+        while block < range.end {
+            if self.blocks.block_id(block as usize) != 0 {
+                block += 1;
+                continue;
+            }
+            let extent = if disk_inode.has_extents() {
+                disk_inode.find_extent(block, &self.fs.manager)?
+            } else {
+                None
+            };
+            match (extent, runs.last_mut()) {
+                (Some(extent), _) => {
+                    let len = (extent.block + extent.len).min(range.end) - block;
+                    if extent.unwritten && !unwritten {
+                        runs.push((block, len, Some(extent.start + (block - extent.block))));
+                    }
+                    block += len;
+                    continue;
+                }
+                (None, Some((first, len, None))) if *first + *len == block => *len += 1,
+                (None, _) => runs.push((block, 1, None)),
+            }
+            block += 1;
+        }
+        if runs.is_empty() {
+            return Ok(());
+        }
+
+        let new_num: u32 = runs.iter()
+            .filter(|(_, _, start)| start.is_none())
+            .map(|(_, len, _)| len)
+            .sum();
+        // right after the blocks before, so that the file stays contiguous
+        let goal = match self.blocks.last_before(range.start as usize) {
+            Some(block) => Some(block + 1),
+            None => self.fs.data_goal(self.inode_id as u32),
+        };
+        let new_blocks = self.fs.alloc_inode_blocks(self.inode_id as u32, goal, new_num as usize)?;
+        let mut used = 0;
+        for (first, len, start) in runs {
+            let blocks: Vec<u32> = match start {
+                Some(start) => (start..start + len).collect(),
+                None => new_blocks[used..used + len as usize].to_vec(),
+            };
+            if start.is_some() {
+                for block_id in blocks.iter() {
+                    self.fs.zero_block(*block_id)?;
+                }
+            }
+            let res = self.modify_disk_inode(|disk_inode| {
+                disk_inode.map_blocks(first, &blocks, unwritten, &self.fs.manager, &mut |n| self.fs.alloc_data_runs(goal, n))
+            })?;
+            let freed = match res {
+                Ok(freed) => freed,
+                Err(err) => {
+                    self.fs.batch_dealloc_block(&new_blocks[used..].to_vec())?;
+                    return Err(err);
+                }
+            };
+            self.fs.batch_dealloc_block(&freed)?;
+            if start.is_none() {
+                used += len as usize;
+            }
+            if !unwritten {
+                self.blocks.map(first as usize, &blocks);
+            }
+        }
+        self.fs.seal_extent_tree(self.inode_id as u32, &self.disk_inode()?)
+    }
+
+    fn decrease_nlink(&mut self, by: usize) -> Ext2Result {
+        let links = self.modify_disk_inode(|disk_inode| {
+            if disk_inode.i_links_count < by as u16 {
+                return None;
+            }
             disk_inode.i_links_count -= by as u16;
-            clean = disk_inode.i_links_count == 0;
-        });
+            Some(disk_inode.i_links_count)
+        })?;
+        let links = match links {
+            Some(links) => links,
+            None => {
+                error!("Inode {} has fewer than {} links", self.inode_id, by);
+                return Err(Ext2Error::Corrupted);
+            }
+        };
 
-        if clean {
-            self.clear();
-            self.fs.dealloc_inode(self.inode_id as u32);
+        if links == 0 {
+            // uncache it first, the inode number may be reused once it is freed
             self.fs.inode_manager.lock().try_to_remove(self.inode_id);
-            self.valid = false;
+            if self.file_type == EXT2_FT_DIR {
+                // nothing can be done in a removed directory
+                self.release()?;
+            } else {
+                // open handles keep using the data until the last one is dropped
+                self.fs.add_orphan(self.inode_id as u32)?;
+                self.orphan = true;
+            }
         }
+        Ok(())
     }
 
-    pub fn increase_nlink(&self, by: usize) {
+    pub fn increase_nlink(&self, by: usize) -> Ext2Result {
         self.modify_disk_inode(|disk_inode| {
             disk_inode.i_links_count += by as u16;
-        });
+        })
     }
 
-    fn cache_increase_size(&mut self, new_size: u32) {
-        if new_size <= self.size as _{
-            return;
+    /// Grow the file, the blocks past its old end are holes until written
+    fn cache_increase_size(&mut self, new_size: usize) -> Ext2Result {
+        if new_size <= self.size {
+            return Ok(());
         }
-        let extra_blocks = self.modify_disk_inode(|disk_inode| {
-            self.increase_size(new_size, disk_inode)
-        });
-        for block in extra_blocks {
-            self.blocks.push(block);
+        let block_size = self.fs.block_size();
+        self.modify_disk_inode(|disk_inode| disk_inode.increase_size(new_size as u64, block_size))??;
+        if new_size > LARGE_FILE_SIZE {
+            self.fs.set_large_file();
         }
-        self.size = new_size as _;
+        self.blocks.resize(new_size.div_ceil(block_size));
+        self.size = new_size;
+        Ok(())
     }
 
-    fn cache_decrease_size(&mut self, new_size: u32) {
-        if new_size >= self.size as _ {
-            return;
+    fn cache_decrease_size(&mut self, new_size: usize) -> Ext2Result {
+        if new_size >= self.size {
+            return Ok(());
         }
+        // the rest of the last block reads as zeros if the file grows again
+        let block_size = self.fs.block_size();
+        let tail_end = new_size.div_ceil(block_size) * block_size;
+        self.zero_bytes(new_size, tail_end)?;
         let remain_blocks = self.modify_disk_inode(|disk_inode| {
             self.decrease_size(new_size, disk_inode)
-        });
-        self.blocks.drain(remain_blocks..self.blocks.len());
-        self.size = new_size as _;
-    }
-
-    /// Increase the size of a disk inode
-    fn increase_size(
-        &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
-    ) -> Vec<u32> {
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let new_blocks = self.fs.batch_alloc_data(blocks_needed as _);
-        assert!(new_blocks.len() == blocks_needed as _);
-        disk_inode.increase_size(new_size, new_blocks, &self.fs.manager)
+        })??;
+        self.fs.seal_extent_tree(self.inode_id as u32, &self.disk_inode()?)?;
+        self.fs.release_window(self.inode_id as u32);
+        self.blocks.resize(remain_blocks);
+        self.size = new_size;
+        Ok(())
     }
 
     /// Decrease the size of a disk node
     fn decrease_size(
         &self,
-        new_size: u32,
+        new_size: usize,
         disk_inode: &mut DiskInode,
-    ) -> usize {
-        let blocks_unused = disk_inode.decrease_size(new_size, &self.fs.manager);
-        self.fs.batch_dealloc_block(&blocks_unused);
-        return disk_inode.data_blocks() as usize;
+    ) -> Ext2Result<usize> {
+        let blocks_unused = disk_inode.decrease_size(new_size as u64, &self.fs.manager)?;
+        self.fs.batch_dealloc_block(&blocks_unused)?;
+        Ok(disk_inode.data_blocks(self.fs.block_size()) as usize)
     }
-    /// Clear the data in current inode
+    /// Clear the data and extended attributes in current inode
     /// # Safety
     /// 
     /// The inodecache should be marked as invalid and removed from cache manager right away
-    pub fn clear(&self) {
+    pub fn clear(&self) -> Ext2Result {
         self.modify_disk_inode(|disk_inode| {
-            let blocks = disk_inode.i_blocks;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.fs.manager);
-            if data_blocks_dealloc.len() != DiskInode::total_blocks(blocks * 512) as usize {
-                error!("clear: {} != {}", data_blocks_dealloc.len(), DiskInode::total_blocks(blocks * 512) as usize);
+            let block_size = self.fs.block_size();
+            let total_blocks = ((disk_inode.i_blocks - disk_inode.acl_blocks(block_size)) / (block_size as u32 / 512)) as usize;
+            let data_blocks_dealloc = disk_inode.clear_size(&self.fs.manager)?;
+            if data_blocks_dealloc.len() != total_blocks {
+                error!("clear: {} != {}", data_blocks_dealloc.len(), total_blocks);
             }
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(blocks * 512) as usize);
             let cur_time = self.fs.timer.get_current_time();
             disk_inode.i_atime = cur_time;
             disk_inode.i_mtime = cur_time;
-            self.fs.batch_dealloc_block(&data_blocks_dealloc);
-        });
+            self.fs.batch_dealloc_block(&data_blocks_dealloc)
+        })??;
+        let xattr_block = self.read_disk_inode(|disk_inode| disk_inode.xattr_block())?;
+        if xattr_block != 0 {
+            self.fs.release_xattr_block(xattr_block)?;
+            let block_size = self.fs.block_size();
+            self.modify_disk_inode(|disk_inode| disk_inode.set_xattr_block(0, block_size))?;
+        }
+        Ok(())
     }
     /// Read data from current inode
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        self.modify_disk_inode(|disk_inode| {
-            let cur_time = self.fs.timer.get_current_time();
-            disk_inode.i_atime = cur_time;
-            disk_inode.read_at(offset, buf, &self.fs.manager, Some(&self.blocks))
-        })
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Ext2Result<usize> {
+        let disk_inode = if self.fs.is_read_only() {
+            self.disk_inode()?
+        } else {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.i_atime = self.fs.timer.get_current_time();
+                *disk_inode
+            })?
+        };
+        // data is copied without holding the block of the inode, which other
+        // inodes share
+        disk_inode.read_at(offset, buf, &self.fs.manager, Some(&self.blocks))
+    }
+    /// Load the logical blocks `blocks` of this file into the block cache, with
+    /// as few requests as possible
+    fn load_blocks(&self, blocks: Range<usize>) -> Ext2Result {
+        let block_ids: Vec<usize> = self.blocks.mapped(blocks)
+            .map(|(_, block_id)| block_id as usize)
+            .collect();
+        self.fs.manager.read_ahead(&block_ids)
+    }
+    /// Read the target of a symlink
+    pub fn readlink(&self) -> Ext2Result<String> {
+        let disk_inode = self.disk_inode()?;
+        if disk_inode.is_fast_symlink(self.fs.block_size()) {
+            Ok(String::from_utf8_lossy(disk_inode.fast_symlink_target()).to_string())
+        } else {
+            let mut buf = vec![0; self.size];
+            let size = self.read_at(0, &mut buf)?;
+            Ok(String::from_utf8_lossy(&buf[..size]).to_string())
+        }
     }
     /// Write data to current inode
-    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> usize {
-        self.cache_increase_size((offset + buf.len()) as _);
-        let size = self.modify_disk_inode(|disk_inode| {
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Ext2Result<usize> {
+        let old_size = self.size;
+        self.cache_increase_size(offset.checked_add(buf.len()).ok_or(Ext2Error::FileTooLarge)?)?;
+        let block_size = self.fs.block_size();
+        let first = offset / block_size;
+        let end = if buf.is_empty() { first } else { (offset + buf.len()).div_ceil(block_size) };
+        if let Err(err) = self.map_holes(first as u32..end as u32, false) {
+            self.cache_decrease_size(old_size)?;
+            return Err(err);
+        }
+        let disk_inode = self.modify_disk_inode(|disk_inode| {
             let cur_time = self.fs.timer.get_current_time();
             disk_inode.i_atime = cur_time;
             disk_inode.i_mtime = cur_time;
-            disk_inode.write_at(offset, buf, &self.fs.manager, Some(&self.blocks))
-        });
-        size
+            *disk_inode
+        })?;
+        let written = disk_inode.write_at(offset, buf, &self.fs.manager, Some(&self.blocks))?;
+        if self.file_type == EXT2_FT_DIR {
+            self.seal_dir_blocks(first..end)?;
+        }
+        Ok(written)
     }
     /// Write data at the end of file
-    pub fn append(&mut self, buf: &[u8]) -> usize {
-        let origin_size = self.size;
-        self.cache_increase_size((origin_size + buf.len()) as _);
-        let size = self.modify_disk_inode(|disk_inode| {
-            // let origin_size = disk_inode.i_size as usize;
-            // self.increase_size((origin_size + buf.len()) as u32, disk_inode);
-            let cur_time = self.fs.timer.get_current_time();
-            disk_inode.i_atime = cur_time;
-            disk_inode.i_mtime = cur_time;
-            disk_inode.write_at(origin_size, buf, &self.fs.manager, Some(&self.blocks))
-        });
-        size
+    pub fn append(&mut self, buf: &[u8]) -> Ext2Result<usize> {
+        self.write_at(self.size, buf)
     }
-    pub fn append_dir_entry(&mut self, inode: usize, name: &str, file_type: u8) {
+    /// Add an entry to a directory: in its hash index if it has one, else in
+    /// the first free space large enough or in a new block. A directory
+    /// growing past one block gets an index if the file system keeps them.
+    pub fn add_dir_entry(&mut self, inode: usize, name: &str, file_type: u8) -> Ext2Result {
+        let file_type = if self.fs.has_file_type() { file_type } else { EXT2_FT_UNKNOWN };
         let dir_entry = DirEntryHead::create(inode, name, file_type);
-        self.append(dir_entry.as_bytes());
-        let name_len = name.as_bytes().len();
-        self.append(&name.as_bytes()[0..name_len.min(MAX_NAME_LEN)]);
+        let name = &name.as_bytes()[..dir_entry.name_len as usize];
+        if let Some((root, hash_info)) = self.dx_root()? {
+            if self.dx_add_entry(root, hash_info, dir_entry, name)? {
+                return Ok(());
+            }
+            warn!("Hash index of inode {} is full or broken, drop it", self.inode_id);
+        }
+        // a linear directory, the index of which may not be kept up to date
+        if self.read_disk_inode(|disk_inode| disk_inode.i_flags & EXT2_INDEX_FL != 0)? {
+            self.drop_index()?;
+        }
+        if self.add_entry_in(None, dir_entry, name)? {
+            return Ok(());
+        }
+        if let (1, Some(hash_info)) = (self.blocks.len(), self.fs.hash_info()) {
+            if let Some(root) = self.make_index(hash_info)? {
+                if self.dx_add_entry(root, hash_info, dir_entry, name)? {
+                    return Ok(());
+                }
+                self.drop_index()?;
+            }
+        }
+        self.append_dir_block(&self.leaf_block(&[LeafEntry { hash: 0, head: dir_entry, name: name.to_vec() }]))?;
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Add an entry in the first free space large enough of the logical
+    /// block `idx`, or of any block. Returns whether there was room.
+    fn add_entry_in(&mut self, idx: Option<usize>, mut dir_entry: DirEntryHead, name: &[u8]) -> Ext2Result<bool> {
+        let needed = dir_entry.rec_len as usize;
+        // an entry with enough room after its name, and the length it keeps
+        let fits = |offset, head: &DirEntryHead, _: &[u8]| {
+            let used = if head.inode == 0 { 0 } else { DirEntryHead::rec_len_of(head.name_len as usize) };
+            if head.rec_len as usize >= used + needed {
+                Some((*head, offset, used))
+            } else {
+                None
+            }
+        };
+        let slot = match idx {
+            Some(idx) => self.walk_dir_block(idx, fits)?,
+            None => self.walk_dir(fits)?,
+        };
+        let (mut head, offset, used) = match slot {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+        dir_entry.rec_len = head.rec_len - used as u16;
+        let offset = if used == 0 {
+            offset
+        } else {
+            head.rec_len = used as u16;
+            self.write_at(offset, head.as_bytes())?;
+            offset + used
+        };
+        let mut buf = dir_entry.as_bytes().to_vec();
+        buf.extend_from_slice(name);
+        self.write_at(offset, &buf)?;
+        Ok(true)
+    }
+
+    /// Add a block to the end of this directory, returns its logical block
+    fn append_dir_block(&mut self, data: &[u8]) -> Ext2Result<usize> {
+        let idx = self.blocks.len();
+        let offset = self.size;
+        self.write_at(offset, data)?;
+        Ok(idx)
+    }
+
+    /// Stop using the hash index of this directory, whose blocks are still
+    /// valid directory blocks. With checksums, its index blocks turn into
+    /// leaves to make room for theirs.
+    fn drop_index(&mut self) -> Ext2Result {
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.i_flags &= !EXT2_INDEX_FL;
+        })?;
+        if self.fs.checksum().is_none() {
+            return Ok(());
+        }
+        let block_size = self.fs.block_size();
+        for idx in 0..self.blocks.len() {
+            let data = self.read_dir_block(idx)?;
+            if csum::has_dir_tail(&data) {
+                continue;
+            }
+            // only `.` and `..` are in use in the root, no entry in the others
+            let mut entries = Vec::new();
+            let _ = DirEntryHead::walk_block(&data, |_, head, name| {
+                if head.inode != 0 {
+                    entries.push(LeafEntry { hash: 0, head: *head, name: name.to_vec() });
+                }
+                None::<()>
+            });
+            self.write_at(idx * block_size, &self.leaf_block(&entries))?;
+        }
+        Ok(())
+    }
+
+    /// Index a directory of a single full block: its entries but `.` and
+    /// `..` move to a new leaf, and the first block becomes the root
+    fn make_index(&mut self, hash_info: HashInfo) -> Ext2Result<Option<DxRoot>> {
+        let block_size = self.fs.block_size();
+        if !htree::is_known_version(hash_info.def_version) {
+            return Ok(None);
+        }
+        let mut dots = Vec::new();
+        let mut entries = Vec::new();
+        let walked = DirEntryHead::walk_block(&self.read_dir_block(0)?, |_, head, name| {
+            if dots.len() < 2 {
+                dots.push((*head, name.to_vec()));
+            } else if head.inode != 0 {
+                let hash = hash_info.hash(name, hash_info.def_version).unwrap();
+                entries.push(LeafEntry { hash, head: *head, name: name.to_vec() });
+            }
+            None::<()>
+        });
+        if walked.is_err() || dots.len() < 2 || dots[0].1 != b"." || dots[1].1 != b".." {
+            return Ok(None);
+        }
+        debug!("index directory {}", self.inode_id);
+        let leaf = self.append_dir_block(&self.leaf_block(&entries))?;
+        let root = DxRoot::new(hash_info.def_version, block_size - self.dx_tail(), leaf as u32);
+        self.write_at(0, &root.to_block(dots[0].0, dots[1].0, block_size))?;
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.i_flags |= EXT2_INDEX_FL;
+        })?;
+        Ok(Some(root))
+    }
+
+    /// Add an entry to the leaf of the index its name hashes to, splitting
+    /// the leaf if it is full. Returns false if the index is full or broken.
+    fn dx_add_entry(&mut self, mut root: DxRoot, hash_info: HashInfo, dir_entry: DirEntryHead, name: &[u8]) -> Ext2Result<bool> {
+        let block_size = self.fs.block_size();
+        let hash = hash_info.hash(name, root.hash_version).unwrap();
+        let root_pos = root.index.find(hash);
+        // the dx_node below the root, with its logical block
+        let mut node = None;
+        if root.levels > 0 {
+            let block = root.index.entries[root_pos].block;
+            match self.read_dx_node(block)? {
+                Some(entries) => node = Some((block, entries)),
+                None => return Ok(false),
+            }
+        }
+        let leaf = match &node {
+            Some((_, entries)) => entries.entries[entries.find(hash)].block,
+            None => root.index.entries[root_pos].block,
+        } as usize;
+        if leaf == 0 || leaf >= self.blocks.len() {
+            return Ok(false);
+        }
+        if self.add_entry_in(Some(leaf), dir_entry, name)? {
+            return Ok(true);
+        }
+
+        // make room for a new leaf in the index
+        match &mut node {
+            None if root.index.is_full() => {
+                if !root.can_grow() {
+                    return Ok(false);
+                }
+                let block = self.blocks.len() as u32;
+                let entries = root.add_level(block, block_size - self.dx_tail());
+                self.append_dir_block(&htree::node_block(&entries, block_size))?;
+                node = Some((block, entries));
+            }
+            Some((block, entries)) if entries.is_full() => {
+                if root.index.is_full() {
+                    return Ok(false);
+                }
+                let (split_hash, upper) = htree::split_node(entries);
+                let upper_block = self.append_dir_block(&htree::node_block(&upper, block_size))? as u32;
+                root.index.entries.insert(root_pos + 1, DxEntry { hash: split_hash, block: upper_block });
+                if hash >= split_hash {
+                    self.write_at(*block as usize * block_size, &htree::node_block(entries, block_size))?;
+                    node = Some((upper_block, upper));
+                }
+            }
+            _ => {}
+        }
+
+        // split the leaf, the upper half of its names by hash move to a new one
+        let mut entries = Vec::new();
+        self.walk_dir_block(leaf, |_, head, entry_name| {
+            if head.inode != 0 {
+                let hash = hash_info.hash(entry_name, root.hash_version).unwrap();
+                entries.push(LeafEntry { hash, head: *head, name: entry_name.to_vec() });
+            }
+            None::<()>
+        })?;
+        if entries.len() < 2 {
+            return Ok(false);
+        }
+        let (lower, upper, split_hash) = htree::split_leaf(entries, self.leaf_room());
+        self.write_at(leaf * block_size, &self.leaf_block(&lower))?;
+        let new_leaf = self.append_dir_block(&self.leaf_block(&upper))?;
+        let index = match &mut node {
+            Some((_, entries)) => entries,
+            None => &mut root.index,
+        };
+        let pos = index.find(hash);
+        index.entries.insert(pos + 1, DxEntry { hash: split_hash, block: new_leaf as u32 });
+        if let Some((block, entries)) = &node {
+            self.write_at(*block as usize * block_size, &htree::node_block(entries, block_size))?;
+        }
+        let mut root_block = self.read_dir_block(0)?;
+        root.write(&mut root_block);
+        self.write_at(0, &root_block)?;
+
+        let leaf = if hash >= (split_hash & !1) { new_leaf } else { leaf };
+        self.add_entry_in(Some(leaf), dir_entry, name)
+    }
+}
+
+impl Drop for InodeCache {
+    fn drop(&mut self) {
+        self.fs.release_window(self.inode_id as u32);
+        if self.orphan {
+            let _op = Ext2FileSystem::begin_op(&self.fs);
+            if let Err(err) = self.release() {
+                error!("Failed to free orphan inode {}: {}", self.inode_id, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Extended attributes, kept in a block of their own as in ext2.
+//!
+//! `i_file_acl` of an inode points to its attribute block, which starts with
+//! a header: a magic number, how many inodes share the block, and a hash of
+//! its contents. Entries follow, sorted by name index, name length and name,
+//! each giving the offset and size of its value, then 4 zero bytes end them.
+//! Values are packed from the end of the block. A name is stored without its
+//! prefix (`user.`, `trusted.`, ...), which the name index stands for.
+//! Inodes with the same attributes may share a block, the hash helps to find
+//! one to share.
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::error::{Ext2Error, Ext2Result};
+
+pub const XATTR_MAGIC: u32 = 0xEA02_0000;
+/// Most inodes sharing a block, as in Linux
+pub const XATTR_REFCOUNT_MAX: u32 = 1024;
+/// Longest name, without its prefix
+pub const XATTR_NAME_MAX: usize = 255;
+
+const HEADER_SIZE: usize = 32;
+/// Size of an entry before its name
+const ENTRY_HEAD_SIZE: usize = 16;
+
+const NAME_HASH_SHIFT: u32 = 5;
+const VALUE_HASH_SHIFT: u32 = 16;
+const BLOCK_HASH_SHIFT: u32 = 16;
+
+/// Name indexes and the prefixes they stand for. The POSIX ACL indexes stand
+/// for whole names, so they come before the `system.` prefix.
+const PREFIXES: [(u8, &str); 6] = [
+    (1, "user."),
+    (2, "system.posix_acl_access"),
+    (3, "system.posix_acl_default"),
+    (4, "trusted."),
+    (6, "security."),
+    (7, "system."),
+];
+
+/// An attribute, named by its index and the rest of its name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XattrEntry {
+    pub index: u8,
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl XattrEntry {
+    /// Split a full name into its index and the rest, `Unsupported` if no
+    /// prefix matches
+    pub fn split_name(name: &str) -> Ext2Result<(u8, &[u8])> {
+        let (index, rest) = PREFIXES.iter()
+            .find_map(|(index, prefix)| {
+                let rest = name.strip_prefix(prefix)?;
+                // the POSIX ACL names are whole, other prefixes need a name after them
+                if prefix.ends_with('.') == rest.is_empty() {
+                    return None;
+                }
+                Some((*index, rest.as_bytes()))
+            })
+            .ok_or(Ext2Error::Unsupported)?;
+        if rest.len() > XATTR_NAME_MAX {
+            return Err(Ext2Error::NameTooLong);
+        }
+        Ok((index, rest))
+    }
+
+    /// The name with its prefix, `None` if the index is unknown
+    pub fn full_name(&self) -> Option<String> {
+        let (_, prefix) = PREFIXES.iter().find(|(index, _)| *index == self.index)?;
+        Some(String::from(*prefix) + &String::from_utf8_lossy(&self.name))
+    }
+
+    fn size(&self) -> usize {
+        pad(ENTRY_HEAD_SIZE + self.name.len())
+    }
+
+    /// Key entries are sorted by
+    fn key(&self) -> (u8, usize, &[u8]) {
+        (self.index, self.name.len(), &self.name)
+    }
+
+    fn hash(&self) -> u32 {
+        let mut hash: u32 = 0;
+        for c in self.name.iter() {
+            // Linux hashes names as signed chars on x86
+            hash = (hash << NAME_HASH_SHIFT) ^ (hash >> (32 - NAME_HASH_SHIFT)) ^ (*c as i8 as i32 as u32);
+        }
+        let mut value = self.value.clone();
+        value.resize(pad(value.len()), 0);
+        for word in value.chunks(4) {
+            hash = (hash << VALUE_HASH_SHIFT) ^ (hash >> (32 - VALUE_HASH_SHIFT)) ^ read_u32(word, 0);
+        }
+        hash
+    }
+}
+
+/// The attributes of an attribute block
+#[derive(Clone, Debug, Default)]
+pub struct XattrBlock {
+    /// Inodes sharing the block
+    pub refcount: u32,
+    /// Sorted by `XattrEntry::key`
+    pub entries: Vec<XattrEntry>,
+}
+
+impl XattrBlock {
+    /// Read an attribute block, `None` if it is not a valid one
+    pub fn read(data: &[u8]) -> Option<Self> {
+        // h_blocks, other sizes are not used
+        if read_u32(data, 0) != XATTR_MAGIC || read_u32(data, 8) != 1 {
+            return None;
+        }
+        let mut entries = Vec::new();
+        let mut offset = HEADER_SIZE;
+        loop {
+            if offset + 4 > data.len() {
+                return None;
+            }
+            if read_u32(data, offset) == 0 {
+                break;
+            }
+            if offset + ENTRY_HEAD_SIZE > data.len() {
+                return None;
+            }
+            let name_len = data[offset] as usize;
+            let index = data[offset + 1];
+            let value_offs = read_u16(data, offset + 2) as usize;
+            let value_inum = read_u32(data, offset + 4);
+            let value_size = read_u32(data, offset + 8) as usize;
+            let name_start = offset + ENTRY_HEAD_SIZE;
+            // values in inodes of their own are an ext4 feature
+            if value_inum != 0 || name_start + name_len > data.len() || value_offs + value_size > data.len() {
+                return None;
+            }
+            let entry = XattrEntry {
+                index,
+                name: data[name_start..name_start + name_len].to_vec(),
+                value: data[value_offs..value_offs + value_size].to_vec(),
+            };
+            offset += entry.size();
+            entries.push(entry);
+        }
+        entries.sort_by(|a, b| a.key().cmp(&b.key()));
+        Some(Self { refcount: read_u32(data, 4), entries })
+    }
+
+    /// Write the block, which must fit in `data`
+    pub fn write(&self, data: &mut [u8]) {
+        assert!(self.fits(data.len()));
+        data.fill(0);
+        data[0..4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+        data[4..8].copy_from_slice(&self.refcount.to_le_bytes());
+        data[8..12].copy_from_slice(&1u32.to_le_bytes());
+        data[12..16].copy_from_slice(&self.hash().to_le_bytes());
+        let mut offset = HEADER_SIZE;
+        let mut value_end = data.len();
+        for entry in self.entries.iter() {
+            let value_offs = if entry.value.is_empty() {
+                0
+            } else {
+                value_end -= pad(entry.value.len());
+                data[value_end..value_end + entry.value.len()].copy_from_slice(&entry.value);
+                value_end
+            };
+            data[offset] = entry.name.len() as u8;
+            data[offset + 1] = entry.index;
+            data[offset + 2..offset + 4].copy_from_slice(&(value_offs as u16).to_le_bytes());
+            data[offset + 8..offset + 12].copy_from_slice(&(entry.value.len() as u32).to_le_bytes());
+            data[offset + 12..offset + 16].copy_from_slice(&entry.hash().to_le_bytes());
+            let name_start = offset + ENTRY_HEAD_SIZE;
+            data[name_start..name_start + entry.name.len()].copy_from_slice(&entry.name);
+            offset += entry.size();
+        }
+    }
+
+    /// Whether the attributes fit in a block of `block_size`
+    pub fn fits(&self, block_size: usize) -> bool {
+        let used: usize = self.entries.iter()
+            .map(|entry| entry.size() + pad(entry.value.len()))
+            .sum();
+        HEADER_SIZE + used + 4 <= block_size
+    }
+
+    /// Hash of the attributes, 0 if blocks holding them should not be shared
+    pub fn hash(&self) -> u32 {
+        let mut hash: u32 = 0;
+        for entry in self.entries.iter() {
+            let entry_hash = entry.hash();
+            if entry_hash == 0 {
+                return 0;
+            }
+            hash = (hash << BLOCK_HASH_SHIFT) ^ (hash >> (32 - BLOCK_HASH_SHIFT)) ^ entry_hash;
+        }
+        hash
+    }
+
+    pub fn get(&self, index: u8, name: &[u8]) -> Option<&[u8]> {
+        self.find(index, name).ok().map(|pos| self.entries[pos].value.as_slice())
+    }
+
+    /// Set an attribute, creating it if it does not exist
+    pub fn set(&mut self, index: u8, name: &[u8], value: &[u8]) {
+        match self.find(index, name) {
+            Ok(pos) => self.entries[pos].value = value.to_vec(),
+            Err(pos) => self.entries.insert(pos, XattrEntry { index, name: name.to_vec(), value: value.to_vec() }),
+        }
+    }
+
+    /// Remove an attribute, return whether it existed
+    pub fn remove(&mut self, index: u8, name: &[u8]) -> bool {
+        match self.find(index, name) {
+            Ok(pos) => {
+                self.entries.remove(pos);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn find(&self, index: u8, name: &[u8]) -> Result<usize, usize> {
+        self.entries.binary_search_by(|entry| entry.key().cmp(&(index, name.len(), name)))
+    }
+}
+
+/// Hash of an attribute block as recorded in its header
+pub fn block_hash(data: &[u8]) -> u32 {
+    read_u32(data, 12)
+}
+
+/// Set how many inodes share an attribute block
+pub fn set_refcount(data: &mut [u8], refcount: u32) {
+    data[4..8].copy_from_slice(&refcount.to_le_bytes());
+}
+
+/// Round up to a multiple of 4 bytes, as entries and values are
+fn pad(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
@@ -0,0 +1,100 @@
+#!/bin/sh
+# Rebuild the golden images used by the ext2fs tests with Linux mke2fs.
+#
+# ext2_1k.img: 1 KiB blocks, two groups with a backup super block in the
+#   second one, 256-byte inodes, the default ext2 features of mke2fs, and a
+#   directory with a hash index
+# ext2_4k.img: 4 KiB blocks, revision 0 (128-byte inodes, no file type in
+#   directory entries)
+# ext2_extents.img: 1 KiB blocks with the extents feature, a file of 24
+#   scattered blocks whose extent tree has a leaf block
+# ext2_csum.img: 1 KiB blocks with metadata checksums and extents, a
+#   directory with a hash index, a file whose extent tree has a leaf block,
+#   and groups left uninitialized by mke2fs
+set -e
+cd "$(dirname "$0")"
+export E2FSPROGS_FAKE_TIME=1700000000
+root=$(mktemp -d)
+trap 'rm -rf "$root"' EXIT
+
+# byte i of a pattern file is i % 251
+pattern() {
+    python3 -c "import sys; sys.stdout.buffer.write(bytes(i % 251 for i in range($2)))" > "$1"
+}
+
+# block 2i of a sparse file holds block i of a pattern file
+sparse() {
+    pattern "$1.pattern" $((24 * 1024))
+    python3 -c "
+import sys
+data = open(sys.argv[1], 'rb').read()
+with open(sys.argv[2], 'wb') as f:
+    for i in range(24):
+        f.seek(2 * i * 1024)
+        f.write(data[i * 1024:(i + 1) * 1024])
+    f.truncate(48 * 1024)
+" "$1.pattern" "$1"
+    rm "$1.pattern"
+}
+
+populate() {
+    rm -rf "$root"/*
+    printf 'Hello, ext2!\n' > "$root/hello.txt"
+    mkdir -p "$root/dir/sub"
+    printf 'deep\n' > "$root/dir/sub/deep.txt"
+    ln "$root/hello.txt" "$root/dir/hello.link"
+    ln -s hello.txt "$root/short"
+    ln -s "dir/$(printf 'x%.0s' $(seq 80))" "$root/long"
+    touch -h -d @1700000000 "$root" "$root"/* "$root"/dir/* "$root"/dir/sub/*
+}
+
+populate
+pattern "$root/big.bin" $((300 * 1024))
+mkdir "$root/many"
+for i in $(seq -w 0 99); do
+    touch -d @1700000000 "$root/many/file-with-a-long-name-$i"
+done
+touch -d @1700000000 "$root/big.bin" "$root/many"
+rm -f ext2_1k.img
+mke2fs -q -F -t ext2 -b 1024 -g 512 -N 160 -L golden-1k \
+    -U 6d8ba3f0-1c2e-4f5a-9b7d-0e1f2a3b4c5d \
+    -E hash_seed=0b1c2d3e-4f50-4617-8293-a4b5c6d7e8f9,root_owner=0:0 \
+    -d "$root" ext2_1k.img 640
+# build the hash index of "many"
+e2fsck -fyD ext2_1k.img > /dev/null || [ $? -eq 1 ]
+
+populate
+pattern "$root/mid.bin" $((60 * 1024))
+touch -d @1700000000 "$root/mid.bin"
+rm -f ext2_4k.img
+mke2fs -q -F -t ext2 -r 0 -b 4096 -N 32 -L golden-4k \
+    -U 1f2e3d4c-5b6a-4978-8695-a4b3c2d1e0f9 \
+    -E root_owner=0:0 \
+    -d "$root" ext2_4k.img 64
+
+rm -rf "$root"/*
+printf 'Hello, extents!\n' > "$root/hello.txt"
+ln -s "$(printf 'y%.0s' $(seq 90))" "$root/long"
+pattern "$root/big.bin" $((200 * 1024))
+sparse "$root/sparse.bin"
+touch -h -d @1700000000 "$root" "$root"/*
+rm -f ext2_extents.img
+mke2fs -q -F -t ext2 -O extents -b 1024 -N 32 -L golden-ext \
+    -U 2a3b4c5d-6e7f-4081-92a3-b4c5d6e7f809 \
+    -E root_owner=0:0 \
+    -d "$root" ext2_extents.img 512
+
+rm -rf "$root"/*
+printf 'Hello, checksums!\n' > "$root/hello.txt"
+sparse "$root/sparse.bin"
+mkdir "$root/many"
+for i in $(seq -w 0 99); do
+    touch -d @1700000000 "$root/many/file-with-a-long-name-$i"
+done
+touch -h -d @1700000000 "$root" "$root"/*
+rm -f ext2_csum.img
+mke2fs -q -F -t ext2 -O metadata_csum,extents -b 1024 -g 512 -N 480 -L golden-csum \
+    -U 3b4c5d6e-7f80-4192-a3b4-c5d6e7f8091a \
+    -E hash_seed=0b1c2d3e-4f50-4617-8293-a4b5c6d7e8f9,root_owner=0:0 \
+    -d "$root" ext2_csum.img 1536
+e2fsck -fyD ext2_csum.img > /dev/null || [ $? -eq 1 ]
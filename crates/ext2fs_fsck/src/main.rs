@@ -0,0 +1,126 @@
+use clap::{App, Arg};
+use ext2fs::{fsck, BlockDevice, Ext2Error, Ext2FileSystem, Ext2Result, ZeroTimeProvider};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::Mutex;
+use log::*;
+
+/// The image is accessed in sectors, the file system finds its block size by itself
+const SECTOR_SIZE: usize = 512;
+
+/// Exit codes of e2fsck
+const EXIT_OK: i32 = 0;
+const EXIT_FIXED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+
+struct BlockFile {
+    file: Mutex<File>,
+    num_blocks: usize
+}
+
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|err| {
+                error!("Read sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|err| {
+                error!("Write sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
+    }
+
+    // the file is read and written at any length in one go
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        self.write_block(block_id, buf)
+    }
+
+    fn block_num(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("ext2fs fsck")
+        .about("Check an ext2 image, and optionally repair it")
+        .arg(Arg::with_name("image")
+            .required(true)
+            .help("Path of the image"))
+        .arg(Arg::with_name("repair")
+            .short("y")
+            .long("repair")
+            .help("Repair the problems found"))
+        .arg(Arg::with_name("superblock")
+            .short("b")
+            .long("superblock")
+            .takes_value(true)
+            .help("Block of a backup super block to use instead of the primary one"))
+        .get_matches();
+
+    let path = matches.value_of("image").unwrap();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            exit(EXIT_UNCORRECTED);
+        });
+    let num_blocks = file.metadata().unwrap().len() as usize / SECTOR_SIZE;
+    let block_file = Arc::new(BlockFile { file: Mutex::new(file), num_blocks });
+    let fail = |err: Ext2Error| -> ! {
+        eprintln!("{}: {}", path, err);
+        exit(EXIT_UNCORRECTED);
+    };
+    let efs = match matches.value_of("superblock") {
+        Some(block_no) => {
+            let block_no = block_no.parse().unwrap_or_else(|_| {
+                eprintln!("Bad super block {}", block_no);
+                exit(EXIT_UNCORRECTED);
+            });
+            Ext2FileSystem::open_with_superblock(block_file, Arc::new(ZeroTimeProvider), block_no)
+        }
+        None => Ext2FileSystem::open(block_file, Arc::new(ZeroTimeProvider)),
+    };
+    let efs = efs.unwrap_or_else(|err| fail(err));
+
+    let report = fsck::check(&efs, matches.is_present("repair")).unwrap_or_else(|err| fail(err));
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    if report.is_clean() {
+        println!("{}: clean", path);
+        exit(EXIT_OK);
+    }
+    if !report.repaired {
+        println!("{}: {} problems found", path, report.problems.len());
+        exit(EXIT_UNCORRECTED);
+    }
+    let left = fsck::check(&efs, false).unwrap_or_else(|err| fail(err));
+    for problem in left.problems.iter() {
+        println!("Not repaired: {}", problem);
+    }
+    println!("{}: {} problems found, {} left", path, report.problems.len(), left.problems.len());
+    exit(if left.is_clean() { EXIT_FIXED } else { EXIT_UNCORRECTED });
+}
@@ -1,15 +1,27 @@
-#![allow(unused)]
 use clap::{App, Arg};
-use ext2fs::{BlockDevice, Ext2FileSystem, BLOCK_SIZE, BLOCKS_PER_GRP, EXT2_S_IFDIR, EXT2_S_IFREG,
-            TimeProvider, ZeroTimeProvider};
-use std::fs::{read_dir, File, OpenOptions};
+use ext2fs::{BlockDevice, Ext2Error, Ext2FileSystem, Ext2Result, Inode, TimeProvider, EXT2_FT_DIR,
+            EXT2_FT_REG_FILE, EXT2_FT_SYMLINK, EXT2_S_IFDIR, EXT2_S_IFREG, IMODE};
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData,
+            ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID};
+use libc::{EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENOENT, ENOSPC, ENOSYS, ENOTDIR,
+           ENOTEMPTY, EOPNOTSUPP, EPERM, ERANGE, EROFS, XATTR_CREATE, XATTR_REPLACE};
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use log::*;
 
-const NUM_BLOCKS: usize = BLOCKS_PER_GRP;
+/// The image is accessed in sectors, the file system finds its block size by itself
+const SECTOR_SIZE: usize = 512;
+
+/// How long the kernel may cache attributes and entries
+const TTL: Duration = Duration::from_secs(1);
+
+/// Inode of the root directory in ext2, FUSE calls it `FUSE_ROOT_ID`
+const EXT2_ROOT_INO: u64 = 2;
 
 struct BlockFile {
     file: Mutex<File>,
@@ -17,18 +29,34 @@ struct BlockFile {
 }
 
 impl BlockDevice for BlockFile {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
         let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SIZE) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SIZE, "Not a complete block!");
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|err| {
+                error!("Read sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
     }
 
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
         let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SIZE) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SIZE, "Not a complete block!");
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|err| {
+                error!("Write sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
+    }
+
+    // the file is read and written at any length in one go
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        self.write_block(block_id, buf)
     }
 
     fn block_num(&self) -> usize {
@@ -36,148 +64,495 @@ impl BlockDevice for BlockFile {
     }
 
     fn block_size(&self) -> usize {
-        BLOCK_SIZE
+        SECTOR_SIZE
     }
 }
 
 impl BlockFile {
-    pub fn new(f: File, num_blocks: usize) -> Self {
-        f.set_len((BLOCK_SIZE * num_blocks) as u64);
+    pub fn new(f: File) -> Self {
+        let num_blocks = f.metadata().unwrap().len() as usize / SECTOR_SIZE;
         Self { file: Mutex::new(f), num_blocks }
     }
 }
 
-fn main() {
-    env_logger::init();
-    efs_test();
-}
-
-fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile::new(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?, NUM_BLOCKS 
-    ));
-    Ext2FileSystem::create(block_file.clone(), Arc::new(ZeroTimeProvider));
-    let efs = Ext2FileSystem::open(
-        block_file.clone(), 
-        Arc::new(ZeroTimeProvider)
-    );
-
-    let root_inode = Ext2FileSystem::root_inode(&efs);
-    let filea = root_inode.create("filea", EXT2_S_IFREG).unwrap();
-    let fileb = root_inode.create("fileb", EXT2_S_IFREG).unwrap();
-    let dirc = root_inode.create("dirc", EXT2_S_IFDIR).unwrap();
-    let filed = dirc.create("filed", EXT2_S_IFREG).unwrap();
-    let dire = dirc.create("dire", EXT2_S_IFDIR).unwrap();
-    dire.create("filef", EXT2_S_IFREG);
-    dire.create("dirg", EXT2_S_IFDIR).unwrap();
-    dirc.link("filealink", filea.inode_id().unwrap());
-    let filealink = dirc.find("filealink").unwrap();
-
-    println!("After initialize");
-    println!("Under root:");
-    for name in root_inode.ls().unwrap() {
-        println!("{}", name);
-    }
-    println!("Under dirc:");
-    for name in dirc.ls().unwrap() {
-        println!("{}", name);
-    }
-    println!("Under dire:");
-    for name in dire.ls().unwrap() {
-        println!("{}", name);
-    }
-    let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
-    fileb.write_at(0, greet_str.as_bytes());
-
-    // basic read and write
-    let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer).unwrap();
-    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap());
-
-    // ftruncate file
-    assert!(fileb.ftruncate(4096).unwrap());
-    assert!(fileb.ftruncate(4).unwrap());
-    let lenb = fileb.read_at(0, &mut buffer).unwrap();
-    println!("fileb content after truncate:");
-    println!("{}", core::str::from_utf8(&buffer[..lenb]).unwrap());
-
-    // write from another place
-    filealink.append(greet_str.as_bytes());
-    let lena = filea.read_at(0, &mut buffer).unwrap();
-    println!("filea content after write from filealink:");
-    println!("{}", core::str::from_utf8(&buffer[..lena]).unwrap());
-
-    // rm file
-    assert!(root_inode.rm_file("fileb").unwrap());
-    println!("After remove fileb");
-    println!("Under root:");
-    for name in root_inode.ls().unwrap() {
-        println!("{}", name);
-    }
-
-    // invalid
-    assert!(fileb.disk_inode().is_none());
-
-    // rm empty dir
-    assert!(dire.rm_dir("dirg", false).unwrap());
-
-    // rm non-empty dir FAIL
-    assert!(!dirc.rm_dir("dire", false).unwrap());
-
-    // rm non-empty dir recursively SUCCESS
-    assert!(dirc.rm_dir("dire", true).unwrap());
-
-    println!("After remove dire recursively, dirc:");
-    for name in dirc.ls().unwrap() {
-        println!("{}", name);
-    }
-
-    // link count
-    let disk_inode_a_before = filealink.disk_inode().unwrap();
-    assert_eq!(disk_inode_a_before.i_links_count, 2);
-    assert!(dirc.rm_file("filealink").unwrap());
-    let disk_inode_a_after = filealink.disk_inode().unwrap();
-    assert_eq!(disk_inode_a_after.i_links_count, 1);
-
-    assert!(root_inode.rm_dir("dirc", true).unwrap());
-
-    let mut random_str_test = |len: usize| {
-        filea.ftruncate(0);
-        assert_eq!(filea.read_at(0, &mut buffer).unwrap(), 0,);
-        let mut str = String::new();
-        use rand;
-        // random digit
-        for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
-        }
-        filea.write_at(0, str.as_bytes());
-        let mut read_buffer = [0u8; 127];
-        let mut offset = 0usize;
-        let mut read_str = String::new();
-        loop {
-            let len = filea.read_at(offset, &mut read_buffer).unwrap();
-            if len == 0 {
+struct SystemTimeProvider;
+
+impl TimeProvider for SystemTimeProvider {
+    fn get_current_time(&self) -> u32 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    }
+}
+
+/// An ext2 image served through FUSE
+struct Ext2Fuse {
+    efs: Arc<Ext2FileSystem>,
+}
+
+fn to_ext2_ino(ino: u64) -> u64 {
+    if ino == FUSE_ROOT_ID { EXT2_ROOT_INO } else { ino }
+}
+
+fn to_fuse_ino(ino: u64) -> u64 {
+    if ino == EXT2_ROOT_INO { FUSE_ROOT_ID } else { ino }
+}
+
+fn to_system_time(secs: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+}
+
+fn to_secs(time: TimeOrNow) -> u32 {
+    let time = match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    };
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32)
+}
+
+fn errno(err: Ext2Error) -> i32 {
+    match err {
+        Ext2Error::NotFound => ENOENT,
+        Ext2Error::AlreadyExists => EEXIST,
+        Ext2Error::NotADirectory => ENOTDIR,
+        Ext2Error::IsADirectory => EISDIR,
+        Ext2Error::DirectoryNotEmpty => ENOTEMPTY,
+        Ext2Error::InvalidInput => EINVAL,
+        Ext2Error::NoSpace => ENOSPC,
+        Ext2Error::NameTooLong => ENAMETOOLONG,
+        Ext2Error::FileTooLarge => EFBIG,
+        Ext2Error::ReadOnly => EROFS,
+        Ext2Error::Unsupported => ENOSYS,
+        Ext2Error::Corrupted | Ext2Error::Io => EIO,
+    }
+}
+
+/// Errors of extended attribute requests: a missing attribute is ENODATA, and
+/// an unknown name prefix EOPNOTSUPP, as ENOSYS would disable them all
+fn xattr_errno(err: Ext2Error) -> i32 {
+    match err {
+        Ext2Error::NotFound => ENODATA,
+        Ext2Error::Unsupported => EOPNOTSUPP,
+        _ => errno(err),
+    }
+}
+
+/// Reply with an attribute value or a list of names, only with its size if
+/// `size` is 0
+fn reply_xattr(data: Result<Vec<u8>, i32>, size: u32, reply: ReplyXattr) {
+    match data {
+        Ok(data) if size == 0 => reply.size(data.len() as u32),
+        Ok(data) if data.len() > size as usize => reply.error(ERANGE),
+        Ok(data) => reply.data(&data),
+        Err(err) => reply.error(err),
+    }
+}
+
+fn file_kind(mode: u16) -> FileType {
+    match mode as u32 & libc::S_IFMT {
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFBLK => FileType::BlockDevice,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFSOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+impl Ext2Fuse {
+    fn inode(&self, ino: u64) -> Result<Inode, i32> {
+        Ext2FileSystem::get_inode_cache(&self.efs, to_ext2_ino(ino) as usize)
+            .map(Inode::new)
+            .map_err(errno)
+    }
+
+    fn attr(&self, inode: &Inode) -> Result<FileAttr, i32> {
+        let disk_inode = inode.disk_inode().map_err(errno)?;
+        Ok(FileAttr {
+            ino: to_fuse_ino(inode.inode_id().map_err(errno)? as u64),
+            size: disk_inode.size(),
+            blocks: disk_inode.i_blocks as u64,
+            atime: to_system_time(disk_inode.i_atime),
+            mtime: to_system_time(disk_inode.i_mtime),
+            ctime: to_system_time(disk_inode.i_ctime),
+            crtime: to_system_time(disk_inode.i_ctime),
+            kind: file_kind(disk_inode.i_mode),
+            perm: disk_inode.i_mode & 0o7777,
+            nlink: disk_inode.i_links_count as u32,
+            uid: disk_inode.i_uid as u32,
+            gid: disk_inode.i_gid as u32,
+            rdev: 0,
+            blksize: self.efs.block_size() as u32,
+            flags: 0,
+        })
+    }
+
+    /// Find the entry `name` of directory `parent`
+    fn lookup_entry(&self, parent: u64, name: &OsStr) -> Result<Inode, i32> {
+        let parent = self.inode(parent)?;
+        parent.find(name.to_str().ok_or(EINVAL)?).map_err(errno)
+    }
+
+    /// Create a new inode in directory `parent`, owned by the caller
+    fn create_entry(&self, req: &Request<'_>, parent: u64, name: &OsStr, file_type: u16, mode: u32)
+        -> Result<Inode, i32>
+    {
+        let name = name.to_str().ok_or(EINVAL)?;
+        let parent = self.inode(parent)?;
+        let inode = parent.create(name, file_type).map_err(errno)?;
+        inode.chmod(IMODE::from_bits_truncate(mode as u16)).map_err(errno)?;
+        inode.chown(Some(req.uid() as usize), Some(req.gid() as usize)).map_err(errno)?;
+        Ok(inode)
+    }
+
+    fn reply_entry(&self, inode: Result<Inode, i32>, reply: ReplyEntry) {
+        match inode.and_then(|inode| self.attr(&inode)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+}
+
+impl Filesystem for Ext2Fuse {
+    fn destroy(&mut self) {
+        if let Err(err) = self.efs.sync() {
+            error!("Failed to sync: {}", err);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.reply_entry(self.lookup_entry(parent, name), reply);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.inode(ino).and_then(|inode| self.attr(&inode)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let updated = self.inode(ino).and_then(|inode| {
+            if let Some(size) = size {
+                inode.ftruncate(size as usize).map_err(errno)?;
+            }
+            if let Some(mode) = mode {
+                inode.chmod(IMODE::from_bits_truncate(mode as u16)).map_err(errno)?;
+            }
+            if uid.is_some() || gid.is_some() {
+                inode.chown(uid.map(|uid| uid as usize), gid.map(|gid| gid as usize)).map_err(errno)?;
+            }
+            if atime.is_some() || mtime.is_some() {
+                inode.set_times(atime.map(to_secs), mtime.map(to_secs)).map_err(errno)?;
+            }
+            self.attr(&inode)
+        });
+        match updated {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.inode(ino).and_then(|inode| inode.readlink().map_err(errno)) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        // ext2fs can not create device files, fifos or sockets yet
+        if mode & libc::S_IFMT != libc::S_IFREG {
+            return reply.error(ENOSYS);
+        }
+        self.reply_entry(self.create_entry(req, parent, name, EXT2_S_IFREG, mode & !umask), reply);
+    }
+
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        self.reply_entry(self.create_entry(req, parent, name, EXT2_S_IFDIR, mode & !umask), reply);
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let removed = self.inode(parent).and_then(|parent| {
+            parent.rm_file(name.to_str().ok_or(EINVAL)?).map_err(errno)
+        });
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let removed = self.inode(parent).and_then(|parent| {
+            parent.rm_dir(name.to_str().ok_or(EINVAL)?, false).map_err(errno)
+        });
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let created = (|| {
+            let target = link.to_str().ok_or(EINVAL)?;
+            let name_str = name.to_str().ok_or(EINVAL)?;
+            let parent_inode = self.inode(parent)?;
+            parent_inode.symlink(name_str, target).map_err(errno)?;
+            let inode = parent_inode.find(name_str).map_err(errno)?;
+            inode.chown(Some(req.uid() as usize), Some(req.gid() as usize)).map_err(errno)?;
+            Ok(inode)
+        })();
+        self.reply_entry(created, reply);
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        if flags != 0 {
+            return reply.error(EINVAL);
+        }
+        let renamed = (|| {
+            let parent = self.inode(parent)?;
+            let newparent = self.inode(newparent)?;
+            let name = name.to_str().ok_or(EINVAL)?;
+            let newname = newname.to_str().ok_or(EINVAL)?;
+            parent.rename(name, &newparent, newname).map_err(errno)
+        })();
+        match renamed {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let linked = (|| {
+            let inode = self.inode(ino)?;
+            if inode.file_type() != EXT2_FT_REG_FILE {
+                return Err(EPERM);
+            }
+            let name = newname.to_str().ok_or(EINVAL)?;
+            let parent = self.inode(newparent)?;
+            parent.link(name, inode.inode_id().map_err(errno)?).map_err(errno)?;
+            Ok(inode)
+        })();
+        self.reply_entry(linked, reply);
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let mut buf = vec![0; size as usize];
+        match self.inode(ino).and_then(|inode| inode.read_at(offset as usize, &mut buf).map_err(errno)) {
+            Ok(len) => reply.data(&buf[..len]),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.inode(ino).and_then(|inode| inode.write_at(offset as usize, data).map_err(errno)) {
+            Ok(len) => reply.written(len as u32),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.inode(ino).and_then(|inode| inode.fsync().map_err(errno)) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let set = self.inode(ino).and_then(|inode| {
+            let name = name.to_str().ok_or(EINVAL)?;
+            match inode.getxattr(name) {
+                Ok(_) if flags & XATTR_CREATE != 0 => return Err(EEXIST),
+                Err(Ext2Error::NotFound) if flags & XATTR_REPLACE != 0 => return Err(ENODATA),
+                _ => {}
+            }
+            inode.setxattr(name, value).map_err(xattr_errno)
+        });
+        match set {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let value = self.inode(ino).and_then(|inode| {
+            inode.getxattr(name.to_str().ok_or(EINVAL)?).map_err(xattr_errno)
+        });
+        reply_xattr(value, size, reply);
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        // names, each ended by a nul byte
+        let names = self.inode(ino).and_then(|inode| inode.listxattr().map_err(xattr_errno)).map(|names| {
+            names.into_iter().flat_map(|name| name.into_bytes().into_iter().chain([0])).collect()
+        });
+        reply_xattr(names, size, reply);
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let removed = self.inode(ino).and_then(|inode| {
+            inode.removexattr(name.to_str().ok_or(EINVAL)?).map_err(xattr_errno)
+        });
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let dir = match self.inode(ino) {
+            Ok(dir) => dir,
+            Err(err) => return reply.error(err),
+        };
+        let names = match dir.ls() {
+            Ok(names) => names,
+            Err(err) => return reply.error(errno(err)),
+        };
+        for (idx, name) in names.iter().enumerate().skip(offset as usize) {
+            let child = match dir.find(name) {
+                Ok(child) => child,
+                Err(_) => continue,
+            };
+            let kind = match child.file_type() {
+                EXT2_FT_DIR => FileType::Directory,
+                EXT2_FT_SYMLINK => FileType::Symlink,
+                _ => child.disk_inode().map_or(FileType::RegularFile, |disk_inode| file_kind(disk_inode.i_mode)),
+            };
+            let child_ino = to_fuse_ino(child.inode_id().unwrap() as u64);
+            // the offset given back to us is that of the next entry
+            if reply.add(child_ino, (idx + 1) as i64, kind, name) {
                 break;
             }
-            offset += len;
-            read_str.push_str(core::str::from_utf8(&read_buffer[..len]).unwrap());
         }
-        assert_eq!(str, read_str);
-    };
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let created = self.create_entry(req, parent, name, EXT2_S_IFREG, mode & !umask)
+            .and_then(|inode| self.attr(&inode));
+        match created {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+}
 
-    random_str_test(4 * BLOCK_SIZE);
-    random_str_test(8 * BLOCK_SIZE + BLOCK_SIZE / 2);
-    random_str_test(100 * BLOCK_SIZE);
-    random_str_test(70 * BLOCK_SIZE + BLOCK_SIZE / 7);
-    random_str_test((12 + 128) * BLOCK_SIZE);
-    random_str_test(400 * BLOCK_SIZE);
-    random_str_test(1000 * BLOCK_SIZE);
-    random_str_test(2000 * BLOCK_SIZE);
-
-    Ok(())
-}
\ No newline at end of file
+fn main() {
+    env_logger::init();
+    let matches = App::new("ext2fs fuse")
+        .about("Mount an ext2 image on a host directory")
+        .arg(Arg::with_name("image")
+            .required(true)
+            .help("Path of the image"))
+        .arg(Arg::with_name("mountpoint")
+            .required(true)
+            .help("Directory to mount the image on"))
+        .get_matches();
+
+    let image = matches.value_of("image").unwrap();
+    let mountpoint = matches.value_of("mountpoint").unwrap();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .unwrap_or_else(|err| panic!("{}: {}", image, err));
+    let block_file = Arc::new(BlockFile::new(file));
+    let efs = Ext2FileSystem::open(block_file, Arc::new(SystemTimeProvider))
+        .unwrap_or_else(|err| panic!("{}: {}", image, err));
+    info!("Mount {} on {}", image, mountpoint);
+
+    // write dirty blocks back in the background, the writer waits for
+    // requests holding the blocks it needs
+    ext2fs::set_yield_now(std::thread::yield_now);
+    let writeback = efs.clone();
+    let mounted = Instant::now();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(writeback.writeback_policy().interval);
+        if let Err(err) = writeback.writeback(mounted.elapsed()) {
+            error!("Failed to write back: {}", err);
+        }
+    });
+
+    let options = [
+        MountOption::FSName("ext2fs".into()),
+        MountOption::Subtype("ext2".into()),
+        // let the kernel check permissions against the modes we report
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(Ext2Fuse { efs: efs.clone() }, mountpoint, &options).unwrap();
+    // the file system is kept alive by its inodes, write it back before leaving
+    efs.sync().unwrap_or_else(|err| panic!("{}: {}", image, err));
+}
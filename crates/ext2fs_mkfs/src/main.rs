@@ -0,0 +1,279 @@
+use clap::{App, Arg};
+use ext2fs::{BlockDevice, CreateOptions, Ext2Error, Ext2FileSystem, Ext2Result, Inode, TimeProvider,
+            EXT2_S_IFDIR, EXT2_S_IFREG, IMODE};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::*;
+
+/// The image is accessed in sectors, the file system finds its block size by itself
+const SECTOR_SIZE: usize = 512;
+
+/// Regular files are copied in chunks of this many bytes
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Fewest bytes of space per inode, as in mke2fs
+const MIN_INODE_RATIO: u64 = 1024;
+
+/// Longest volume label the super block can hold
+const MAX_LABEL_LEN: usize = 16;
+
+struct BlockFile {
+    file: Mutex<File>,
+    num_blocks: usize
+}
+
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|err| {
+                error!("Read sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|err| {
+                error!("Write sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
+    }
+
+    // the file is read and written at any length in one go
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        self.write_block(block_id, buf)
+    }
+
+    fn block_num(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+}
+
+struct SystemTimeProvider;
+
+impl TimeProvider for SystemTimeProvider {
+    fn get_current_time(&self) -> u32 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    }
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("{}", msg);
+    exit(1);
+}
+
+/// Parse a size in bytes, with an optional K, M or G suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, unit) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Copy the mode, owner and timestamps of a host file
+fn copy_attrs(inode: &Inode, meta: &Metadata) -> Ext2Result {
+    inode.chmod(IMODE::from_bits_truncate((meta.mode() & 0o7777) as u16))?;
+    inode.chown(Some(meta.uid() as usize), Some(meta.gid() as usize))?;
+    inode.set_times(Some(meta.atime() as u32), Some(meta.mtime() as u32))
+}
+
+fn copy_file(inode: &Inode, path: &Path) {
+    let mut file = File::open(path)
+        .unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let len = file.read(&mut buf)
+            .unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+        if len == 0 {
+            break;
+        }
+        inode.write_at(offset, &buf[..len])
+            .unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+        offset += len;
+    }
+}
+
+/// Recursively copy the entries of the host directory `host` into `dir`.
+/// `links` maps host files with several links to the inode they were
+/// copied to, so that later links to them become hard links too.
+fn copy_dir(dir: &Inode, host: &Path, links: &mut HashMap<(u64, u64), usize>) {
+    let mut entries: Vec<_> = fs::read_dir(host)
+        .unwrap_or_else(|err| fail(format!("{}: {}", host.display(), err)))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let name = match path.file_name().unwrap().to_str() {
+            Some(name) => name,
+            None => {
+                warn!("Skip {}: the name is not UTF-8", path.display());
+                continue;
+            }
+        };
+        let meta = fs::symlink_metadata(&path)
+            .unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+        let file_type = meta.file_type();
+        let key = (meta.dev(), meta.ino());
+        if file_type.is_file() && meta.nlink() > 1 {
+            if let Some(&inode_id) = links.get(&key) {
+                debug!("Link {} to inode {}", path.display(), inode_id);
+                dir.link(name, inode_id)
+                    .unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+                continue;
+            }
+        }
+
+        debug!("Copy {}", path.display());
+        let inode = if file_type.is_dir() {
+            dir.create(name, EXT2_S_IFDIR)
+        } else if file_type.is_file() {
+            dir.create(name, EXT2_S_IFREG)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&path)
+                .unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+            let target = target.to_str()
+                .unwrap_or_else(|| fail(format!("{}: the target is not UTF-8", path.display())));
+            dir.symlink(name, target).and_then(|_| dir.find(name))
+        } else {
+            warn!("Skip {}: special files are not supported", path.display());
+            continue;
+        };
+        let inode = inode.unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+
+        if file_type.is_dir() {
+            copy_dir(&inode, &path, links);
+        } else if file_type.is_file() {
+            copy_file(&inode, &path);
+            if meta.nlink() > 1 {
+                links.insert(key, inode.inode_id().unwrap());
+            }
+        }
+        // after the contents, which update the modification time
+        copy_attrs(&inode, &meta).unwrap_or_else(|err| fail(format!("{}: {}", path.display(), err)));
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("ext2fs mkfs")
+        .about("Create an ext2 image, and optionally fill it with a host directory")
+        .arg(Arg::with_name("image")
+            .required(true)
+            .help("Path of the image, created or truncated"))
+        .arg(Arg::with_name("size")
+            .required(true)
+            .help("Size of the image in bytes, with an optional K, M or G suffix"))
+        .arg(Arg::with_name("block-size")
+            .short("b")
+            .long("block-size")
+            .takes_value(true)
+            .help("Size of a block in bytes: 1024, 2048 or 4096"))
+        .arg(Arg::with_name("inode-ratio")
+            .short("i")
+            .long("inode-ratio")
+            .takes_value(true)
+            .help("Bytes of space per inode, one inode per block by default"))
+        .arg(Arg::with_name("label")
+            .short("L")
+            .long("label")
+            .takes_value(true)
+            .help("Volume label, at most 16 bytes"))
+        .arg(Arg::with_name("root")
+            .short("d")
+            .long("root-directory")
+            .takes_value(true)
+            .help("Host directory copied into the root of the image"))
+        .arg(Arg::with_name("extents")
+            .long("extents")
+            .help("Map the data of files and directories through extent trees"))
+        .arg(Arg::with_name("metadata-csum")
+            .long("metadata-csum")
+            .help("Checksum the metadata with crc32c, as ext4 does"))
+        .arg(Arg::with_name("no-resize-inode")
+            .long("no-resize-inode")
+            .help("Reserve no blocks for the group descriptor table, the file system can then only \
+                   grow as far as its last group descriptor block allows"))
+        .get_matches();
+
+    let mut options = CreateOptions::default();
+    if let Some(block_size) = matches.value_of("block-size") {
+        options.block_size = match block_size.parse() {
+            Ok(size @ (1024 | 2048 | 4096)) => size,
+            _ => fail(format!("Bad block size {}", block_size)),
+        };
+    }
+    if let Some(ratio) = matches.value_of("inode-ratio") {
+        options.bytes_per_inode = match parse_size(ratio) {
+            Some(ratio) if ratio >= MIN_INODE_RATIO => Some(ratio as usize),
+            _ => fail(format!("Bad inode ratio {}, it must be at least {}", ratio, MIN_INODE_RATIO)),
+        };
+    }
+    if let Some(label) = matches.value_of("label") {
+        if label.len() > MAX_LABEL_LEN {
+            fail(format!("Label {} is longer than {} bytes", label, MAX_LABEL_LEN));
+        }
+        options.volume_name = label.to_string();
+    }
+    options.extents = matches.is_present("extents");
+    options.metadata_csum = matches.is_present("metadata-csum");
+    options.resize_inode = !matches.is_present("no-resize-inode");
+    let size = matches.value_of("size").unwrap();
+    let size = parse_size(size).unwrap_or_else(|| fail(format!("Bad size {}", size)));
+    let size = size / SECTOR_SIZE as u64 * SECTOR_SIZE as u64;
+
+    let path = matches.value_of("image").unwrap();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    file.set_len(size).unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    let block_file = Arc::new(BlockFile {
+        file: Mutex::new(file),
+        num_blocks: size as usize / SECTOR_SIZE
+    });
+    let efs = Ext2FileSystem::create_with_options(block_file, Arc::new(SystemTimeProvider), &options)
+        .unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+
+    if let Some(root_dir) = matches.value_of("root") {
+        let root_dir = Path::new(root_dir);
+        let meta = fs::metadata(root_dir)
+            .unwrap_or_else(|err| fail(format!("{}: {}", root_dir.display(), err)));
+        if !meta.is_dir() {
+            fail(format!("{}: not a directory", root_dir.display()));
+        }
+        let root = Ext2FileSystem::root_inode(&efs)
+            .unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+        copy_dir(&root, root_dir, &mut HashMap::new());
+        copy_attrs(&root, &meta).unwrap_or_else(|err| fail(format!("{}: {}", root_dir.display(), err)));
+    }
+    // the file system is kept alive by its inodes, write it back before leaving
+    efs.sync().unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    println!("{}: {} bytes, {} byte blocks", path, size, options.block_size);
+}
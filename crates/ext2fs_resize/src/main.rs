@@ -0,0 +1,116 @@
+use clap::{App, Arg};
+use ext2fs::{BlockDevice, Ext2Error, Ext2FileSystem, Ext2Result, ZeroTimeProvider};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::Mutex;
+use log::*;
+
+/// The image is accessed in sectors, the file system finds its block size by itself
+const SECTOR_SIZE: usize = 512;
+
+struct BlockFile {
+    file: Mutex<File>,
+    num_blocks: usize
+}
+
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|err| {
+                error!("Read sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * SECTOR_SIZE) as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|err| {
+                error!("Write sector {}: {}", block_id, err);
+                Ext2Error::Io
+            })
+    }
+
+    // the file is read and written at any length in one go
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        self.write_block(block_id, buf)
+    }
+
+    fn block_num(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("{}", msg);
+    exit(1);
+}
+
+/// Parse a size in bytes, with an optional K, M or G suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, unit) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("ext2fs resize")
+        .about("Grow the ext2 file system of an image, as resize2fs does")
+        .arg(Arg::with_name("image")
+            .required(true)
+            .help("Path of the image"))
+        .arg(Arg::with_name("size")
+            .help("New size in bytes, with an optional K, M or G suffix, the size of the image by default. \
+                   The image is extended if it is smaller"))
+        .get_matches();
+
+    let path = matches.value_of("image").unwrap();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    let image_size = file.metadata()
+        .unwrap_or_else(|err| fail(format!("{}: {}", path, err)))
+        .len();
+    let size = match matches.value_of("size") {
+        Some(size) => parse_size(size).unwrap_or_else(|| fail(format!("Invalid size {}", size))),
+        None => image_size,
+    };
+    if size > image_size {
+        file.set_len(size)
+            .unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    }
+    let num_blocks = size as usize / SECTOR_SIZE;
+    let block_file = Arc::new(BlockFile { file: Mutex::new(file), num_blocks });
+    let efs = Ext2FileSystem::open(block_file, Arc::new(ZeroTimeProvider))
+        .unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    let old_blocks = efs.stats().blocks;
+    efs.resize(size as usize / efs.block_size())
+        .unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    let stats = efs.stats();
+    if stats.blocks == old_blocks {
+        println!("{}: the file system is already {} blocks long, nothing to do", path, stats.blocks);
+    } else {
+        println!("{}: the file system is now {} ({}k) blocks long", path, stats.blocks, stats.block_size / 1024);
+    }
+}
@@ -8,6 +8,11 @@ pub trait MutexSupport {
     fn before_lock() -> Self::GuardData;
     /// Called when MutexGuard dropping
     fn after_unlock(_: &mut Self::GuardData);
+    /// Called while waiting for the lock
+    #[inline(always)]
+    fn relax() {
+        core::hint::spin_loop();
+    }
 }
 
 /// 什么也不做的Spin
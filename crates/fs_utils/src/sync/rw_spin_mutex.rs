@@ -87,7 +87,7 @@ impl<T: ?Sized, S: MutexSupport> RwSpinMutex<T, S> {
         loop {
             let mut cnt = 0;
             while self.lock.load(Ordering::Relaxed) != 0 {
-                core::hint::spin_loop();
+                S::relax();
                 cnt += 1;
                 if cnt == 0x10000000 {
                     panic!("dead lock");
@@ -132,7 +132,7 @@ impl<T: ?Sized, S: MutexSupport> RwSpinMutex<T, S> {
                 panic!("dead lock");
             }
             cnt += 1;
-            core::hint::spin_loop();
+            S::relax();
         }
         atomic::fence(Ordering::Acquire);
         SharedRwMutexGuard { _not_send_sync: PhantomData, mutex: self, guard }
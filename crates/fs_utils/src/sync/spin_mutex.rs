@@ -70,7 +70,7 @@ impl<T: ?Sized, S: MutexSupport> SpinMutex<T, S> {
     fn wait_unlock(&self) {
         let mut try_count = 0usize;
         while self.lock.load(Ordering::Relaxed) {
-            core::hint::spin_loop();
+            S::relax();
             try_count += 1;
             if try_count == 0x10000000 {
                 panic!("Mutex: deadlock detected! try_count > {:#x}\n", try_count);
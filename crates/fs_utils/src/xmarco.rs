@@ -1,12 +1,6 @@
 #[macro_export]
 macro_rules! offset_of {
     ($ty:ty, $field:ident) => {
-        //  Undefined Behavior: dereferences a null pointer.
-        //  Undefined Behavior: accesses field outside of valid memory area.
-        #[allow(clippy::zero_ptr)]
-        #[allow(deref_nullptr, unused_unsafe)]
-        unsafe {
-            &(*(0 as *const $ty)).$field as *const _ as usize
-        }
+        core::mem::offset_of!($ty, $field)
     };
 }
@@ -10,13 +10,16 @@ pub type FileType = fops::FileType;
 /// Representation of the various permissions on a file.
 pub type Permissions = fops::FilePerm;
 
+/// How [`File::allocate`] changes a range of a file.
+pub type FallocMode = fops::FallocMode;
+
 /// An object providing access to an open file on the filesystem.
 pub struct File {
     inner: fops::File,
 }
 
 /// Metadata information about a file.
-pub struct Metadata(fops::FileAttr);
+pub struct Metadata(pub(super) fops::FileAttr);
 
 /// Options and flags which can be used to configure how a file is opened.
 #[derive(Clone, Debug)]
@@ -64,6 +67,18 @@ impl OpenOptions {
         self
     }
 
+    /// Sets the option to execute the file, which must be a regular file.
+    pub fn execute(&mut self, execute: bool) -> &mut Self {
+        self.0.execute(execute);
+        self
+    }
+
+    /// Sets the mode bits that a new file will be created with.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.0.mode(mode);
+        self
+    }
+
     /// Opens a file at `path` with the options specified by `self`.
     pub fn open(&self, path: &str) -> Result<File> {
         fops::File::open(path, &self.0).map(|inner| File { inner })
@@ -90,16 +105,58 @@ impl Metadata {
         self.0.is_file()
     }
 
+    /// Returns `true` if this metadata is for a symbolic link, which is only
+    /// the case if it was queried by [`symlink_metadata`].
+    ///
+    /// [`symlink_metadata`]: super::symlink_metadata
+    pub const fn is_symlink(&self) -> bool {
+        self.0.is_symlink()
+    }
+
     /// Returns the size of the file, in bytes, this metadata is for.
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u64 {
         self.0.size()
     }
 
+    /// Returns the number of 512-byte blocks allocated to the file, which
+    /// is less than its size asks for if it has holes.
+    pub const fn blocks(&self) -> u64 {
+        self.0.blocks()
+    }
+
     /// Returns the permissions of the file this metadata is for.
     pub fn permissions(&self) -> Permissions {
         self.0.perm()
     }
+
+    /// Returns the user ID of the owner of the file.
+    pub const fn uid(&self) -> u32 {
+        self.0.uid()
+    }
+
+    /// Returns the group ID of the owner of the file.
+    pub const fn gid(&self) -> u32 {
+        self.0.gid()
+    }
+
+    /// Returns the last access time of the file, in seconds since the Unix
+    /// epoch.
+    pub const fn accessed(&self) -> u64 {
+        self.0.atime()
+    }
+
+    /// Returns the last modification time of the file, in seconds since the
+    /// Unix epoch.
+    pub const fn modified(&self) -> u64 {
+        self.0.mtime()
+    }
+
+    /// Returns the last status change time of the file, in seconds since the
+    /// Unix epoch.
+    pub const fn changed(&self) -> u64 {
+        self.0.ctime()
+    }
 }
 
 impl fmt::Debug for Metadata {
@@ -108,6 +165,7 @@ impl fmt::Debug for Metadata {
             .field("file_type", &self.0.file_type())
             .field("is_dir", &self.0.is_dir())
             .field("is_file", &self.0.is_file())
+            .field("is_symlink", &self.0.is_symlink())
             .finish_non_exhaustive()
     }
 }
@@ -147,6 +205,12 @@ impl File {
         self.inner.truncate(size)
     }
 
+    /// Allocates, deallocates or zeroes the `len` bytes of the file from
+    /// `offset` on, as `mode` says.
+    pub fn allocate(&self, mode: FallocMode, offset: u64, len: u64) -> Result<()> {
+        self.inner.fallocate(mode, offset, len)
+    }
+
     /// Queries metadata about the underlying file.
     pub fn metadata(&self) -> Result<Metadata> {
         self.inner.get_attr().map(Metadata)
@@ -4,11 +4,17 @@ mod dir;
 mod file;
 
 pub use self::dir::{DirBuilder, DirEntry, ReadDir};
-pub use self::file::{File, FileType, Metadata, OpenOptions, Permissions};
+pub use self::file::{FallocMode, File, FileType, Metadata, OpenOptions, Permissions};
 
 use alloc::{string::String, vec::Vec};
 use axio::{self as io, prelude::*};
 
+/// Attributes of a mounted filesystem, see [`statfs`].
+pub type FileSystemInfo = crate::fops::FileSystemInfo;
+
+/// How a filesystem is mounted.
+pub type MountFlags = crate::fops::MountFlags;
+
 /// Returns an iterator over the entries within a directory.
 pub fn read_dir(path: &str) -> io::Result<ReadDir> {
     ReadDir::new(path)
@@ -56,7 +62,38 @@ pub fn write<C: AsRef<[u8]>>(path: &str, contents: C) -> io::Result<()> {
 /// Given a path, query the file system to get information about a file,
 /// directory, etc.
 pub fn metadata(path: &str) -> io::Result<Metadata> {
-    File::open(path)?.metadata()
+    crate::root::lookup(None, path)?.get_attr().map(Metadata)
+}
+
+/// Query the metadata about a file without following symlinks.
+pub fn symlink_metadata(path: &str) -> io::Result<Metadata> {
+    crate::root::lookup_link(None, path)?.get_attr().map(Metadata)
+}
+
+/// Changes the permissions found on a file or a directory, which only its
+/// owner may do.
+pub fn set_permissions(path: &str, perm: Permissions) -> io::Result<()> {
+    crate::root::set_perm(path, perm)
+}
+
+/// Changes the owner and group of a file or a directory, `None` keeps them.
+///
+/// Only root may change the owner, the owner may change the group to one
+/// they are in.
+pub fn chown(path: &str, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    crate::root::set_owner(path, uid, gid)
+}
+
+/// Reads a symbolic link, returning the file that the link points to.
+pub fn read_link(path: &str) -> io::Result<String> {
+    crate::root::read_link(None, path)
+}
+
+/// Creates a new symbolic link on the filesystem.
+///
+/// The `link` path will be a symbolic link pointing to the `original` path.
+pub fn soft_link(original: &str, link: &str) -> io::Result<()> {
+    crate::root::symlink(None, original, link)
 }
 
 /// Creates a new, empty directory at the provided path.
@@ -79,3 +116,42 @@ pub fn remove_dir(path: &str) -> io::Result<()> {
 pub fn remove_file(path: &str) -> io::Result<()> {
     crate::root::remove_file(None, path)
 }
+
+/// Rename a file or directory to a new name, replacing the original file if
+/// `to` already exists.
+pub fn rename(from: &str, to: &str) -> io::Result<()> {
+    crate::root::rename(from, to)
+}
+
+/// Retrieves the value of the extended attribute `name` of a file, following
+/// symlinks.
+pub fn get_xattr(path: &str, name: &str) -> io::Result<Vec<u8>> {
+    crate::root::lookup(None, path)?.getxattr(name)
+}
+
+/// Sets the extended attribute `name` of a file to `value`, creating it if it
+/// does not exist.
+pub fn set_xattr(path: &str, name: &str, value: &[u8]) -> io::Result<()> {
+    crate::root::lookup(None, path)?.setxattr(name, value)
+}
+
+/// Lists the names of the extended attributes of a file.
+pub fn list_xattr(path: &str) -> io::Result<Vec<String>> {
+    crate::root::lookup(None, path)?.listxattr()
+}
+
+/// Removes the extended attribute `name` of a file.
+pub fn remove_xattr(path: &str, name: &str) -> io::Result<()> {
+    crate::root::lookup(None, path)?.removexattr(name)
+}
+
+/// Returns the attributes of the filesystem that holds `path`, such as its
+/// size and free space.
+pub fn statfs(path: &str) -> io::Result<FileSystemInfo> {
+    crate::root::statfs(path)
+}
+
+/// Writes all dirty data of the filesystems to their devices.
+pub fn sync() -> io::Result<()> {
+    crate::root::sync()
+}
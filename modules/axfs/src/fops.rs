@@ -9,6 +9,9 @@ pub type FileType = axfs_vfs::VfsNodeType;
 pub type DirEntry = axfs_vfs::VfsDirEntry;
 pub type FileAttr = axfs_vfs::VfsNodeAttr;
 pub type FilePerm = axfs_vfs::VfsNodePerm;
+pub type FallocMode = axfs_vfs::VfsFallocMode;
+pub type FileSystemInfo = axfs_vfs::FileSystemInfo;
+pub type MountFlags = axfs_vfs::VfsMountFlags;
 
 pub struct File {
     node: WithCap<VfsNodeRef>,
@@ -30,9 +33,10 @@ pub struct OpenOptions {
     truncate: bool,
     create: bool,
     create_new: bool,
+    execute: bool,
     // system-specific
     _custom_flags: i32,
-    _mode: u32,
+    mode: u32,
 }
 
 impl OpenOptions {
@@ -45,9 +49,10 @@ impl OpenOptions {
             truncate: false,
             create: false,
             create_new: false,
+            execute: false,
             // system-specific
             _custom_flags: 0,
-            _mode: 0o666,
+            mode: 0o666,
         }
     }
     pub fn read(&mut self, read: bool) {
@@ -68,9 +73,15 @@ impl OpenOptions {
     pub fn create_new(&mut self, create_new: bool) {
         self.create_new = create_new;
     }
+    pub fn execute(&mut self, execute: bool) {
+        self.execute = execute;
+    }
+    pub fn mode(&mut self, mode: u32) {
+        self.mode = mode;
+    }
 
     const fn is_valid(&self) -> bool {
-        if !self.read && !self.write && !self.append {
+        if !self.read && !self.write && !self.append && !self.execute {
             return false;
         }
         match (self.write, self.append) {
@@ -98,22 +109,25 @@ impl File {
         }
 
         let node_option = crate::root::lookup(dir, path);
-        let node = if opts.create || opts.create_new {
+        let (node, created) = if opts.create || opts.create_new {
             match node_option {
                 Ok(node) => {
                     // already exists
                     if opts.create_new {
                         return ax_err!(AlreadyExists);
                     }
-                    node
+                    (node, false)
                 }
                 // not exists, create new
-                Err(VfsError::NotFound) => crate::root::create_file(dir, path)?,
+                Err(VfsError::NotFound) => {
+                    let perm = FilePerm::from_bits_truncate(opts.mode as u16);
+                    (crate::root::create_file(dir, path, perm)?, true)
+                }
                 Err(e) => return Err(e),
             }
         } else {
             // just open the existing
-            node_option?
+            (node_option?, false)
         };
 
         let attr = node.get_attr()?;
@@ -122,10 +136,14 @@ impl File {
         {
             return ax_err!(IsADirectory);
         }
-        let access_cap = opts.into();
-        if !perm_to_cap(attr.perm()).contains(access_cap) {
+        if opts.execute && !attr.is_file() {
             return ax_err!(PermissionDenied);
         }
+        let access_cap = opts.into();
+        // the creator may access a new file whatever its mode
+        if !created {
+            check_access(&attr, access_cap)?;
+        }
 
         node.open()?;
         if opts.truncate {
@@ -147,6 +165,11 @@ impl File {
         Ok(())
     }
 
+    pub fn fallocate(&self, mode: FallocMode, offset: u64, len: u64) -> AxResult {
+        self.node.access(Cap::WRITE)?.fallocate(mode, offset, len)?;
+        Ok(())
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> AxResult<usize> {
         let node = self.node.access(Cap::READ)?;
         let read_len = node.read_at(self.offset, buf)?;
@@ -190,9 +213,7 @@ impl Directory {
             return ax_err!(NotADirectory);
         }
         let access_cap = opts.into();
-        if !perm_to_cap(attr.perm()).contains(access_cap) {
-            return ax_err!(PermissionDenied);
-        }
+        check_access(&attr, access_cap)?;
 
         node.open()?;
         Ok(Self {
@@ -222,7 +243,7 @@ impl Directory {
     }
 
     pub fn create_file(&self, path: &str) -> AxResult<VfsNodeRef> {
-        crate::root::create_file(self.access_at(path)?, path)
+        crate::root::create_file(self.access_at(path)?, path, FilePerm::default_file())
     }
 
     pub fn create_dir(&self, path: &str) -> AxResult {
@@ -280,6 +301,7 @@ impl fmt::Debug for OpenOptions {
         fmt_opt!(truncate, "TRUNC");
         fmt_opt!(create, "CREATE");
         fmt_opt!(create_new, "CREATE_NEW");
+        fmt_opt!(execute, "EXEC");
         Ok(())
     }
 }
@@ -293,20 +315,83 @@ impl From<&OpenOptions> for Cap {
         if opts.write | opts.append {
             cap |= Cap::WRITE;
         }
+        if opts.execute {
+            cap |= Cap::EXECUTE;
+        }
         cap
     }
 }
 
-fn perm_to_cap(perm: FilePerm) -> Cap {
+/// Capabilities the current task has on a node with `attr`, given by the
+/// owner, group or other permission bits, whichever apply first.
+///
+/// Root may read and write anything, and execute anything that someone may
+/// execute.
+pub(crate) fn access_cap(attr: &FileAttr) -> Cap {
+    let cred = axtask::current_cred();
+    let perm = attr.perm();
+    if cred.is_root() {
+        let mut cap = Cap::READ | Cap::WRITE;
+        let any_exec = FilePerm::OWNER_EXEC | FilePerm::GROUP_EXEC | FilePerm::OTHER_EXEC;
+        if attr.is_dir() || perm.intersects(any_exec) {
+            cap |= Cap::EXECUTE;
+        }
+        return cap;
+    }
+    let bits = if cred.uid() == attr.uid() {
+        perm.bits() >> 6
+    } else if cred.in_group(attr.gid()) {
+        perm.bits() >> 3
+    } else {
+        perm.bits()
+    };
     let mut cap = Cap::empty();
-    if perm.owner_readable() {
+    if bits & 0o4 != 0 {
         cap |= Cap::READ;
     }
-    if perm.owner_writable() {
+    if bits & 0o2 != 0 {
         cap |= Cap::WRITE;
     }
-    if perm.owner_executable() {
+    if bits & 0o1 != 0 {
         cap |= Cap::EXECUTE;
     }
     cap
 }
+
+/// Check that the current task has `cap` on a node with `attr`.
+pub(crate) fn check_access(attr: &FileAttr, cap: Cap) -> AxResult {
+    if access_cap(attr).contains(cap) {
+        Ok(())
+    } else {
+        ax_err!(PermissionDenied)
+    }
+}
+
+/// Check that the current task may remove an entry with `attr` from a
+/// directory with `dir_attr`, or replace it.
+///
+/// It needs to write and search the directory, and if the directory is
+/// sticky, to own either the directory or the entry.
+pub(crate) fn check_remove(dir_attr: &FileAttr, attr: &FileAttr) -> AxResult {
+    check_access(dir_attr, Cap::WRITE | Cap::EXECUTE)?;
+    let cred = axtask::current_cred();
+    if dir_attr.perm().contains(FilePerm::STICKY)
+        && !cred.is_root()
+        && cred.uid() != dir_attr.uid()
+        && cred.uid() != attr.uid()
+    {
+        return ax_err!(PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Check that the current task may change the mode of a node with `attr`,
+/// which only its owner and root may do.
+pub(crate) fn check_owner(attr: &FileAttr) -> AxResult {
+    let cred = axtask::current_cred();
+    if cred.is_root() || cred.uid() == attr.uid() {
+        Ok(())
+    } else {
+        ax_err!(PermissionDenied)
+    }
+}
@@ -0,0 +1,412 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use axfs_vfs::{FileSystemInfo, VfsDirEntry, VfsError, VfsFallocMode, VfsMountFlags, VfsNodePerm, VfsResult};
+use axfs_vfs::{VfsNodeAttr, VfsNodeOps, VfsNodeRef, VfsNodeType, VfsOps};
+use axsync::Mutex;
+use ext2fs::{Ext2Error, FallocMode, Inode, ZeroTimeProvider, IMODE};
+use ext2fs::{EXT2_FT_DIR, EXT2_FT_SYMLINK, EXT2_S_IFDIR, EXT2_S_IFREG};
+
+use crate::dev::Disk;
+
+pub struct Ext2FileSystem {
+    inner: Arc<ext2fs::Ext2FileSystem>,
+    root_dir: VfsNodeRef,
+}
+
+pub struct FileWrapper {
+    inode: Inode,
+    fs: Arc<ext2fs::Ext2FileSystem>,
+}
+
+pub struct DirWrapper {
+    inode: Inode,
+    fs: Arc<ext2fs::Ext2FileSystem>,
+}
+
+/// Sector size of [`Disk`].
+const SECTOR_SIZE: usize = 512;
+
+/// Adapts [`Disk`] to the block interface of `ext2fs`, which reads the file
+/// system block size from the super block and groups sectors accordingly.
+struct Ext2Disk(Mutex<Disk>);
+
+impl Ext2FileSystem {
+    pub fn new(disk: Disk) -> Self {
+        // tasks waiting for a busy inode or buffer give up the CPU
+        #[cfg(feature = "multitask")]
+        ext2fs::set_yield_now(axtask::yield_now);
+        let dev = Arc::new(Ext2Disk(Mutex::new(disk)));
+        let inner = ext2fs::Ext2FileSystem::open(dev, Arc::new(ZeroTimeProvider))
+            .expect("failed to initialize ext2 filesystem");
+        let root_inode = ext2fs::Ext2FileSystem::root_inode(&inner)
+            .expect("failed to read ext2 root directory");
+        let root_dir = Self::new_node(root_inode, &inner);
+        #[cfg(feature = "multitask")]
+        Self::spawn_writeback(inner.clone());
+        Self { inner, root_dir }
+    }
+
+    /// Write dirty blocks back in the background, as the writeback policy of
+    /// `fs` asks.
+    #[cfg(feature = "multitask")]
+    fn spawn_writeback(fs: Arc<ext2fs::Ext2FileSystem>) {
+        axtask::spawn(move || {
+            // time since the task started, which is all the policy needs
+            let mut now = core::time::Duration::ZERO;
+            loop {
+                let interval = fs.writeback_policy().interval;
+                axtask::sleep(interval);
+                now += interval;
+                if let Err(err) = fs.writeback(now) {
+                    error!("ext2fs writeback failed: {}", err);
+                }
+            }
+        });
+    }
+
+    fn new_node(inode: Inode, fs: &Arc<ext2fs::Ext2FileSystem>) -> VfsNodeRef {
+        let fs = fs.clone();
+        if inode.file_type() == EXT2_FT_DIR {
+            Arc::new(DirWrapper { inode, fs })
+        } else {
+            Arc::new(FileWrapper { inode, fs })
+        }
+    }
+}
+
+impl VfsNodeOps for FileWrapper {
+    axfs_vfs::impl_vfs_non_dir_default! {}
+
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        inode_attr(&self.inode)
+    }
+
+    fn set_perm(&self, perm: VfsNodePerm) -> VfsResult {
+        set_perm(&self.inode, perm)
+    }
+
+    fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> VfsResult {
+        set_owner(&self.inode, uid, gid)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        self.inode
+            .read_at(offset as usize, buf)
+            .map_err(as_vfs_err)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        self.inode
+            .write_at(offset as usize, buf)
+            .map_err(as_vfs_err)
+    }
+
+    fn fsync(&self) -> VfsResult {
+        self.inode.fsync().map_err(as_vfs_err)
+    }
+
+    fn truncate(&self, size: u64) -> VfsResult {
+        self.inode.ftruncate(size as usize).map_err(as_vfs_err)
+    }
+
+    fn fallocate(&self, mode: VfsFallocMode, offset: u64, len: u64) -> VfsResult {
+        // both take the flags of Linux
+        let mode = FallocMode::from_bits_truncate(mode.bits());
+        self.inode
+            .fallocate(mode, offset as usize, len as usize)
+            .map_err(as_vfs_err)
+    }
+
+    fn readlink(&self) -> VfsResult<String> {
+        self.inode.readlink().map_err(as_vfs_err)
+    }
+
+    fn getxattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.inode.getxattr(name).map_err(as_vfs_err)
+    }
+
+    fn setxattr(&self, name: &str, value: &[u8]) -> VfsResult {
+        self.inode.setxattr(name, value).map_err(as_vfs_err)
+    }
+
+    fn listxattr(&self) -> VfsResult<Vec<String>> {
+        self.inode.listxattr().map_err(as_vfs_err)
+    }
+
+    fn removexattr(&self, name: &str) -> VfsResult {
+        self.inode.removexattr(name).map_err(as_vfs_err)
+    }
+}
+
+impl DirWrapper {
+    /// Walk `path` from this directory and return the inode it refers to.
+    fn walk(&self, path: &str) -> VfsResult<Inode> {
+        let mut cur = self.inode.clone();
+        for name in path.split('/') {
+            if name.is_empty() || name == "." {
+                continue;
+            }
+            cur = cur.find(name).map_err(as_vfs_err)?;
+        }
+        Ok(cur)
+    }
+
+    /// Split `path` into its parent directory and the last component.
+    fn walk_parent<'a>(&self, path: &'a str) -> VfsResult<(Inode, &'a str)> {
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (self.walk(parent)?, name),
+            None => (self.inode.clone(), path),
+        };
+        if parent.file_type() != EXT2_FT_DIR {
+            return Err(VfsError::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+}
+
+impl VfsNodeOps for DirWrapper {
+    axfs_vfs::impl_vfs_dir_default! {}
+
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        inode_attr(&self.inode)
+    }
+
+    fn set_perm(&self, perm: VfsNodePerm) -> VfsResult {
+        set_perm(&self.inode, perm)
+    }
+
+    fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> VfsResult {
+        set_owner(&self.inode, uid, gid)
+    }
+
+    fn parent(&self) -> Option<VfsNodeRef> {
+        self.inode
+            .find("..")
+            .ok()
+            .map(|inode| Ext2FileSystem::new_node(inode, &self.fs))
+    }
+
+    fn lookup(self: Arc<Self>, path: &str) -> VfsResult<VfsNodeRef> {
+        debug!("lookup at ext2fs: {}", path);
+        let inode = self.walk(path)?;
+        Ok(Ext2FileSystem::new_node(inode, &self.fs))
+    }
+
+    fn create(&self, path: &str, ty: VfsNodeType) -> VfsResult {
+        debug!("create {:?} at ext2fs: {}", ty, path);
+        let path = path.trim_matches('/');
+        if path.is_empty() || path == "." {
+            return Ok(());
+        }
+        let (parent, name) = self.walk_parent(path)?;
+        if name == "." || name == ".." {
+            return Ok(());
+        }
+        let file_type = match ty {
+            VfsNodeType::File => EXT2_S_IFREG,
+            VfsNodeType::Dir => EXT2_S_IFDIR,
+            _ => return Err(VfsError::Unsupported),
+        };
+        match parent.create(name, file_type) {
+            Ok(_) | Err(Ext2Error::AlreadyExists) => Ok(()),
+            Err(err) => Err(as_vfs_err(err)),
+        }
+    }
+
+    fn remove(&self, path: &str) -> VfsResult {
+        debug!("remove at ext2fs: {}", path);
+        let path = path.trim_matches('/');
+        assert!(!path.is_empty()); // already check at `root.rs`
+        let (parent, name) = self.walk_parent(path)?;
+        if name == "." || name == ".." {
+            return Err(VfsError::InvalidInput);
+        }
+        let node = parent.find(name).map_err(as_vfs_err)?;
+        let removed = if node.file_type() == EXT2_FT_DIR {
+            parent.rm_dir(name, false)
+        } else {
+            parent.rm_file(name)
+        };
+        removed.map_err(as_vfs_err)
+    }
+
+    fn symlink(&self, path: &str, target: &str) -> VfsResult {
+        debug!("symlink at ext2fs: {} -> {}", path, target);
+        if target.is_empty() {
+            return Err(VfsError::NotFound);
+        }
+        let (parent, name) = self.walk_parent(path.trim_matches('/'))?;
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(VfsError::AlreadyExists);
+        }
+        parent.symlink(name, target).map_err(as_vfs_err)
+    }
+
+    fn rename(&self, src_path: &str, dst_path: &str) -> VfsResult {
+        debug!("rename at ext2fs: {} -> {}", src_path, dst_path);
+        let (src_parent, src_name) = self.walk_parent(src_path.trim_matches('/'))?;
+        let (dst_parent, dst_name) = self.walk_parent(dst_path.trim_matches('/'))?;
+        for name in [src_name, dst_name] {
+            if name.is_empty() || name == "." || name == ".." {
+                return Err(VfsError::InvalidInput);
+            }
+        }
+        src_parent
+            .rename(src_name, &dst_parent, dst_name)
+            .map_err(as_vfs_err)
+    }
+
+    fn read_dir(&self, start_idx: usize, dirents: &mut [VfsDirEntry]) -> VfsResult<usize> {
+        let entries = self
+            .inode
+            .read_dir(start_idx, dirents.len())
+            .map_err(as_vfs_err)?;
+        for ((name, file_type), out_entry) in entries.iter().zip(dirents.iter_mut()) {
+            *out_entry = VfsDirEntry::new(name, as_vfs_type(*file_type));
+        }
+        Ok(entries.len())
+    }
+
+    fn getxattr(&self, name: &str) -> VfsResult<Vec<u8>> {
+        self.inode.getxattr(name).map_err(as_vfs_err)
+    }
+
+    fn setxattr(&self, name: &str, value: &[u8]) -> VfsResult {
+        self.inode.setxattr(name, value).map_err(as_vfs_err)
+    }
+
+    fn listxattr(&self) -> VfsResult<Vec<String>> {
+        self.inode.listxattr().map_err(as_vfs_err)
+    }
+
+    fn removexattr(&self, name: &str) -> VfsResult {
+        self.inode.removexattr(name).map_err(as_vfs_err)
+    }
+}
+
+impl VfsOps for Ext2FileSystem {
+    fn umount(&self) -> VfsResult {
+        self.inner.sync().map_err(as_vfs_err)
+    }
+
+    fn statfs(&self) -> VfsResult<FileSystemInfo> {
+        let stats = self.inner.stats();
+        let flags = if self.inner.is_read_only() {
+            VfsMountFlags::RDONLY
+        } else {
+            VfsMountFlags::empty()
+        };
+        Ok(FileSystemInfo::new(stats.magic as u64, stats.block_size as u64, stats.name_len as u64)
+            .with_blocks(stats.blocks as u64, stats.free_blocks as u64, stats.avail_blocks as u64)
+            .with_files(stats.inodes as u64, stats.free_inodes as u64)
+            .with_fsid(stats.fsid)
+            .with_flags(flags))
+    }
+
+    fn sync(&self) -> VfsResult {
+        self.inner.sync().map_err(as_vfs_err)
+    }
+
+    fn root_dir(&self) -> VfsNodeRef {
+        self.root_dir.clone()
+    }
+}
+
+impl ext2fs::BlockDevice for Ext2Disk {
+    fn read_block(&self, block_id: usize, mut buf: &mut [u8]) -> ext2fs::Ext2Result {
+        let mut disk = self.0.lock();
+        disk.set_position((block_id * SECTOR_SIZE) as u64);
+        while !buf.is_empty() {
+            let n = disk.read_one(buf).map_err(|_| Ext2Error::Io)?;
+            let tmp = buf;
+            buf = &mut tmp[n..];
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, mut buf: &[u8]) -> ext2fs::Ext2Result {
+        let mut disk = self.0.lock();
+        disk.set_position((block_id * SECTOR_SIZE) as u64);
+        while !buf.is_empty() {
+            let n = disk.write_one(buf).map_err(|_| Ext2Error::Io)?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    // runs of sectors are transferred under one lock of the disk
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> ext2fs::Ext2Result {
+        self.read_block(block_id, buf)
+    }
+
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) -> ext2fs::Ext2Result {
+        self.write_block(block_id, buf)
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_num(&self) -> usize {
+        (self.0.lock().size() / SECTOR_SIZE as u64) as usize
+    }
+}
+
+fn inode_attr(inode: &Inode) -> VfsResult<VfsNodeAttr> {
+    let disk_inode = inode.disk_inode().map_err(as_vfs_err)?;
+    let perm = VfsNodePerm::from_bits_truncate(disk_inode.i_mode & 0o7777);
+    let ty = as_vfs_type(inode.file_type());
+    Ok(VfsNodeAttr::new(
+        perm,
+        ty,
+        disk_inode.size(),
+        disk_inode.i_blocks as u64,
+    )
+    .with_owner(disk_inode.i_uid as u32, disk_inode.i_gid as u32)
+    .with_times(
+        disk_inode.i_atime as u64,
+        disk_inode.i_mtime as u64,
+        disk_inode.i_ctime as u64,
+    ))
+}
+
+fn set_perm(inode: &Inode, perm: VfsNodePerm) -> VfsResult {
+    inode
+        .chmod(IMODE::from_bits_truncate(perm.bits()))
+        .map_err(as_vfs_err)
+}
+
+fn set_owner(inode: &Inode, uid: Option<u32>, gid: Option<u32>) -> VfsResult {
+    // IDs are 16 bits on disk
+    if uid.into_iter().chain(gid).any(|id| id > u16::MAX as u32) {
+        return Err(VfsError::InvalidInput);
+    }
+    inode
+        .chown(uid.map(|uid| uid as usize), gid.map(|gid| gid as usize))
+        .map_err(as_vfs_err)
+}
+
+const fn as_vfs_type(file_type: u8) -> VfsNodeType {
+    match file_type {
+        EXT2_FT_DIR => VfsNodeType::Dir,
+        EXT2_FT_SYMLINK => VfsNodeType::SymLink,
+        _ => VfsNodeType::File,
+    }
+}
+
+const fn as_vfs_err(err: Ext2Error) -> VfsError {
+    match err {
+        Ext2Error::NotFound => VfsError::NotFound,
+        Ext2Error::AlreadyExists => VfsError::AlreadyExists,
+        Ext2Error::NotADirectory => VfsError::NotADirectory,
+        Ext2Error::IsADirectory => VfsError::IsADirectory,
+        Ext2Error::DirectoryNotEmpty => VfsError::DirectoryNotEmpty,
+        Ext2Error::InvalidInput | Ext2Error::NameTooLong => VfsError::InvalidInput,
+        Ext2Error::NoSpace | Ext2Error::FileTooLarge => VfsError::StorageFull,
+        Ext2Error::ReadOnly => VfsError::PermissionDenied,
+        Ext2Error::Unsupported => VfsError::Unsupported,
+        Ext2Error::Corrupted => VfsError::InvalidData,
+        Ext2Error::Io => VfsError::Io,
+    }
+}
@@ -1,7 +1,7 @@
 use alloc::sync::Arc;
 use core::cell::UnsafeCell;
 
-use axfs_vfs::{VfsDirEntry, VfsError, VfsNodePerm, VfsResult};
+use axfs_vfs::{FileSystemInfo, VfsDirEntry, VfsError, VfsNodePerm, VfsResult};
 use axfs_vfs::{VfsNodeAttr, VfsNodeOps, VfsNodeRef, VfsNodeType, VfsOps};
 use axsync::Mutex;
 use fatfs::{Dir, File, LossyOemCpConverter, NullTimeProvider, Read, Seek, SeekFrom, Write};
@@ -9,6 +9,10 @@ use fatfs::{Dir, File, LossyOemCpConverter, NullTimeProvider, Read, Seek, SeekFr
 use crate::dev::Disk;
 
 const BLOCK_SIZE: usize = 512;
+/// Filesystem type reported by `statfs`, as Linux does for FAT.
+const MSDOS_SUPER_MAGIC: u64 = 0x4d44;
+/// Longest long file name, in bytes.
+const MAX_NAME_LEN: u64 = 255;
 
 pub struct FatFileSystem {
     inner: fatfs::FileSystem<Disk, NullTimeProvider, LossyOemCpConverter>,
@@ -151,6 +155,40 @@ impl VfsNodeOps for DirWrapper<'static> {
         self.0.remove(path).map_err(as_vfs_err)
     }
 
+    fn rename(&self, src_path: &str, dst_path: &str) -> VfsResult {
+        debug!("rename at fatfs: {} -> {}", src_path, dst_path);
+        let src_path = src_path.trim_matches('/');
+        let dst_path = dst_path.trim_matches('/');
+        if src_path.eq_ignore_ascii_case(dst_path) {
+            // the same entry, as FAT file names are case insensitive
+            return if src_path == dst_path {
+                Ok(())
+            } else {
+                self.0.rename(src_path, &self.0, dst_path).map_err(as_vfs_err)
+            };
+        }
+
+        // fatfs does not replace an existing entry, so remove it first
+        let src_is_dir = self.0.open_dir(src_path).is_ok();
+        if !src_is_dir && self.0.open_file(src_path).is_err() {
+            return Err(VfsError::NotFound);
+        }
+        if self.0.open_dir(dst_path).is_ok() {
+            if !src_is_dir {
+                return Err(VfsError::IsADirectory);
+            }
+            self.0.remove(dst_path).map_err(as_vfs_err)?;
+        } else if self.0.open_file(dst_path).is_ok() {
+            if src_is_dir {
+                return Err(VfsError::NotADirectory);
+            }
+            self.0.remove(dst_path).map_err(as_vfs_err)?;
+        }
+        self.0
+            .rename(src_path, &self.0, dst_path)
+            .map_err(as_vfs_err)
+    }
+
     fn read_dir(&self, start_idx: usize, dirents: &mut [VfsDirEntry]) -> VfsResult<usize> {
         let mut iter = self.0.iter().skip(start_idx);
         for (i, out_entry) in dirents.iter_mut().enumerate() {
@@ -174,6 +212,18 @@ impl VfsNodeOps for DirWrapper<'static> {
 }
 
 impl VfsOps for FatFileSystem {
+    fn statfs(&self) -> VfsResult<FileSystemInfo> {
+        let stats = self.inner.stats().map_err(as_vfs_err)?;
+        let clusters = stats.total_clusters() as u64;
+        let free = stats.free_clusters() as u64;
+        // FAT has no inodes, and nothing is reserved for root
+        Ok(
+            FileSystemInfo::new(MSDOS_SUPER_MAGIC, stats.cluster_size() as u64, MAX_NAME_LEN)
+                .with_blocks(clusters, free, free)
+                .with_fsid(self.inner.volume_id() as u64),
+        )
+    }
+
     fn root_dir(&self) -> VfsNodeRef {
         let root_dir = unsafe { (*self.root_dir.get()).as_ref().unwrap() };
         root_dir.clone()
@@ -1,5 +1,8 @@
 #[cfg(feature = "fatfs")]
 pub mod fatfs;
 
+#[cfg(feature = "ext2fs")]
+pub mod ext2fs;
+
 #[cfg(feature = "devfs")]
 pub use axfs_devfs as devfs;
@@ -4,17 +4,28 @@
 
 use alloc::{string::String, sync::Arc, vec::Vec};
 use axerrno::{ax_err, AxError, AxResult};
-use axfs_vfs::{VfsNodeAttr, VfsNodeOps, VfsNodeRef, VfsNodeType, VfsOps, VfsResult};
+use axfs_vfs::{FileSystemInfo, VfsNodeAttr, VfsNodeOps, VfsNodePerm, VfsNodeRef, VfsNodeType};
+use axfs_vfs::{VfsOps, VfsResult};
 use axsync::Mutex;
+use capability::Cap;
 use lazy_init::LazyInit;
 
+use crate::fops::{check_access, check_owner, check_remove};
 use crate::{api::FileType, fs};
 
 static CURRENT_DIR_PATH: Mutex<String> = Mutex::new(String::new());
 static CURRENT_DIR: LazyInit<Mutex<VfsNodeRef>> = LazyInit::new();
 
-#[cfg(feature = "fatfs")]
-type MainFileSystem = fs::fatfs::FatFileSystem;
+/// Maximum number of symbolic links followed in resolving a path.
+const MAX_SYMLINKS: usize = 40;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "ext2fs")] {
+        type MainFileSystem = fs::ext2fs::Ext2FileSystem;
+    } else if #[cfg(feature = "fatfs")] {
+        type MainFileSystem = fs::fatfs::FatFileSystem;
+    }
+}
 
 struct MountPoint {
     path: &'static str,
@@ -74,6 +85,20 @@ impl RootDirectory {
         self.mounts.iter().any(|mp| mp.path == path)
     }
 
+    /// Write all dirty data of the main and mounted filesystems to their devices.
+    pub fn sync(&self) -> AxResult {
+        self.main_fs.sync()?;
+        for mp in self.mounts.iter() {
+            mp.fs.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Attributes of the filesystem `path` is in.
+    pub fn statfs(&self, path: &str) -> AxResult<FileSystemInfo> {
+        self.lookup_mounted_fs(path, |fs, _| fs.statfs())
+    }
+
     fn lookup_mounted_fs<F, T>(&self, path: &str, f: F) -> AxResult<T>
     where
         F: FnOnce(Arc<dyn VfsOps>, &str) -> AxResult<T>,
@@ -135,14 +160,46 @@ impl VfsNodeOps for RootDirectory {
             }
         })
     }
+
+    fn symlink(&self, path: &str, target: &str) -> VfsResult {
+        self.lookup_mounted_fs(path, |fs, rest_path| {
+            if rest_path.is_empty() {
+                ax_err!(AlreadyExists)
+            } else {
+                fs.root_dir().symlink(rest_path, target)
+            }
+        })
+    }
+
+    fn rename(&self, src_path: &str, dst_path: &str) -> VfsResult {
+        self.lookup_mounted_fs(src_path, |src_fs, src_rest| {
+            if src_rest.is_empty() {
+                return ax_err!(PermissionDenied); // cannot rename mount points
+            }
+            self.lookup_mounted_fs(dst_path, |dst_fs, dst_rest| {
+                if dst_rest.is_empty() {
+                    ax_err!(PermissionDenied) // cannot replace mount points
+                } else if !Arc::ptr_eq(&src_fs, &dst_fs) {
+                    ax_err!(Unsupported, "cannot rename across filesystems")
+                } else {
+                    src_fs.root_dir().rename(src_rest, dst_rest)
+                }
+            })
+        })
+    }
 }
 
 pub(crate) fn init_rootfs(disk: crate::dev::Disk) {
-    #[cfg(feature = "fatfs")]
-    let main_fs = fs::fatfs::FatFileSystem::new(disk);
-
-    MAIN_FS.init_by(Arc::new(main_fs));
-    MAIN_FS.init();
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "ext2fs")] {
+            let main_fs = fs::ext2fs::Ext2FileSystem::new(disk);
+            MAIN_FS.init_by(Arc::new(main_fs));
+        } else if #[cfg(feature = "fatfs")] {
+            let main_fs = fs::fatfs::FatFileSystem::new(disk);
+            MAIN_FS.init_by(Arc::new(main_fs));
+            MAIN_FS.init();
+        }
+    }
 
     let mut root_dir = RootDirectory::new(MAIN_FS.clone());
 
@@ -167,14 +224,6 @@ pub(crate) fn init_rootfs(disk: crate::dev::Disk) {
     *CURRENT_DIR_PATH.lock() = "/".into();
 }
 
-fn parent_node_of(dir: Option<&VfsNodeRef>, path: &str) -> VfsNodeRef {
-    if path.starts_with('/') {
-        ROOT_DIR.clone()
-    } else {
-        dir.cloned().unwrap_or_else(|| CURRENT_DIR.lock().clone())
-    }
-}
-
 pub(crate) fn absolute_path(path: &str) -> AxResult<String> {
     if path.starts_with('/') {
         Ok(axfs_vfs::path::canonicalize(path))
@@ -184,11 +233,79 @@ pub(crate) fn absolute_path(path: &str) -> AxResult<String> {
     }
 }
 
-pub(crate) fn lookup(dir: Option<&VfsNodeRef>, path: &str) -> AxResult<VfsNodeRef> {
+/// Follow the symbolic links in `path`, return the directory to look it up
+/// from and the path without links relative to it, which is absolute if the
+/// directory is the root.
+///
+/// The last component is followed only if `follow_last` is set or `path` ends
+/// with '/', and it may not exist. The current task needs to be allowed to
+/// search every directory on the way.
+fn resolve_path(
+    dir: Option<&VfsNodeRef>,
+    path: &str,
+    follow_last: bool,
+) -> AxResult<(VfsNodeRef, String)> {
+    let follow_last = follow_last || path.ends_with('/');
+    let (mut base, path, mut at_root): (VfsNodeRef, _, _) = match dir {
+        Some(dir) if !path.starts_with('/') => (dir.clone(), String::from(path), false),
+        _ => (ROOT_DIR.clone(), absolute_path(path)?, true),
+    };
+    // components still to resolve, the next one on the top
+    let mut pending: Vec<String> = path.rsplit('/').map(String::from).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    // the nodes `resolved` names, each a directory
+    let mut dirs: Vec<VfsNodeRef> = Vec::new();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        match name.as_str() {
+            "" | "." => continue,
+            ".." if matches!(resolved.last(), Some(last) if last != "..") => {
+                resolved.pop();
+                dirs.pop();
+                continue;
+            }
+            ".." if at_root => continue,
+            _ => {}
+        }
+        check_access(&dirs.last().unwrap_or(&base).get_attr()?, Cap::EXECUTE)?;
+        resolved.push(name);
+        let is_last = pending.iter().all(|name| name.is_empty() || name == ".");
+        if is_last && !follow_last {
+            break;
+        }
+        let node = match base.clone().lookup(&resolved.join("/")) {
+            Ok(node) => node,
+            Err(AxError::NotFound) if is_last => break,
+            Err(e) => return Err(e),
+        };
+        if !node.get_attr()?.is_symlink() {
+            dirs.push(node);
+            continue;
+        }
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return ax_err!(FilesystemLoop);
+        }
+        let target = node.readlink()?;
+        resolved.pop();
+        if target.starts_with('/') {
+            base = ROOT_DIR.clone();
+            at_root = true;
+            resolved.clear();
+            dirs.clear();
+        }
+        pending.extend(target.rsplit('/').map(String::from));
+    }
+    let path = resolved.join("/");
+    Ok((base, if at_root { String::from("/") + &path } else { path }))
+}
+
+fn lookup_at(dir: Option<&VfsNodeRef>, path: &str, follow_last: bool) -> AxResult<VfsNodeRef> {
     if path.is_empty() {
         return ax_err!(NotFound);
     }
-    let node = parent_node_of(dir, path).lookup(path)?;
+    let (base, resolved) = resolve_path(dir, path, follow_last)?;
+    let node = base.lookup(&resolved)?;
     if path.ends_with('/') && !node.get_attr()?.is_dir() {
         ax_err!(NotADirectory)
     } else {
@@ -196,34 +313,106 @@ pub(crate) fn lookup(dir: Option<&VfsNodeRef>, path: &str) -> AxResult<VfsNodeRe
     }
 }
 
-pub(crate) fn create_file(dir: Option<&VfsNodeRef>, path: &str) -> AxResult<VfsNodeRef> {
+pub(crate) fn lookup(dir: Option<&VfsNodeRef>, path: &str) -> AxResult<VfsNodeRef> {
+    lookup_at(dir, path, true)
+}
+
+/// Like [`lookup`], but return the symbolic link itself if `path` names one.
+pub(crate) fn lookup_link(dir: Option<&VfsNodeRef>, path: &str) -> AxResult<VfsNodeRef> {
+    lookup_at(dir, path, false)
+}
+
+/// The directory holding the last component of `path`, as [`resolve_path`]
+/// returns them.
+fn parent_of(base: &VfsNodeRef, path: &str) -> AxResult<VfsNodeRef> {
+    match path.rsplit_once('/') {
+        Some((parent, _)) if !parent.is_empty() => base.clone().lookup(parent),
+        _ => Ok(base.clone()),
+    }
+}
+
+/// Give `node`, just created in a directory with `dir_attr`, to the current
+/// task and set its permission to `perm`.
+///
+/// It belongs to the group of the directory instead if the directory is
+/// setgid, and a new directory is setgid then too. Filesystems without
+/// owners or permissions keep their own.
+fn init_node(dir_attr: &VfsNodeAttr, node: &VfsNodeRef, mut perm: VfsNodePerm) -> AxResult {
+    let cred = axtask::current_cred();
+    let attr = node.get_attr()?;
+    let gid = if dir_attr.perm().contains(VfsNodePerm::SET_GID) {
+        if attr.is_dir() {
+            perm |= VfsNodePerm::SET_GID;
+        }
+        dir_attr.gid()
+    } else {
+        cred.gid()
+    };
+    let unsupported_ok = |res: AxResult| match res {
+        Err(AxError::Unsupported) => Ok(()),
+        res => res,
+    };
+    if attr.uid() != cred.uid() || attr.gid() != gid {
+        unsupported_ok(node.set_owner(Some(cred.uid()), Some(gid)))?;
+    }
+    // links have no permissions of their own
+    if !attr.is_symlink() && attr.perm().bits() != perm.bits() {
+        unsupported_ok(node.set_perm(perm))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn create_file(
+    dir: Option<&VfsNodeRef>,
+    path: &str,
+    perm: VfsNodePerm,
+) -> AxResult<VfsNodeRef> {
     if path.is_empty() {
         return ax_err!(NotFound);
     } else if path.ends_with('/') {
         return ax_err!(NotADirectory);
     }
-    let parent = parent_node_of(dir, path);
-    parent.create(path, VfsNodeType::File)?;
-    parent.lookup(path)
+    // a dangling link creates its target
+    let (base, path) = resolve_path(dir, path, true)?;
+    match base.clone().lookup(&path) {
+        Ok(node) => return Ok(node), // already exists
+        Err(AxError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    let dir_attr = parent_of(&base, &path)?.get_attr()?;
+    check_access(&dir_attr, Cap::WRITE | Cap::EXECUTE)?;
+    base.create(&path, VfsNodeType::File)?;
+    let node = base.lookup(&path)?;
+    init_node(&dir_attr, &node, perm)?;
+    Ok(node)
 }
 
 pub(crate) fn create_dir(dir: Option<&VfsNodeRef>, path: &str) -> AxResult {
-    match lookup(dir, path) {
-        Ok(_) => ax_err!(AlreadyExists),
-        Err(AxError::NotFound) => parent_node_of(dir, path).create(path, VfsNodeType::Dir),
-        Err(e) => Err(e),
+    if path.is_empty() {
+        return ax_err!(NotFound);
+    }
+    let (base, path) = resolve_path(dir, path.trim_end_matches('/'), false)?;
+    match base.clone().lookup(&path) {
+        Ok(_) => return ax_err!(AlreadyExists),
+        Err(AxError::NotFound) => {}
+        Err(e) => return Err(e),
     }
+    let dir_attr = parent_of(&base, &path)?.get_attr()?;
+    check_access(&dir_attr, Cap::WRITE | Cap::EXECUTE)?;
+    base.create(&path, VfsNodeType::Dir)?;
+    let node = base.lookup(&path)?;
+    init_node(&dir_attr, &node, VfsNodePerm::default_dir())
 }
 
 pub(crate) fn remove_file(dir: Option<&VfsNodeRef>, path: &str) -> AxResult {
-    let node = lookup(dir, path)?;
+    let node = lookup_link(dir, path)?;
     let attr = node.get_attr()?;
     if attr.is_dir() {
         ax_err!(IsADirectory)
-    } else if !attr.perm().owner_writable() {
-        ax_err!(PermissionDenied)
     } else {
-        parent_node_of(dir, path).remove(path)
+        let (base, path) = resolve_path(dir, path, false)?;
+        check_remove(&parent_of(&base, &path)?.get_attr()?, &attr)?;
+        base.remove(&path)
     }
 }
 
@@ -241,19 +430,107 @@ pub(crate) fn remove_dir(dir: Option<&VfsNodeRef>, path: &str) -> AxResult {
     {
         return ax_err!(InvalidInput);
     }
-    if ROOT_DIR.contains(&absolute_path(path)?) {
+    let (base, path) = resolve_path(dir, path, false)?;
+    if ROOT_DIR.contains(&path) {
         return ax_err!(PermissionDenied);
     }
 
-    let node = lookup(dir, path)?;
+    let node = base.clone().lookup(&path)?;
     let attr = node.get_attr()?;
     if !attr.is_dir() {
         ax_err!(NotADirectory)
-    } else if !attr.perm().owner_writable() {
-        ax_err!(PermissionDenied)
     } else {
-        parent_node_of(dir, path).remove(path)
+        check_remove(&parent_of(&base, &path)?.get_attr()?, &attr)?;
+        base.remove(&path)
+    }
+}
+
+pub(crate) fn rename(old: &str, new: &str) -> AxResult {
+    if old.is_empty() || new.is_empty() {
+        return ax_err!(NotFound);
+    }
+    for path in [old, new] {
+        let path_check = path.trim_end_matches('/');
+        if path_check.is_empty()
+            || path_check == "."
+            || path_check == ".."
+            || path_check.ends_with("/.")
+            || path_check.ends_with("/..")
+        {
+            return ax_err!(InvalidInput);
+        }
+    }
+    // both are absolute, as they are resolved from the root
+    let (_, old_path) = resolve_path(None, old, false)?;
+    let (_, new_path) = resolve_path(None, new.trim_end_matches('/'), false)?;
+    let attr = lookup_link(None, &old_path)?.get_attr()?;
+    if old.ends_with('/') && !attr.is_dir() {
+        return ax_err!(NotADirectory);
+    }
+    if old_path == new_path {
+        return Ok(());
+    }
+    if new_path.starts_with(&(old_path.clone() + "/")) {
+        return ax_err!(InvalidInput); // move a directory into itself
+    }
+    let root: VfsNodeRef = ROOT_DIR.clone();
+    check_remove(&parent_of(&root, &old_path)?.get_attr()?, &attr)?;
+    let new_dir_attr = parent_of(&root, &new_path)?.get_attr()?;
+    match lookup_link(None, &new_path) {
+        Ok(node) => check_remove(&new_dir_attr, &node.get_attr()?)?,
+        Err(AxError::NotFound) => check_access(&new_dir_attr, Cap::WRITE | Cap::EXECUTE)?,
+        Err(e) => return Err(e),
+    }
+    ROOT_DIR.rename(&old_path, &new_path)
+}
+
+pub(crate) fn statfs(path: &str) -> AxResult<FileSystemInfo> {
+    // absolute, as it is resolved from the root
+    let (_, path) = resolve_path(None, path, true)?;
+    lookup(None, &path)?;
+    ROOT_DIR.statfs(&path)
+}
+
+pub(crate) fn sync() -> AxResult {
+    ROOT_DIR.sync()
+}
+
+pub(crate) fn read_link(dir: Option<&VfsNodeRef>, path: &str) -> AxResult<String> {
+    lookup_link(dir, path)?.readlink()
+}
+
+pub(crate) fn symlink(dir: Option<&VfsNodeRef>, target: &str, path: &str) -> AxResult {
+    if path.is_empty() {
+        return ax_err!(NotFound);
+    }
+    let (base, path) = resolve_path(dir, path.trim_end_matches('/'), false)?;
+    let dir_attr = parent_of(&base, &path)?.get_attr()?;
+    check_access(&dir_attr, Cap::WRITE | Cap::EXECUTE)?;
+    base.symlink(&path, target)?;
+    let node = base.lookup(&path)?;
+    init_node(&dir_attr, &node, node.get_attr()?.perm())
+}
+
+pub(crate) fn set_perm(path: &str, perm: VfsNodePerm) -> AxResult {
+    let node = lookup(None, path)?;
+    check_owner(&node.get_attr()?)?;
+    node.set_perm(perm)
+}
+
+/// Change the owner and group of the node at `path`. Only root may give a
+/// node away, its owner may change its group to one they are in.
+pub(crate) fn set_owner(path: &str, uid: Option<u32>, gid: Option<u32>) -> AxResult {
+    let node = lookup(None, path)?;
+    let attr = node.get_attr()?;
+    let cred = axtask::current_cred();
+    let allowed = cred.is_root()
+        || (cred.uid() == attr.uid()
+            && !matches!(uid, Some(uid) if uid != attr.uid())
+            && !matches!(gid, Some(gid) if !cred.in_group(gid)));
+    if !allowed {
+        return ax_err!(PermissionDenied);
     }
+    node.set_owner(uid, gid)
 }
 
 pub(crate) fn current_dir() -> AxResult<String> {
@@ -261,7 +538,7 @@ pub(crate) fn current_dir() -> AxResult<String> {
 }
 
 pub(crate) fn set_current_dir(path: &str) -> AxResult {
-    let mut abs_path = absolute_path(path)?;
+    let (_, mut abs_path) = resolve_path(None, path, true)?;
     if !abs_path.ends_with('/') {
         abs_path += "/";
     }
@@ -275,9 +552,8 @@ pub(crate) fn set_current_dir(path: &str) -> AxResult {
     let attr = node.get_attr()?;
     if !attr.is_dir() {
         ax_err!(NotADirectory)
-    } else if !attr.perm().owner_executable() {
-        ax_err!(PermissionDenied)
     } else {
+        check_access(&attr, Cap::EXECUTE)?;
         *CURRENT_DIR.lock() = node;
         *CURRENT_DIR_PATH.lock() = abs_path;
         Ok(())
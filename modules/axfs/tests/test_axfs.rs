@@ -1,4 +1,4 @@
-#![cfg(not(feature = "use-virtio-blk"))]
+#![cfg(not(any(feature = "use-virtio-blk", feature = "ext2fs")))]
 
 use axfs::api as fs;
 use axio as io;
@@ -177,6 +177,45 @@ fn test_remove_file_dir() -> Result<()> {
     Ok(())
 }
 
+fn test_rename() -> Result<()> {
+    // rename a file in the same directory
+    let fname = "/very/old.txt";
+    println!("test rename file {:?}:", fname);
+    fs::write(fname, "rename me\n")?;
+    fs::rename(fname, "/very/new.txt")?;
+    assert_err!(fs::metadata(fname), NotFound);
+    assert_eq!(fs::read_to_string("/very/new.txt")?, "rename me\n");
+
+    // move it to another directory, replacing an existing file
+    fs::write("/victim.txt", "replaced\n")?;
+    fs::rename("very/./new.txt", "/victim.txt")?;
+    assert_err!(fs::metadata("/very/new.txt"), NotFound);
+    assert_eq!(fs::read_to_string("/victim.txt")?, "rename me\n");
+
+    // move a directory with its contents
+    let dirname = "/from";
+    println!("test rename dir {:?}:", dirname);
+    fs::create_dir("/from")?;
+    fs::create_dir("/from/sub")?;
+    fs::write("/from/sub/file.txt", "moved\n")?;
+    fs::rename(dirname, "/very/to")?;
+    assert_err!(fs::metadata(dirname), NotFound);
+    assert_eq!(fs::read_to_string("/very/to/sub/file.txt")?, "moved\n");
+
+    // error cases
+    assert_err!(fs::rename("/very/to", "/very/to/sub/inner"), InvalidInput);
+    assert_err!(fs::rename("/very/.", "/x"), InvalidInput);
+    assert_err!(fs::rename("/not/exist", "/x"), NotFound);
+    assert_err!(fs::rename("/victim.txt", "/very"), IsADirectory);
+    assert_err!(fs::rename("/very/to", "/victim.txt"), NotADirectory);
+    assert_err!(fs::rename("/very/to", "/a"), DirectoryNotEmpty);
+    assert_err!(fs::rename("/dev", "/devices"), PermissionDenied);
+    assert_err!(fs::rename("/dev/zero", "/zero"), Unsupported);
+
+    println!("test_rename() OK!");
+    Ok(())
+}
+
 fn test_devfs() -> Result<()> {
     const N: usize = 32;
     let mut buf = [1; N];
@@ -257,5 +296,6 @@ fn test_axfs() {
     test_file_permission().expect("test_file_permission() failed");
     test_create_file_dir().expect("test_create_file_dir() failed");
     test_remove_file_dir().expect("test_remove_file_dir() failed");
+    test_rename().expect("test_rename() failed");
     test_devfs().expect("test_devfs() failed");
 }
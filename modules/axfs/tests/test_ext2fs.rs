@@ -0,0 +1,442 @@
+#![cfg(all(feature = "ext2fs", not(feature = "use-virtio-blk")))]
+
+use std::sync::{Arc, Mutex};
+
+use axfs::api as fs;
+use axio as io;
+
+use driver_block::ramdisk::RamDisk;
+use ext2fs::{BlockDevice, Ext2FileSystem, Ext2Result, ZeroTimeProvider, EXT2_S_IFDIR, EXT2_S_IFREG};
+use axtask::Credentials;
+use fs::{FallocMode, File, FileType, OpenOptions, Permissions};
+use io::{prelude::*, Error, Result};
+
+const DISK_SIZE: usize = 16 * 1024 * 1024;
+const BLOCK_SIZE: usize = 512;
+
+macro_rules! assert_err {
+    ($expr: expr) => {
+        assert!(($expr).is_err())
+    };
+    ($expr: expr, $err: ident) => {
+        assert_eq!(($expr).err(), Some(Error::$err))
+    };
+}
+
+struct MemDisk(Mutex<Vec<u8>>);
+
+impl BlockDevice for MemDisk {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Ext2Result {
+        let data = self.0.lock().unwrap();
+        buf.copy_from_slice(&data[block_id * BLOCK_SIZE..(block_id + 1) * BLOCK_SIZE]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Ext2Result {
+        let mut data = self.0.lock().unwrap();
+        data[block_id * BLOCK_SIZE..(block_id + 1) * BLOCK_SIZE].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_num(&self) -> usize {
+        self.0.lock().unwrap().len() / BLOCK_SIZE
+    }
+}
+
+fn make_disk() -> RamDisk {
+    let disk = Arc::new(MemDisk(Mutex::new(vec![0; DISK_SIZE])));
+    // 4 KiB file system blocks on a disk of 512-byte sectors
+    let efs = Ext2FileSystem::create_with_block_size(disk.clone(), Arc::new(ZeroTimeProvider), 4096).unwrap();
+    let root = Ext2FileSystem::root_inode(&efs).unwrap();
+    let short = root.create("short.txt", EXT2_S_IFREG).unwrap();
+    short.write_at(0, b"Rust is cool!\n").unwrap();
+    let very = root.create("very", EXT2_S_IFDIR).unwrap();
+    let long = very.create("long", EXT2_S_IFDIR).unwrap();
+    let path = long.create("path", EXT2_S_IFDIR).unwrap();
+    let test = path.create("test.txt", EXT2_S_IFREG).unwrap();
+    test.write_at(0, b"Rust is cool!\n").unwrap();
+    efs.sync().unwrap();
+
+    let data = disk.0.lock().unwrap();
+    RamDisk::from(&data)
+}
+
+fn test_read_write_file() -> Result<()> {
+    let fname = "///very/long//.././long//./path/./test.txt";
+    println!("read and write file {:?}:", fname);
+
+    // read and write
+    let mut file = File::options().read(true).write(true).open(fname)?;
+    let file_size = file.metadata()?.len();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    assert_eq!(contents, "Rust is cool!\n");
+    assert_eq!(contents.len(), file_size as usize);
+    assert_eq!(file.write(b"Hello, world!\n")?, 14); // append
+    drop(file);
+
+    // read again and check
+    let new_contents = fs::read_to_string(fname)?;
+    assert_eq!(new_contents, contents + "Hello, world!\n");
+
+    // append and check
+    let mut file = OpenOptions::new().append(true).open(fname)?;
+    assert_eq!(file.write(b"new line\n")?, 9);
+    drop(file);
+
+    let new_contents2 = fs::read_to_string(fname)?;
+    assert_eq!(new_contents2, new_contents + "new line\n");
+
+    // truncate and check
+    let file = OpenOptions::new().write(true).open(fname)?;
+    file.set_len(4)?;
+    assert_eq!(file.metadata()?.len(), 4);
+    drop(file);
+    assert_eq!(fs::read_to_string(fname)?, "Rust");
+
+    // open a non-exist file
+    assert_err!(File::open("/not/exist/file"), NotFound);
+
+    println!("test_read_write_file() OK!");
+    Ok(())
+}
+
+fn test_read_dir() -> Result<()> {
+    let dir = "/././//./";
+    println!("list directory {:?}:", dir);
+    let dirents = fs::read_dir(dir)?
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    println!("dirents = {:?}", dirents);
+    assert!(dirents.contains(&"short.txt".into()));
+    assert!(dirents.contains(&"very".into()));
+    assert!(fs::metadata("/very")?.is_dir());
+    assert!(fs::metadata("/short.txt")?.is_file());
+    println!("test_read_dir() OK!");
+    Ok(())
+}
+
+fn test_create_file_dir() -> Result<()> {
+    // create a file and test existence
+    let fname = "././/very/long/..///new-file.txt";
+    println!("test create file {:?}:", fname);
+    assert_err!(fs::metadata(fname), NotFound);
+    let contents = "create a new file!\n";
+    fs::write(fname, contents)?;
+
+    let dirents = fs::read_dir("./very")?
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    println!("dirents = {:?}", dirents);
+    assert!(dirents.contains(&"new-file.txt".into()));
+    assert_eq!(fs::read_to_string(fname)?, contents);
+    assert_err!(File::create_new(fname), AlreadyExists);
+
+    // create a directory and test existence
+    let dirname = "///././/very//.//long/./new-dir";
+    println!("test create dir {:?}:", dirname);
+    assert_err!(fs::metadata(dirname), NotFound);
+    fs::create_dir(dirname)?;
+
+    let md = fs::metadata(dirname)?;
+    assert_eq!(md.file_type(), FileType::Dir);
+    assert_err!(fs::create_dir(dirname), AlreadyExists);
+    fs::create_dir("/a")?;
+    fs::create_dir("/a/b")?;
+    fs::create_dir("/a/b/c")?;
+    assert!(fs::metadata("/a/b/c")?.is_dir());
+
+    println!("test_create_file_dir() OK!");
+    Ok(())
+}
+
+fn test_remove_file_dir() -> Result<()> {
+    // remove a file and test existence
+    let fname = "//very/long/..///new-file.txt";
+    println!("test remove file {:?}:", fname);
+    assert_err!(fs::remove_dir(fname), NotADirectory);
+    assert!(fs::remove_file(fname).is_ok());
+    assert_err!(fs::metadata(fname), NotFound);
+    assert_err!(fs::remove_file(fname), NotFound);
+
+    // remove a directory and test existence
+    let dirname = "very//.//long/../long/.//./new-dir////";
+    println!("test remove dir {:?}:", dirname);
+    assert_err!(fs::remove_file(dirname), IsADirectory);
+    assert!(fs::remove_dir(dirname).is_ok());
+    assert_err!(fs::metadata(dirname), NotFound);
+
+    // error cases
+    assert_err!(fs::remove_dir("/a/b"), DirectoryNotEmpty);
+    assert_err!(fs::remove_dir("/very/."), InvalidInput);
+    assert_err!(fs::remove_file("short.txt/"), NotADirectory);
+
+    println!("test_remove_file_dir() OK!");
+    Ok(())
+}
+
+fn test_rename() -> Result<()> {
+    // rename a file in the same directory
+    let fname = "/very/old.txt";
+    println!("test rename file {:?}:", fname);
+    fs::write(fname, "rename me\n")?;
+    fs::rename(fname, "/very/new.txt")?;
+    assert_err!(fs::metadata(fname), NotFound);
+    assert_eq!(fs::read_to_string("/very/new.txt")?, "rename me\n");
+
+    // move it to another directory, replacing an existing file
+    fs::write("/victim.txt", "replaced\n")?;
+    fs::rename("very/./new.txt", "/victim.txt")?;
+    assert_err!(fs::metadata("/very/new.txt"), NotFound);
+    assert_eq!(fs::read_to_string("/victim.txt")?, "rename me\n");
+
+    // move a directory with its contents
+    let dirname = "/from";
+    println!("test rename dir {:?}:", dirname);
+    fs::create_dir("/from")?;
+    fs::create_dir("/from/sub")?;
+    fs::write("/from/sub/file.txt", "moved\n")?;
+    fs::rename(dirname, "/very/to")?;
+    assert_err!(fs::metadata(dirname), NotFound);
+    assert_eq!(fs::read_to_string("/very/to/sub/file.txt")?, "moved\n");
+    // its '..' follows it
+    assert_eq!(fs::read_to_string("/very/to/sub/../../to/sub/file.txt")?, "moved\n");
+
+    // error cases
+    assert_err!(fs::rename("/very/to", "/very/to/sub/inner"), InvalidInput);
+    assert_err!(fs::rename("/very/.", "/x"), InvalidInput);
+    assert_err!(fs::rename("/not/exist", "/x"), NotFound);
+    assert_err!(fs::rename("/victim.txt", "/very"), IsADirectory);
+    assert_err!(fs::rename("/very/to", "/victim.txt"), NotADirectory);
+    assert_err!(fs::rename("/very/to", "/a"), DirectoryNotEmpty);
+    assert_err!(fs::rename("/dev", "/devices"), PermissionDenied);
+    assert_err!(fs::rename("/dev/zero", "/zero"), Unsupported);
+
+    println!("test_rename() OK!");
+    Ok(())
+}
+
+fn test_symlink() -> Result<()> {
+    // a relative link resolves from its own directory
+    println!("test symlinks:");
+    fs::soft_link("long/path", "/very/to-path")?;
+    assert_eq!(fs::read_link("/very/to-path")?, "long/path");
+    assert_eq!(fs::read_to_string("/very/to-path/test.txt")?, "Rust");
+    assert!(fs::metadata("/very/to-path")?.is_dir());
+    assert!(fs::symlink_metadata("/very/to-path")?.is_symlink());
+    assert!(!fs::symlink_metadata("/very/long")?.is_symlink());
+    fs::soft_link("../short.txt", "/very/up")?;
+    assert_eq!(fs::read_to_string("very/up")?, "Rust is cool!\n");
+    assert_err!(fs::read_link("/very/long"), InvalidInput);
+    assert_err!(fs::soft_link("x", "/short.txt"), AlreadyExists);
+
+    // a long target, and links to links across a mount point
+    let long_target = format!("/very/long/{}path/test.txt", "./".repeat(30));
+    fs::soft_link(&long_target, "/far")?;
+    assert_eq!(fs::read_link("/far")?, long_target);
+    assert_eq!(fs::read_to_string("/far")?, "Rust");
+    fs::soft_link("/dev", "/mnt")?;
+    fs::soft_link("mnt/zero", "/zero")?;
+    let mut buf = [1; 8];
+    assert_eq!(File::open("/zero")?.read(&mut buf)?, 8);
+    assert_eq!(buf, [0; 8]);
+
+    // the working directory is kept without links
+    fs::set_current_dir("/very/to-path")?;
+    assert_eq!(fs::current_dir()?, "/very/long/path/");
+    assert_eq!(fs::read_to_string("test.txt")?, "Rust");
+    fs::set_current_dir("/")?;
+
+    // writing through a dangling link creates its target
+    fs::soft_link("created.txt", "/dangling")?;
+    assert_err!(fs::metadata("/dangling"), NotFound);
+    fs::write("/dangling", "through a link\n")?;
+    assert_eq!(fs::read_to_string("/created.txt")?, "through a link\n");
+
+    // renaming and removing a link leave its target alone
+    fs::rename("/very/up", "/very/up2")?;
+    fs::remove_file("/very/up2")?;
+    assert_err!(fs::symlink_metadata("/very/up2"), NotFound);
+    assert_eq!(fs::read_to_string("/short.txt")?, "Rust is cool!\n");
+    assert_err!(fs::remove_dir("/very/to-path"), NotADirectory);
+
+    // loops are cut short
+    fs::soft_link("loop2", "/loop1")?;
+    fs::soft_link("loop1", "/loop2")?;
+    assert_err!(fs::metadata("/loop1"), FilesystemLoop);
+    assert_err!(fs::read_to_string("/loop2/file"), FilesystemLoop);
+    assert!(fs::symlink_metadata("/loop1")?.is_symlink());
+
+    println!("test_symlink() OK!");
+    Ok(())
+}
+
+fn test_xattr() -> Result<()> {
+    println!("test extended attributes:");
+    fs::set_xattr("/short.txt", "user.checksum", b"0x1234")?;
+    fs::set_xattr("/very", "security.label", b"top")?;
+    assert_eq!(fs::get_xattr("/short.txt", "user.checksum")?, b"0x1234");
+    // symlinks are followed
+    fs::soft_link("short.txt", "/to-short")?;
+    assert_eq!(fs::list_xattr("/to-short")?, ["user.checksum"]);
+    assert_eq!(fs::list_xattr("/very")?, ["security.label"]);
+    assert_err!(fs::get_xattr("/short.txt", "user.missing"), NotFound);
+    assert_err!(fs::set_xattr("/short.txt", "unknown.name", b""), Unsupported);
+    assert_err!(fs::set_xattr("/short.txt", "user.big", &[0; 8192]), StorageFull);
+    // devfs has no extended attributes
+    assert_err!(fs::get_xattr("/dev/zero", "user.checksum"), Unsupported);
+    fs::remove_xattr("/short.txt", "user.checksum")?;
+    assert_eq!(fs::list_xattr("/short.txt")?, Vec::<String>::new());
+    fs::remove_file("/to-short")?;
+
+    println!("test_xattr() OK!");
+    Ok(())
+}
+
+fn test_sparse() -> Result<()> {
+    println!("test sparse files:");
+    let path = "/sparse.bin";
+    // growing far past the end of the disk leaves a hole
+    File::create(path)?.set_len(1 << 30)?;
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(b"end")?;
+    assert_eq!(fs::metadata(path)?.len(), (1 << 30) + 3);
+    assert!(fs::metadata(path)?.blocks() < 64);
+    let mut buf = [1; 8];
+    File::open(path)?.read_exact(&mut buf)?;
+    assert_eq!(buf, [0; 8]);
+
+    file.allocate(FallocMode::KEEP_SIZE, 0, 64 * 1024)?;
+    let blocks = fs::metadata(path)?.blocks();
+    assert!(blocks >= 128);
+    assert_err!(file.allocate(FallocMode::PUNCH_HOLE, 0, 4096), InvalidInput);
+    file.allocate(FallocMode::KEEP_SIZE | FallocMode::PUNCH_HOLE, 0, 64 * 1024)?;
+    assert!(fs::metadata(path)?.blocks() <= blocks - 128);
+    assert_eq!(fs::metadata(path)?.len(), (1 << 30) + 3);
+    fs::remove_file(path)?;
+
+    println!("test_sparse() OK!");
+    Ok(())
+}
+
+fn test_statfs() -> Result<()> {
+    println!("test statfs:");
+    let info = fs::statfs("/")?;
+    assert_eq!(info.fs_type(), 0xef53);
+    assert_eq!(info.block_size(), 4096);
+    assert_eq!(info.blocks(), (DISK_SIZE / 4096) as u64);
+    assert!(info.avail_blocks() <= info.free_blocks() && info.free_blocks() < info.blocks());
+    assert!(info.free_files() < info.files());
+    assert_eq!(info.name_len(), 255);
+    assert!(!info.flags().contains(fs::MountFlags::RDONLY));
+
+    // files count where they are, through links too
+    fs::write("/statfs.bin", vec![1; 64 * 1024])?;
+    let used = fs::statfs("/very/long/path/test.txt")?;
+    // 16 data blocks and an indirect one
+    assert_eq!(used.free_blocks(), info.free_blocks() - 17);
+    assert_eq!(used.free_files(), info.free_files() - 1);
+    fs::soft_link("/dev/zero", "/zero.link")?;
+    assert_eq!(fs::statfs("/zero.link")?.fs_type(), 0x1373);
+    assert_err!(fs::statfs("/statfs.none"), NotFound);
+    fs::remove_file("/zero.link")?;
+    fs::remove_file("/statfs.bin")?;
+
+    println!("test_statfs() OK!");
+    Ok(())
+}
+
+fn test_permissions() -> Result<()> {
+    println!("test permissions:");
+    let alice = Credentials::new(1000, 1000);
+    let bob = Credentials::new(1001, 1001).with_groups(&[1000]);
+    let mode = Permissions::from_bits_truncate;
+    // a sticky directory anyone may write, and a setgid home the group may search
+    fs::create_dir("/tmp")?;
+    fs::set_permissions("/tmp", mode(0o1777))?;
+    fs::create_dir("/home")?;
+    fs::create_dir("/home/alice")?;
+    fs::chown("/home/alice", Some(1000), Some(1000))?;
+    fs::set_permissions("/home/alice", mode(0o2750))?;
+
+    axtask::set_current_cred(alice);
+    assert_err!(fs::create_dir("/home/bob"), PermissionDenied);
+    fs::write("/home/alice/notes", "hello")?;
+    let meta = fs::metadata("/home/alice/notes")?;
+    assert_eq!((meta.uid(), meta.gid()), (1000, 1000));
+    fs::create_dir("/home/alice/sub")?;
+    assert!(fs::metadata("/home/alice/sub")?.permissions().contains(Permissions::SET_GID));
+    OpenOptions::new().write(true).create(true).mode(0o600).open("/home/alice/secret")?;
+    assert_err!(OpenOptions::new().execute(true).open("/home/alice/notes"), PermissionDenied);
+    assert_err!(fs::chown("/home/alice/notes", Some(1001), None), PermissionDenied);
+    fs::write("/tmp/alice", "a")?;
+
+    // bob reads through the group, but may not change the directory
+    axtask::set_current_cred(bob);
+    assert_eq!(fs::read_to_string("/home/alice/notes")?, "hello");
+    assert_err!(File::open("/home/alice/secret"), PermissionDenied);
+    assert_err!(fs::remove_file("/home/alice/notes"), PermissionDenied);
+    assert_err!(fs::set_permissions("/home/alice/notes", mode(0o777)), PermissionDenied);
+    fs::write("/tmp/bob", "b")?;
+    assert_err!(fs::remove_file("/tmp/alice"), PermissionDenied);
+    assert_err!(fs::rename("/tmp/alice", "/tmp/mine"), PermissionDenied);
+
+    // others may not even search the home
+    axtask::set_current_cred(Credentials::new(1002, 1002));
+    assert_err!(fs::read_dir("/home/alice"), PermissionDenied);
+    assert_err!(fs::metadata("/home/alice/notes"), PermissionDenied);
+    assert_err!(fs::set_current_dir("/home/alice/sub"), PermissionDenied);
+
+    axtask::set_current_cred(alice);
+    assert_err!(fs::remove_file("/tmp/bob"), PermissionDenied);
+    fs::remove_file("/tmp/alice")?;
+
+    // root passes the checks, but executes only what someone may
+    axtask::set_current_cred(Credentials::ROOT);
+    assert_err!(OpenOptions::new().execute(true).open("/home/alice/notes"), PermissionDenied);
+    fs::set_permissions("/home/alice/notes", mode(0o744))?;
+    OpenOptions::new().execute(true).open("/home/alice/notes")?;
+    fs::remove_file("/tmp/bob")?;
+
+    println!("test_permissions() OK!");
+    Ok(())
+}
+
+fn test_devfs() -> Result<()> {
+    // devfs is mounted on a directory created in the ext2 root
+    let dirents = fs::read_dir("/")?
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert!(dirents.contains(&"dev".into()));
+
+    let mut buf = [1; 32];
+    let mut file = File::open("/dev/zero")?;
+    assert_eq!(file.read(&mut buf)?, 32);
+    assert_eq!(buf, [0; 32]);
+
+    println!("test_devfs() OK!");
+    Ok(())
+}
+
+#[test]
+fn test_ext2fs() {
+    axtask::init_scheduler(); // call this to use `axsync::Mutex`.
+
+    axfs::init_filesystems(make_disk());
+
+    test_read_write_file().expect("test_read_write_file() failed");
+    test_read_dir().expect("test_read_dir() failed");
+    test_create_file_dir().expect("test_create_file_dir() failed");
+    test_remove_file_dir().expect("test_remove_file_dir() failed");
+    test_rename().expect("test_rename() failed");
+    test_symlink().expect("test_symlink() failed");
+    test_xattr().expect("test_xattr() failed");
+    test_sparse().expect("test_sparse() failed");
+    test_statfs().expect("test_statfs() failed");
+    test_permissions().expect("test_permissions() failed");
+    test_devfs().expect("test_devfs() failed");
+}
@@ -0,0 +1,65 @@
+/// Most supplementary groups a task can be in
+pub const NGROUPS_MAX: usize = 32;
+
+/// Who a task acts on behalf of: its user and group IDs, and the
+/// supplementary groups it is in.
+///
+/// Files and other objects a task accesses are checked against them. A new
+/// task gets the credentials of the task spawning it, the first task is root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    uid: u32,
+    gid: u32,
+    ngroups: usize,
+    groups: [u32; NGROUPS_MAX],
+}
+
+impl Credentials {
+    /// The super user, who passes most permission checks
+    pub const ROOT: Self = Self::new(0, 0);
+
+    /// Credentials of user `uid` in group `gid`, without supplementary groups
+    pub const fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            ngroups: 0,
+            groups: [0; NGROUPS_MAX],
+        }
+    }
+
+    /// Set the supplementary groups, at most [`NGROUPS_MAX`] of them are kept
+    pub fn with_groups(mut self, groups: &[u32]) -> Self {
+        self.ngroups = groups.len().min(NGROUPS_MAX);
+        self.groups[..self.ngroups].copy_from_slice(&groups[..self.ngroups]);
+        self
+    }
+
+    pub const fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub const fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// The supplementary groups
+    pub fn groups(&self) -> &[u32] {
+        &self.groups[..self.ngroups]
+    }
+
+    pub const fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether `gid` is the group or one of the supplementary groups
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups().contains(&gid)
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::ROOT
+    }
+}
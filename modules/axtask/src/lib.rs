@@ -4,6 +4,10 @@
 #[macro_use]
 extern crate log;
 
+mod cred;
+
+pub use self::cred::{Credentials, NGROUPS_MAX};
+
 struct KernelGuardIfImpl;
 
 #[crate_interface::impl_interface]
@@ -113,6 +117,16 @@ pub fn exit(exit_code: i32) -> ! {
     RUN_QUEUE.lock().exit_current(exit_code)
 }
 
+/// Credentials of the current task, root before any task runs
+pub fn current_cred() -> Credentials {
+    current_may_uninit().map_or(Credentials::ROOT, |curr| curr.cred())
+}
+
+/// Change the credentials of the current task, tasks it spawns later get them
+pub fn set_current_cred(cred: Credentials) {
+    current().set_cred(cred);
+}
+
 } else { // if #[cfg(feature = "multitask")]
 
 pub fn yield_now() {
@@ -135,6 +149,18 @@ pub fn sleep_until(deadline: axhal::time::TimeValue) {
     }
 }
 
+static CRED: spinlock::SpinNoIrq<Credentials> = spinlock::SpinNoIrq::new(Credentials::ROOT);
+
+/// Credentials of the only task
+pub fn current_cred() -> Credentials {
+    *CRED.lock()
+}
+
+/// Change the credentials of the only task
+pub fn set_current_cred(cred: Credentials) {
+    *CRED.lock() = cred;
+}
+
 } // else
 } // cfg_if::cfg_if!
 
@@ -8,8 +8,9 @@ use core::sync::atomic::AtomicUsize;
 
 use axhal::arch::TaskContext;
 use memory_addr::{align_up_4k, VirtAddr};
+use spinlock::SpinNoIrq;
 
-use crate::{AxTask, AxTaskRef};
+use crate::{AxTask, AxTaskRef, Credentials};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct TaskId(u64);
@@ -42,6 +43,8 @@ pub struct TaskInner {
 
     kstack: Option<TaskStack>,
     ctx: UnsafeCell<TaskContext>,
+
+    cred: SpinNoIrq<Credentials>,
 }
 
 impl TaskId {
@@ -82,6 +85,15 @@ impl TaskInner {
     pub fn id_name(&self) -> alloc::string::String {
         alloc::format!("Task({}, {:?})", self.id.as_u64(), self.name)
     }
+
+    /// Who the task acts on behalf of
+    pub fn cred(&self) -> Credentials {
+        *self.cred.lock()
+    }
+
+    pub(crate) fn set_cred(&self, cred: Credentials) {
+        *self.cred.lock() = cred;
+    }
 }
 
 // private methods
@@ -102,6 +114,7 @@ impl TaskInner {
             preempt_disable_count: AtomicUsize::new(0),
             kstack: None,
             ctx: UnsafeCell::new(TaskContext::new()),
+            cred: SpinNoIrq::new(Credentials::ROOT),
         }
     }
 
@@ -111,6 +124,7 @@ impl TaskInner {
     {
         let mut t = Self::new_common(TaskId::new(), name);
         debug!("new task: {}", t.id_name());
+        t.cred = SpinNoIrq::new(crate::current_cred());
         let kstack = TaskStack::alloc(align_up_4k(stack_size));
         t.entry = Some(Box::into_raw(Box::new(entry)));
         t.ctx.get_mut().init(task_entry as usize, kstack.top());
@@ -13,6 +13,7 @@ else
 endif
 
 features-$(FS) += libax/fs
+features-$(EXT2FS) += libax/ext2fs
 features-$(NET) += libax/net
 features-$(GRAPHIC) += libax/display
 
@@ -3,4 +3,5 @@
 define unittest
   cargo test -p percpu $(1) -- --nocapture
   cargo test --workspace --exclude "arceos-*" --exclude "libax_bindings" $(1) -- --nocapture
+  cargo test -p axfs --features ext2fs $(1) -- --nocapture
 endef
@@ -0,0 +1,29 @@
+#ifndef __SYS_STATVFS_H__
+#define __SYS_STATVFS_H__
+
+#include <sys/types.h>
+
+#define ST_RDONLY 1 /* read-only */
+#define ST_NOSUID 2 /* set-user-ID and set-group-ID bits are ignored */
+#define ST_NODEV  4 /* device files cannot be opened */
+#define ST_NOEXEC 8 /* files cannot be executed */
+
+struct statvfs {
+    unsigned long f_bsize;   /* filesystem block size */
+    unsigned long f_frsize;  /* fragment size */
+    fsblkcnt_t f_blocks;     /* size of fs in f_frsize units */
+    fsblkcnt_t f_bfree;      /* number of free blocks */
+    fsblkcnt_t f_bavail;     /* number of free blocks for unprivileged users */
+    fsfilcnt_t f_files;      /* number of inodes */
+    fsfilcnt_t f_ffree;      /* number of free inodes */
+    fsfilcnt_t f_favail;     /* number of free inodes for unprivileged users */
+    unsigned long f_fsid;    /* filesystem ID */
+    unsigned long f_flag;    /* mount flags */
+    unsigned long f_namemax; /* maximum filename length */
+};
+
+#ifdef AX_CONFIG_FS
+int statvfs(const char *path, struct statvfs *buf);
+#endif
+
+#endif // __SYS_STATVFS_H__
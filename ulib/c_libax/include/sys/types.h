@@ -23,4 +23,7 @@ typedef int blkcnt_t;
 
 typedef unsigned int __off_t;
 
+typedef unsigned long long fsblkcnt_t;
+typedef unsigned long long fsfilcnt_t;
+
 #endif // __SYS_TYPES_H__
@@ -0,0 +1,45 @@
+//! Filesystem calls of the C user program.
+
+use axerrno::LinuxError;
+use core::ffi::{c_char, c_int, c_ulong, CStr};
+
+/// Attributes of a filesystem, the fields of `struct statvfs`.
+#[repr(C)]
+pub struct AxStatVfs {
+    pub bsize: c_ulong,
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub fsid: c_ulong,
+    pub flag: c_ulong,
+    pub namemax: c_ulong,
+}
+
+/// Get the attributes of the filesystem that holds `path`.
+///
+/// Returns 0 on success, or a negative Linux error code.
+#[no_mangle]
+pub unsafe extern "C" fn ax_statvfs(path: *const c_char, buf: *mut AxStatVfs) -> c_int {
+    let Ok(path) = CStr::from_ptr(path).to_str() else {
+        return LinuxError::EINVAL.code();
+    };
+    match libax::fs::statfs(path) {
+        Ok(info) => {
+            *buf = AxStatVfs {
+                bsize: info.block_size() as _,
+                blocks: info.blocks(),
+                bfree: info.free_blocks(),
+                bavail: info.avail_blocks(),
+                files: info.files(),
+                ffree: info.free_files(),
+                fsid: info.fsid() as _,
+                flag: info.flags().bits() as _,
+                namemax: info.name_len() as _,
+            };
+            0
+        }
+        Err(e) => LinuxError::from(e).code(),
+    }
+}
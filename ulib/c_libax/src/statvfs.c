@@ -0,0 +1,30 @@
+#include <errno.h>
+#include <sys/statvfs.h>
+
+#include <libax.h>
+
+#ifdef AX_CONFIG_FS
+
+int statvfs(const char *path, struct statvfs *buf)
+{
+    struct AxStatVfs st;
+    int ret = ax_statvfs(path, &st);
+    if (ret < 0) {
+        errno = -ret;
+        return -1;
+    }
+    buf->f_bsize = st.bsize;
+    buf->f_frsize = st.bsize;
+    buf->f_blocks = st.blocks;
+    buf->f_bfree = st.bfree;
+    buf->f_bavail = st.bavail;
+    buf->f_files = st.files;
+    buf->f_ffree = st.ffree;
+    buf->f_favail = st.ffree;
+    buf->f_fsid = st.fsid;
+    buf->f_flag = st.flag;
+    buf->f_namemax = st.namemax;
+    return 0;
+}
+
+#endif
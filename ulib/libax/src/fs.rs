@@ -1,3 +1,7 @@
 pub use axfs::api::{canonicalize, metadata, read, read_to_string, remove_file, write};
-pub use axfs::api::{create_dir, create_dir_all, read_dir, remove_dir};
-pub use axfs::api::{DirEntry, File, FileType, Metadata, OpenOptions, Permissions, ReadDir};
+pub use axfs::api::{chown, set_permissions};
+pub use axfs::api::{read_link, soft_link, symlink_metadata};
+pub use axfs::api::{get_xattr, list_xattr, remove_xattr, set_xattr};
+pub use axfs::api::{create_dir, create_dir_all, read_dir, remove_dir, rename, statfs, sync};
+pub use axfs::api::{DirEntry, FallocMode, File, FileType, Metadata, OpenOptions, Permissions, ReadDir};
+pub use axfs::api::{FileSystemInfo, MountFlags};
@@ -1,5 +1,7 @@
 #[cfg(feature = "multitask")]
 pub use axtask::{current, exit, sleep, sleep_until, spawn, yield_now, TaskId};
+#[cfg(feature = "multitask")]
+pub use axtask::{current_cred, set_current_cred, Credentials};
 
 #[cfg(not(feature = "multitask"))]
 pub fn yield_now() {